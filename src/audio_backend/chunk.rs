@@ -15,9 +15,42 @@ pub struct SamplesChunk {
     pub data: Arc<[f32; CHUNK_SIZE]>,
     pub song_id: SongID,
     pub last_chunk: bool,
+    /// chunks of an older generation than the source control are faded out and dropped by the source, e.g. after seeking
+    pub generation: u32,
 }
 
 impl SamplesChunk {
+    /// Returns the part of this chunk, that starts at the given position.
+    pub fn starting_at(&self, position: usize) -> SamplesChunk {
+        if position <= self.start_position {
+            return self.clone();
+        }
+        let offset = (position - self.start_position).min(self.length);
+        let mut data = [0.0; CHUNK_SIZE];
+        data[..self.length - offset].copy_from_slice(&self.data[offset..self.length]);
+        SamplesChunk {
+            start_position: self.start_position + offset,
+            length: self.length - offset,
+            data: Arc::new(data),
+            ..self.clone()
+        }
+    }
+
+    /// Returns this chunk with all samples multiplied by gain. Samples are limited to [-1, 1].
+    pub fn with_gain(self, gain: f32) -> SamplesChunk {
+        if gain == 1.0 {
+            return self;
+        }
+        let mut data = [0.0; CHUNK_SIZE];
+        for (sample, value) in data.iter_mut().zip(&self.data[..self.length]) {
+            *sample = (value * gain).clamp(-1.0, 1.0);
+        }
+        SamplesChunk {
+            data: Arc::new(data),
+            ..self
+        }
+    }
+
     #[allow(unused)]
     pub fn get_start_time(&self) -> Duration {
         position_to_duration(self.start_position, self.sample_rate, self.channels)
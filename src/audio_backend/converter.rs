@@ -0,0 +1,252 @@
+use rubato::{FftFixedIn, Resampler};
+
+use crate::audio_backend::OpenError;
+use crate::audio_backend::decoder::SongDecoder;
+
+/// The number of input frames the resampler processes at once.
+const RESAMPLER_CHUNK_FRAMES: usize = 1024;
+/// The factor of center and surround channels, when they are mixed into the front channels (-3 dB).
+const DOWNMIX_FACTOR: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Decodes a song and converts its samples into the given output format.
+/// All positions of the converter are given in samples of the output format.
+pub struct FormatConverter {
+	decoder: SongDecoder,
+	resampler: Option<FftFixedIn<f32>>,
+	/// channel mixed frames, that are not resampled yet. One buffer per channel.
+	input: Vec<Vec<f32>>,
+	/// the number of resampled frames, that still have to be dropped because of the delay of the resampler
+	delay_frames: usize,
+	/// the number of frames given to and returned by the resampler since opening or the last seek
+	input_frames: usize,
+	output_frames: usize,
+	finished: bool,
+	mixed: Vec<f32>,
+	samples: Vec<f32>,
+	pub sample_rate: u32,
+	pub channels: u16,
+}
+
+impl FormatConverter {
+	/// Converts the samples of the given decoder into the given sample rate and number of channels.
+	pub fn new(decoder: SongDecoder, sample_rate: u32, channels: u16) -> Result<FormatConverter, OpenError> {
+		let resampler = if decoder.sample_rate != sample_rate {
+			let resampler = FftFixedIn::new(decoder.sample_rate as usize, sample_rate as usize, RESAMPLER_CHUNK_FRAMES, 2, channels as usize)
+				.map_err(|_| OpenError::NotDecodable)?;
+			Some(resampler)
+		} else {
+			None
+		};
+		let delay_frames = resampler.as_ref().map_or(0, |r| r.output_delay());
+		Ok(FormatConverter {
+			decoder,
+			resampler,
+			input: vec![Vec::new(); channels as usize],
+			delay_frames,
+			input_frames: 0,
+			output_frames: 0,
+			finished: false,
+			mixed: Vec::new(),
+			samples: Vec::new(),
+			sample_rate,
+			channels,
+		})
+	}
+
+	pub fn get_decoder(&self) -> &SongDecoder {
+		&self.decoder
+	}
+
+	/// Returns the next converted samples. Returns None, if the end of the song is reached.
+	pub fn next_samples(&mut self) -> Option<&[f32]> {
+		loop {
+			if self.finished {
+				return None;
+			}
+			self.mixed.clear();
+			let decoded = match self.decoder.next_frames() {
+				Some((samples, channels)) => {
+					mix_channels(samples, channels, self.channels, &mut self.mixed);
+					true
+				}
+				None => false,
+			};
+			let resampler = match &mut self.resampler {
+				Some(resampler) => resampler,
+				None => {
+					if !decoded {
+						self.finished = true;
+						return None;
+					}
+					return Some(&self.mixed);
+				}
+			};
+
+			let channels = self.channels as usize;
+			for frame in self.mixed.chunks_exact(channels) {
+				for (buffer, sample) in self.input.iter_mut().zip(frame) {
+					buffer.push(*sample);
+				}
+			}
+			self.samples.clear();
+			while self.input[0].len() >= resampler.input_frames_next() {
+				let num_frames = resampler.input_frames_next();
+				let output = resampler.process(&self.input, None).ok()?;
+				for buffer in self.input.iter_mut() {
+					buffer.drain(..num_frames);
+				}
+				self.input_frames += num_frames;
+				append_frames(&output, usize::MAX, &mut self.delay_frames, &mut self.output_frames, &mut self.samples);
+			}
+			if !decoded {
+				// resample the remaining frames and flush the delayed frames out of the resampler
+				let expected_frames = (self.input_frames + self.input[0].len()) as u64 * self.sample_rate as u64 / self.decoder.sample_rate as u64;
+				let expected_frames = expected_frames as usize;
+				let mut remaining = Some(std::mem::take(&mut self.input));
+				while self.output_frames < expected_frames {
+					let output = resampler.process_partial(remaining.as_deref(), None).ok()?;
+					remaining = None;
+					append_frames(&output, expected_frames, &mut self.delay_frames, &mut self.output_frames, &mut self.samples);
+				}
+				self.input = vec![Vec::new(); channels];
+				self.finished = true;
+			}
+			if !self.samples.is_empty() {
+				return Some(&self.samples);
+			}
+		}
+	}
+
+	/// Seeks to the given position. Returns the position of the next converted sample.
+	pub fn seek(&mut self, position: usize) -> Result<usize, OpenError> {
+		let frame = (position / self.channels as usize) as u64;
+		let input_frame = frame * self.decoder.sample_rate as u64 / self.sample_rate as u64;
+		let input_position = self.decoder.seek(input_frame as usize * self.decoder.channels as usize)?;
+		let input_frame = (input_position / self.decoder.channels as usize) as u64;
+
+		if let Some(resampler) = &mut self.resampler {
+			resampler.reset();
+			self.delay_frames = resampler.output_delay();
+		}
+		for buffer in self.input.iter_mut() {
+			buffer.clear();
+		}
+		self.input_frames = 0;
+		self.output_frames = 0;
+		self.finished = false;
+		let frame = input_frame * self.sample_rate as u64 / self.decoder.sample_rate as u64;
+		Ok(frame as usize * self.channels as usize)
+	}
+}
+
+/// Interleaves the resampled frames into samples. Drops delayed frames and frames after max_frames.
+fn append_frames(output: &[Vec<f32>], max_frames: usize, delay_frames: &mut usize, output_frames: &mut usize, samples: &mut Vec<f32>) {
+	let num_frames = output.first().map_or(0, |o| o.len());
+	let skip = num_frames.min(*delay_frames);
+	*delay_frames -= skip;
+	let end = num_frames.min(skip.saturating_add(max_frames.saturating_sub(*output_frames)));
+	for frame in skip..end {
+		samples.extend(output.iter().map(|channel| channel[frame]));
+	}
+	*output_frames += end - skip;
+}
+
+/// Converts interleaved samples with input_channels channels into samples with output_channels channels.
+/// Channels are expected in the order front left, front right, center, LFE, surround left, surround right, ...
+pub fn mix_channels(input: &[f32], input_channels: u16, output_channels: u16, output: &mut Vec<f32>) {
+	let input_channels = input_channels.max(1) as usize;
+	let output_channels = output_channels as usize;
+	if input_channels == output_channels {
+		output.extend_from_slice(input);
+		return;
+	}
+	for frame in input.chunks_exact(input_channels) {
+		if output_channels == 1 {
+			output.push(frame.iter().sum::<f32>() / input_channels as f32);
+		} else if input_channels == 1 {
+			output.extend(std::iter::repeat_n(frame[0], output_channels));
+		} else if output_channels == 2 {
+			// downmix surround to stereo
+			let (mut left, mut right, mut left_weight, mut right_weight) = (0.0, 0.0, 0.0, 0.0);
+			for (index, sample) in frame.iter().enumerate() {
+				let (left_factor, right_factor) = match index {
+					0 => (1.0, 0.0),
+					1 => (0.0, 1.0),
+					2 => (DOWNMIX_FACTOR, DOWNMIX_FACTOR),
+					3 => (0.0, 0.0), // the LFE channel is dropped
+					i if i % 2 == 0 => (DOWNMIX_FACTOR, 0.0),
+					_ => (0.0, DOWNMIX_FACTOR),
+				};
+				left += sample * left_factor;
+				right += sample * right_factor;
+				left_weight += left_factor;
+				right_weight += right_factor;
+			}
+			output.push(left / left_weight);
+			output.push(right / right_weight);
+		} else {
+			// channels, that are missing in the input, stay silent
+			output.extend((0..output_channels).map(|index| frame.get(index).copied().unwrap_or(0.0)));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::f32::consts::PI;
+
+	use super::*;
+
+	#[test]
+	fn test_mix_channels() {
+		let mut output = Vec::new();
+		mix_channels(&[0.5, -0.5], 1, 2, &mut output);
+		assert_eq!(output, vec![0.5, 0.5, -0.5, -0.5]);
+
+		output.clear();
+		mix_channels(&[0.2, 0.4, 1.0, 0.0], 2, 1, &mut output);
+		assert!((output[0] - 0.3).abs() < 1e-6);
+		assert!((output[1] - 0.5).abs() < 1e-6);
+
+		// 5.1 to stereo keeps the level of a signal on all channels and drops the LFE
+		output.clear();
+		mix_channels(&[0.5, 0.5, 0.5, 1.0, 0.5, 0.5], 6, 2, &mut output);
+		assert!((output[0] - 0.5).abs() < 1e-6);
+		assert!((output[1] - 0.5).abs() < 1e-6);
+	}
+
+	#[test]
+	fn test_resample_mono_to_stereo() {
+		let path = std::env::temp_dir().join(format!("musicus_test_converter_{}.wav", std::process::id()));
+		let spec = hound::WavSpec {
+			channels: 1,
+			sample_rate: 22050,
+			bits_per_sample: 32,
+			sample_format: hound::SampleFormat::Float,
+		};
+		let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+		for index in 0..22050 {
+			writer.write_sample((2.0 * PI * 1000.0 * index as f32 / 22050.0).sin() * 0.5).unwrap();
+		}
+		writer.finalize().unwrap();
+
+		let mut converter = FormatConverter::new(SongDecoder::open(&path).unwrap(), 48000, 2).unwrap();
+		let mut samples = Vec::new();
+		while let Some(s) = converter.next_samples() {
+			samples.extend_from_slice(s);
+		}
+		let seek_position = converter.seek(48000).unwrap();
+		let _ = std::fs::remove_file(&path);
+
+		assert_eq!(samples.len(), 48000 * 2);
+		assert!(samples.chunks_exact(2).all(|frame| frame[0] == frame[1]));
+		// one second of a 1 kHz sine crosses zero 2000 times
+		let crossings = samples.chunks_exact(2).collect::<Vec<_>>().windows(2)
+			.filter(|w| (w[0][0] < 0.0) != (w[1][0] < 0.0))
+			.count();
+		assert!((1990..=2010).contains(&crossings));
+		// no clicks at the start: the delay of the resampler is removed
+		assert!(samples[2].abs() < 0.2);
+		assert!((seek_position as i64 - 48000).abs() <= 4);
+	}
+}
@@ -0,0 +1,265 @@
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CODEC_TYPE_NULL, CodecParameters, Decoder, DecoderOptions};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::TimeBase;
+
+use crate::audio_backend::OpenError;
+use crate::audio_backend::format::{AudioFormat, get_codecs, get_probe};
+use crate::audio_backend::loudness::read_replay_gain_tags;
+use crate::song::{Loudness, Song};
+
+/// Decodes a song file packet by packet into interleaved f32 samples.
+/// Positions are given as number of samples. A sample is one f32 value.
+pub struct SongDecoder {
+	format: Box<dyn FormatReader>,
+	decoder: Box<dyn Decoder>,
+	track_id: u32,
+	time_base: Option<TimeBase>,
+	sample_buffer: Option<SampleBuffer<f32>>,
+	/// after seeking, decoded frames before this timestamp are dropped
+	seek_target: Option<u64>,
+	/// the first frame of the song in the file. Positions are relative to this frame.
+	start_frame: u64,
+	/// decoding stops at this frame, if the song ends before the file
+	end_frame: Option<u64>,
+	pub sample_rate: u32,
+	pub channels: u16,
+	pub total_duration: Option<Duration>,
+	/// the loudness given by the ReplayGain tags of the song
+	pub replay_gain: Option<Loudness>,
+}
+
+impl SongDecoder {
+	pub fn open(path: &Path) -> Result<SongDecoder, OpenError> {
+		let file = File::open(path).map_err(|_| OpenError::FileNotFound)?;
+		let audio_format = AudioFormat::sniff(path).ok_or_else(|| OpenError::UnsupportedFormat("unknown".to_string()))?;
+		if !audio_format.is_supported() {
+			return Err(OpenError::UnsupportedFormat(format!("{:?}", audio_format)));
+		}
+		let media_source_stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+		let mut hint = Hint::new();
+		hint.with_extension(audio_format.get_extension());
+		// gapless mode removes encoder delay and padding, so songs of an album follow each other without a gap
+		let format_options = FormatOptions { enable_gapless: true, ..Default::default() };
+		let mut probed = get_probe()
+			.format(&hint, media_source_stream, &format_options, &MetadataOptions::default())
+			.map_err(|e| match e {
+				Error::Unsupported(_) => OpenError::UnsupportedFormat(format!("{:?}", audio_format)),
+				_ => OpenError::NotDecodable,
+			})?;
+		// tags can be found before the container (e.g. ID3v2) or inside of it
+		let mut replay_gain = probed.metadata.get().as_ref()
+			.and_then(|metadata| metadata.current())
+			.and_then(|revision| read_replay_gain_tags(revision.tags()));
+		let mut format = probed.format;
+		if replay_gain.is_none() {
+			replay_gain = format.metadata().current().and_then(|revision| read_replay_gain_tags(revision.tags()));
+		}
+
+		let track = format.tracks().iter()
+			.find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+			.ok_or(OpenError::NotDecodable)?;
+		let decoder = make_decoder(&track.codec_params).map_err(|e| match e {
+			Error::Unsupported(_) => OpenError::UnsupportedFormat(format!("{:?} with codec {}", audio_format, track.codec_params.codec)),
+			_ => OpenError::NotDecodable,
+		})?;
+
+		let sample_rate = track.codec_params.sample_rate.ok_or(OpenError::NotDecodable)?;
+		let channels = track.codec_params.channels.map(|c| c.count() as u16).unwrap_or(2);
+		let time_base = track.codec_params.time_base;
+		let total_duration = track.codec_params.n_frames.map(|n_frames| {
+			match time_base {
+				Some(time_base) => time_to_duration(time_base.calc_time(n_frames)),
+				None => Duration::from_secs_f64(n_frames as f64 / sample_rate as f64),
+			}
+		});
+		let track_id = track.id;
+
+		Ok(SongDecoder {
+			format,
+			decoder,
+			track_id,
+			time_base,
+			sample_buffer: None,
+			seek_target: None,
+			start_frame: 0,
+			end_frame: None,
+			sample_rate,
+			channels,
+			total_duration,
+			replay_gain,
+		})
+	}
+
+	/// Opens the file of the given song. Tracks of CUE sheets only decode their part of the file.
+	pub fn open_song(song: &Song) -> Result<SongDecoder, OpenError> {
+		let mut decoder = SongDecoder::open(song.get_path())?;
+		if let Some(range) = song.get_track_range() {
+			let sample_rate = decoder.sample_rate;
+			decoder.start_frame = duration_to_frame(range.start, sample_rate);
+			decoder.end_frame = range.end.map(|end| duration_to_frame(end, sample_rate));
+			decoder.total_duration = range.end.or(decoder.total_duration).map(|end| end.saturating_sub(range.start));
+			// the tags of the file describe the whole album
+			decoder.replay_gain = None;
+			if decoder.start_frame > 0 {
+				decoder.seek(0)?;
+			}
+		}
+		Ok(decoder)
+	}
+
+	/// Decodes the next packet. Returns None, if the end of the song is reached or the song cannot be decoded further.
+	pub fn next_samples(&mut self) -> Option<&[f32]> {
+		self.next_frames().map(|(samples, _)| samples)
+	}
+
+	/// Like next_samples, but also returns the number of channels of the decoded packet.
+	pub fn next_frames(&mut self) -> Option<(&[f32], u16)> {
+		loop {
+			let packet = match self.format.next_packet() {
+				Ok(packet) => packet,
+				Err(Error::ResetRequired) => {
+					self.decoder.reset();
+					continue;
+				}
+				Err(_) => return None,
+			};
+			if packet.track_id() != self.track_id {
+				continue;
+			}
+			let decoded = match self.decoder.decode(&packet) {
+				Ok(decoded) => decoded,
+				Err(Error::DecodeError(_)) => continue, // skip corrupted packets
+				Err(_) => return None,
+			};
+			let spec = *decoded.spec();
+			self.channels = spec.channels.count() as u16;
+
+			// drop frames before the seek target
+			let mut skip_frames = 0;
+			if let Some(seek_target) = self.seek_target {
+				if packet.ts() + packet.dur() <= seek_target {
+					continue;
+				}
+				skip_frames = timestamp_to_frame(seek_target.saturating_sub(packet.ts()), self.time_base, self.sample_rate);
+				self.seek_target = None;
+			}
+
+			if self.sample_buffer.as_ref().is_none_or(|b| b.capacity() < decoded.capacity() * spec.channels.count()) {
+				self.sample_buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+			}
+			let sample_buffer = self.sample_buffer.as_mut().unwrap();
+			sample_buffer.copy_interleaved_ref(decoded);
+			let skip_samples = (skip_frames as usize * self.channels as usize).min(sample_buffer.len());
+			let mut samples = &sample_buffer.samples()[skip_samples..];
+			if let Some(end_frame) = self.end_frame {
+				let first_frame = timestamp_to_frame(packet.ts(), self.time_base, self.sample_rate) + skip_frames;
+				let remaining_frames = end_frame.saturating_sub(first_frame) as usize;
+				if remaining_frames == 0 {
+					return None;
+				}
+				samples = &samples[..samples.len().min(remaining_frames * self.channels as usize)];
+			}
+			return Some((samples, self.channels));
+		}
+	}
+
+	/// Seeks to the given position. Returns the position of the next decoded sample.
+	pub fn seek(&mut self, position: usize) -> Result<usize, OpenError> {
+		let frame = (position / self.channels as usize) as u64 + self.start_frame;
+		let seek_to = SeekTo::TimeStamp { ts: self.frame_to_timestamp(frame), track_id: self.track_id };
+		let seeked_to = self.format.seek(SeekMode::Accurate, seek_to).map_err(|_| OpenError::NotDecodable)?;
+		self.decoder.reset();
+		self.seek_target = Some(seeked_to.required_ts);
+		let frame = timestamp_to_frame(seeked_to.required_ts, self.time_base, self.sample_rate).saturating_sub(self.start_frame);
+		Ok(frame as usize * self.channels as usize)
+	}
+
+	fn frame_to_timestamp(&self, frame: u64) -> u64 {
+		match self.time_base {
+			Some(time_base) => frame * time_base.denom as u64 / (time_base.numer as u64 * self.sample_rate as u64),
+			None => frame,
+		}
+	}
+}
+
+/// Creates a decoder for the codec of a track. Opus is decoded with libopus, if it is installed.
+fn make_decoder(codec_params: &CodecParameters) -> symphonia::core::errors::Result<Box<dyn Decoder>> {
+	get_codecs().make(codec_params, &DecoderOptions::default())
+}
+
+fn timestamp_to_frame(timestamp: u64, time_base: Option<TimeBase>, sample_rate: u32) -> u64 {
+	match time_base {
+		Some(time_base) => timestamp * time_base.numer as u64 * sample_rate as u64 / time_base.denom as u64,
+		None => timestamp,
+	}
+}
+
+fn duration_to_frame(duration: Duration, sample_rate: u32) -> u64 {
+	(duration.as_secs_f64() * sample_rate as f64).round() as u64
+}
+
+fn time_to_duration(time: symphonia::core::units::Time) -> Duration {
+	Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::song::TrackRange;
+	use crate::song::cue::CueTrack;
+	use crate::song::song_buffer::SongBuffer;
+
+	use super::*;
+
+	#[test]
+	fn test_decode_track_range() {
+		let path = std::env::temp_dir().join(format!("musicus_test_track_range_{}.wav", std::process::id()));
+		let spec = hound::WavSpec {
+			channels: 1,
+			sample_rate: 8000,
+			bits_per_sample: 32,
+			sample_format: hound::SampleFormat::Float,
+		};
+		let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+		for index in 0..8000 {
+			writer.write_sample(index as f32 / 8000.0).unwrap();
+		}
+		writer.finalize().unwrap();
+
+		let track = CueTrack {
+			number: 2,
+			title: None,
+			performer: None,
+			path: path.clone(),
+			range: TrackRange { start: Duration::from_millis(500), end: Some(Duration::from_millis(750)) },
+			loudness: None,
+		};
+		let mut song_buffer = SongBuffer::new();
+		let song_id = song_buffer.import_track(&track);
+		let mut decoder = SongDecoder::open_song(song_buffer.get(song_id).unwrap()).unwrap();
+		let mut samples = Vec::new();
+		while let Some(s) = decoder.next_samples() {
+			samples.extend_from_slice(s);
+		}
+		let seek_position = decoder.seek(1000).unwrap();
+		let after_seek = decoder.next_samples().unwrap()[0];
+		let _ = std::fs::remove_file(&path);
+
+		assert_eq!(decoder.total_duration, Some(Duration::from_millis(250)));
+		assert_eq!(samples.len(), 2000);
+		assert_eq!(samples[0], 0.5);
+		assert_eq!(samples[1999], 5999.0 / 8000.0);
+		// positions are relative to the start of the track
+		assert_eq!(seek_position, 1000);
+		assert_eq!(after_seek, 5000.0 / 8000.0);
+	}
+}
@@ -0,0 +1,138 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use rodio::{cpal, Device, DeviceTrait, OutputStream, OutputStreamHandle, Sink};
+use rodio::cpal::traits::HostTrait;
+
+use crate::audio_backend::output::AudioOutput;
+use crate::audio_backend::receiver_source::ReceiverSource;
+
+/// An opened output device, that plays everything appended to its sink.
+pub struct RodioOutput {
+	sink: Sink,
+	stream_handle: OutputStreamHandle,
+	_stream: OutputStream,
+	device_name: String,
+	/// sample rate and channels of the device
+	format: (u32, u16),
+}
+
+impl AudioOutput for RodioOutput {
+	fn start(&mut self, source: ReceiverSource) -> io::Result<()> {
+		// a new sink drops the source of the old one
+		if let Ok(sink) = Sink::try_new(&self.stream_handle) {
+			sink.set_volume(self.sink.volume());
+			if self.sink.is_paused() {
+				sink.pause();
+			}
+			self.sink.stop();
+			self.sink = sink;
+		}
+		self.sink.append(source);
+		Ok(())
+	}
+
+	fn play(&mut self) {
+		self.sink.play();
+	}
+
+	fn pause(&mut self) {
+		self.sink.pause();
+	}
+
+	fn set_volume(&mut self, volume: f32) {
+		self.sink.set_volume(volume);
+	}
+
+	fn get_name(&self) -> String {
+		self.device_name.clone()
+	}
+
+	fn get_format(&self) -> (u32, u16) {
+		self.format
+	}
+}
+
+#[derive(Debug)]
+pub enum DeviceError {
+	NoDevices,
+	DeviceNotFound(String),
+	OpenFailed(String, String), // device name, error message
+	ListFailed(String),
+}
+
+impl Display for DeviceError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			DeviceError::NoDevices => write!(f, "no output device available"),
+			DeviceError::DeviceNotFound(name) => write!(f, "output device \"{}\" not found", name),
+			DeviceError::OpenFailed(name, message) => write!(f, "failed to open output device \"{}\": {}", name, message),
+			DeviceError::ListFailed(message) => write!(f, "failed to list output devices: {}", message),
+		}
+	}
+}
+
+/// Returns the names of all output devices of the default host.
+pub fn list_output_devices() -> Result<Vec<String>, DeviceError> {
+	let devices = cpal::default_host().output_devices().map_err(|e| DeviceError::ListFailed(e.to_string()))?;
+	Ok(devices.filter_map(|d| d.name().ok()).collect())
+}
+
+/// Opens the output device with the given name.
+/// If no name is given, the default device is used. If this fails, every other device is tried.
+pub fn open_device_output(device_name: Option<&str>) -> Result<RodioOutput, DeviceError> {
+	match device_name {
+		Some(device_name) => {
+			let device = find_device(device_name)?;
+			open_device(&device, device_name.to_string())
+		}
+		None => open_default_output(),
+	}
+}
+
+fn find_device(device_name: &str) -> Result<Device, DeviceError> {
+	let mut devices = cpal::default_host().output_devices().map_err(|e| DeviceError::ListFailed(e.to_string()))?;
+	devices.find(|d| d.name().map(|n| n == device_name).unwrap_or(false))
+		.ok_or_else(|| DeviceError::DeviceNotFound(device_name.to_string()))
+}
+
+fn open_default_output() -> Result<RodioOutput, DeviceError> {
+	let host = cpal::default_host();
+	let mut last_error = DeviceError::NoDevices;
+	if let Some(device) = host.default_output_device() {
+		let name = device.name().unwrap_or_else(|_| "<default>".to_string());
+		match open_device(&device, name) {
+			Ok(output) => return Ok(output),
+			Err(e) => last_error = e,
+		}
+	}
+	// default device didn't work, try other ones
+	if let Ok(devices) = host.output_devices() {
+		for device in devices {
+			let name = device.name().unwrap_or_else(|_| "<unknown>".to_string());
+			match open_device(&device, name) {
+				Ok(output) => return Ok(output),
+				Err(e) => last_error = e,
+			}
+		}
+	}
+	Err(last_error)
+}
+
+fn open_device(device: &Device, device_name: String) -> Result<RodioOutput, DeviceError> {
+	let (stream, stream_handle): (OutputStream, OutputStreamHandle) = OutputStream::try_from_device(device)
+		.map_err(|e| DeviceError::OpenFailed(device_name.clone(), e.to_string()))?;
+	let sink = Sink::try_new(&stream_handle)
+		.map_err(|e| DeviceError::OpenFailed(device_name.clone(), e.to_string()))?;
+	// rodio plays on the default config of the device, so songs are converted to this format
+	let format = device.default_output_config()
+		.map(|config| (config.sample_rate().0, config.channels()))
+		.unwrap_or((44100, 2));
+	Ok(RodioOutput {
+		sink,
+		stream_handle,
+		_stream: stream,
+		device_name,
+		format,
+	})
+}
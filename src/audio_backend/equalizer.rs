@@ -0,0 +1,195 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+
+use crate::audio_backend::chunk::{CHUNK_SIZE, SamplesChunk};
+use crate::audio_backend::filter::Biquad;
+
+/// The center frequencies of the equalizer bands in Hz.
+pub const BAND_FREQUENCIES: [f64; NUM_BANDS] = [31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0];
+pub const NUM_BANDS: usize = 10;
+/// The gain of a band is limited to +/- this value in dB.
+pub const MAX_BAND_GAIN: f32 = 12.0;
+/// The quality of the band filters. Neighbouring bands are one octave apart.
+const BAND_Q: f64 = std::f64::consts::SQRT_2;
+
+/// Named gains for the bands of the equalizer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EqualizerPreset {
+	pub name: String,
+	/// gain in dB, that is applied before the bands. Should be negative, if bands are raised, to avoid clipping.
+	#[serde(default)]
+	pub preamp: f32,
+	/// the gain of each band in dB
+	pub gains: [f32; NUM_BANDS],
+}
+
+impl EqualizerPreset {
+	pub fn new(name: &str, preamp: f32, gains: [f32; NUM_BANDS]) -> EqualizerPreset {
+		EqualizerPreset {
+			name: name.to_string(),
+			preamp,
+			gains,
+		}
+	}
+
+	/// The presets, that are created, if no equalizer config exists.
+	pub fn defaults() -> Vec<EqualizerPreset> {
+		vec![
+			EqualizerPreset::new("Flat", 0.0, [0.0; NUM_BANDS]),
+			EqualizerPreset::new("Bass Boost", -6.0, [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+			EqualizerPreset::new("Treble Boost", -6.0, [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 2.0, 4.0, 5.0, 6.0]),
+			EqualizerPreset::new("V-Shape", -5.0, [5.0, 4.0, 2.0, 0.0, -2.0, -2.0, 0.0, 2.0, 4.0, 5.0]),
+			EqualizerPreset::new("Vocal", -4.0, [-2.0, -2.0, -1.0, 1.0, 3.0, 4.0, 3.0, 1.0, 0.0, -1.0]),
+		]
+	}
+
+	/// Changes the gain of the given band by delta dB.
+	pub fn change_gain(&mut self, band: usize, delta: f32) {
+		if let Some(gain) = self.gains.get_mut(band) {
+			*gain = (*gain + delta).clamp(-MAX_BAND_GAIN, MAX_BAND_GAIN);
+		}
+	}
+}
+
+/// Filters chunks with a peaking filter for every band of a preset.
+pub struct Equalizer {
+	preset: EqualizerPreset,
+	sample_rate: u32,
+	/// the filters of every channel
+	filters: Vec<[Biquad; NUM_BANDS]>,
+}
+
+impl Equalizer {
+	pub fn new(preset: EqualizerPreset, sample_rate: u32, channels: u16) -> Equalizer {
+		let filters = vec![create_band_filters(&preset, sample_rate); channels as usize];
+		Equalizer {
+			preset,
+			sample_rate,
+			filters,
+		}
+	}
+
+	/// Changes the preset without resetting the filter state.
+	pub fn set_preset(&mut self, preset: EqualizerPreset) {
+		let band_filters = create_band_filters(&preset, self.sample_rate);
+		for filters in self.filters.iter_mut() {
+			for (filter, band_filter) in filters.iter_mut().zip(&band_filters) {
+				filter.set_coefficients(band_filter);
+			}
+		}
+		self.preset = preset;
+	}
+
+	pub fn get_preset(&self) -> &EqualizerPreset {
+		&self.preset
+	}
+
+	/// Filters the samples of the given chunk. Chunks have to be given in the order they are played.
+	pub fn apply(&mut self, chunk: SamplesChunk) -> SamplesChunk {
+		let channels = chunk.channels as usize;
+		if channels != self.filters.len() || chunk.sample_rate != self.sample_rate {
+			*self = Equalizer::new(self.preset.clone(), chunk.sample_rate, chunk.channels);
+		}
+		let preamp = 10f64.powf(self.preset.preamp as f64 / 20.0);
+		let active_bands: Vec<usize> = (0..NUM_BANDS).filter(|band| self.preset.gains[*band] != 0.0).collect();
+		if active_bands.is_empty() && self.preset.preamp == 0.0 {
+			return chunk;
+		}
+		let mut data = [0.0; CHUNK_SIZE];
+		for (index, (sample, value)) in data.iter_mut().zip(&chunk.data[..chunk.length]).enumerate() {
+			let filters = &mut self.filters[(chunk.start_position + index) % channels];
+			let mut x = *value as f64 * preamp;
+			for band in active_bands.iter() {
+				x = filters[*band].process(x);
+			}
+			*sample = (x as f32).clamp(-1.0, 1.0);
+		}
+		SamplesChunk {
+			data: Arc::new(data),
+			..chunk
+		}
+	}
+}
+
+/// Creates the peaking filters (Audio EQ Cookbook) for all bands of the given preset.
+/// Bands above the nyquist frequency do nothing.
+fn create_band_filters(preset: &EqualizerPreset, sample_rate: u32) -> [Biquad; NUM_BANDS] {
+	let sample_rate = sample_rate as f64;
+	let mut filters = [Biquad::new([1.0, 0.0, 0.0], [0.0, 0.0]); NUM_BANDS];
+	for (filter, (frequency, gain)) in filters.iter_mut().zip(BAND_FREQUENCIES.iter().zip(preset.gains)) {
+		if *frequency >= sample_rate / 2.0 {
+			continue;
+		}
+		let a = 10f64.powf(gain as f64 / 40.0);
+		let w0 = 2.0 * PI * frequency / sample_rate;
+		let alpha = w0.sin() / (2.0 * BAND_Q);
+		let a0 = 1.0 + alpha / a;
+		*filter = Biquad::new(
+			[(1.0 + alpha * a) / a0, -2.0 * w0.cos() / a0, (1.0 - alpha * a) / a0],
+			[-2.0 * w0.cos() / a0, (1.0 - alpha / a) / a0],
+		);
+	}
+	filters
+}
+
+/// Returns the name of a band like "125" or "2k".
+pub fn format_band_frequency(band: usize) -> String {
+	let frequency = BAND_FREQUENCIES[band];
+	if frequency >= 1000.0 {
+		format!("{}k", frequency / 1000.0)
+	} else {
+		format!("{}", frequency)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Filters a sine of the given frequency through the equalizer and returns the peak of the second half.
+	fn filtered_peak(equalizer: &mut Equalizer, frequency: f64) -> f32 {
+		let sample_rate = 48000;
+		let mut peak = 0.0f32;
+		for chunk_index in 0..96 {
+			let mut data = [0.0; CHUNK_SIZE];
+			for (index, sample) in data.iter_mut().enumerate() {
+				let frame = (chunk_index * CHUNK_SIZE + index) / 2;
+				*sample = ((2.0 * PI * frequency * frame as f64 / sample_rate as f64).sin() * 0.25) as f32;
+			}
+			let chunk = SamplesChunk {
+				channels: 2,
+				sample_rate,
+				start_position: chunk_index * CHUNK_SIZE,
+				length: CHUNK_SIZE,
+				data: Arc::new(data),
+				song_id: 0,
+				last_chunk: false,
+				generation: 0,
+			};
+			let chunk = equalizer.apply(chunk);
+			if chunk_index >= 48 {
+				peak = chunk.data.iter().fold(peak, |p, s| p.max(s.abs()));
+			}
+		}
+		peak
+	}
+
+	#[test]
+	fn test_band_gain() {
+		let mut gains = [0.0; NUM_BANDS];
+		gains[5] = 6.0;
+		let mut equalizer = Equalizer::new(EqualizerPreset::new("test", 0.0, gains), 48000, 2);
+		// the center frequency of the band is raised by 6 dB
+		assert!((filtered_peak(&mut equalizer, 1000.0) / 0.25 - 10f32.powf(6.0 / 20.0)).abs() < 0.05);
+		// far away frequencies are not changed
+		assert!((filtered_peak(&mut equalizer, 62.0) / 0.25 - 1.0).abs() < 0.05);
+	}
+
+	#[test]
+	fn test_flat_preset_keeps_samples() {
+		let mut equalizer = Equalizer::new(EqualizerPreset::defaults()[0].clone(), 48000, 2);
+		assert!((filtered_peak(&mut equalizer, 440.0) - 0.25).abs() < 1e-3);
+	}
+}
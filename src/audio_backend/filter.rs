@@ -0,0 +1,27 @@
+/// A biquad filter in transposed direct form 2.
+#[derive(Copy, Clone)]
+pub struct Biquad {
+	b: [f64; 3],
+	a: [f64; 2], // a1 and a2, a0 is normalized to 1
+	z1: f64,
+	z2: f64,
+}
+
+impl Biquad {
+	pub fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+		Biquad { b, a, z1: 0.0, z2: 0.0 }
+	}
+
+	pub fn process(&mut self, x: f64) -> f64 {
+		let y = self.b[0] * x + self.z1;
+		self.z1 = self.b[1] * x - self.a[0] * y + self.z2;
+		self.z2 = self.b[2] * x - self.a[1] * y;
+		y
+	}
+
+	/// Replaces the coefficients, but keeps the state of the filter, so changing a filter does not click.
+	pub fn set_coefficients(&mut self, other: &Biquad) {
+		self.b = other.b;
+		self.a = other.a;
+	}
+}
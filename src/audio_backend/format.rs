@@ -0,0 +1,456 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use symphonia::core::codecs::CodecRegistry;
+use symphonia::core::probe::Probe;
+
+use crate::audio_backend::wavpack::{WavPackDecoder, WavPackReader};
+
+/// The number of bytes read from the start of a file to detect its format.
+const SNIFF_SIZE: usize = 64;
+/// The brands of MP4 files, that can contain audio. Generic brands like isom are used for video files as well.
+const MP4_BRANDS: [&[u8; 4]; 13] = [b"M4A ", b"M4B ", b"M4P ", b"F4A ", b"F4B ", b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"dash"];
+/// The number of elements of a matroska segment, that are read to find the tracks.
+const MAX_MATROSKA_ELEMENTS: usize = 64;
+/// Extensions of files, that are found next to songs and are never read to detect their format.
+const NON_AUDIO_EXTENSIONS: [&str; 16] = ["jpg", "jpeg", "png", "gif", "bmp", "webp", "txt", "nfo", "log", "cue", "m3u", "m3u8", "pdf", "md5", "sfv", "accurip"];
+
+/// The container or codec of a song file, detected by the content of the file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AudioFormat {
+	Wav,
+	Mp3,
+	Aac, // raw AAC in ADTS frames
+	Flac,
+	Vorbis, // in an ogg container
+	Opus, // in an ogg container
+	Mp4, // AAC or ALAC in an MP4 container (.m4a)
+	Matroska,
+	WavPack,
+}
+
+impl AudioFormat {
+	/// Reads the start of the file and detects its format. Returns None, if the file cannot be read or is no known audio format.
+	pub fn sniff(path: &Path) -> Option<AudioFormat> {
+		let mut file = File::open(path).ok()?;
+		AudioFormat::detect(&mut file)
+	}
+
+	/// Detects the format of the file by its extension. Only files with unknown or missing extensions and MP4 or matroska files,
+	/// that could contain videos, are read. The decoder detects the format by the content again, when the song is opened.
+	pub fn guess(path: &Path) -> Option<AudioFormat> {
+		let extension = path.extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_ascii_lowercase());
+		match extension.as_deref() {
+			Some("wav") => Some(AudioFormat::Wav),
+			Some("mp3") => Some(AudioFormat::Mp3),
+			Some("aac") => Some(AudioFormat::Aac),
+			Some("flac") => Some(AudioFormat::Flac),
+			Some("ogg" | "oga") => Some(AudioFormat::Vorbis),
+			Some("opus") => Some(AudioFormat::Opus),
+			Some("m4a" | "m4b") => Some(AudioFormat::Mp4),
+			Some("mka") => Some(AudioFormat::Matroska),
+			Some("wv") => Some(AudioFormat::WavPack),
+			Some(extension) if NON_AUDIO_EXTENSIONS.contains(&extension) => None,
+			_ => AudioFormat::sniff(path),
+		}
+	}
+
+	/// Detects the format of the content of reader. MP4 and matroska files are only accepted, if they contain audio, but no video.
+	fn detect<R: Read + Seek>(reader: &mut R) -> Option<AudioFormat> {
+		let header = read_header(reader)?;
+		// ID3v2 tags are put in front of mp3 files, but also in front of other formats
+		let (start, audio_format) = match get_id3_tag_size(&header) {
+			Some(tag_size) => {
+				reader.seek(SeekFrom::Start(tag_size as u64)).ok()?;
+				let header = read_header(reader)?;
+				(tag_size as u64, AudioFormat::from_header(&header).unwrap_or(AudioFormat::Mp3))
+			}
+			None => (0, AudioFormat::from_header(&header)?),
+		};
+		let only_audio = match audio_format {
+			AudioFormat::Mp4 => {
+				let handler_types = read_mp4_handler_types(reader, start)?;
+				handler_types.contains(b"soun") && !handler_types.contains(b"vide")
+			}
+			AudioFormat::Matroska => {
+				// track type 1 is video, 2 is audio
+				let track_types = read_matroska_track_types(reader, start)?;
+				track_types.contains(&2) && !track_types.contains(&1)
+			}
+			_ => true,
+		};
+		only_audio.then_some(audio_format)
+	}
+
+	/// Detects the format by the magic bytes at the start of a file.
+	/// MP4 and matroska files can contain video as well, so their tracks are checked by sniff().
+	pub fn from_header(header: &[u8]) -> Option<AudioFormat> {
+		if header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE" {
+			return Some(AudioFormat::Wav);
+		}
+		if header.starts_with(b"fLaC") {
+			return Some(AudioFormat::Flac);
+		}
+		if header.starts_with(b"OggS") {
+			// the first page of an ogg stream contains the identification header of the codec
+			let page = &header[header.len().min(28)..];
+			return if page.starts_with(b"OpusHead") {
+				Some(AudioFormat::Opus)
+			} else if page.starts_with(b"\x01vorbis") {
+				Some(AudioFormat::Vorbis)
+			} else if page.starts_with(b"\x7fFLAC") {
+				Some(AudioFormat::Flac)
+			} else {
+				None
+			};
+		}
+		if header.len() >= 12 && &header[4..8] == b"ftyp" {
+			// images (heic, avif), quicktime and 3gp files use other brands
+			let box_size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+			let major_brand = &header[8..12];
+			let compatible_brands = header.get(16..box_size.min(header.len())).unwrap_or(&[]);
+			let has_audio_brand = std::iter::once(major_brand).chain(compatible_brands.chunks_exact(4))
+				.any(|brand| MP4_BRANDS.iter().any(|audio_brand| brand == *audio_brand));
+			return has_audio_brand.then_some(AudioFormat::Mp4);
+		}
+		if header.starts_with(b"\x1a\x45\xdf\xa3") {
+			return read_matroska_doc_type(header)
+				.filter(|doc_type| *doc_type == b"matroska" || *doc_type == b"webm")
+				.map(|_| AudioFormat::Matroska);
+		}
+		if header.starts_with(b"wvpk") {
+			return Some(AudioFormat::WavPack);
+		}
+		// MPEG audio frames and ADTS frames start with 11 or 12 set sync bits
+		if header.len() >= 2 && header[0] == 0xff && header[1] & 0xe0 == 0xe0 {
+			let layer = (header[1] >> 1) & 0b11;
+			return match layer {
+				0 if header[1] & 0xf0 == 0xf0 => Some(AudioFormat::Aac),
+				0 => None,
+				_ => Some(AudioFormat::Mp3),
+			};
+		}
+		None
+	}
+
+	/// Returns whether songs of this format can be decoded.
+	pub fn is_supported(&self) -> bool {
+		// symphonia cannot decode opus, it is decoded with libopus if it is installed
+		*self != AudioFormat::Opus || crate::audio_backend::opus::is_available()
+	}
+
+	/// Returns the usual file extension of this format, that is used as hint for the decoder.
+	pub fn get_extension(&self) -> &'static str {
+		match self {
+			AudioFormat::Wav => "wav",
+			AudioFormat::Mp3 => "mp3",
+			AudioFormat::Aac => "aac",
+			AudioFormat::Flac => "flac",
+			AudioFormat::Vorbis => "ogg",
+			AudioFormat::Opus => "opus",
+			AudioFormat::Mp4 => "m4a",
+			AudioFormat::Matroska => "mka",
+			AudioFormat::WavPack => "wv",
+		}
+	}
+}
+
+/// Returns the probe of symphonia, that additionally knows the WavPack container.
+pub fn get_probe() -> &'static Probe {
+	static PROBE: OnceLock<Probe> = OnceLock::new();
+	PROBE.get_or_init(|| {
+		let mut probe = Probe::default();
+		symphonia::default::register_enabled_formats(&mut probe);
+		probe.register_all::<WavPackReader>();
+		probe
+	})
+}
+
+/// Returns the codecs of symphonia together with the WavPack and the opus decoder.
+pub fn get_codecs() -> &'static CodecRegistry {
+	static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+	CODECS.get_or_init(|| {
+		let mut codecs = CodecRegistry::new();
+		symphonia::default::register_enabled_codecs(&mut codecs);
+		codecs.register_all::<WavPackDecoder>();
+		codecs.register_all::<crate::audio_backend::opus::OpusDecoder>();
+		codecs
+	})
+}
+
+fn read_header<R: Read>(reader: &mut R) -> Option<Vec<u8>> {
+	let mut header = Vec::with_capacity(SNIFF_SIZE);
+	reader.take(SNIFF_SIZE as u64).read_to_end(&mut header).ok()?;
+	Some(header)
+}
+
+/// Returns the handler types of the tracks of the MP4 file starting at start, e.g. "soun" for audio and "vide" for video.
+/// Only the headers of the boxes are read, the media data is skipped.
+fn read_mp4_handler_types<R: Read + Seek>(reader: &mut R, start: u64) -> Option<Vec<[u8; 4]>> {
+	let end = reader.seek(SeekFrom::End(0)).ok()?;
+	let mut handler_types = Vec::new();
+	read_mp4_boxes(reader, start, end, &mut handler_types)?;
+	Some(handler_types)
+}
+
+fn read_mp4_boxes<R: Read + Seek>(reader: &mut R, start: u64, end: u64, handler_types: &mut Vec<[u8; 4]>) -> Option<()> {
+	let mut position = start;
+	while position + 8 <= end {
+		reader.seek(SeekFrom::Start(position)).ok()?;
+		let mut box_header = [0; 8];
+		reader.read_exact(&mut box_header).ok()?;
+		let mut header_size = 8;
+		let box_size = match u32::from_be_bytes([box_header[0], box_header[1], box_header[2], box_header[3]]) {
+			// the box extends to the end of the file
+			0 => end - position,
+			// the size follows as 64 bit number
+			1 => {
+				let mut large_size = [0; 8];
+				reader.read_exact(&mut large_size).ok()?;
+				header_size = 16;
+				u64::from_be_bytes(large_size)
+			}
+			size => size as u64,
+		};
+		if box_size < header_size {
+			return None;
+		}
+		let box_end = (position + box_size).min(end);
+		match &box_header[4..8] {
+			b"moov" | b"trak" | b"mdia" => read_mp4_boxes(reader, position + header_size, box_end, handler_types)?,
+			b"hdlr" => {
+				// version, flags and pre_defined come before the handler type
+				let mut handler = [0; 12];
+				reader.read_exact(&mut handler).ok()?;
+				handler_types.push([handler[8], handler[9], handler[10], handler[11]]);
+			}
+			_ => {}
+		}
+		position += box_size;
+	}
+	Some(())
+}
+
+/// Reads the id of an EBML element. The length marker is kept as part of the id.
+fn read_ebml_id<R: Read>(reader: &mut R) -> Option<u32> {
+	let mut first_byte = [0];
+	reader.read_exact(&mut first_byte).ok()?;
+	let length = first_byte[0].leading_zeros() + 1;
+	if length > 4 {
+		return None;
+	}
+	let mut id = first_byte[0] as u32;
+	for _ in 1..length {
+		let mut byte = [0];
+		reader.read_exact(&mut byte).ok()?;
+		id = (id << 8) | byte[0] as u32;
+	}
+	Some(id)
+}
+
+/// Reads the size of an EBML element. Returns Some(None), if the size is unknown.
+fn read_ebml_size<R: Read>(reader: &mut R) -> Option<Option<u64>> {
+	let mut first_byte = [0];
+	reader.read_exact(&mut first_byte).ok()?;
+	let length = first_byte[0].leading_zeros() + 1;
+	if length > 8 {
+		return None;
+	}
+	let mut size = (first_byte[0] & (0xff >> length)) as u64;
+	for _ in 1..length {
+		let mut byte = [0];
+		reader.read_exact(&mut byte).ok()?;
+		size = (size << 8) | byte[0] as u64;
+	}
+	// a size with all bits set is unknown
+	let unknown_size = (1 << (7 * length)) - 1;
+	Some((size != unknown_size).then_some(size))
+}
+
+/// Returns the DocType of the EBML header at the start of header, e.g. "matroska" or "webm".
+fn read_matroska_doc_type(header: &[u8]) -> Option<&[u8]> {
+	let mut reader = std::io::Cursor::new(header);
+	if read_ebml_id(&mut reader)? != 0x1a45dfa3 {
+		return None;
+	}
+	let header_end = reader.position() + read_ebml_size(&mut reader)??;
+	while reader.position() < header_end {
+		let id = read_ebml_id(&mut reader)?;
+		let size = read_ebml_size(&mut reader)?? as usize;
+		let position = reader.position() as usize;
+		if id == 0x4282 {
+			let doc_type = header.get(position..position + size)?;
+			// strings can be padded with zeros
+			return Some(doc_type.split(|byte| *byte == 0).next().unwrap_or(doc_type));
+		}
+		reader.set_position((position + size) as u64);
+	}
+	None
+}
+
+/// Returns the types of the tracks of the matroska file starting at start. The tracks have to be stored before the first cluster.
+fn read_matroska_track_types<R: Read + Seek>(reader: &mut R, start: u64) -> Option<Vec<u64>> {
+	reader.seek(SeekFrom::Start(start)).ok()?;
+	// the EBML header is followed by the segment
+	read_ebml_id(reader)?;
+	let header_size = read_ebml_size(reader)??;
+	reader.seek(SeekFrom::Current(header_size as i64)).ok()?;
+	if read_ebml_id(reader)? != 0x18538067 {
+		return None;
+	}
+	read_ebml_size(reader)?;
+
+	for _ in 0..MAX_MATROSKA_ELEMENTS {
+		let id = read_ebml_id(reader)?;
+		let size = read_ebml_size(reader)??;
+		match id {
+			// tracks
+			0x1654ae6b => {
+				let mut tracks = vec![0; size as usize];
+				reader.read_exact(&mut tracks).ok()?;
+				return read_track_types(&tracks);
+			}
+			// cluster
+			0x1f43b675 => return None,
+			_ => {
+				reader.seek(SeekFrom::Current(size as i64)).ok()?;
+			}
+		}
+	}
+	None
+}
+
+/// Returns the track types of the track entries of the content of a matroska tracks element.
+fn read_track_types(tracks: &[u8]) -> Option<Vec<u64>> {
+	let mut reader = std::io::Cursor::new(tracks);
+	let mut track_types = Vec::new();
+	while (reader.position() as usize) < tracks.len() {
+		let id = read_ebml_id(&mut reader)?;
+		let size = read_ebml_size(&mut reader)??;
+		let entry_end = reader.position() + size;
+		// track entry
+		if id == 0xae {
+			while reader.position() < entry_end {
+				let id = read_ebml_id(&mut reader)?;
+				let size = read_ebml_size(&mut reader)?? as usize;
+				let position = reader.position() as usize;
+				// track type
+				if id == 0x83 {
+					let value = tracks.get(position..position + size)?;
+					track_types.push(value.iter().fold(0, |track_type, byte| (track_type << 8) | *byte as u64));
+				}
+				reader.set_position((position + size) as u64);
+			}
+		}
+		reader.set_position(entry_end);
+	}
+	Some(track_types)
+}
+
+/// Returns the size of the ID3v2 tag at the start of header including its header and footer.
+fn get_id3_tag_size(header: &[u8]) -> Option<usize> {
+	if header.len() < 10 || !header.starts_with(b"ID3") {
+		return None;
+	}
+	// the size is stored in four bytes with seven bits each
+	let size = header[6..10].iter().fold(0, |size, byte| (size << 7) | (*byte as usize & 0x7f));
+	let footer_size = if header[5] & 0x10 != 0 { 10 } else { 0 };
+	Some(10 + size + footer_size)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ogg_page(identification: &[u8]) -> Vec<u8> {
+		let mut page = b"OggS".to_vec();
+		page.resize(28, 0);
+		page.extend_from_slice(identification);
+		page
+	}
+
+	#[test]
+	fn test_sniff_formats() {
+		assert_eq!(AudioFormat::from_header(b"RIFF\x24\x08\x00\x00WAVEfmt "), Some(AudioFormat::Wav));
+		assert_eq!(AudioFormat::from_header(b"fLaC\x00\x00\x00\x22"), Some(AudioFormat::Flac));
+		assert_eq!(AudioFormat::from_header(&ogg_page(b"OpusHead\x01\x02")), Some(AudioFormat::Opus));
+		assert_eq!(AudioFormat::from_header(&ogg_page(b"\x01vorbis\x00")), Some(AudioFormat::Vorbis));
+		assert_eq!(AudioFormat::from_header(b"\x00\x00\x00\x20ftypM4A "), Some(AudioFormat::Mp4));
+		assert_eq!(AudioFormat::from_header(b"wvpk\x00\x10\x00\x00"), Some(AudioFormat::WavPack));
+		assert_eq!(AudioFormat::from_header(b"\xff\xfb\x90\x64"), Some(AudioFormat::Mp3));
+		assert_eq!(AudioFormat::from_header(b"\xff\xf1\x50\x80"), Some(AudioFormat::Aac));
+	}
+
+	#[test]
+	fn test_guess_by_extension() {
+		// files with known extensions are not read
+		assert_eq!(AudioFormat::guess(Path::new("/missing/song.FLAC")), Some(AudioFormat::Flac));
+		assert_eq!(AudioFormat::guess(Path::new("/missing/song.opus")), Some(AudioFormat::Opus));
+		assert_eq!(AudioFormat::guess(Path::new("/missing/cover.jpg")), None);
+		assert_eq!(AudioFormat::guess(Path::new("/missing/video.mp4")), None);
+		assert_eq!(AudioFormat::guess(Path::new("/missing/song")), None);
+	}
+
+	#[test]
+	fn test_id3_tag_size() {
+		assert_eq!(get_id3_tag_size(b"ID3\x04\x00\x00\x00\x00\x02\x01"), Some(10 + 257));
+		assert_eq!(get_id3_tag_size(b"ID3\x04\x00\x10\x00\x00\x00\x05"), Some(10 + 5 + 10));
+		assert_eq!(get_id3_tag_size(b"fLaC\x00\x00\x00\x22\x00\x00"), None);
+	}
+
+	#[test]
+	fn test_sniff_no_audio() {
+		assert_eq!(AudioFormat::from_header(b""), None);
+		assert_eq!(AudioFormat::from_header(b"\x89PNG\r\n\x1a\n"), None);
+		assert_eq!(AudioFormat::from_header(&ogg_page(b"\x80theora")), None);
+		assert_eq!(AudioFormat::from_header(b"just some text"), None);
+		assert_eq!(AudioFormat::from_header(b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00mif1heic"), None);
+		assert_eq!(AudioFormat::from_header(b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00avifmif1miaf"), None);
+		assert_eq!(AudioFormat::from_header(b"\x00\x00\x00\x14ftypqt  \x00\x00\x02\x00qt  "), None);
+		assert_eq!(AudioFormat::from_header(b"\x00\x00\x00\x18ftyp3gp4\x00\x00\x02\x003gp43gp6"), None);
+	}
+
+	fn mp4_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+		let mut mp4_box = (content.len() as u32 + 8).to_be_bytes().to_vec();
+		mp4_box.extend_from_slice(kind);
+		mp4_box.extend_from_slice(content);
+		mp4_box
+	}
+
+	fn mp4_track(handler_type: &[u8; 4]) -> Vec<u8> {
+		let handler = [&[0; 8], handler_type.as_slice(), &[0; 12]].concat();
+		mp4_box(b"trak", &mp4_box(b"mdia", &mp4_box(b"hdlr", &handler)))
+	}
+
+	fn mp4_file(tracks: &[Vec<u8>]) -> Vec<u8> {
+		[mp4_box(b"ftyp", b"isom\x00\x00\x02\x00isomiso2mp41"), mp4_box(b"mdat", &[0; 32]), mp4_box(b"moov", &tracks.concat())].concat()
+	}
+
+	fn ebml_element(id: &[u8], content: &[u8]) -> Vec<u8> {
+		[id, &[0x80 | content.len() as u8], content].concat()
+	}
+
+	fn matroska_file(doc_type: &[u8], track_types: &[u8]) -> Vec<u8> {
+		let header = ebml_element(b"\x1a\x45\xdf\xa3", &[ebml_element(b"\x42\x86", &[1]), ebml_element(b"\x42\x82", doc_type)].concat());
+		let tracks: Vec<u8> = track_types.iter().flat_map(|track_type| ebml_element(b"\xae", &ebml_element(b"\x83", &[*track_type]))).collect();
+		let segment = [ebml_element(b"\x15\x49\xa9\x66", &[0; 8]), ebml_element(b"\x16\x54\xae\x6b", &tracks)].concat();
+		[header, ebml_element(b"\x18\x53\x80\x67", &segment)].concat()
+	}
+
+	fn detect(bytes: Vec<u8>) -> Option<AudioFormat> {
+		AudioFormat::detect(&mut std::io::Cursor::new(bytes))
+	}
+
+	#[test]
+	fn test_sniff_containers() {
+		assert_eq!(detect(mp4_file(&[mp4_track(b"soun")])), Some(AudioFormat::Mp4));
+		assert_eq!(detect(mp4_file(&[mp4_track(b"vide"), mp4_track(b"soun")])), None);
+		assert_eq!(detect(mp4_file(&[])), None);
+		assert_eq!(detect(matroska_file(b"matroska", &[2])), Some(AudioFormat::Matroska));
+		assert_eq!(detect(matroska_file(b"webm", &[2])), Some(AudioFormat::Matroska));
+		assert_eq!(detect(matroska_file(b"webm", &[1, 2])), None);
+		assert_eq!(detect(matroska_file(b"other", &[2])), None);
+	}
+}
@@ -0,0 +1,132 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// The interval, in which the audio backend reports its health.
+pub const HEALTH_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Measurements of the audio backend, that help to find the cause of stutters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackendHealth {
+	/// the number of times the source ran out of chunks while a song was playing, since the backend started
+	pub underruns: u32,
+	/// the duration of the decoded audio divided by the time spent decoding it. Below 1.0 the loader cannot keep up.
+	pub decode_speed: Option<f32>,
+	/// the number of chunks, that wait for the source at the moment
+	pub queue_depth: usize,
+	/// the lowest number of waiting chunks in the last interval
+	pub min_queue_depth: Option<usize>,
+	/// the mean and maximum time between receiving a command and finishing it in the last interval
+	pub command_latency: Option<Duration>,
+	pub max_command_latency: Option<Duration>,
+}
+
+impl Display for BackendHealth {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(f, "underruns: {}", self.underruns)?;
+		if let Some(decode_speed) = self.decode_speed {
+			write!(f, "  decode: {:.1}x realtime", decode_speed)?;
+		}
+		write!(f, "  queue: {}", self.queue_depth)?;
+		if let Some(min_queue_depth) = self.min_queue_depth {
+			write!(f, " (min {})", min_queue_depth)?;
+		}
+		if let (Some(latency), Some(max_latency)) = (self.command_latency, self.max_command_latency) {
+			write!(f, "  command latency: {:.2} ms (max {:.2} ms)", latency.as_secs_f64() * 1000.0, max_latency.as_secs_f64() * 1000.0)?;
+		}
+		Ok(())
+	}
+}
+
+/// Collects the measurements of the audio backend for one report interval.
+pub struct HealthMonitor {
+	interval_start: Instant,
+	decoded_audio: Duration,
+	decode_time: Duration,
+	min_queue_depth: Option<usize>,
+	command_latency_sum: Duration,
+	max_command_latency: Option<Duration>,
+	num_commands: u32,
+}
+
+impl HealthMonitor {
+	pub fn new() -> HealthMonitor {
+		HealthMonitor {
+			interval_start: Instant::now(),
+			decoded_audio: Duration::ZERO,
+			decode_time: Duration::ZERO,
+			min_queue_depth: None,
+			command_latency_sum: Duration::ZERO,
+			max_command_latency: None,
+			num_commands: 0,
+		}
+	}
+
+	/// Adds a chunk of the given duration, that took decode_time to decode.
+	pub fn add_decoded_chunk(&mut self, audio_duration: Duration, decode_time: Duration) {
+		self.decoded_audio += audio_duration;
+		self.decode_time += decode_time;
+	}
+
+	pub fn add_queue_depth(&mut self, queue_depth: usize) {
+		self.min_queue_depth = Some(self.min_queue_depth.map_or(queue_depth, |depth| depth.min(queue_depth)));
+	}
+
+	pub fn add_command_latency(&mut self, latency: Duration) {
+		self.command_latency_sum += latency;
+		self.max_command_latency = Some(self.max_command_latency.map_or(latency, |max| max.max(latency)));
+		self.num_commands += 1;
+	}
+
+	/// Returns the health of the last interval and starts a new one, if the report interval is over.
+	pub fn report(&mut self, underruns: u32, queue_depth: usize) -> Option<BackendHealth> {
+		if self.interval_start.elapsed() < HEALTH_REPORT_INTERVAL {
+			return None;
+		}
+		let health = BackendHealth {
+			underruns,
+			decode_speed: if self.decode_time.is_zero() {
+				None
+			} else {
+				Some(self.decoded_audio.as_secs_f32() / self.decode_time.as_secs_f32())
+			},
+			queue_depth,
+			min_queue_depth: self.min_queue_depth,
+			command_latency: (self.num_commands > 0).then(|| self.command_latency_sum / self.num_commands),
+			max_command_latency: self.max_command_latency,
+		};
+		*self = HealthMonitor::new();
+		Some(health)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_health_report() {
+		let mut monitor = HealthMonitor::new();
+		monitor.add_decoded_chunk(Duration::from_millis(20), Duration::from_millis(1));
+		monitor.add_decoded_chunk(Duration::from_millis(20), Duration::from_millis(3));
+		monitor.add_queue_depth(3);
+		monitor.add_queue_depth(1);
+		monitor.add_command_latency(Duration::from_millis(1));
+		monitor.add_command_latency(Duration::from_millis(5));
+		assert!(monitor.report(0, 4).is_none());
+
+		monitor.interval_start -= HEALTH_REPORT_INTERVAL;
+		let health = monitor.report(2, 4).unwrap();
+		assert_eq!(health.underruns, 2);
+		assert!((health.decode_speed.unwrap() - 10.0).abs() < 1e-3);
+		assert_eq!(health.queue_depth, 4);
+		assert_eq!(health.min_queue_depth, Some(1));
+		assert_eq!(health.command_latency, Some(Duration::from_millis(3)));
+		assert_eq!(health.max_command_latency, Some(Duration::from_millis(5)));
+
+		// the next interval starts empty
+		monitor.interval_start -= HEALTH_REPORT_INTERVAL;
+		let health = monitor.report(2, 4).unwrap();
+		assert_eq!(health.decode_speed, None);
+		assert_eq!(health.command_latency, None);
+	}
+}
@@ -0,0 +1,284 @@
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::Instant;
+
+use crossbeam::Sender;
+use threadpool::ThreadPool;
+
+use crate::audio_backend::{AudioBackendCommand, LoadInfo};
+use crate::audio_backend::chunk::{CHUNK_SIZE, position_to_duration, SamplesChunk};
+use crate::audio_backend::converter::FormatConverter;
+use crate::audio_backend::decoder::SongDecoder;
+use crate::audio_backend::loudness::{ANALYZER_THREAD_NAME, spawn_analyzer};
+use crate::song::{Song, SongID};
+
+/// The number of samples the loader decodes ahead of the play position.
+pub const LOAD_AHEAD_SAMPLES: usize = CHUNK_SIZE * 512;
+/// The number of threads of the loader pool. Every loader uses one thread, until its handle is dropped. The current,
+/// the next and the prefetched songs are loaded at the same time.
+pub const LOADER_THREADS: usize = 8;
+
+struct LoaderState {
+	/// the loader does not decode chunks starting at or after this position
+	window_end: usize,
+	/// if set, the loader continues decoding at this position
+	seek_position: Option<usize>,
+	seek_generation: u32,
+	cancelled: bool,
+}
+
+struct LoaderControl {
+	state: Mutex<LoaderState>,
+	condvar: Condvar,
+}
+
+/// Handle to a loader thread, that decodes a song into chunks.
+/// The loader only decodes up to the window end and blocks afterwards, until the window is moved.
+/// Dropping the handle stops the loader thread.
+pub struct LoaderHandle {
+	control: Arc<LoaderControl>,
+	seek_generation: u32,
+}
+
+impl LoaderHandle {
+	/// Starts a loader in the given thread pool, that starts decoding the given song at start_position.
+	/// The song is converted into the given output format. All positions are given in samples of this format.
+	pub fn spawn(
+		loader_pool: &ThreadPool, song: Song, start_position: usize, output_format: (u32, u16), sender: Sender<AudioBackendCommand>
+	) -> LoaderHandle {
+		let control = Arc::new(LoaderControl {
+			state: Mutex::new(LoaderState {
+				window_end: start_position + LOAD_AHEAD_SAMPLES,
+				seek_position: if start_position == 0 { None } else { Some(start_position) },
+				seek_generation: 0,
+				cancelled: false,
+			}),
+			condvar: Condvar::new(),
+		});
+		let loader_control = control.clone();
+		loader_pool.execute(move || {
+			load_chunks(song, output_format, loader_control, sender);
+		});
+
+		LoaderHandle {
+			control,
+			seek_generation: 0,
+		}
+	}
+
+	/// Allows the loader to decode chunks up to the given position.
+	pub fn set_window_end(&self, window_end: usize) {
+		let mut state = self.control.state.lock().unwrap_or_else(PoisonError::into_inner);
+		if state.window_end != window_end {
+			state.window_end = window_end;
+			self.control.condvar.notify_all();
+		}
+	}
+
+	/// Lets the loader continue decoding at the given position.
+	/// Returns the new seek generation. Chunks of older generations should be dropped.
+	pub fn seek(&mut self, position: usize) -> u32 {
+		self.seek_generation += 1;
+		let mut state = self.control.state.lock().unwrap_or_else(PoisonError::into_inner);
+		state.seek_position = Some(position);
+		state.seek_generation = self.seek_generation;
+		state.window_end = position + LOAD_AHEAD_SAMPLES;
+		self.control.condvar.notify_all();
+		self.seek_generation
+	}
+
+	pub fn get_seek_generation(&self) -> u32 {
+		self.seek_generation
+	}
+}
+
+impl Drop for LoaderHandle {
+	fn drop(&mut self) {
+		let mut state = self.control.state.lock().unwrap_or_else(PoisonError::into_inner);
+		state.cancelled = true;
+		self.control.condvar.notify_all();
+	}
+}
+
+/// Collects decoded samples into chunks.
+struct ChunkBuilder {
+	data: Box<[f32; CHUNK_SIZE]>,
+	length: usize,
+	start_position: usize,
+}
+
+impl ChunkBuilder {
+	fn new(start_position: usize) -> ChunkBuilder {
+		ChunkBuilder {
+			data: Box::new([0.0; CHUNK_SIZE]),
+			length: 0,
+			start_position,
+		}
+	}
+
+	fn reset(&mut self, start_position: usize) {
+		self.length = 0;
+		self.start_position = start_position;
+	}
+
+	/// Adds samples to the chunk. Returns the number of samples used.
+	fn fill(&mut self, samples: &[f32]) -> usize {
+		let count = samples.len().min(CHUNK_SIZE - self.length);
+		self.data[self.length..self.length + count].copy_from_slice(&samples[..count]);
+		self.length += count;
+		count
+	}
+
+	fn is_full(&self) -> bool {
+		self.length == CHUNK_SIZE
+	}
+
+	fn build(&mut self, song_id: SongID, sample_rate: u32, channels: u16, last_chunk: bool) -> SamplesChunk {
+		let mut data = self.data.clone();
+		data[self.length..].fill(0.0);
+		let chunk = SamplesChunk {
+			channels,
+			sample_rate,
+			start_position: self.start_position,
+			length: self.length,
+			data: Arc::from(data),
+			song_id,
+			last_chunk,
+			generation: 0,
+		};
+		self.reset(self.start_position + self.length);
+		chunk
+	}
+}
+
+/// Waits until the next chunk may be decoded.
+/// Returns None, if the loader is cancelled, otherwise the seek position and generation, if a seek was requested.
+fn wait_for_window(control: &LoaderControl, next_position: usize, finished: bool) -> Option<Option<(usize, u32)>> {
+	let mut state = control.state.lock().unwrap_or_else(PoisonError::into_inner);
+	loop {
+		if state.cancelled {
+			return None;
+		}
+		if let Some(seek_position) = state.seek_position.take() {
+			return Some(Some((seek_position, state.seek_generation)));
+		}
+		if !finished && next_position < state.window_end {
+			return Some(None);
+		}
+		state = control.condvar.wait(state).unwrap_or_else(PoisonError::into_inner);
+	}
+}
+
+/// Loads chunks of the given song, while applying back-pressure from the loader control.
+fn load_chunks(song: Song, output_format: (u32, u16), control: Arc<LoaderControl>, sender: Sender<AudioBackendCommand>) {
+	let song_id = song.get_id();
+	let (sample_rate, channels) = output_format;
+	let mut decoder = match SongDecoder::open_song(&song).and_then(|decoder| FormatConverter::new(decoder, sample_rate, channels)) {
+		Ok(decoder) => decoder,
+		Err(e) => {
+			let _ = sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Err(song_id, e)));
+			return;
+		}
+	};
+	let _ = sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Format(song_id, decoder.sample_rate, decoder.channels)));
+	match decoder.get_decoder().replay_gain {
+		Some(replay_gain) => {
+			let _ = sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Loudness(song_id, replay_gain)));
+		}
+		None => {
+			if song.get_loudness().is_none() {
+				if let Err(e) = spawn_analyzer(song.clone(), sender.clone()) {
+					let _ = sender.send(AudioBackendCommand::LoadInfo(LoadInfo::SpawnFailed(ANALYZER_THREAD_NAME, e)));
+				}
+			}
+		}
+	}
+	if let Some(duration) = decoder.get_decoder().total_duration {
+		let _ = sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Duration(song_id, duration)));
+	}
+
+	let mut seek_generation = 0;
+	let mut chunk_builder = ChunkBuilder::new(0);
+	// decoded samples, that are not yet added to a chunk
+	let mut pending: Vec<f32> = Vec::new();
+	let mut finished = false;
+
+	loop {
+		match wait_for_window(&control, chunk_builder.start_position, finished) {
+			None => return,
+			Some(Some((seek_position, generation))) => {
+				seek_generation = generation;
+				pending.clear();
+				finished = false;
+				let position = match decoder.seek(seek_position) {
+					Ok(position) => position,
+					Err(_) => {
+						// the decoder can not seek -> decode from the start and skip samples
+						match skip_to_position(&song, output_format, seek_position) {
+							Some((new_decoder, samples, position)) => {
+								decoder = new_decoder;
+								pending = samples;
+								position
+							}
+							None => {
+								finished = true;
+								0
+							}
+						}
+					}
+				};
+				chunk_builder.reset(position);
+				continue;
+			}
+			Some(None) => {}
+		}
+
+		// fill the next chunk
+		let decode_start = Instant::now();
+		while !chunk_builder.is_full() {
+			if pending.is_empty() {
+				match decoder.next_samples() {
+					Some(samples) => pending.extend_from_slice(samples),
+					None => break,
+				}
+			}
+			let used = chunk_builder.fill(&pending);
+			pending.drain(..used);
+		}
+
+		// peek, whether this is the last chunk
+		if pending.is_empty() {
+			if let Some(samples) = decoder.next_samples() {
+				pending.extend_from_slice(samples);
+			}
+		}
+		let last_chunk = pending.is_empty();
+		let chunk = chunk_builder.build(song_id, decoder.sample_rate, decoder.channels, last_chunk);
+		if last_chunk {
+			finished = true;
+			if decoder.get_decoder().total_duration.is_none() {
+				let duration = position_to_duration(chunk.start_position + chunk.length, decoder.sample_rate, decoder.channels);
+				let _ = sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Duration(song_id, duration)));
+			}
+		}
+		if sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Chunk(chunk, seek_generation, decode_start.elapsed()))).is_err() {
+			return;
+		}
+	}
+}
+
+/// Opens the song again and drops all samples before the given position.
+/// Returns the decoder, the decoded samples starting at the returned position and this position.
+/// If the song ends before the given position, the end of the song is returned.
+fn skip_to_position(song: &Song, output_format: (u32, u16), position: usize) -> Option<(FormatConverter, Vec<f32>, usize)> {
+	let mut decoder = FormatConverter::new(SongDecoder::open_song(song).ok()?, output_format.0, output_format.1).ok()?;
+	let position = position - position % decoder.channels as usize;
+	let mut current_position = 0;
+	while let Some(samples) = decoder.next_samples() {
+		if current_position + samples.len() > position {
+			let remaining = samples[position - current_position..].to_vec();
+			return Some((decoder, remaining, position));
+		}
+		current_position += samples.len();
+	}
+	Some((decoder, Vec::new(), current_position))
+}
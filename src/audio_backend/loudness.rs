@@ -0,0 +1,316 @@
+use std::f64::consts::PI;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::thread;
+
+use crossbeam::Sender;
+use serde::{Serialize, Deserialize};
+use symphonia::core::meta::{StandardTagKey, Tag};
+
+use crate::audio_backend::{AudioBackendCommand, LoadInfo, OpenError};
+use crate::audio_backend::decoder::SongDecoder;
+use crate::audio_backend::filter::Biquad;
+use crate::song::{Loudness, Song};
+
+pub const ANALYZER_THREAD_NAME: &str = "loudness analyzer";
+/// The loudness in LUFS, that songs are normalized to (ReplayGain 2.0).
+const REFERENCE_LOUDNESS: f64 = -18.0;
+/// Blocks quieter than this (in LUFS) are ignored.
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Blocks quieter than the mean loudness minus this (in LU) are ignored.
+const RELATIVE_GATE: f64 = 10.0;
+/// Loudness measurement uses blocks of 400 ms, that overlap by 75%.
+const STEPS_PER_SECOND: u32 = 10;
+const STEPS_PER_BLOCK: usize = 4;
+
+/// Selects which gain of a song is applied by the audio backend.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Default)]
+pub enum ReplayGainMode {
+	#[default]
+	Off,
+	Track,
+	Album, // uses the track gain, if a song has no album gain
+}
+
+impl Display for ReplayGainMode {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ReplayGainMode::Off => write!(f, "off"),
+			ReplayGainMode::Track => write!(f, "track"),
+			ReplayGainMode::Album => write!(f, "album"),
+		}
+	}
+}
+
+impl ReplayGainMode {
+	pub fn next(self) -> ReplayGainMode {
+		match self {
+			ReplayGainMode::Off => ReplayGainMode::Track,
+			ReplayGainMode::Track => ReplayGainMode::Album,
+			ReplayGainMode::Album => ReplayGainMode::Off,
+		}
+	}
+}
+
+/// Returns the factor, the samples of a song with the given loudness are multiplied with.
+/// The gain is reduced, if the peak of the song would clip otherwise.
+pub fn get_gain(loudness: Option<&Loudness>, mode: ReplayGainMode) -> f32 {
+	let (gain, peak) = match (mode, loudness) {
+		(ReplayGainMode::Off, _) | (_, None) => return 1.0,
+		(ReplayGainMode::Track, Some(loudness)) => (loudness.track_gain, loudness.track_peak),
+		(ReplayGainMode::Album, Some(loudness)) => match loudness.album_gain {
+			Some(album_gain) => (album_gain, loudness.album_peak),
+			None => (loudness.track_gain, loudness.track_peak),
+		},
+	};
+	let factor = 10f32.powf(gain / 20.0);
+	match peak {
+		Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+		_ => factor,
+	}
+}
+
+/// Reads the ReplayGain tags of a song. Returns None, if the song has no track or album gain.
+pub fn read_replay_gain_tags(tags: &[Tag]) -> Option<Loudness> {
+	let mut track_gain = None;
+	let mut track_peak = None;
+	let mut album_gain = None;
+	let mut album_peak = None;
+	for tag in tags {
+		let value = tag.value.to_string();
+		match tag.std_key {
+			Some(StandardTagKey::ReplayGainTrackGain) => track_gain = parse_tag_value(&value),
+			Some(StandardTagKey::ReplayGainTrackPeak) => track_peak = parse_tag_value(&value),
+			Some(StandardTagKey::ReplayGainAlbumGain) => album_gain = parse_tag_value(&value),
+			Some(StandardTagKey::ReplayGainAlbumPeak) => album_peak = parse_tag_value(&value),
+			_ => {}
+		}
+	}
+	Some(Loudness {
+		track_gain: track_gain.or(album_gain)?,
+		track_peak: track_peak.or(album_peak),
+		album_gain,
+		album_peak,
+	})
+}
+
+/// Parses tag values like "-6.54 dB" or "0.988525".
+fn parse_tag_value(value: &str) -> Option<f32> {
+	let value = value.trim().to_lowercase();
+	let value = value.strip_suffix("db").unwrap_or(&value).trim();
+	value.parse().ok().filter(|v: &f32| v.is_finite())
+}
+
+/// Computes the loudness of the given song in a background thread and sends it to the audio backend.
+pub fn spawn_analyzer(song: Song, sender: Sender<AudioBackendCommand>) -> io::Result<()> {
+	thread::Builder::new().name(ANALYZER_THREAD_NAME.to_string()).spawn(move || {
+		if let Ok(loudness) = analyze_song(&song) {
+			let _ = sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Loudness(song.get_id(), loudness)));
+		}
+	})?;
+	Ok(())
+}
+
+/// Decodes the whole song and measures its loudness.
+pub fn analyze_song(song: &Song) -> Result<Loudness, OpenError> {
+	let mut decoder = SongDecoder::open_song(song)?;
+	let mut meter = LoudnessMeter::new(decoder.sample_rate, decoder.channels);
+	while let Some(samples) = decoder.next_samples() {
+		meter.add_samples(samples);
+	}
+	// silent songs are not changed
+	let track_gain = meter.get_integrated_loudness().map_or(0.0, |loudness| REFERENCE_LOUDNESS - loudness);
+	Ok(Loudness {
+		track_gain: track_gain as f32,
+		track_peak: Some(meter.peak),
+		album_gain: None,
+		album_peak: None,
+	})
+}
+
+/// Creates the two filters of the K-weighting (ITU-R BS.1770) for the given sample rate.
+fn k_weighting_filters(sample_rate: u32) -> [Biquad; 2] {
+	let sample_rate = sample_rate as f64;
+
+	// high shelf, that models the acoustic effect of the head
+	let f0 = 1681.974450955533;
+	let gain = 3.999843853973347;
+	let q = 0.7071752369554196;
+	let k = (PI * f0 / sample_rate).tan();
+	let vh = 10f64.powf(gain / 20.0);
+	let vb = vh.powf(0.4996667741545416);
+	let a0 = 1.0 + k / q + k * k;
+	let shelf = Biquad::new(
+		[(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+		[2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+	);
+
+	// high pass
+	let f0 = 38.13547087602444;
+	let q = 0.5003270373238773;
+	let k = (PI * f0 / sample_rate).tan();
+	let a0 = 1.0 + k / q + k * k;
+	let high_pass = Biquad::new(
+		[1.0, -2.0, 1.0],
+		[2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+	);
+
+	[shelf, high_pass]
+}
+
+/// Returns the weight of a channel. Surround channels are louder, the LFE channel is ignored.
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+	match (channels, channel) {
+		(5, 3..=4) | (6, 4..=5) => 1.41,
+		(6, 3) => 0.0,
+		_ => 1.0,
+	}
+}
+
+/// Measures the integrated loudness as defined by EBU R128.
+pub struct LoudnessMeter {
+	channels: usize,
+	filters: Vec<[Biquad; 2]>,
+	frames_per_step: usize,
+	/// sum of the squared, filtered samples of the current step per channel
+	step_sums: Vec<f64>,
+	step_frames: usize,
+	/// weighted mean square of the last steps
+	last_steps: Vec<f64>,
+	/// weighted mean square of all 400 ms blocks
+	blocks: Vec<f64>,
+	/// the current channel of the next sample
+	channel: usize,
+	pub peak: f32,
+}
+
+impl LoudnessMeter {
+	pub fn new(sample_rate: u32, channels: u16) -> LoudnessMeter {
+		let channels = channels.max(1) as usize;
+		LoudnessMeter {
+			channels,
+			filters: vec![k_weighting_filters(sample_rate); channels],
+			frames_per_step: (sample_rate / STEPS_PER_SECOND).max(1) as usize,
+			step_sums: vec![0.0; channels],
+			step_frames: 0,
+			last_steps: Vec::with_capacity(STEPS_PER_BLOCK),
+			blocks: Vec::new(),
+			channel: 0,
+			peak: 0.0,
+		}
+	}
+
+	/// Adds interleaved samples.
+	pub fn add_samples(&mut self, samples: &[f32]) {
+		for sample in samples {
+			self.peak = self.peak.max(sample.abs());
+			let [shelf, high_pass] = &mut self.filters[self.channel];
+			let filtered = high_pass.process(shelf.process(*sample as f64));
+			self.step_sums[self.channel] += filtered * filtered;
+
+			self.channel += 1;
+			if self.channel == self.channels {
+				self.channel = 0;
+				self.step_frames += 1;
+				if self.step_frames == self.frames_per_step {
+					self.finish_step();
+				}
+			}
+		}
+	}
+
+	fn finish_step(&mut self) {
+		let step: f64 = self.step_sums.iter().enumerate()
+			.map(|(channel, sum)| channel_weight(channel, self.channels) * sum / self.step_frames as f64)
+			.sum();
+		self.step_sums.iter_mut().for_each(|s| *s = 0.0);
+		self.step_frames = 0;
+
+		if self.last_steps.len() == STEPS_PER_BLOCK {
+			self.last_steps.remove(0);
+		}
+		self.last_steps.push(step);
+		if self.last_steps.len() == STEPS_PER_BLOCK {
+			self.blocks.push(self.last_steps.iter().sum::<f64>() / STEPS_PER_BLOCK as f64);
+		}
+	}
+
+	/// Returns the gated loudness in LUFS of all samples added so far. Returns None, if the signal is silent.
+	pub fn get_integrated_loudness(&self) -> Option<f64> {
+		let absolute_threshold = loudness_to_energy(ABSOLUTE_GATE);
+		let mean_energy = mean(self.blocks.iter().copied().filter(|e| *e > absolute_threshold))?;
+		let relative_threshold = mean_energy * 10f64.powf(-RELATIVE_GATE / 10.0);
+		let gated_energy = mean(self.blocks.iter().copied().filter(|e| *e > relative_threshold))?;
+		Some(energy_to_loudness(gated_energy))
+	}
+}
+
+fn mean(values: impl Iterator<Item=f64>) -> Option<f64> {
+	let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+	if count == 0 {
+		None
+	} else {
+		Some(sum / count as f64)
+	}
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+	-0.691 + 10.0 * energy.log10()
+}
+
+fn loudness_to_energy(loudness: f64) -> f64 {
+	10f64.powf((loudness + 0.691) / 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Creates a stereo sine with the given frequency, amplitude and duration in seconds.
+	fn create_sine(frequency: f64, amplitude: f64, sample_rate: u32, seconds: u32) -> Vec<f32> {
+		(0..sample_rate * seconds).flat_map(|frame| {
+			let value = (amplitude * (2.0 * PI * frequency * frame as f64 / sample_rate as f64).sin()) as f32;
+			[value, value]
+		}).collect()
+	}
+
+	#[test]
+	fn test_integrated_loudness_of_sine() {
+		// EBU Tech 3341, test case 1: a stereo sine of 1 kHz at -23 dBFS is measured as -23 LUFS
+		for sample_rate in [44100, 48000] {
+			let mut meter = LoudnessMeter::new(sample_rate, 2);
+			meter.add_samples(&create_sine(1000.0, 10f64.powf(-23.0 / 20.0), sample_rate, 20));
+			let loudness = meter.get_integrated_loudness().unwrap();
+			assert!((loudness + 23.0).abs() < 0.1, "measured {} LUFS", loudness);
+		}
+	}
+
+	#[test]
+	fn test_silence_has_no_loudness() {
+		let mut meter = LoudnessMeter::new(44100, 2);
+		meter.add_samples(&[0.0; 44100 * 2 * 2]);
+		assert!(meter.get_integrated_loudness().is_none());
+	}
+
+	#[test]
+	fn test_get_gain() {
+		let loudness = Loudness { track_gain: 6.0, track_peak: Some(0.25), album_gain: Some(-6.0), album_peak: None };
+		assert_eq!(get_gain(Some(&loudness), ReplayGainMode::Off), 1.0);
+		assert_eq!(get_gain(None, ReplayGainMode::Track), 1.0);
+		assert!((get_gain(Some(&loudness), ReplayGainMode::Track) - 1.995).abs() < 0.001);
+		assert!((get_gain(Some(&loudness), ReplayGainMode::Album) - 0.501).abs() < 0.001);
+
+		// clipping prevention
+		let loudness = Loudness { track_gain: 6.0, track_peak: Some(0.8), album_gain: None, album_peak: None };
+		assert_eq!(get_gain(Some(&loudness), ReplayGainMode::Track), 1.25);
+		assert_eq!(get_gain(Some(&loudness), ReplayGainMode::Album), 1.25);
+	}
+
+	#[test]
+	fn test_parse_tag_value() {
+		assert_eq!(parse_tag_value("-6.54 dB"), Some(-6.54));
+		assert_eq!(parse_tag_value("+2.10 db"), Some(2.1));
+		assert_eq!(parse_tag_value("0.988525"), Some(0.988525));
+		assert_eq!(parse_tag_value("loud"), None);
+	}
+}
@@ -1,59 +1,329 @@
-use std::fmt::{Debug, Formatter};
-use std::fs::File;
-use std::io::BufReader;
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_2;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crossbeam::{bounded, Receiver, Sender};
-use rodio::{cpal, Decoder, DeviceTrait, Sink, Source, OutputStream};
-use rodio::cpal::traits::HostTrait;
+use threadpool::ThreadPool;
 
-use crate::audio_backend::chunk::{CHUNK_SIZE, duration_to_position, position_to_duration, SamplesChunk};
-use crate::audio_backend::receiver_source::ReceiverSource;
+use crate::audio_backend::chunk::{CHUNK_SIZE, duration_to_position, position_to_duration};
+use crate::audio_backend::device::{DeviceError, list_output_devices};
+use crate::audio_backend::equalizer::{Equalizer, EqualizerPreset};
+use crate::audio_backend::health::{BackendHealth, HealthMonitor};
+use crate::audio_backend::loader::{LOAD_AHEAD_SAMPLES, LOADER_THREADS, LoaderHandle};
+use crate::audio_backend::loudness::{get_gain, ReplayGainMode};
+use crate::audio_backend::output::{AudioOutput, NullOutput, open_output, OutputConfig, PUMP_THREAD_NAME};
+use crate::audio_backend::receiver_source::{ReceiverSource, SourceControl};
+use crate::audio_backend::song_cache::{CACHED_HEAD_SAMPLES, SongCache};
+pub use crate::audio_backend::receiver_source::DEFAULT_FADE_DURATION;
+pub use crate::audio_backend::chunk::SamplesChunk;
+use crate::audio_backend::supervisor::BackendError;
+use crate::audio_backend::time_stretch::{MAX_SPEED, MIN_SPEED, TimeStretcher};
 use crate::musicus::log;
-use crate::song::{Song, SongID};
+use crate::song::{Loudness, Song, SongID};
 
 mod receiver_source;
 mod chunk;
+mod device;
+pub mod output;
+mod decoder;
+pub mod format;
+mod opus;
+mod wavpack;
+mod converter;
+mod filter;
+pub mod equalizer;
+mod loader;
+mod song_cache;
+mod time_stretch;
+pub mod health;
+pub mod supervisor;
+pub mod loudness;
 
 const CHUNK_BUFFER_SIZE: usize = 4;
+/// The number of already played samples, that are kept in memory to allow fast seeking backwards.
+const KEEP_BEHIND_SAMPLES: usize = CHUNK_SIZE * 512;
 
 pub struct AudioBackend {
-	sink: Sink,
-	_stream: OutputStream,
+	output: Box<dyn AudioOutput>,
 
 	/// sender for info to musicus
     info_sender: Sender<AudioInfo>,
 	/// sender to source
 	source_chunk_sender: Sender<SamplesChunk>,
-	/// sender to audio backend, used to create loader threads
+	/// sender to audio backend, given to loader threads
 	audio_backend_sender: Sender<AudioBackendCommand>,
+	/// lets the source fade out and in on pause, unpause, seek and skip
+	source_control: Arc<SourceControl>,
+	/// receives copies of the played chunks, while the sample tap is enabled
+	sample_tap: Sender<SamplesChunk>,
+	health_monitor: HealthMonitor,
+	/// runs the loaders of all songs
+	loader_pool: ThreadPool,
+	/// the decoded start of recently played and prefetched songs
+	song_cache: SongCache,
+	/// loaders, that decode the start of songs, that will be played soon, into the song cache
+	prefetch_loaders: Vec<(SongID, LoaderHandle)>,
 
 	current_song: Option<CurrentSongState>,
 	next_song: Option<(Song, AudioSong)>,
 	volume: f32,
+	paused: bool,
+	/// duration of the crossfade between two songs, zero for gapless playback
+	crossfade: Duration,
+	replay_gain_mode: ReplayGainMode,
+	/// sample rate and channels of the output. All songs are converted to this format.
+	output_format: (u32, u16),
+	/// filters all chunks before they are sent to the source, None if the equalizer is disabled
+	equalizer: Option<Equalizer>,
+	/// changes the speed of songs, that are not played at normal speed
+	time_stretcher: TimeStretcher,
+	/// playback stops, when the current song is finished
+	stop_after_current: bool,
 }
 
+/// The decoded part of a song. Only a window around the play position is kept in memory.
 struct AudioSong {
+	song: Song,
 	song_id: SongID,
-	chunks: Vec<SamplesChunk>,
+	/// the directory of the song file. Songs of the same directory are not crossfaded.
+	directory: Option<PathBuf>,
+	/// contiguous chunks around the play position
+	chunks: VecDeque<SamplesChunk>,
 	sample_rate_and_channels: Option<(u32, u16)>,
+	loader: Option<LoaderHandle>,
+	/// the position the loader started decoding at after the last seek
+	load_start: usize,
+	/// the position after the last sample of the song, if it is known
+	end_position: Option<usize>,
+	total_duration: Option<Duration>,
+	/// a seek, that can only be done after the format or duration of the song is known
+	pending_seek: Option<SeekPosition>,
+	/// the song could not be opened
+	failed: bool,
+	loudness: Option<Loudness>,
+	/// the factor all samples are multiplied with
+	gain: f32,
+	/// some samples of this song were sent to the source
+	started: bool,
+	/// the playback speed, 1.0 is normal speed
+	speed: f32,
 }
 
 impl AudioSong {
-	fn new(song_id: SongID) -> AudioSong {
+	/// Creates an audio song, that starts with the given cached chunks from the start of the song. The loader continues after them.
+	fn new(
+		song: Song, cached_chunks: Vec<SamplesChunk>, loader_pool: &ThreadPool, audio_backend_sender: Sender<AudioBackendCommand>,
+		replay_gain_mode: ReplayGainMode, output_format: (u32, u16)
+	) -> AudioSong {
+		let loudness = song.get_loudness().copied();
+		let cached_end = cached_chunks.last().map_or(0, |c| c.start_position + c.length);
 		AudioSong {
-			song_id,
-			chunks: Vec::new(),
-			sample_rate_and_channels: None,
+			song_id: song.get_id(),
+			directory: song.get_path().parent().map(|p| p.to_path_buf()),
+			sample_rate_and_channels: cached_chunks.first().map(|c| (c.sample_rate, c.channels)),
+			end_position: cached_chunks.last().filter(|c| c.last_chunk).map(|_| cached_end),
+			chunks: VecDeque::from(cached_chunks),
+			loader: Some(LoaderHandle::spawn(loader_pool, song.clone(), cached_end, output_format, audio_backend_sender)),
+			song,
+			load_start: 0,
+			total_duration: None,
+			pending_seek: None,
+			failed: false,
+			loudness,
+			gain: get_gain(loudness.as_ref(), replay_gain_mode),
+			started: false,
+			speed: 1.0,
+		}
+	}
+
+	/// Decodes the song again in another output format, starting at the given position in this format.
+	fn restart(&mut self, position: usize, output_format: (u32, u16), loader_pool: &ThreadPool, audio_backend_sender: Sender<AudioBackendCommand>) {
+		self.loader = Some(LoaderHandle::spawn(loader_pool, self.song.clone(), position, output_format, audio_backend_sender));
+		self.chunks.clear();
+		self.load_start = position;
+		self.end_position = None;
+		if self.sample_rate_and_channels.is_some() {
+			self.sample_rate_and_channels = Some(output_format);
+		}
+	}
+
+	/// Sets the loudness of the song. To avoid jumps in volume, the gain is only changed, if the song did not start yet.
+	fn set_loudness(&mut self, loudness: Loudness, replay_gain_mode: ReplayGainMode) {
+		self.loudness = Some(loudness);
+		if !self.started {
+			self.gain = get_gain(self.loudness.as_ref(), replay_gain_mode);
+		}
+	}
+
+	/// Returns the chunk containing the given position.
+	fn get_chunk(&self, position: usize) -> Option<&SamplesChunk> {
+		let index = self.chunks.partition_point(|c| c.start_position + c.length <= position);
+		self.chunks.get(index).filter(|c| c.start_position <= position)
+	}
+
+	/// Copies the loaded samples starting at position into buffer. Returns the number of copied samples.
+	fn read_samples(&self, position: usize, buffer: &mut [f32]) -> usize {
+		let mut count = 0;
+		while count < buffer.len() {
+			match self.get_chunk(position + count) {
+				Some(chunk) => {
+					let offset = position + count - chunk.start_position;
+					let length = (chunk.length - offset).min(buffer.len() - count);
+					buffer[count..count + length].copy_from_slice(&chunk.data[offset..offset + length]);
+					count += length;
+				}
+				None => break,
+			}
+		}
+		count
+	}
+
+	/// Returns the position after the last sample. If the last chunk is not loaded yet, the position is estimated by the duration.
+	fn get_end_position(&self) -> Option<usize> {
+		self.end_position.or_else(|| self.get_seek_position(SeekPosition::Fraction(1.0)))
+	}
+
+	fn get_loaded_end(&self) -> usize {
+		self.chunks.back().map_or(self.load_start, |c| c.start_position + c.length)
+	}
+
+	/// Adds a chunk from the loader. Chunks from before the last seek or chunks that do not continue the loaded window are dropped.
+	fn add_chunk(&mut self, mut chunk: SamplesChunk, seek_generation: u32) {
+		if self.loader.as_ref().is_none_or(|l| l.get_seek_generation() != seek_generation) {
+			return;
+		}
+		let continues_window = if self.chunks.is_empty() {
+			// after a seek the first chunk can start a little before the requested position or end before it, if the song is shorter
+			chunk.start_position <= self.load_start && (chunk.start_position + chunk.length > self.load_start || chunk.last_chunk)
+		} else {
+			// the loader of a song, that started with cached chunks, can continue a little before their end
+			let loaded_end = self.get_loaded_end();
+			if chunk.start_position < loaded_end && chunk.start_position + chunk.length > loaded_end {
+				chunk = chunk.starting_at(loaded_end);
+			}
+			chunk.start_position == loaded_end
+		};
+		// chunks of a loader with another output format are dropped
+		let format_changed = self.sample_rate_and_channels.is_some_and(|format| format != (chunk.sample_rate, chunk.channels));
+		if !continues_window || format_changed {
+			return;
+		}
+		if self.sample_rate_and_channels.is_none() {
+			self.sample_rate_and_channels = Some((chunk.sample_rate, chunk.channels));
+		}
+		if chunk.last_chunk {
+			self.end_position = Some(chunk.start_position + chunk.length);
+		}
+		self.chunks.push_back(chunk);
+	}
+
+	/// Returns the frame aligned position of the given seek position or None, if the format or duration of the song is not known yet.
+	fn get_seek_position(&self, seek_position: SeekPosition) -> Option<usize> {
+		let (sample_rate, channels) = self.sample_rate_and_channels?;
+		let duration = match seek_position {
+			SeekPosition::Time(duration) => duration,
+			SeekPosition::Fraction(fraction) => self.total_duration?.mul_f64(fraction.clamp(0.0, 1.0)),
+		};
+		let position = duration_to_position(&duration, sample_rate, channels);
+		Some(position - position % channels as usize)
+	}
+
+	/// Makes sure the given position will be loaded. If the position is outside of the loaded window and the loader will not
+	/// reach it soon, the loader is moved to this position.
+	fn request_position(&mut self, position: usize) {
+		let loaded_start = self.chunks.front().map_or(self.load_start, |c| c.start_position);
+		let loaded_end = self.get_loaded_end();
+		let reachable = position >= loaded_start && position < loaded_end + LOAD_AHEAD_SAMPLES;
+		if !reachable && self.end_position.is_none_or(|end| position < end) {
+			if let Some(loader) = &mut self.loader {
+				loader.seek(position);
+				self.chunks.clear();
+				self.load_start = position;
+			}
+		}
+	}
+
+	/// Drops chunks that are far behind the play position and lets the loader decode further.
+	/// Chunks after keep_from are never dropped, e.g. the chunks of a loop.
+	fn update_window(&mut self, play_position: usize, keep_from: Option<usize>) {
+		while let Some(chunk) = self.chunks.front() {
+			let chunk_end = chunk.start_position + chunk.length;
+			if chunk_end + KEEP_BEHIND_SAMPLES <= play_position && keep_from.is_none_or(|keep_from| chunk_end <= keep_from) {
+				self.chunks.pop_front();
+			} else {
+				break;
+			}
+		}
+		if let Some(loader) = &self.loader {
+			loader.set_window_end(play_position.max(self.load_start) + LOAD_AHEAD_SAMPLES);
 		}
 	}
 }
 
 struct CurrentSongState {
-	play_position: usize, // the number of samples already sent to source. A sample is one f32 value.
+	play_position: usize, // the position of the next sample, that is sent to the source. A sample is one f32 value.
 	audio_song: AudioSong,
+	transition: Transition,
+	/// start and end time of a region, that is repeated until the loop is removed
+	loop_range: Option<(Duration, Duration)>,
+}
+
+impl CurrentSongState {
+	fn new(audio_song: AudioSong, play_position: usize) -> CurrentSongState {
+		CurrentSongState {
+			play_position,
+			audio_song,
+			transition: Transition::Undecided,
+			loop_range: None,
+		}
+	}
+
+	/// Returns the start and end position of the loop or None, if there is no loop or its positions are not known yet.
+	/// The end of the loop is limited to the end of the song.
+	fn get_loop_positions(&self) -> Option<(usize, usize)> {
+		let (loop_start, loop_end) = self.loop_range?;
+		let loop_start = self.audio_song.get_seek_position(SeekPosition::Time(loop_start))?;
+		let mut loop_end = self.audio_song.get_seek_position(SeekPosition::Time(loop_end))?;
+		if let Some(end_position) = self.audio_song.end_position {
+			loop_end = loop_end.min(end_position);
+		}
+		Some((loop_start, loop_end)).filter(|(loop_start, loop_end)| loop_start < loop_end)
+	}
+
+	/// The transition has to be decided again, e.g. because another song was queued. A running crossfade is not interrupted.
+	fn reset_transition(&mut self) {
+		if let Transition::Crossfade(fade_start, _) = self.transition {
+			if self.play_position > fade_start {
+				return;
+			}
+		}
+		self.transition = Transition::Undecided;
+	}
+
+	/// Returns the position the next song starts at, when the current song is finished.
+	fn get_next_song_start(&self) -> usize {
+		match self.transition {
+			Transition::Crossfade(fade_start, _) => {
+				// the end of the crossfade can be estimated, so the actual end of the song is used
+				let end_position = self.audio_song.end_position.unwrap_or(self.play_position);
+				self.play_position.min(end_position).saturating_sub(fade_start)
+			}
+			_ => 0,
+		}
+	}
+}
+
+/// Describes how the current song is followed by the next song.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Transition {
+	Undecided,
+	Gapless,
+	Crossfade(usize, usize), // start and end position of the crossfade in the current song
 }
 
 pub enum AudioCommand {
@@ -61,8 +331,38 @@ pub enum AudioCommand {
 	Queue(Song),
 	Pause,
     Unpause,
+	Stop,
 	Seek(SeekCommand),
+	SeekTo(SeekPosition),
     SetVolume(f32),
+	SetCrossfade(Duration),
+	SetReplayGainMode(ReplayGainMode),
+	SetEqualizer(Option<EqualizerPreset>), // None disables the equalizer
+	SetSpeed(SongID, f32), // song, playback speed between 0.5 and 3.0
+	SetLoop(Option<(Duration, Duration)>), // start and end of a region of the current song, that is repeated. None removes the loop
+	SetStopAfterCurrent(bool),
+	SetFadeDuration(Duration), // duration of the volume ramps on pause, unpause, seek and skip
+	SetSampleTap(bool), // whether played chunks are sent to the sample tap
+	Prefetch(Vec<Song>), // songs, that are played soon. Their start is decoded into the song cache.
+	ListOutputDevices,
+	SetOutput(OutputConfig),
+}
+
+impl AudioCommand {
+	/// Returns the commands, that continue playing the given song after the backend was restarted.
+	pub fn restore_song(song: Song, speed: f32, position: Duration, loop_range: Option<(Duration, Duration)>, paused: bool) -> Vec<AudioCommand> {
+		let song_id = song.get_id();
+		let mut commands = vec![
+			AudioCommand::Play(song),
+			AudioCommand::SetSpeed(song_id, speed),
+			AudioCommand::SeekTo(SeekPosition::Time(position)),
+			AudioCommand::SetLoop(loop_range),
+		];
+		if paused {
+			commands.push(AudioCommand::Pause);
+		}
+		commands
+	}
 }
 
 impl Debug for AudioCommand {
@@ -72,8 +372,23 @@ impl Debug for AudioCommand {
 			AudioCommand::Queue(song) => f.debug_struct("AudioCommand::Queue").field("song", &song.get_id()).finish(),
 			AudioCommand::Pause => f.debug_struct("AudioCommand::Pause").finish(),
 			AudioCommand::Unpause => f.debug_struct("AudioCommand::Unpause").finish(),
+			AudioCommand::Stop => f.debug_struct("AudioCommand::Stop").finish(),
 			AudioCommand::Seek(_) => f.debug_struct("AudioCommand::Seek").finish(),
+			AudioCommand::SeekTo(seek_position) => f.debug_struct("AudioCommand::SeekTo").field("seek_position", seek_position).finish(),
 			AudioCommand::SetVolume(volume) => f.debug_struct("AudioCommand::SetVolume").field("volume", volume).finish(),
+			AudioCommand::SetCrossfade(duration) => f.debug_struct("AudioCommand::SetCrossfade").field("duration", duration).finish(),
+			AudioCommand::SetReplayGainMode(mode) => f.debug_struct("AudioCommand::SetReplayGainMode").field("mode", mode).finish(),
+			AudioCommand::SetEqualizer(preset) => f.debug_struct("AudioCommand::SetEqualizer").field("preset", &preset.as_ref().map(|p| &p.name)).finish(),
+			AudioCommand::SetSpeed(song_id, speed) => f.debug_struct("AudioCommand::SetSpeed").field("song", song_id).field("speed", speed).finish(),
+			AudioCommand::SetLoop(loop_range) => f.debug_struct("AudioCommand::SetLoop").field("loop_range", loop_range).finish(),
+			AudioCommand::SetStopAfterCurrent(stop) => f.debug_struct("AudioCommand::SetStopAfterCurrent").field("stop", stop).finish(),
+			AudioCommand::SetFadeDuration(duration) => f.debug_struct("AudioCommand::SetFadeDuration").field("duration", duration).finish(),
+			AudioCommand::SetSampleTap(enabled) => f.debug_struct("AudioCommand::SetSampleTap").field("enabled", enabled).finish(),
+			AudioCommand::Prefetch(songs) => {
+				f.debug_struct("AudioCommand::Prefetch").field("songs", &songs.iter().map(|s| s.get_id()).collect::<Vec<_>>()).finish()
+			}
+			AudioCommand::ListOutputDevices => f.debug_struct("AudioCommand::ListOutputDevices").finish(),
+			AudioCommand::SetOutput(output_config) => f.debug_struct("AudioCommand::SetOutput").field("output_config", output_config).finish(),
 		}
 	}
 }
@@ -110,12 +425,58 @@ pub enum SeekDirection {
 	Backward,
 }
 
+/// An absolute position in a song.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SeekPosition {
+	Time(Duration), // time since the start of the song
+	Fraction(f64), // 0.0 is the start and 1.0 the end of the song
+}
+
+impl FromStr for SeekPosition {
+	type Err = String;
+
+	/// Parses seek positions like "2:31", "1:02:03", "95" (seconds) or "40%".
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		if let Some(percent) = s.strip_suffix('%') {
+			let percent: f64 = percent.trim().parse().map_err(|_| format!("invalid percentage \"{}\"", s))?;
+			if !(0.0..=100.0).contains(&percent) {
+				return Err(format!("percentage \"{}\" is not between 0% and 100%", s));
+			}
+			return Ok(SeekPosition::Fraction(percent / 100.0));
+		}
+
+		let parts: Vec<&str> = s.split(':').collect();
+		if parts.len() > 3 {
+			return Err(format!("invalid time \"{}\"", s));
+		}
+		let mut seconds = 0.0;
+		for part in parts {
+			let value: f64 = part.trim().parse().map_err(|_| format!("invalid time \"{}\"", s))?;
+			if !value.is_finite() || value < 0.0 {
+				return Err(format!("invalid time \"{}\"", s));
+			}
+			seconds = seconds * 60.0 + value;
+		}
+		Ok(SeekPosition::Time(Duration::from_secs_f64(seconds)))
+	}
+}
+
 #[derive(Debug)]
 pub enum AudioInfo {
 	Playing(SongID, Duration), // playing song, play duration
 	SongStarts(SongID),
+	PlaybackFinished, // the last song was played completely and no song follows
 	FailedOpen(SongID, OpenError),
 	SongDuration(SongID, Duration),
+	SongLoudness(SongID, Loudness),
+	OutputDevices(Vec<String>),
+	OutputChanged(OutputConfig, String), // the output that is used now and its name
+	OutputDeviceFailed(DeviceError),
+	Health(BackendHealth), // sent periodically
+	BackendRestarted(BackendError), // the backend failed and was replaced by a new one, that does not know the played song
+	BackendStopped(BackendError), // the backend failed too often and was not restarted
+	SpawnFailed(&'static str, io::Error), // name of the thread, that could not be started
 }
 
 #[derive(Debug)]
@@ -128,6 +489,7 @@ pub struct PlayingUpdate {
 pub enum AudioUpdate {
 	Playing(PlayingUpdate),
 	SongStarts(SongID),
+	FadedOut, // the source is silent after pausing, so the output can be paused
 }
 
 pub enum AudioBackendCommand {
@@ -139,60 +501,206 @@ pub enum AudioBackendCommand {
 
 #[derive(Debug)]
 pub enum LoadInfo {
-	Chunk(SamplesChunk),
+	Chunk(SamplesChunk, u32, Duration), // chunk, seek generation of the loader, time spent decoding the chunk
+	Format(SongID, u32, u16), // song, sample rate, channels
+	Loudness(SongID, Loudness), // from ReplayGain tags or the loudness analyzer
 	Duration(SongID, Duration),
 	Err(SongID, OpenError),
+	SpawnFailed(&'static str, io::Error), // name of the thread, that could not be started
 }
 
 #[derive(Debug)]
 pub enum OpenError {
 	FileNotFound,
 	NotDecodable,
+	UnsupportedFormat(String), // the format or codec, that cannot be decoded
+}
+
+impl Display for OpenError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			OpenError::FileNotFound => write!(f, "file not found"),
+			OpenError::NotDecodable => write!(f, "not decodable"),
+			OpenError::UnsupportedFormat(format) => write!(f, "unsupported format: {}", format),
+		}
+	}
 }
 
 impl AudioBackend {
-	pub fn new(info_sender: Sender<AudioInfo>, audio_backend_sender: Sender<AudioBackendCommand>, volume: f32) -> AudioBackend {
-		// sink and devices
-		let pulse_device = cpal::default_host().output_devices().unwrap().find(|d| d.name().unwrap().contains("pulse")).unwrap(); // TODO: dont force pulse device
-		let (stream, stream_handle) = OutputStream::try_from_device(&pulse_device)
-			.unwrap_or_else(|_| OutputStream::try_default().unwrap());
+	/// Creates a new AudioBackend playing on the output described by output_config.
+	/// If this output is not available, the default device is used. If no device can be opened, a NullOutput is used.
+	/// Chunks are sent to sample_tap, when they start playing and the sample tap is enabled.
+	pub fn new(
+		info_sender: Sender<AudioInfo>, audio_backend_sender: Sender<AudioBackendCommand>, sample_tap: Sender<SamplesChunk>, volume: f32,
+		output_config: OutputConfig
+	) -> AudioBackend {
+		let fallback_configs = [output_config, OutputConfig::default(), OutputConfig::Null(1.0)];
+		let mut output = None;
+		for config in fallback_configs {
+			match open_output(&config) {
+				Ok(o) => {
+					output = Some((o, config));
+					break;
+				}
+				Err(e) => {
+					let _ = info_sender.send(AudioInfo::OutputDeviceFailed(e));
+				}
+			}
+		}
+		let (output, output_config) = output.unwrap_or_else(|| (Box::new(NullOutput::new(1.0)), OutputConfig::Null(1.0)));
 
-		let sink = Sink::try_new(&stream_handle).unwrap();
+		let audio_backend = AudioBackend::with_output(info_sender, audio_backend_sender, sample_tap, volume, output, output_config);
+		audio_backend.send_output_devices();
+		audio_backend
+	}
 
-		// receiver source
+	/// Creates a new AudioBackend playing on the given output.
+	pub fn with_output(
+		info_sender: Sender<AudioInfo>, audio_backend_sender: Sender<AudioBackendCommand>, sample_tap: Sender<SamplesChunk>, volume: f32,
+		mut output: Box<dyn AudioOutput>, output_config: OutputConfig
+	) -> AudioBackend {
 		let (source_chunk_sender, chunk_receiver) = bounded(CHUNK_BUFFER_SIZE);
-		let receiver_source = ReceiverSource::new(chunk_receiver, audio_backend_sender.clone());
+		let source_control = SourceControl::new();
+		let receiver_source = ReceiverSource::new(chunk_receiver, audio_backend_sender.clone(), sample_tap.clone(), source_control.clone());
 
-		sink.append(receiver_source);
-		sink.play();
-		sink.set_volume(volume);
+		if let Err(e) = output.start(receiver_source) {
+			let _ = info_sender.send(AudioInfo::SpawnFailed(PUMP_THREAD_NAME, e));
+		}
+		output.set_volume(volume);
+		output.play();
+		let _ = info_sender.send(AudioInfo::OutputChanged(output_config.clone(), output.get_name()));
+		let output_format = output.get_format();
 
 		AudioBackend {
-			sink,
-			_stream: stream,
+			output,
 
 			info_sender,
 			source_chunk_sender,
 			audio_backend_sender,
+			source_control,
+			sample_tap,
+			health_monitor: HealthMonitor::new(),
+			loader_pool: ThreadPool::with_name("loader".to_string(), LOADER_THREADS),
+			song_cache: SongCache::new(),
+			prefetch_loaders: Vec::new(),
 
 			current_song: None,
 			next_song: None,
 			volume,
+			paused: false,
+			crossfade: Duration::ZERO,
+			replay_gain_mode: ReplayGainMode::Off,
+			output_format,
+			equalizer: None,
+			time_stretcher: TimeStretcher::new(),
+			stop_after_current: false,
+		}
+	}
+
+	fn send_output_devices(&self) {
+		match list_output_devices() {
+			Ok(devices) => {
+				let _ = self.info_sender.send(AudioInfo::OutputDevices(devices));
+			}
+			Err(e) => {
+				let _ = self.info_sender.send(AudioInfo::OutputDeviceFailed(e));
+			}
+		}
+	}
+
+	/// Opens the given output and moves playback to it.
+	/// If the output cannot be opened, the old output is kept and the error is reported.
+	fn set_output(&mut self, output_config: OutputConfig) {
+		let mut output = match open_output(&output_config) {
+			Ok(output) => output,
+			Err(e) => {
+				let _ = self.info_sender.send(AudioInfo::OutputDeviceFailed(e));
+				return;
+			}
+		};
+		let (source_chunk_sender, chunk_receiver) = bounded(CHUNK_BUFFER_SIZE);
+		let receiver_source = ReceiverSource::new(chunk_receiver, self.audio_backend_sender.clone(), self.sample_tap.clone(), self.source_control.clone());
+		if let Err(e) = output.start(receiver_source) {
+			let _ = self.info_sender.send(AudioInfo::SpawnFailed(PUMP_THREAD_NAME, e));
+			return;
+		}
+
+		// chunks that were sent to the old source, but not played yet, have to be sent again
+		if let Some(current_song) = &mut self.current_song {
+			let unplayed_samples = (self.source_chunk_sender.len() * CHUNK_SIZE) as f32 * current_song.audio_song.speed;
+			let position = match self.time_stretcher.get_song_id() {
+				Some(song_id) if song_id == current_song.audio_song.song_id => self.time_stretcher.reset().unwrap_or(current_song.play_position),
+				_ => current_song.play_position,
+			};
+			let position = position.saturating_sub(unplayed_samples as usize);
+			current_song.play_position = position - position % self.output_format.1 as usize;
+		}
+		self.time_stretcher.reset();
+
+		output.set_volume(self.volume);
+		if self.paused {
+			output.pause();
+		} else {
+			output.play();
+		}
+		let _ = self.info_sender.send(AudioInfo::OutputChanged(output_config.clone(), output.get_name()));
+
+		if output.get_format() != self.output_format {
+			self.set_output_format(output.get_format());
 		}
+		// dropping the old output stops it
+		self.output = output;
+		self.source_chunk_sender = source_chunk_sender;
+		self.send_next_chunks();
+	}
+
+	/// Converts the current and the next song into the given format. The current song continues at the same time.
+	fn set_output_format(&mut self, output_format: (u32, u16)) {
+		let old_format = self.output_format;
+		self.output_format = output_format;
+		if let Some(equalizer) = &mut self.equalizer {
+			*equalizer = Equalizer::new(equalizer.get_preset().clone(), output_format.0, output_format.1);
+		}
+		if let Some(current_song) = &mut self.current_song {
+			let duration = position_to_duration(current_song.play_position, old_format.0, old_format.1);
+			let position = duration_to_position(&duration, output_format.0, output_format.1);
+			current_song.play_position = position - position % output_format.1 as usize;
+			// positions of a crossfade are given in the old format
+			current_song.transition = Transition::Undecided;
+			current_song.audio_song.restart(current_song.play_position, output_format, &self.loader_pool, self.audio_backend_sender.clone());
+		}
+		if let Some((_, next_audio_song)) = &mut self.next_song {
+			next_audio_song.restart(0, output_format, &self.loader_pool, self.audio_backend_sender.clone());
+		}
+		// cached songs are in the old format
+		self.song_cache.clear();
+		self.prefetch_loaders.clear();
 	}
 
 	pub fn run(&mut self, audio_backend_receiver: Receiver<AudioBackendCommand>) {
 		while let Ok(command) = audio_backend_receiver.recv() {
+			let received = Instant::now();
 			let mut commands = vec![command];
 			commands.extend(audio_backend_receiver.try_iter());
 			let commands = AudioBackendCommand::simplify(commands);
 			for command in commands.into_iter() {
 				match command {
-					AudioBackendCommand::Command(command) => self.handle_command(command),
+					AudioBackendCommand::Command(command) => {
+						self.handle_command(command);
+						self.health_monitor.add_command_latency(received.elapsed());
+					}
 					AudioBackendCommand::Update(update) => self.handle_update(update),
 					AudioBackendCommand::LoadInfo(load_info) => self.handle_load_info(load_info),
 				}
 			}
+			self.report_health();
+		}
+	}
+
+	/// Sends the health of the backend to musicus, once per report interval.
+	fn report_health(&mut self) {
+		if let Some(health) = self.health_monitor.report(self.source_control.get_underruns(), self.source_chunk_sender.len()) {
+			let _ = self.info_sender.send(AudioInfo::Health(health));
 		}
 	}
 
@@ -202,105 +710,303 @@ impl AudioBackend {
 			AudioCommand::Queue(song) => self.queue(song),
 			AudioCommand::Pause => self.pause(),
 			AudioCommand::Unpause => self.unpause(),
+			AudioCommand::Stop => self.stop(),
 			AudioCommand::Seek(seek_command) => self.seek(seek_command),
+			AudioCommand::SeekTo(seek_position) => self.seek_to(seek_position),
 			AudioCommand::SetVolume(volume) => self.set_volume(volume),
+			AudioCommand::SetCrossfade(duration) => self.set_crossfade(duration),
+			AudioCommand::SetReplayGainMode(mode) => self.set_replay_gain_mode(mode),
+			AudioCommand::SetEqualizer(preset) => self.set_equalizer(preset),
+			AudioCommand::SetSpeed(song_id, speed) => self.set_speed(song_id, speed),
+			AudioCommand::SetLoop(loop_range) => self.set_loop(loop_range),
+			AudioCommand::SetStopAfterCurrent(stop) => self.set_stop_after_current(stop),
+			AudioCommand::SetFadeDuration(duration) => self.source_control.set_fade_duration(duration),
+			AudioCommand::SetSampleTap(enabled) => self.source_control.set_sample_tap_enabled(enabled),
+			AudioCommand::Prefetch(songs) => self.prefetch(songs),
+			AudioCommand::ListOutputDevices => self.send_output_devices(),
+			AudioCommand::SetOutput(output_config) => self.set_output(output_config),
 		}
 	}
 
 	/// Tries to send the next chunks to source
-	fn send_next_chunks(&mut self) { // TODO: why is this called so often?
+	fn send_next_chunks(&mut self) {
 		loop {
+			// stretched chunks are sent before new chunks are stretched
+			let generation = self.source_control.get_generation();
+			if !Self::send_stretched_chunks(&self.source_chunk_sender, &mut self.time_stretcher, &mut self.equalizer, generation) {
+				break;
+			}
 			let current_song = match &mut self.current_song {
-				Some(x) => x,
+				Some(current_song) => current_song,
 				None => break,
 			};
-
-			let next_chunk_index = current_song.play_position / CHUNK_SIZE + 1;
-			match current_song.audio_song.chunks.get(next_chunk_index) {
+			let loop_positions = current_song.get_loop_positions();
+			let audio_song = &mut current_song.audio_song;
+			if let Some((loop_start, loop_end)) = loop_positions {
+				if current_song.play_position >= loop_end {
+					// jump back to the start of the loop. Its chunks are kept in memory, so there is no gap
+					current_song.play_position = loop_start;
+					audio_song.request_position(loop_start);
+					continue;
+				}
+			}
+			if audio_song.end_position.is_some_and(|end| current_song.play_position >= end) {
+				// we are already past the last chunk (can happen by seeking)
+				let next_song_start = current_song.get_next_song_start();
+				self.finish_current_song(next_song_start);
+				continue;
+			}
+			match audio_song.get_chunk(current_song.play_position) {
 				Some(chunk) => {
-					match self.source_chunk_sender.try_send(chunk.clone()) {
-						Ok(_) => {
-							// we can use CHUNK_SIZE here, as play_position will be set to 0 if this is last_chunk and length != CHUNK_SIZE
-							current_song.play_position += CHUNK_SIZE;
-						}
-						Err(crossbeam::TrySendError::Full(_)) => {
-							break; // channel is full -> stop to try sending chunks
-						}
-						Err(_) => {
-							todo!()
+					let mut chunk = chunk.starting_at(current_song.play_position).with_gain(audio_song.gain);
+
+					if let Some((_, loop_end)) = loop_positions {
+						// the part after the loop is not played and a looped song never ends
+						chunk.length = chunk.length.min(loop_end.saturating_sub(chunk.start_position));
+						chunk.last_chunk = false;
+					}
+
+					let crossfade_range = match current_song.transition {
+						_ if loop_positions.is_some() || self.stop_after_current => None,
+						Transition::Undecided => Self::get_crossfade_range(audio_song, self.crossfade),
+						Transition::Gapless => None,
+						Transition::Crossfade(fade_start, fade_end) => Some((fade_start, fade_end)),
+					};
+					if let Some((fade_start, fade_end)) = crossfade_range {
+						if chunk.start_position < fade_start {
+							// the part before the crossfade is sent unmixed
+							if chunk.start_position + chunk.length > fade_start {
+								chunk.length = fade_start - chunk.start_position;
+								chunk.last_chunk = false;
+							}
+						} else if current_song.transition == Transition::Undecided {
+							match Self::decide_transition(current_song, self.next_song.as_ref(), fade_start, fade_end) {
+								Some(transition) => {
+									current_song.transition = transition;
+									continue;
+								}
+								None => break, // wait until the next song is opened
+							}
+						} else if let Some((_, next_audio_song)) = &mut self.next_song {
+							match Self::mix_crossfade(chunk, next_audio_song, fade_start, fade_end) {
+								Some(mixed_chunk) => chunk = mixed_chunk,
+								None => break, // wait for the samples of the next song
+							}
 						}
 					}
-					if chunk.last_chunk {
+
+					if self.source_chunk_sender.is_full() {
+						break; // channel is full -> stop to try sending chunks
+					}
+					let last_chunk = chunk.last_chunk;
+					let next_position = chunk.start_position + chunk.length;
+					if audio_song.speed != 1.0 || self.time_stretcher.is_active() {
+						self.time_stretcher.push(&chunk, audio_song.speed);
+					} else if !Self::send_chunk(&self.source_chunk_sender, &mut self.equalizer, generation, chunk) {
+						break; // the output dropped the source -> wait until a new output is set
+					}
+					current_song.play_position = next_position;
+					current_song.audio_song.started = true;
+					if last_chunk {
 						// we have completed the current song -> switch to next song
-						Self::play_next_song(&mut self.current_song, &mut self.next_song);
+						let next_song_start = current_song.get_next_song_start();
+						self.finish_current_song(next_song_start);
 					}
 				}
 				None => {
-					// is last chunk in chunks? This would mean we are already past the last chunk (can happen by seeking)
-					if current_song.audio_song.chunks.last().map(|c| c.last_chunk).unwrap_or(false) {
-						Self::play_next_song(&mut self.current_song, &mut self.next_song);
-					} else {
-						// we have to wait for further chunks
-						break;
-					}
+					// we have to wait for further chunks
+					audio_song.request_position(current_song.play_position);
+					break;
 				}
 			}
 		}
+		if let Some(current_song) = &mut self.current_song {
+			let keep_from = current_song.get_loop_positions().map(|(loop_start, _)| loop_start);
+			current_song.audio_song.update_window(current_song.play_position, keep_from);
+		}
 	}
 
-	fn play_next_song(current_song: &mut Option<CurrentSongState>, next_song: &mut Option<(Song, AudioSong)>) {
-		if let Some(next_song) = next_song.take() {
-			*current_song = Some(CurrentSongState {
-				play_position: 0,
-				audio_song: next_song.1
-			});
-		} else {
-			*current_song = None;
+	/// Sends the given chunk with the given generation to the source. Returns false, if the chunk could not be sent.
+	fn send_chunk(source_chunk_sender: &Sender<SamplesChunk>, equalizer: &mut Option<Equalizer>, generation: u32, mut chunk: SamplesChunk) -> bool {
+		// the equalizer keeps state between chunks, so only chunks that are sent are filtered
+		if source_chunk_sender.is_full() {
+			return false;
+		}
+		if let Some(equalizer) = equalizer {
+			chunk = equalizer.apply(chunk);
 		}
+		chunk.generation = generation;
+		source_chunk_sender.try_send(chunk).is_ok()
 	}
 
-	// TODO: This is probably not the best implementation
-	fn is_song_loading(&self, song_id: SongID) -> bool {
-		if let Some(current_song) = &self.current_song {
-			if current_song.audio_song.song_id == song_id {
-				return true;
+	/// Sends the stretched chunks to the source. Returns false, if there are chunks left, that could not be sent.
+	fn send_stretched_chunks(source_chunk_sender: &Sender<SamplesChunk>, time_stretcher: &mut TimeStretcher, equalizer: &mut Option<Equalizer>, generation: u32) -> bool {
+		while !source_chunk_sender.is_full() {
+			match time_stretcher.next_chunk() {
+				Some(chunk) => {
+					if !Self::send_chunk(source_chunk_sender, equalizer, generation, chunk) {
+						return false;
+					}
+				}
+				None => return true,
 			}
 		}
-		if let Some(next_audio_song) = &self.next_song {
-			if next_audio_song.1.song_id == song_id {
-				return true;
-			}
+		!time_stretcher.has_chunk()
+	}
+
+	/// Returns the start and end position of a crossfade at the end of the given song.
+	/// Returns None, if crossfading is disabled or the end of the song is not known yet.
+	fn get_crossfade_range(audio_song: &AudioSong, crossfade: Duration) -> Option<(usize, usize)> {
+		if crossfade.is_zero() {
+			return None;
+		}
+		let (sample_rate, channels) = audio_song.sample_rate_and_channels?;
+		let fade_end = audio_song.get_end_position()?;
+		// short songs are crossfaded at most for half of their length
+		let fade_length = duration_to_position(&crossfade, sample_rate, channels).min(fade_end / 2);
+		Some((fade_end - (fade_length - fade_length % channels as usize), fade_end))
+	}
+
+	/// Decides whether the current song is crossfaded with the next song.
+	/// Returns None, if this can only be decided after the next song is opened.
+	fn decide_transition(current_song: &CurrentSongState, next_song: Option<&(Song, AudioSong)>, fade_start: usize, fade_end: usize) -> Option<Transition> {
+		let next_audio_song = match next_song {
+			Some((_, next_audio_song)) => next_audio_song,
+			None => return Some(Transition::Gapless),
+		};
+		let audio_song = &current_song.audio_song;
+		if current_song.play_position > fade_start // the crossfade would start in the middle
+			|| next_audio_song.failed
+			|| (audio_song.directory.is_some() && audio_song.directory == next_audio_song.directory) // e.g. songs of an album
+		{
+			return Some(Transition::Gapless);
+		}
+		match next_audio_song.sample_rate_and_channels {
+			Some(format) if Some(format) == audio_song.sample_rate_and_channels => Some(Transition::Crossfade(fade_start, fade_end)),
+			Some(_) => Some(Transition::Gapless),
+			None => None,
 		}
-		false
 	}
 
-	fn load(&mut self, song: Song) {
-		if !self.is_song_loading(song.get_id()) {
-			let abs = self.audio_backend_sender.clone();
-			thread::Builder::new().name("loader".to_string()).spawn(move || {
-				load_chunks(song, abs);
-			}).expect("Failed to spawn loader thread");
+	/// Mixes the given chunk of the current song with the samples of the next song.
+	/// Returns None, if the samples of the next song are not loaded yet.
+	fn mix_crossfade(chunk: SamplesChunk, next_audio_song: &mut AudioSong, fade_start: usize, fade_end: usize) -> Option<SamplesChunk> {
+		let next_position = chunk.start_position - fade_start;
+		let mut next_samples = [0.0; CHUNK_SIZE];
+		let count = next_audio_song.read_samples(next_position, &mut next_samples[..chunk.length]);
+		let next_song_finished = next_audio_song.end_position.is_some_and(|end| next_position + count >= end);
+		if count < chunk.length && !next_song_finished {
+			next_audio_song.request_position(next_position + count);
+			return None;
+		}
+		next_audio_song.update_window(next_position, None);
+		next_audio_song.started = true;
+
+		let channels = chunk.channels as usize;
+		let fade_frames = ((fade_end - fade_start) / channels).max(1) as f32;
+		let mut data = [0.0; CHUNK_SIZE];
+		for (index, sample) in data.iter_mut().enumerate().take(chunk.length) {
+			let frame = (chunk.start_position + index - fade_start) / channels;
+			// equal power crossfade
+			let angle = (frame as f32 / fade_frames).min(1.0) * FRAC_PI_2;
+			*sample = chunk.data[index] * angle.cos() + next_samples[index] * next_audio_song.gain * angle.sin();
+		}
+		Some(SamplesChunk {
+			data: Arc::new(data),
+			..chunk
+		})
+	}
+
+	/// Switches to the next song, after the current song is finished. Playback stops, if there is no next song or it
+	/// should stop after the current song.
+	fn finish_current_song(&mut self, next_song_start: usize) {
+		if self.stop_after_current {
+			// the next song is kept, so its chunks can be used, when it is played
+			self.stop_after_current = false;
+			self.current_song = None;
+		} else {
+			Self::play_next_song(&mut self.current_song, &mut self.next_song, next_song_start);
+		}
+		if self.current_song.is_none() {
+			let _ = self.info_sender.send(AudioInfo::PlaybackFinished);
+		}
+	}
+
+	fn play_next_song(current_song: &mut Option<CurrentSongState>, next_song: &mut Option<(Song, AudioSong)>, play_position: usize) {
+		// a song, that could not be opened, would never finish
+		if let Some(next_song) = next_song.take().filter(|(_, audio_song)| !audio_song.failed) {
+			*current_song = Some(CurrentSongState::new(next_song.1, play_position));
+		} else {
+			*current_song = None;
 		}
 	}
 
 	fn play(&mut self, song: Song) {
-		self.load(song.clone());
-		self.current_song = Some(CurrentSongState {
-			audio_song: AudioSong::new(song.get_id()),
-			play_position: 0,
-		});
-		if let Some(next_song) = &mut self.next_song {
-			if next_song.0.get_id() == song.get_id() {
-				std::mem::swap(&mut self.current_song.as_mut().unwrap().audio_song.chunks, &mut next_song.1.chunks);
-			}
+		if self.current_song.as_ref().is_some_and(|s| s.audio_song.started) {
+			// a song is skipped -> fade out the chunks, that were already sent
+			self.source_control.next_generation();
 		}
+		let mut loop_range = None;
+		let audio_song = match self.next_song.take() {
+			Some((next_song, audio_song)) if next_song.get_id() == song.get_id() => audio_song,
+			next_song => {
+				self.next_song = next_song;
+				match self.current_song.take() {
+					// the current song is played again -> reuse the loaded chunks and keep the loop
+					Some(current_song) if current_song.audio_song.song_id == song.get_id() => {
+						loop_range = current_song.loop_range;
+						if current_song.audio_song.get_chunk(0).is_some() {
+							current_song.audio_song
+						} else {
+							self.create_audio_song(song)
+						}
+					}
+					_ => self.create_audio_song(song),
+				}
+			}
+		};
+		let mut current_song = CurrentSongState::new(audio_song, 0);
+		current_song.loop_range = loop_range;
+		self.current_song = Some(current_song);
+		self.time_stretcher.reset();
 		self.send_next_chunks();
-		self.sink.play();
+		self.unpause();
 	}
 
 	fn queue(&mut self, song: Song) {
-		self.load(song.clone());
-		self.next_song = Some((song.clone(), AudioSong::new(song.get_id())));
+		if self.next_song.as_ref().is_some_and(|(next_song, _)| next_song.get_id() == song.get_id()) {
+			return;
+		}
+		let audio_song = self.create_audio_song(song.clone());
+		self.next_song = Some((song, audio_song));
+		if let Some(current_song) = &mut self.current_song {
+			current_song.reset_transition();
+		}
+		self.send_next_chunks();
+	}
+
+	/// Creates the audio song of the given song. Its cached start is played, while the loader decodes the rest.
+	fn create_audio_song(&mut self, song: Song) -> AudioSong {
+		// the audio song has its own loader
+		self.prefetch_loaders.retain(|(song_id, _)| *song_id != song.get_id());
+		let cached_chunks = self.song_cache.get(song.get_id(), self.output_format).unwrap_or_default();
+		AudioSong::new(song, cached_chunks, &self.loader_pool, self.audio_backend_sender.clone(), self.replay_gain_mode, self.output_format)
+	}
+
+	/// Decodes the start of the given songs into the song cache. Songs, that are not given anymore, are not prefetched further.
+	fn prefetch(&mut self, songs: Vec<Song>) {
+		self.prefetch_loaders.retain(|(song_id, _)| songs.iter().any(|song| song.get_id() == *song_id));
+		for song in songs {
+			let song_id = song.get_id();
+			let loading = self.prefetch_loaders.iter().any(|(id, _)| *id == song_id)
+				|| Self::get_audio_song(self.current_song.as_mut(), self.next_song.as_mut(), song_id).is_some();
+			if loading || self.song_cache.is_complete(song_id, self.output_format) {
+				continue;
+			}
+			let loader = LoaderHandle::spawn(&self.loader_pool, song, 0, self.output_format, self.audio_backend_sender.clone());
+			loader.set_window_end(CACHED_HEAD_SAMPLES);
+			self.prefetch_loaders.push((song_id, loader));
+		}
 	}
 
 	#[allow(unused)]
@@ -316,8 +1022,72 @@ impl AudioBackend {
 		));
 	}
 
+	fn set_crossfade(&mut self, crossfade: Duration) {
+		self.crossfade = crossfade;
+		if let Some(current_song) = &mut self.current_song {
+			current_song.reset_transition();
+		}
+		self.send_next_chunks();
+	}
+
+	/// Changes the gain of the current and the queued song immediately.
+	fn set_replay_gain_mode(&mut self, replay_gain_mode: ReplayGainMode) {
+		self.replay_gain_mode = replay_gain_mode;
+		let current_audio_song = self.current_song.as_mut().map(|s| &mut s.audio_song);
+		let next_audio_song = self.next_song.as_mut().map(|s| &mut s.1);
+		for audio_song in current_audio_song.into_iter().chain(next_audio_song) {
+			audio_song.gain = get_gain(audio_song.loudness.as_ref(), replay_gain_mode);
+		}
+	}
+
+	/// Enables the equalizer with the given preset or disables it. Changes apply to chunks, that are not sent yet.
+	fn set_equalizer(&mut self, preset: Option<EqualizerPreset>) {
+		match (preset, &mut self.equalizer) {
+			(Some(preset), Some(equalizer)) => equalizer.set_preset(preset),
+			(Some(preset), None) => self.equalizer = Some(Equalizer::new(preset, self.output_format.0, self.output_format.1)),
+			(None, _) => self.equalizer = None,
+		}
+	}
+
+	/// Changes the speed of the current or the next song. The current song continues at the first sample, that was not
+	/// sent to the source.
+	fn set_speed(&mut self, song_id: SongID, speed: f32) {
+		let speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+		if let Some(current_song) = self.current_song.as_mut().filter(|s| s.audio_song.song_id == song_id) {
+			if current_song.audio_song.speed == speed {
+				return;
+			}
+			current_song.audio_song.speed = speed;
+			if self.time_stretcher.get_song_id() == Some(song_id) {
+				if let Some(position) = self.time_stretcher.reset() {
+					current_song.play_position = position;
+				}
+			}
+			self.send_next_chunks();
+		} else if let Some((_, next_audio_song)) = self.next_song.as_mut().filter(|(s, _)| s.get_id() == song_id) {
+			next_audio_song.speed = speed;
+		}
+	}
+
+	/// Repeats the given region of the current song or removes the loop. Chunks, that were already sent, are played.
+	fn set_loop(&mut self, loop_range: Option<(Duration, Duration)>) {
+		if let Some(current_song) = &mut self.current_song {
+			current_song.loop_range = loop_range.filter(|(loop_start, loop_end)| loop_start < loop_end);
+			current_song.reset_transition();
+			self.send_next_chunks();
+		}
+	}
+
+	fn set_stop_after_current(&mut self, stop_after_current: bool) {
+		self.stop_after_current = stop_after_current;
+		// a crossfade into the next song is not started anymore
+		if let Some(current_song) = &mut self.current_song {
+			current_song.reset_transition();
+		}
+	}
+
 	fn set_volume(&mut self, volume: f32) {
-		self.sink.set_volume(volume);
+		self.output.set_volume(volume);
 		self.volume = volume;
 	}
 
@@ -329,137 +1099,155 @@ impl AudioBackend {
 	fn handle_update(&mut self, update: AudioUpdate) {
 		match update {
 			AudioUpdate::Playing(playing_update) => {
+				// the source just took a chunk, so the queue is at its lowest
+				self.health_monitor.add_queue_depth(self.source_chunk_sender.len());
 				let audio_song = Self::get_audio_song(self.current_song.as_mut(), self.next_song.as_mut(), playing_update.song_id);
 				if let Some(audio_song) = audio_song {
 					if let Some((sample_rate, channels)) = audio_song.sample_rate_and_channels {
 						let duration = position_to_duration(playing_update.samples_played, sample_rate, channels);
-						self.info_sender.send(AudioInfo::Playing(playing_update.song_id, duration)).unwrap();
+						let _ = self.info_sender.send(AudioInfo::Playing(playing_update.song_id, duration));
 					}
 				}
 				self.send_next_chunks();
 			}
 			AudioUpdate::SongStarts(song_id) => {
-				self.info_sender.send(AudioInfo::SongStarts(song_id)).unwrap();
+				let _ = self.info_sender.send(AudioInfo::SongStarts(song_id));
+			}
+			AudioUpdate::FadedOut => {
+				if self.paused {
+					self.output.pause();
+				}
 			}
 		}
 	}
 
 	fn handle_load_info(&mut self, load_info: LoadInfo) {
 		match load_info {
-			LoadInfo::Chunk(chunk) => {
+			LoadInfo::Chunk(chunk, seek_generation, decode_time) => {
+				self.health_monitor.add_decoded_chunk(position_to_duration(chunk.length, chunk.sample_rate, chunk.channels), decode_time);
+				self.song_cache.add_chunk(&chunk);
+				let song_id = chunk.song_id;
+				if self.song_cache.is_complete(song_id, self.output_format) {
+					self.prefetch_loaders.retain(|(id, _)| *id != song_id);
+				}
 				if let Some(audio_song) = Self::get_audio_song(self.current_song.as_mut(), self.next_song.as_mut(), chunk.song_id) {
-					if audio_song.sample_rate_and_channels.is_none() {
-						audio_song.sample_rate_and_channels = Some((chunk.sample_rate, chunk.channels));
-					}
-					audio_song.chunks.push(chunk);
+					audio_song.add_chunk(chunk, seek_generation);
 					self.send_next_chunks();
 				}
 			}
+			LoadInfo::Format(song_id, sample_rate, channels) => {
+				if let Some(audio_song) = Self::get_audio_song(self.current_song.as_mut(), self.next_song.as_mut(), song_id) {
+					audio_song.sample_rate_and_channels = Some((sample_rate, channels));
+					self.apply_pending_seek();
+				}
+			}
+			LoadInfo::Loudness(song_id, loudness) => {
+				if let Some(audio_song) = Self::get_audio_song(self.current_song.as_mut(), self.next_song.as_mut(), song_id) {
+					audio_song.set_loudness(loudness, self.replay_gain_mode);
+				}
+				let _ = self.info_sender.send(AudioInfo::SongLoudness(song_id, loudness));
+			}
 			LoadInfo::Duration(song_id, duration) => {
-				self.info_sender.send(AudioInfo::SongDuration(song_id, duration)).unwrap(); // TODO: handle error
+				if let Some(audio_song) = Self::get_audio_song(self.current_song.as_mut(), self.next_song.as_mut(), song_id) {
+					audio_song.total_duration = Some(duration);
+					self.apply_pending_seek();
+				}
+				let _ = self.info_sender.send(AudioInfo::SongDuration(song_id, duration));
 			}
 			LoadInfo::Err(song, e) => {
-				self.info_sender.send(AudioInfo::FailedOpen(song, e)).unwrap(); // TODO: handle error
+				self.prefetch_loaders.retain(|(id, _)| *id != song);
+				if let Some(audio_song) = Self::get_audio_song(self.current_song.as_mut(), self.next_song.as_mut(), song) {
+					audio_song.failed = true;
+					self.send_next_chunks();
+				}
+				let _ = self.info_sender.send(AudioInfo::FailedOpen(song, e));
+			}
+			LoadInfo::SpawnFailed(thread_name, e) => {
+				let _ = self.info_sender.send(AudioInfo::SpawnFailed(thread_name, e));
 			}
 		}
 	}
 
 	fn seek(&mut self, seek_command: SeekCommand) {
 		if let Some(current_song) = &mut self.current_song {
-			if let Some((sample_rate, channels)) = current_song.audio_song.sample_rate_and_channels {
-				let offset = duration_to_position(&seek_command.duration, sample_rate, channels);
-				match seek_command.direction {
-					SeekDirection::Forward => {
-						current_song.play_position += offset;
-					}
-					SeekDirection::Backward => {
-						current_song.play_position = current_song.play_position.checked_sub(offset).unwrap_or(0);
+			match current_song.audio_song.sample_rate_and_channels {
+				Some((sample_rate, channels)) => {
+					let offset = duration_to_position(&seek_command.duration, sample_rate, channels);
+					let position = match seek_command.direction {
+						SeekDirection::Forward => current_song.play_position + offset,
+						SeekDirection::Backward => current_song.play_position.saturating_sub(offset),
+					};
+					// keep the position aligned to frames
+					self.set_play_position(position - position % channels as usize);
+				}
+				None => {
+					// the song is not opened yet -> seek relative to the start of the song
+					if let SeekDirection::Forward = seek_command.direction {
+						let offset = match current_song.audio_song.pending_seek {
+							Some(SeekPosition::Time(pending_time)) => pending_time,
+							_ => Duration::ZERO,
+						};
+						current_song.audio_song.pending_seek = Some(SeekPosition::Time(offset + seek_command.duration));
 					}
 				}
 			}
 		}
 	}
 
+	fn seek_to(&mut self, seek_position: SeekPosition) {
+		if let Some(current_song) = &mut self.current_song {
+			match current_song.audio_song.get_seek_position(seek_position) {
+				Some(position) => self.set_play_position(position),
+				None => current_song.audio_song.pending_seek = Some(seek_position),
+			}
+		}
+	}
+
+	fn apply_pending_seek(&mut self) {
+		if let Some(current_song) = &mut self.current_song {
+			if let Some(seek_position) = current_song.audio_song.pending_seek.take() {
+				self.seek_to(seek_position);
+			}
+		}
+	}
+
+	/// Continues the current song at the given position. If the position is not loaded, the loader is moved there.
+	fn set_play_position(&mut self, position: usize) {
+		if let Some(current_song) = &mut self.current_song {
+			current_song.play_position = position;
+			current_song.reset_transition();
+			self.time_stretcher.reset();
+			self.source_control.next_generation();
+			current_song.audio_song.request_position(position);
+			// inform musicus immediately, the source reports the new position only after the queued chunks are played
+			if let Some((sample_rate, channels)) = current_song.audio_song.sample_rate_and_channels {
+				let duration = position_to_duration(position, sample_rate, channels);
+				let _ = self.info_sender.send(AudioInfo::Playing(current_song.audio_song.song_id, duration));
+			}
+			self.send_next_chunks();
+		}
+	}
+
+	/// Fades out the source. The output is paused, when the source is silent.
 	fn pause(&mut self) {
-		self.sink.pause();
+		self.source_control.set_paused(true);
+		self.paused = true;
 	}
 
 	fn unpause(&mut self) {
-		self.sink.play();
+		self.source_control.set_paused(false);
+		self.output.play();
+		self.paused = false;
 	}
-}
 
-/**
- * Loads chunks of the given song
- */
-fn load_chunks(song: Song, chunk_sender: Sender<AudioBackendCommand>) {
-	if let Ok(file) = File::open(&song.get_path()) {
-		if let Ok(decoder) = Decoder::new(BufReader::new(file)) {
-			let channels = decoder.channels();
-			let sample_rate = decoder.sample_rate();
-			let total_duration = decoder.total_duration();
-
-			if let Some(duration) = total_duration {
-				let _ = chunk_sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Duration(song.get_id(), duration)));
-			}
-
-			let mut data = Box::new([0.0f32; CHUNK_SIZE]);
-			let mut index = 0;
-			let mut next_start_position = 0;
-			let mut converted = decoder.convert_samples().peekable();
-
-			while let Some(sample) = converted.next() {
-				let chunk_index = index % CHUNK_SIZE;
-				data[chunk_index] = sample;
-
-				// send chunk
-				if chunk_index == CHUNK_SIZE-1 {
-					let last_chunk = converted.peek().is_none();
-					let chunk = SamplesChunk {
-						channels,
-						sample_rate,
-						start_position: next_start_position,
-						length: CHUNK_SIZE,
-						data: Arc::from(data.clone()),
-						song_id: song.get_id(),
-						last_chunk,
-					};
-					// calculate duration, if not already done
-					if last_chunk && total_duration.is_none() {
-						let duration = position_to_duration(next_start_position + CHUNK_SIZE, sample_rate, channels);
-						let _ = chunk_sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Duration(song.get_id(), duration)));
-					}
-					next_start_position = index + 1;
-					if chunk_sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Chunk(chunk))).is_err() {
-						return;
-					}
-				}
-				index += 1;
-			}
-			let chunk_index = index % CHUNK_SIZE;
-			if chunk_index != 0 {
-				let chunk = SamplesChunk {
-					channels,
-					sample_rate,
-					start_position: next_start_position,
-					length: index - next_start_position,
-					data: Arc::from(data),
-					song_id: song.get_id(),
-					last_chunk: true,
-				};
-				if total_duration.is_none() {
-					let duration = position_to_duration(next_start_position + CHUNK_SIZE, sample_rate, channels);
-					let _ = chunk_sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Duration(song.get_id(), duration)));
-				}
-				if chunk_sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Chunk(chunk))).is_err() {
-					return;
-				}
-			}
-		} else {
-			let _ = chunk_sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Err(song.get_id(), OpenError::NotDecodable)));
+	/// Stops playing the current song. Chunks, that were already sent, are faded out.
+	fn stop(&mut self) {
+		if self.current_song.is_some() {
+			self.source_control.next_generation();
 		}
-	} else {
-		let _ = chunk_sender.send(AudioBackendCommand::LoadInfo(LoadInfo::Err(song.get_id(), OpenError::FileNotFound)));
+		self.current_song = None;
+		self.time_stretcher.reset();
+		self.stop_after_current = false;
 	}
 }
 
@@ -469,7 +1257,10 @@ impl AudioBackendCommand {
 		let mut last_play_command = None;
 		let mut last_playing_update = None;
 		let mut seek_command: Option<SeekCommand> = None;
+		let mut last_seek_to: Option<SeekPosition> = None;
 		let mut last_set_volume: Option<f32> = None;
+		let mut set_speed_commands = Vec::new();
+		let mut playback_commands = Vec::new();
 		let mut load_infos = Vec::new();
 
 		for command_or_update in vec.into_iter() {
@@ -477,14 +1268,36 @@ impl AudioBackendCommand {
 				AudioBackendCommand::Command(command) => {
 					match command {
 						AudioCommand::Play(play) => {
+							// playing a song unpauses the backend
+							playback_commands.retain(|command| matches!(command, AudioBackendCommand::Command(AudioCommand::SetLoop(_))));
 							last_play_command = Some(play);
 						}
+						AudioCommand::Stop => {
+							// songs played before the stop are not started
+							last_play_command = None;
+							set_speed_commands.clear();
+							playback_commands.clear();
+							result.push(AudioBackendCommand::Command(AudioCommand::Stop));
+						}
+						AudioCommand::SeekTo(seek_position) => {
+							// relative seeks before an absolute seek have no effect
+							last_seek_to = Some(seek_position);
+							seek_command = None;
+						}
 						AudioCommand::Seek(new_seek) => {
 							seek_command = seek_command.map_or(Some(new_seek), |old_seek| Some(SeekCommand::join(old_seek, new_seek)))
 						}
 						AudioCommand::SetVolume(v) => {
 							last_set_volume = Some(v);
 						}
+						command @ AudioCommand::SetSpeed(..) => {
+							// the speed of a song can only be set after it is played
+							set_speed_commands.push(AudioBackendCommand::Command(command));
+						}
+						command @ (AudioCommand::SetLoop(_) | AudioCommand::Pause | AudioCommand::Unpause) => {
+							// the loop and the pause apply to the song, that is played after the play command
+							playback_commands.push(AudioBackendCommand::Command(command));
+						}
 						command => {
 							result.push(AudioBackendCommand::Command(command));
 						}
@@ -505,15 +1318,590 @@ impl AudioBackendCommand {
 		if let Some(play_command) = last_play_command {
 			result.push(AudioBackendCommand::Command(AudioCommand::Play(play_command)));
 		}
+		result.append(&mut set_speed_commands);
 		if let Some(playing_update) = last_playing_update {
 			result.push(AudioBackendCommand::Update(AudioUpdate::Playing(playing_update)))
 		}
+		if let Some(seek_position) = last_seek_to {
+			result.push(AudioBackendCommand::Command(AudioCommand::SeekTo(seek_position)));
+		}
 		if let Some(seek_command) = seek_command {
 			result.push(AudioBackendCommand::Command(AudioCommand::Seek(seek_command)));
 		}
+		result.append(&mut playback_commands);
 		if let Some(v) = last_set_volume {
 			result.push(AudioBackendCommand::Command(AudioCommand::SetVolume(v)));
 		}
 		result
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::cell::RefCell;
+	use std::path::Path;
+	use std::rc::Rc;
+	use std::sync::Arc;
+
+	use crossbeam::unbounded;
+
+	use super::*;
+	use crate::song::song_buffer::SongBuffer;
+
+	const SAMPLE_RATE: u32 = 44100;
+	const CHANNELS: u16 = 2;
+
+	type SharedSource = Rc<RefCell<Option<ReceiverSource>>>;
+
+	/// An output that keeps the source, so samples can be pulled manually.
+	struct TestOutput {
+		source: SharedSource,
+	}
+
+	impl AudioOutput for TestOutput {
+		fn start(&mut self, source: ReceiverSource) -> io::Result<()> {
+			*self.source.borrow_mut() = Some(source);
+			Ok(())
+		}
+
+		fn play(&mut self) {}
+
+		fn pause(&mut self) {}
+
+		fn set_volume(&mut self, _volume: f32) {}
+
+		fn get_name(&self) -> String {
+			"test output".to_string()
+		}
+
+		fn get_format(&self) -> (u32, u16) {
+			(SAMPLE_RATE, CHANNELS)
+		}
+	}
+
+	fn create_backend() -> (AudioBackend, SharedSource) {
+		let (info_sender, _info_receiver) = unbounded();
+		let (audio_backend_sender, _audio_backend_receiver) = unbounded();
+		let source = Rc::new(RefCell::new(None));
+		let output = Box::new(TestOutput { source: source.clone() });
+		let backend = AudioBackend::with_output(info_sender, audio_backend_sender, unbounded().0, 1.0, output, OutputConfig::Null(0.0));
+		(backend, source)
+	}
+
+	fn create_song(song_buffer: &mut SongBuffer, name: &str) -> Song {
+		let song_id = song_buffer.import(Path::new(name), None);
+		song_buffer.get(song_id).unwrap().clone()
+	}
+
+	/// Creates a chunk, whose samples are all set to value.
+	fn create_chunk(song_id: SongID, index: usize, value: f32, last_chunk: bool) -> SamplesChunk {
+		SamplesChunk {
+			channels: CHANNELS,
+			sample_rate: SAMPLE_RATE,
+			start_position: index * CHUNK_SIZE,
+			length: CHUNK_SIZE,
+			data: Arc::new([value; CHUNK_SIZE]),
+			song_id,
+			last_chunk,
+			generation: 0,
+		}
+	}
+
+	/// Pulls one chunk from the source and returns its first sample.
+	fn pull_chunk(source: &SharedSource) -> f32 {
+		let mut source = source.borrow_mut();
+		let source = source.as_mut().unwrap();
+		let first = source.next().unwrap();
+		for _ in 1..CHUNK_SIZE {
+			source.next();
+		}
+		first
+	}
+
+	fn load_chunks(backend: &mut AudioBackend, song_id: SongID, values: &[f32]) {
+		for (index, value) in values.iter().enumerate() {
+			let chunk = create_chunk(song_id, index, *value, index == values.len() - 1);
+			backend.handle_load_info(LoadInfo::Chunk(chunk, 0, Duration::ZERO));
+		}
+	}
+
+	#[test]
+	fn test_send_next_chunks_in_order() {
+		let (mut backend, source) = create_backend();
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		backend.play(song.clone());
+		load_chunks(&mut backend, song.get_id(), &[1.0, 2.0, 3.0]);
+
+		assert_eq!(pull_chunk(&source), 1.0);
+		assert_eq!(pull_chunk(&source), 2.0);
+		assert_eq!(pull_chunk(&source), 3.0);
+	}
+
+	#[test]
+	fn test_send_next_chunks_respects_buffer_size() {
+		let (mut backend, source) = create_backend();
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		backend.play(song.clone());
+		let values: Vec<f32> = (0..CHUNK_BUFFER_SIZE + 4).map(|v| v as f32).collect();
+		load_chunks(&mut backend, song.get_id(), &values);
+
+		assert_eq!(backend.source_chunk_sender.len(), CHUNK_BUFFER_SIZE);
+		assert_eq!(backend.current_song.as_ref().unwrap().play_position, CHUNK_BUFFER_SIZE * CHUNK_SIZE);
+
+		// the source receives the next chunk directly after the last sample of the first chunk
+		assert_eq!(pull_chunk(&source), 0.0);
+		backend.send_next_chunks();
+		assert_eq!(backend.source_chunk_sender.len(), CHUNK_BUFFER_SIZE);
+		assert_eq!(backend.current_song.as_ref().unwrap().play_position, (CHUNK_BUFFER_SIZE + 2) * CHUNK_SIZE);
+	}
+
+	#[test]
+	fn test_queued_song_follows_current_song() {
+		let (mut backend, source) = create_backend();
+		let mut song_buffer = SongBuffer::new();
+		let song_a = create_song(&mut song_buffer, "a.wav");
+		let song_b = create_song(&mut song_buffer, "b.wav");
+		backend.play(song_a.clone());
+		backend.queue(song_b.clone());
+		load_chunks(&mut backend, song_b.get_id(), &[10.0, 11.0]);
+		load_chunks(&mut backend, song_a.get_id(), &[1.0, 2.0]);
+
+		assert_eq!(pull_chunk(&source), 1.0);
+		assert_eq!(pull_chunk(&source), 2.0);
+		assert_eq!(pull_chunk(&source), 10.0);
+		assert_eq!(pull_chunk(&source), 11.0);
+		assert!(backend.current_song.is_none());
+	}
+
+	#[test]
+	fn test_play_queued_song_reuses_chunks() {
+		let (mut backend, source) = create_backend();
+		let mut song_buffer = SongBuffer::new();
+		let song_a = create_song(&mut song_buffer, "a.wav");
+		let song_b = create_song(&mut song_buffer, "b.wav");
+		backend.play(song_a);
+		backend.queue(song_b.clone());
+		load_chunks(&mut backend, song_b.get_id(), &[10.0, 11.0]);
+		backend.play(song_b);
+
+		assert_eq!(pull_chunk(&source), 10.0);
+		assert_eq!(pull_chunk(&source), 11.0);
+	}
+
+	#[test]
+	fn test_seek() {
+		let (mut backend, _source) = create_backend();
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		backend.play(song.clone());
+		let values: Vec<f32> = (0..20).map(|v| v as f32).collect();
+		load_chunks(&mut backend, song.get_id(), &values);
+		let position = backend.current_song.as_ref().unwrap().play_position;
+
+		let seek_duration = Duration::from_secs_f64(0.1);
+		let offset = duration_to_position(&seek_duration, SAMPLE_RATE, CHANNELS);
+		backend.seek(SeekCommand { duration: seek_duration, direction: SeekDirection::Forward });
+		assert_eq!(backend.current_song.as_ref().unwrap().play_position, position + offset);
+
+		backend.seek(SeekCommand { duration: Duration::from_secs(10), direction: SeekDirection::Backward });
+		assert_eq!(backend.current_song.as_ref().unwrap().play_position, 0);
+	}
+
+	#[test]
+	fn test_seek_outside_window_restarts_loader() {
+		let (mut backend, _source) = create_backend();
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		backend.play(song.clone());
+		for index in 0..2 {
+			backend.handle_load_info(LoadInfo::Chunk(create_chunk(song.get_id(), index, 1.0, false), 0, Duration::ZERO));
+		}
+
+		backend.seek(SeekCommand { duration: Duration::from_secs(60), direction: SeekDirection::Forward });
+		let position = backend.current_song.as_ref().unwrap().play_position;
+		let audio_song = &backend.current_song.as_ref().unwrap().audio_song;
+		assert!(audio_song.chunks.is_empty());
+		assert_eq!(audio_song.load_start, position);
+		assert_eq!(audio_song.loader.as_ref().unwrap().get_seek_generation(), 1);
+
+		// chunks of the old generation are dropped
+		let mut chunk = create_chunk(song.get_id(), 0, 2.0, false);
+		chunk.start_position = position;
+		backend.handle_load_info(LoadInfo::Chunk(chunk.clone(), 0, Duration::ZERO));
+		assert!(backend.current_song.as_ref().unwrap().audio_song.chunks.is_empty());
+
+		backend.handle_load_info(LoadInfo::Chunk(chunk, 1, Duration::ZERO));
+		assert_eq!(backend.current_song.as_ref().unwrap().play_position, position + CHUNK_SIZE);
+	}
+
+	#[test]
+	fn test_update_window_drops_played_chunks() {
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		let (audio_backend_sender, _audio_backend_receiver) = unbounded();
+		let mut audio_song = AudioSong::new(song.clone(), Vec::new(), &ThreadPool::new(1), audio_backend_sender, ReplayGainMode::Off, (SAMPLE_RATE, CHANNELS));
+		let num_chunks = KEEP_BEHIND_SAMPLES / CHUNK_SIZE + 4;
+		for index in 0..num_chunks {
+			audio_song.add_chunk(create_chunk(song.get_id(), index, 0.0, false), 0);
+		}
+
+		audio_song.update_window(num_chunks * CHUNK_SIZE, None);
+		assert_eq!(audio_song.chunks.len(), KEEP_BEHIND_SAMPLES / CHUNK_SIZE);
+		assert_eq!(audio_song.chunks.front().unwrap().start_position, 4 * CHUNK_SIZE);
+	}
+
+	#[test]
+	fn test_seek_to() {
+		let (mut backend, _source) = create_backend();
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		backend.play(song.clone());
+		backend.handle_load_info(LoadInfo::Format(song.get_id(), SAMPLE_RATE, CHANNELS));
+		backend.handle_load_info(LoadInfo::Duration(song.get_id(), Duration::from_secs(100)));
+
+		backend.seek_to(SeekPosition::Time(Duration::from_secs(30)));
+		let expected_position = duration_to_position(&Duration::from_secs(30), SAMPLE_RATE, CHANNELS);
+		assert_eq!(backend.current_song.as_ref().unwrap().play_position, expected_position);
+
+		backend.seek_to(SeekPosition::Fraction(0.4));
+		let expected_position = duration_to_position(&Duration::from_secs(40), SAMPLE_RATE, CHANNELS);
+		assert_eq!(backend.current_song.as_ref().unwrap().play_position, expected_position);
+		assert_eq!(backend.current_song.as_ref().unwrap().audio_song.load_start, expected_position);
+	}
+
+	#[test]
+	fn test_seek_before_format_is_known() {
+		let (mut backend, _source) = create_backend();
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		backend.play(song.clone());
+		backend.seek_to(SeekPosition::Time(Duration::from_secs(30)));
+		assert_eq!(backend.current_song.as_ref().unwrap().play_position, 0);
+
+		backend.handle_load_info(LoadInfo::Format(song.get_id(), SAMPLE_RATE, CHANNELS));
+		let expected_position = duration_to_position(&Duration::from_secs(30), SAMPLE_RATE, CHANNELS);
+		assert_eq!(backend.current_song.as_ref().unwrap().play_position, expected_position);
+	}
+
+	#[test]
+	fn test_seek_after_end_plays_next_song() {
+		let (mut backend, _source) = create_backend();
+		let mut song_buffer = SongBuffer::new();
+		let song_a = create_song(&mut song_buffer, "a.wav");
+		let song_b = create_song(&mut song_buffer, "b.wav");
+		backend.play(song_a.clone());
+		backend.queue(song_b.clone());
+		backend.handle_load_info(LoadInfo::Format(song_a.get_id(), SAMPLE_RATE, CHANNELS));
+		backend.seek_to(SeekPosition::Time(Duration::from_secs(60)));
+
+		// the loader reports the end of the song before the requested position
+		let mut chunk = create_chunk(song_a.get_id(), 0, 1.0, true);
+		chunk.start_position = CHUNK_SIZE * 10;
+		chunk.length = 0;
+		backend.handle_load_info(LoadInfo::Chunk(chunk, 1, Duration::ZERO));
+		assert_eq!(backend.current_song.as_ref().unwrap().audio_song.song_id, song_b.get_id());
+	}
+
+	/// Pulls samples from the source, while the backend keeps the source filled.
+	fn pull_samples(backend: &mut AudioBackend, source: &SharedSource, num_samples: usize) -> Vec<f32> {
+		let mut samples = Vec::with_capacity(num_samples);
+		for _ in 0..num_samples {
+			samples.push(source.borrow_mut().as_mut().unwrap().next().unwrap());
+			backend.send_next_chunks();
+		}
+		samples
+	}
+
+	/// Plays song_a followed by song_b with the given crossfade. Both songs consist of four chunks.
+	/// The duration of song_a is known before its chunks are loaded.
+	fn play_two_songs(backend: &mut AudioBackend, song_a: &Song, song_b: &Song, crossfade: Duration) {
+		backend.set_crossfade(crossfade);
+		backend.play(song_a.clone());
+		backend.queue(song_b.clone());
+		backend.handle_load_info(LoadInfo::Format(song_b.get_id(), SAMPLE_RATE, CHANNELS));
+		load_chunks(backend, song_b.get_id(), &[2.0; 4]);
+		backend.handle_load_info(LoadInfo::Format(song_a.get_id(), SAMPLE_RATE, CHANNELS));
+		backend.handle_load_info(LoadInfo::Duration(song_a.get_id(), Duration::from_micros(46440)));
+		load_chunks(backend, song_a.get_id(), &[1.0; 4]);
+	}
+
+	#[test]
+	fn test_crossfade() {
+		let (mut backend, source) = create_backend();
+		let mut song_buffer = SongBuffer::new();
+		let song_a = create_song(&mut song_buffer, "album_a/a.wav");
+		let song_b = create_song(&mut song_buffer, "album_b/b.wav");
+		// a crossfade of 1.5 chunks
+		let fade_length = CHUNK_SIZE * 3 / 2;
+		play_two_songs(&mut backend, &song_a, &song_b, Duration::from_micros(17415));
+
+		let song_length = 4 * CHUNK_SIZE;
+		let fade_start = song_length - fade_length;
+		let samples = pull_samples(&mut backend, &source, 2 * song_length);
+		assert_eq!(samples[fade_start - 1], 1.0);
+		assert_eq!(samples[fade_start], 1.0);
+		// equal power crossfade in the middle
+		assert!((samples[fade_start + fade_length / 2] - 3.0 * (FRAC_PI_2 * 0.5).cos()).abs() < 0.01);
+		assert_eq!(samples[2 * song_length - fade_length - 1], 2.0);
+		// the song ends earlier by the crossfade length, afterwards the source fades out
+		assert!(samples[2 * song_length - fade_length] < 2.0);
+	}
+
+	#[test]
+	fn test_no_crossfade_in_same_directory() {
+		let (mut backend, source) = create_backend();
+		let mut song_buffer = SongBuffer::new();
+		let song_a = create_song(&mut song_buffer, "album/a.wav");
+		let song_b = create_song(&mut song_buffer, "album/b.wav");
+		play_two_songs(&mut backend, &song_a, &song_b, Duration::from_secs(1));
+
+		let song_length = 4 * CHUNK_SIZE;
+		let samples = pull_samples(&mut backend, &source, 2 * song_length + 1);
+		assert!(samples[..song_length].iter().all(|s| *s == 1.0));
+		assert!(samples[song_length..2 * song_length].iter().all(|s| *s == 2.0));
+		assert!(samples[2 * song_length] < 2.0);
+	}
+
+	#[test]
+	fn test_replay_gain() {
+		let (mut backend, source) = create_backend();
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		backend.set_replay_gain_mode(ReplayGainMode::Track);
+		backend.play(song.clone());
+		let loudness = Loudness { track_gain: -6.0, track_peak: None, album_gain: None, album_peak: None };
+		backend.handle_load_info(LoadInfo::Loudness(song.get_id(), loudness));
+		load_chunks(&mut backend, song.get_id(), &[0.5; 8]);
+		assert!((pull_chunk(&source) - 0.5 * 10f32.powf(-6.0 / 20.0)).abs() < 1e-6);
+
+		// the gain of a playing song is only changed by changing the mode
+		let loudness = Loudness { track_gain: -12.0, track_peak: None, album_gain: None, album_peak: None };
+		backend.handle_load_info(LoadInfo::Loudness(song.get_id(), loudness));
+		assert!((backend.current_song.as_ref().unwrap().audio_song.gain - 10f32.powf(-6.0 / 20.0)).abs() < 1e-6);
+		backend.set_replay_gain_mode(ReplayGainMode::Off);
+		assert_eq!(backend.current_song.as_ref().unwrap().audio_song.gain, 1.0);
+	}
+
+	#[test]
+	fn test_speed_keeps_song_positions() {
+		let (mut backend, source) = create_backend();
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		backend.play(song.clone());
+		backend.set_speed(song.get_id(), 2.0);
+		load_chunks(&mut backend, song.get_id(), &[0.5; 64]);
+
+		// the source plays half of the samples
+		let samples = pull_samples(&mut backend, &source, 40 * CHUNK_SIZE);
+		let played = samples.iter().take_while(|s| (**s - 0.5).abs() < 0.01).count();
+		assert!((played as i32 - 32 * CHUNK_SIZE as i32).abs() <= CHUNK_SIZE as i32);
+		assert!(backend.current_song.is_none());
+
+		// the play position is a position in the song
+		backend.play(song.clone());
+		backend.set_speed(song.get_id(), 2.0);
+		load_chunks(&mut backend, song.get_id(), &[0.5; 64]);
+		pull_samples(&mut backend, &source, 8 * CHUNK_SIZE);
+		let play_position = backend.current_song.as_ref().unwrap().play_position;
+		assert!(play_position >= 16 * CHUNK_SIZE);
+	}
+
+	#[test]
+	fn test_simplify_sets_speed_after_play() {
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		let commands = AudioBackendCommand::simplify(vec![
+			AudioBackendCommand::Command(AudioCommand::Play(song.clone())),
+			AudioBackendCommand::Command(AudioCommand::SetSpeed(song.get_id(), 1.5)),
+		]);
+		assert!(matches!(&commands[0], AudioBackendCommand::Command(AudioCommand::Play(_))));
+		assert!(matches!(&commands[1], AudioBackendCommand::Command(AudioCommand::SetSpeed(_, _))));
+	}
+
+	/// Pulls num_chunks chunks, while the backend keeps the source filled, and returns their first samples.
+	fn pull_chunks(backend: &mut AudioBackend, source: &SharedSource, num_chunks: usize) -> Vec<f32> {
+		(0..num_chunks).map(|_| {
+			let value = pull_chunk(source);
+			backend.send_next_chunks();
+			value
+		}).collect()
+	}
+
+	#[test]
+	fn test_loop_repeats_region() {
+		let (mut backend, source) = create_backend();
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		backend.play(song.clone());
+		backend.handle_load_info(LoadInfo::Format(song.get_id(), SAMPLE_RATE, CHANNELS));
+		// loop from the start of the third to the end of the fourth chunk
+		backend.set_loop(Some((Duration::from_micros(23220), Duration::from_micros(46440))));
+		let values: Vec<f32> = (0..8).map(|v| v as f32).collect();
+		load_chunks(&mut backend, song.get_id(), &values);
+		assert_eq!(pull_chunks(&mut backend, &source, 8), vec![0.0, 1.0, 2.0, 3.0, 2.0, 3.0, 2.0, 3.0]);
+
+		// without the loop the song continues after the end of the loop
+		backend.set_loop(None);
+		let played = pull_chunks(&mut backend, &source, CHUNK_BUFFER_SIZE + 4);
+		let continued = played.iter().position(|v| *v == 4.0).unwrap();
+		assert!(played[..continued].iter().all(|v| *v == 2.0 || *v == 3.0));
+		assert_eq!(played[continued + 1], 5.0);
+	}
+
+	#[test]
+	fn test_seek_fades_out_and_in() {
+		let (mut backend, source) = create_backend();
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		backend.play(song.clone());
+		backend.handle_load_info(LoadInfo::Format(song.get_id(), SAMPLE_RATE, CHANNELS));
+		load_chunks(&mut backend, song.get_id(), &[0.5; 64]);
+		pull_chunk(&source);
+
+		backend.seek_to(SeekPosition::Time(Duration::from_millis(200)));
+		let samples = pull_samples(&mut backend, &source, 16 * CHUNK_SIZE);
+		// the samples before the seek are faded out and the samples after the seek are faded in without jumps
+		assert!(samples.contains(&0.0));
+		assert!(samples.windows(2).all(|w| (w[0] - w[1]).abs() < 0.01));
+		assert_eq!(*samples.last().unwrap(), 0.5);
+	}
+
+	#[test]
+	fn test_stop_after_current() {
+		let (info_sender, info_receiver) = unbounded();
+		let (audio_backend_sender, _audio_backend_receiver) = unbounded();
+		let source = Rc::new(RefCell::new(None));
+		let output = Box::new(TestOutput { source: source.clone() });
+		let mut backend = AudioBackend::with_output(info_sender, audio_backend_sender, unbounded().0, 1.0, output, OutputConfig::Null(0.0));
+		let mut song_buffer = SongBuffer::new();
+		let song_a = create_song(&mut song_buffer, "a.wav");
+		let song_b = create_song(&mut song_buffer, "b.wav");
+		backend.play(song_a.clone());
+		backend.queue(song_b.clone());
+		backend.set_stop_after_current(true);
+		load_chunks(&mut backend, song_b.get_id(), &[10.0, 11.0]);
+		load_chunks(&mut backend, song_a.get_id(), &[1.0, 2.0]);
+
+		assert_eq!(pull_chunks(&mut backend, &source, 2), vec![1.0, 2.0]);
+		assert!(backend.current_song.is_none());
+		assert!(!backend.stop_after_current);
+		assert!(info_receiver.try_iter().any(|info| matches!(info, AudioInfo::PlaybackFinished)));
+		// the chunks of the next song are kept
+		backend.play(song_b);
+		assert!(pull_samples(&mut backend, &source, 4 * CHUNK_SIZE).contains(&10.0));
+	}
+
+	#[test]
+	fn test_failed_next_song_is_not_played() {
+		let (info_sender, info_receiver) = unbounded();
+		let source = Rc::new(RefCell::new(None));
+		let output = Box::new(TestOutput { source: source.clone() });
+		let mut backend = AudioBackend::with_output(info_sender, unbounded().0, unbounded().0, 1.0, output, OutputConfig::Null(0.0));
+		let mut song_buffer = SongBuffer::new();
+		let song_a = create_song(&mut song_buffer, "a.wav");
+		let song_b = create_song(&mut song_buffer, "b.wav");
+		backend.play(song_a.clone());
+		backend.queue(song_b.clone());
+		backend.handle_load_info(LoadInfo::Err(song_b.get_id(), OpenError::FileNotFound));
+		load_chunks(&mut backend, song_a.get_id(), &[1.0, 2.0]);
+
+		assert_eq!(pull_chunks(&mut backend, &source, 2), vec![1.0, 2.0]);
+		assert!(backend.current_song.is_none());
+		assert!(info_receiver.try_iter().any(|info| matches!(info, AudioInfo::PlaybackFinished)));
+	}
+
+	#[test]
+	fn test_play_cached_song_again() {
+		let (mut backend, source) = create_backend();
+		let mut song_buffer = SongBuffer::new();
+		let song_a = create_song(&mut song_buffer, "a.wav");
+		let song_b = create_song(&mut song_buffer, "b.wav");
+		backend.play(song_a.clone());
+		load_chunks(&mut backend, song_a.get_id(), &[1.0, 2.0, 3.0]);
+		backend.play(song_b);
+		assert!(backend.song_cache.is_complete(song_a.get_id(), (SAMPLE_RATE, CHANNELS)));
+
+		// the song starts without waiting for its loader
+		backend.play(song_a);
+		assert_eq!(backend.current_song.as_ref().unwrap().audio_song.chunks.len(), 3);
+		assert!(pull_samples(&mut backend, &source, 8 * CHUNK_SIZE).contains(&3.0));
+	}
+
+	#[test]
+	fn test_count_underruns() {
+		let (mut backend, source) = create_backend();
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		backend.play(song.clone());
+		backend.handle_load_info(LoadInfo::Chunk(create_chunk(song.get_id(), 0, 1.0, false), 0, Duration::ZERO));
+
+		// the song is not finished, but no chunk is left. A stall is counted once.
+		assert_eq!(pull_chunk(&source), 1.0);
+		pull_chunk(&source);
+		assert_eq!(backend.source_control.get_underruns(), 1);
+
+		// running out of chunks at the end of a song is no underrun
+		backend.handle_load_info(LoadInfo::Chunk(create_chunk(song.get_id(), 1, 2.0, true), 0, Duration::ZERO));
+		pull_chunk(&source);
+		pull_chunk(&source);
+		assert_eq!(backend.source_control.get_underruns(), 1);
+	}
+
+	#[test]
+	fn test_simplify_keeps_loop_and_pause_after_play() {
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		let loop_range = Some((Duration::from_secs(1), Duration::from_secs(2)));
+		let commands = AudioBackendCommand::simplify(vec![
+			AudioBackendCommand::Command(AudioCommand::Play(song.clone())),
+			AudioBackendCommand::Command(AudioCommand::SetLoop(loop_range)),
+			AudioBackendCommand::Command(AudioCommand::Pause),
+		]);
+		assert_eq!(commands.len(), 3);
+		assert!(matches!(&commands[0], AudioBackendCommand::Command(AudioCommand::Play(_))));
+		assert!(matches!(&commands[1], AudioBackendCommand::Command(AudioCommand::SetLoop(Some(_)))));
+		assert!(matches!(&commands[2], AudioBackendCommand::Command(AudioCommand::Pause)));
+
+		// a pause before a play has no effect
+		let commands = AudioBackendCommand::simplify(vec![
+			AudioBackendCommand::Command(AudioCommand::Pause),
+			AudioBackendCommand::Command(AudioCommand::Play(song.clone())),
+		]);
+		assert_eq!(commands.len(), 1);
+	}
+
+	#[test]
+	fn test_simplify_stop_drops_play() {
+		let song = create_song(&mut SongBuffer::new(), "a.wav");
+		let commands = AudioBackendCommand::simplify(vec![
+			AudioBackendCommand::Command(AudioCommand::Play(song.clone())),
+			AudioBackendCommand::Command(AudioCommand::Stop),
+		]);
+		assert_eq!(commands.len(), 1);
+		assert!(matches!(&commands[0], AudioBackendCommand::Command(AudioCommand::Stop)));
+	}
+
+	#[test]
+	fn test_parse_seek_position() {
+		assert_eq!("2:31".parse(), Ok(SeekPosition::Time(Duration::from_secs(151))));
+		assert_eq!("1:02:03".parse(), Ok(SeekPosition::Time(Duration::from_secs(3723))));
+		assert_eq!(" 95 ".parse(), Ok(SeekPosition::Time(Duration::from_secs(95))));
+		assert_eq!("40%".parse(), Ok(SeekPosition::Fraction(0.4)));
+		assert!("140%".parse::<SeekPosition>().is_err());
+		assert!("-5".parse::<SeekPosition>().is_err());
+		assert!("1:2:3:4".parse::<SeekPosition>().is_err());
+		assert!("abc".parse::<SeekPosition>().is_err());
+		assert!("".parse::<SeekPosition>().is_err());
+	}
+
+	#[test]
+	fn test_simplify_seek_to_overrides_relative_seeks() {
+		let commands = AudioBackendCommand::simplify(vec![
+			AudioBackendCommand::Command(AudioCommand::Seek(SeekCommand::from_secs(5.0))),
+			AudioBackendCommand::Command(AudioCommand::SeekTo(SeekPosition::Fraction(0.5))),
+		]);
+		assert_eq!(commands.len(), 1);
+		assert!(matches!(&commands[0], AudioBackendCommand::Command(AudioCommand::SeekTo(SeekPosition::Fraction(_)))));
+	}
+
+	#[test]
+	fn test_simplify_joins_seeks() {
+		let seek = |secs| AudioBackendCommand::Command(AudioCommand::Seek(SeekCommand::from_secs(secs)));
+		let commands = AudioBackendCommand::simplify(vec![seek(5.0), seek(5.0), seek(-2.0)]);
+		assert_eq!(commands.len(), 1);
+		match &commands[0] {
+			AudioBackendCommand::Command(AudioCommand::Seek(seek_command)) => {
+				assert!((seek_command.as_secs() - 8.0).abs() < 1e-6);
+			}
+			_ => panic!("expected seek command"),
+		}
+	}
+}
@@ -0,0 +1,163 @@
+use std::ffi::c_void;
+use std::ptr::NonNull;
+use std::sync::OnceLock;
+
+use libloading::Library;
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec};
+use symphonia::core::codecs::{CODEC_TYPE_OPUS, CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult};
+use symphonia::core::errors::{Error, Result};
+use symphonia::core::formats::Packet;
+use symphonia::core::support_codec;
+
+/// Opus is always decoded with 48 kHz.
+const OPUS_SAMPLE_RATE: u32 = 48000;
+/// The longest opus packet contains 120 ms.
+const MAX_FRAMES_PER_PACKET: usize = 5760;
+/// The names of the installed libopus on Linux, macOS and Windows.
+const LIBOPUS_NAMES: [&str; 4] = ["libopus.so.0", "libopus.so", "libopus.0.dylib", "opus.dll"];
+
+type CreateFn = unsafe extern "C" fn(sample_rate: i32, channels: i32, error: *mut i32) -> *mut c_void;
+type DecodeFloatFn = unsafe extern "C" fn(state: *mut c_void, data: *const u8, len: i32, pcm: *mut f32, frame_size: i32, decode_fec: i32) -> i32;
+type DestroyFn = unsafe extern "C" fn(state: *mut c_void);
+
+/// The functions of libopus, that decode opus packets. libopus is loaded, when the first opus song is opened,
+/// so musicus is built without libopus and plays opus songs, where libopus is installed.
+struct Libopus {
+	create: CreateFn,
+	decode_float: DecodeFloatFn,
+	destroy: DestroyFn,
+	// the functions are valid, as long as the library is loaded
+	_library: Library,
+}
+
+impl Libopus {
+	fn load() -> Option<Libopus> {
+		LIBOPUS_NAMES.iter().find_map(|name| {
+			// loading libopus runs no initialization code, that could conflict with musicus
+			unsafe {
+				let library = Library::new(name).ok()?;
+				let create = *library.get::<CreateFn>(b"opus_decoder_create\0").ok()?;
+				let decode_float = *library.get::<DecodeFloatFn>(b"opus_decode_float\0").ok()?;
+				let destroy = *library.get::<DestroyFn>(b"opus_decoder_destroy\0").ok()?;
+				Some(Libopus { create, decode_float, destroy, _library: library })
+			}
+		})
+	}
+}
+
+fn get_libopus() -> Option<&'static Libopus> {
+	static LIBOPUS: OnceLock<Option<Libopus>> = OnceLock::new();
+	LIBOPUS.get_or_init(Libopus::load).as_ref()
+}
+
+/// Returns whether libopus is installed, so opus songs can be decoded.
+pub fn is_available() -> bool {
+	get_libopus().is_some()
+}
+
+/// The state of a libopus decoder.
+struct LibopusDecoder {
+	libopus: &'static Libopus,
+	state: NonNull<c_void>,
+	channels: usize,
+}
+
+// the state is only used through &mut self, so it is never used by two threads at once
+unsafe impl Send for LibopusDecoder {}
+unsafe impl Sync for LibopusDecoder {}
+
+impl LibopusDecoder {
+	fn new(channels: usize) -> Result<LibopusDecoder> {
+		let libopus = get_libopus().ok_or(Error::Unsupported("opus: libopus is not installed"))?;
+		let mut error = 0;
+		let state = unsafe { (libopus.create)(OPUS_SAMPLE_RATE as i32, channels as i32, &mut error) };
+		match NonNull::new(state) {
+			Some(state) if error == 0 => Ok(LibopusDecoder { libopus, state, channels }),
+			_ => Err(Error::DecodeError("opus: failed to create decoder")),
+		}
+	}
+
+	/// Decodes the packet into interleaved samples. Returns the number of decoded frames.
+	fn decode(&mut self, data: &[u8], samples: &mut [f32]) -> Result<usize> {
+		if data.is_empty() {
+			// libopus treats an empty packet as lost packet
+			return Err(Error::DecodeError("opus: empty packet"));
+		}
+		let frame_size = (samples.len() / self.channels) as i32;
+		let frames = unsafe {
+			(self.libopus.decode_float)(self.state.as_ptr(), data.as_ptr(), data.len() as i32, samples.as_mut_ptr(), frame_size, 0)
+		};
+		// negative results are error codes of libopus
+		usize::try_from(frames).map_err(|_| Error::DecodeError("opus: invalid packet"))
+	}
+}
+
+impl Drop for LibopusDecoder {
+	fn drop(&mut self) {
+		unsafe { (self.libopus.destroy)(self.state.as_ptr()) };
+	}
+}
+
+/// Decodes opus packets from the ogg reader of symphonia with libopus, as symphonia has no opus decoder.
+pub struct OpusDecoder {
+	decoder: LibopusDecoder,
+	params: CodecParameters,
+	/// interleaved samples, as returned by libopus
+	samples: Vec<f32>,
+	buffer: AudioBuffer<f32>,
+}
+
+impl Decoder for OpusDecoder {
+	fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+		let channels = params.channels.unwrap_or(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+		// more channels need the multistream decoder of libopus
+		if channels.count() > 2 {
+			return Err(Error::Unsupported("opus: more than two channels"));
+		}
+		let spec = SignalSpec::new(OPUS_SAMPLE_RATE, channels);
+		Ok(OpusDecoder {
+			decoder: LibopusDecoder::new(channels.count())?,
+			params: params.clone(),
+			samples: vec![0.0; MAX_FRAMES_PER_PACKET * channels.count()],
+			buffer: AudioBuffer::new(MAX_FRAMES_PER_PACKET as u64, spec),
+		})
+	}
+
+	fn supported_codecs() -> &'static [CodecDescriptor] {
+		&[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+	}
+
+	fn reset(&mut self) {
+		if let Ok(decoder) = LibopusDecoder::new(self.decoder.channels) {
+			self.decoder = decoder;
+		}
+	}
+
+	fn codec_params(&self) -> &CodecParameters {
+		&self.params
+	}
+
+	fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+		let frames = self.decoder.decode(packet.buf(), &mut self.samples)?;
+
+		let num_channels = self.decoder.channels;
+		self.buffer.clear();
+		self.buffer.render_reserved(Some(frames));
+		for channel in 0..num_channels {
+			for (frame, sample) in self.buffer.chan_mut(channel).iter_mut().enumerate() {
+				*sample = self.samples[frame * num_channels + channel];
+			}
+		}
+		// removes the pre-skip at the start and the padding at the end of the song
+		self.buffer.trim(packet.trim_start() as usize, packet.trim_end() as usize);
+		Ok(self.buffer.as_audio_buffer_ref())
+	}
+
+	fn finalize(&mut self) -> FinalizeResult {
+		FinalizeResult::default()
+	}
+
+	fn last_decoded(&self) -> AudioBufferRef<'_> {
+		self.buffer.as_audio_buffer_ref()
+	}
+}
@@ -0,0 +1,300 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use rodio::Source;
+use serde::{Serialize, Deserialize};
+
+use crate::audio_backend::device::{DeviceError, open_device_output};
+use crate::audio_backend::receiver_source::ReceiverSource;
+
+/// The sample rate and number of channels of outputs without a sound card.
+const DEFAULT_FORMAT: (u32, u16) = (44100, 2);
+/// The time between two pulls of a pump thread.
+const PUMP_INTERVAL: Duration = Duration::from_millis(10);
+pub const PUMP_THREAD_NAME: &str = "output pump";
+
+/// Something that plays the samples of a ReceiverSource.
+pub trait AudioOutput {
+	/// Starts playing the given source. A source that was started before is dropped.
+	/// Fails, if the thread that plays the source cannot be spawned.
+	fn start(&mut self, source: ReceiverSource) -> io::Result<()>;
+	fn play(&mut self);
+	fn pause(&mut self);
+	fn set_volume(&mut self, volume: f32);
+	fn get_name(&self) -> String;
+	/// Returns the sample rate and number of channels, all songs are converted to.
+	fn get_format(&self) -> (u32, u16);
+}
+
+/// Describes which output should be used by the audio backend.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum OutputConfig {
+	Device(Option<String>), // device name, None for the default device
+	Null(f32), // speed factor: 1.0 is real time, 0.0 consumes samples as fast as possible
+	Wav(PathBuf), // file the rendered stream is written to
+}
+
+impl Default for OutputConfig {
+	fn default() -> Self {
+		OutputConfig::Device(None)
+	}
+}
+
+impl Display for OutputConfig {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			OutputConfig::Device(Some(name)) => write!(f, "{}", name),
+			OutputConfig::Device(None) => write!(f, "<default device>"),
+			OutputConfig::Null(speed) => write!(f, "<null output {}x>", speed),
+			OutputConfig::Wav(path) => write!(f, "<wav file {}>", path.to_string_lossy()),
+		}
+	}
+}
+
+/// Opens the output described by the given config.
+pub fn open_output(output_config: &OutputConfig) -> Result<Box<dyn AudioOutput>, DeviceError> {
+	Ok(match output_config {
+		OutputConfig::Device(device_name) => Box::new(open_device_output(device_name.as_deref())?),
+		OutputConfig::Null(speed) => Box::new(NullOutput::new(*speed)),
+		OutputConfig::Wav(path) => Box::new(WavOutput::new(path.clone())),
+	})
+}
+
+/// Shared state between an output and its pump thread.
+struct PumpControl {
+	paused: AtomicBool,
+	stopped: AtomicBool,
+	volume: AtomicU32, // bits of an f32
+}
+
+impl PumpControl {
+	fn new() -> Arc<PumpControl> {
+		Arc::new(PumpControl {
+			paused: AtomicBool::new(false),
+			stopped: AtomicBool::new(false),
+			volume: AtomicU32::new(1.0f32.to_bits()),
+		})
+	}
+
+	fn get_volume(&self) -> f32 {
+		f32::from_bits(self.volume.load(Ordering::Relaxed))
+	}
+}
+
+/// Drives a source without a sound card. Pulls the samples of the source in the given speed and passes them to
+/// `consume` together with sample rate and number of channels.
+struct Pump {
+	control: Arc<PumpControl>,
+	thread: Option<JoinHandle<()>>,
+}
+
+impl Pump {
+	fn new() -> Pump {
+		Pump {
+			control: PumpControl::new(),
+			thread: None,
+		}
+	}
+
+	fn start<F>(&mut self, mut source: ReceiverSource, speed: f32, mut consume: F) -> io::Result<()>
+		where F: FnMut(&[f32], u32, u16) + Send + 'static
+	{
+		self.stop();
+		let control = PumpControl::new();
+		control.paused.store(self.control.paused.load(Ordering::Relaxed), Ordering::Relaxed);
+		control.volume.store(self.control.volume.load(Ordering::Relaxed), Ordering::Relaxed);
+		self.control = control.clone();
+
+		self.thread = Some(thread::Builder::new().name(PUMP_THREAD_NAME.to_string()).spawn(move || {
+			let mut buffer = Vec::new();
+			let mut reference_time = Instant::now();
+			let mut played_duration = Duration::ZERO;
+			while !control.stopped.load(Ordering::Relaxed) {
+				if control.paused.load(Ordering::Relaxed) {
+					thread::sleep(PUMP_INTERVAL);
+					reference_time = Instant::now();
+					played_duration = Duration::ZERO;
+					continue;
+				}
+				let sample_rate = source.sample_rate();
+				let channels = source.channels();
+				let num_frames = (sample_rate as f64 * PUMP_INTERVAL.as_secs_f64()) as usize;
+				let volume = control.get_volume();
+				buffer.clear();
+				buffer.extend((0..num_frames * channels as usize).map(|_| source.next().unwrap_or(0.0) * volume));
+				consume(&buffer, sample_rate, channels);
+
+				if speed > 0.0 {
+					played_duration += PUMP_INTERVAL.div_f32(speed);
+					if let Some(wait) = played_duration.checked_sub(reference_time.elapsed()) {
+						thread::sleep(wait);
+					}
+				}
+			}
+		})?);
+		Ok(())
+	}
+
+	fn stop(&mut self) {
+		self.control.stopped.store(true, Ordering::Relaxed);
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+impl Drop for Pump {
+	fn drop(&mut self) {
+		self.stop();
+	}
+}
+
+/// An output that discards all samples.
+pub struct NullOutput {
+	pump: Pump,
+	speed: f32,
+}
+
+impl NullOutput {
+	/// Creates a new NullOutput. A speed of 1.0 consumes samples in real time, 0.0 as fast as possible.
+	pub fn new(speed: f32) -> NullOutput {
+		NullOutput {
+			pump: Pump::new(),
+			speed,
+		}
+	}
+}
+
+impl AudioOutput for NullOutput {
+	fn start(&mut self, source: ReceiverSource) -> io::Result<()> {
+		self.pump.start(source, self.speed, |_, _, _| {})
+	}
+
+	fn play(&mut self) {
+		self.pump.control.paused.store(false, Ordering::Relaxed);
+	}
+
+	fn pause(&mut self) {
+		self.pump.control.paused.store(true, Ordering::Relaxed);
+	}
+
+	fn set_volume(&mut self, volume: f32) {
+		self.pump.control.volume.store(volume.to_bits(), Ordering::Relaxed);
+	}
+
+	fn get_name(&self) -> String {
+		OutputConfig::Null(self.speed).to_string()
+	}
+
+	fn get_format(&self) -> (u32, u16) {
+		DEFAULT_FORMAT
+	}
+}
+
+/// An output that writes the rendered stream in real time into a wav file.
+/// The format of the file is defined by the first samples written.
+pub struct WavOutput {
+	pump: Pump,
+	path: PathBuf,
+}
+
+impl WavOutput {
+	pub fn new(path: PathBuf) -> WavOutput {
+		WavOutput {
+			pump: Pump::new(),
+			path,
+		}
+	}
+}
+
+impl AudioOutput for WavOutput {
+	fn start(&mut self, source: ReceiverSource) -> io::Result<()> {
+		let path = self.path.clone();
+		let mut writer = None;
+		self.pump.start(source, 1.0, move |samples, sample_rate, channels| {
+			if writer.is_none() {
+				let spec = hound::WavSpec {
+					channels,
+					sample_rate,
+					bits_per_sample: 32,
+					sample_format: hound::SampleFormat::Float,
+				};
+				writer = hound::WavWriter::create(&path, spec).ok();
+			}
+			if let Some(writer) = &mut writer {
+				for sample in samples {
+					let _ = writer.write_sample(*sample);
+				}
+			}
+		})
+	}
+
+	fn play(&mut self) {
+		self.pump.control.paused.store(false, Ordering::Relaxed);
+	}
+
+	fn pause(&mut self) {
+		self.pump.control.paused.store(true, Ordering::Relaxed);
+	}
+
+	fn set_volume(&mut self, volume: f32) {
+		self.pump.control.volume.store(volume.to_bits(), Ordering::Relaxed);
+	}
+
+	fn get_name(&self) -> String {
+		OutputConfig::Wav(self.path.clone()).to_string()
+	}
+
+	fn get_format(&self) -> (u32, u16) {
+		DEFAULT_FORMAT
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use crossbeam::{bounded, unbounded};
+
+	use super::*;
+	use crate::audio_backend::chunk::{CHUNK_SIZE, SamplesChunk};
+	use crate::audio_backend::receiver_source::SourceControl;
+
+	#[test]
+	fn test_wav_output_writes_samples() {
+		let path = std::env::temp_dir().join(format!("musicus_test_wav_output_{}.wav", std::process::id()));
+		let (chunk_sender, chunk_receiver) = bounded(4);
+		let (update_sender, _update_receiver) = unbounded();
+		for index in 0..2 {
+			chunk_sender.send(SamplesChunk {
+				channels: 2,
+				sample_rate: 44100,
+				start_position: index * CHUNK_SIZE,
+				length: CHUNK_SIZE,
+				data: Arc::new([0.5; CHUNK_SIZE]),
+				song_id: 0,
+				last_chunk: index == 1,
+				generation: 0,
+			}).unwrap();
+		}
+
+		let mut output = WavOutput::new(path.clone());
+		output.start(ReceiverSource::new(chunk_receiver, update_sender, unbounded().0, SourceControl::new())).unwrap();
+		thread::sleep(Duration::from_millis(100));
+		drop(output);
+
+		let mut reader = hound::WavReader::open(&path).unwrap();
+		assert_eq!(reader.spec().channels, 2);
+		assert_eq!(reader.spec().sample_rate, 44100);
+		let samples: Vec<f32> = reader.samples::<f32>().map(|s| s.unwrap()).collect();
+		let _ = std::fs::remove_file(&path);
+		assert!(samples.len() >= 2 * CHUNK_SIZE);
+		assert!(samples[..2 * CHUNK_SIZE].iter().all(|s| *s == 0.5));
+	}
+}
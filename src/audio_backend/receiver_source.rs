@@ -1,37 +1,121 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::time::Duration;
 use crossbeam::{Receiver, Sender};
 use rodio::Source;
 use crate::audio_backend::{AudioBackendCommand, AudioUpdate, PlayingUpdate};
 use crate::audio_backend::chunk::SamplesChunk;
+use crate::song::SongID;
 
 const SOFT_FADEOUT_DECAY: f32 = 0.01;
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+const DEFAULT_CHANNELS: u16 = 2;
+/// The default duration of the volume ramps on pause, unpause, seek and skip.
+pub const DEFAULT_FADE_DURATION: Duration = Duration::from_millis(20);
+
+/// State shared between the audio backend and its source. Lets the source fade out and in on its own, without changing
+/// the volume of the output.
+pub struct SourceControl {
+    /// the source fades out and stops playing chunks
+    paused: AtomicBool,
+    /// chunks of older generations are faded out and dropped, chunks of this generation are faded in
+    generation: AtomicU32,
+    fade_micros: AtomicU32,
+    /// played chunks are copied to the sample tap
+    sample_tap_enabled: AtomicBool,
+    /// the number of times, the source ran out of chunks in the middle of a song
+    underruns: AtomicU32,
+}
+
+impl SourceControl {
+    pub fn new() -> Arc<SourceControl> {
+        Arc::new(SourceControl {
+            paused: AtomicBool::new(false),
+            generation: AtomicU32::new(0),
+            fade_micros: AtomicU32::new(DEFAULT_FADE_DURATION.as_micros() as u32),
+            sample_tap_enabled: AtomicBool::new(false),
+            underruns: AtomicU32::new(0),
+        })
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Chunks, that were sent before, are faded out and dropped. Returns the generation of the following chunks.
+    pub fn next_generation(&self) -> u32 {
+        self.generation.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+    }
+
+    pub fn get_generation(&self) -> u32 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    pub fn set_fade_duration(&self, fade_duration: Duration) {
+        self.fade_micros.store(fade_duration.as_micros().min(u32::MAX as u128) as u32, Ordering::Relaxed);
+    }
+
+    pub fn set_sample_tap_enabled(&self, enabled: bool) {
+        self.sample_tap_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn get_underruns(&self) -> u32 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+}
 
 
 pub struct ReceiverSource {
     chunk_receiver: Receiver<SamplesChunk>, // Receiver of the chunks to play
     update_sender: Sender<AudioBackendCommand>, // Send AudioUpdates to Backend
+    sample_tap: Sender<SamplesChunk>, // Send chunks, when they start playing, e.g. to the visualizer
+    control: Arc<SourceControl>,
     current_chunk: Option<SamplesChunk>,
+    last_song_id: Option<SongID>, // the song of the last received chunk
     samples_counter: usize, // points to the current position in current_chunk
     last_value: f32,
+    silence_counter: usize, // number of silence samples left, until the next chunk is tried to load
+    sample_rate: u32, // format of the last chunk, used for silence
+    channels: u16,
+    generation: u32, // the generation of the played chunks
+    fade_gain: f32, // all samples are multiplied with this gain, it ramps between 0.0 and 1.0
+    faded_out_reported: bool, // the backend was informed, that the source is faded out after pausing
+    expects_chunk: bool, // the last chunk was not the last chunk of its song, so running out of chunks is an underrun
 }
 
 impl ReceiverSource {
-    pub fn new(chunk_receiver: Receiver<SamplesChunk>, update_sender: Sender<AudioBackendCommand>) -> ReceiverSource {
+    pub fn new(
+        chunk_receiver: Receiver<SamplesChunk>, update_sender: Sender<AudioBackendCommand>, sample_tap: Sender<SamplesChunk>,
+        control: Arc<SourceControl>
+    ) -> ReceiverSource {
+        let paused = control.paused.load(Ordering::Relaxed);
         ReceiverSource {
             chunk_receiver,
             update_sender,
+            sample_tap,
+            generation: control.get_generation(),
+            fade_gain: if paused { 0.0 } else { 1.0 },
+            faded_out_reported: false,
+            expects_chunk: false,
+            control,
             current_chunk: None,
+            last_song_id: None,
             samples_counter: 0,
             last_value: 0.0,
+            silence_counter: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            channels: DEFAULT_CHANNELS,
         }
     }
 
+    /// Loads the next chunk. If no chunk is available, one frame of silence is played before trying again.
+    /// Afterwards there is at least one sample left in the current chunk or silence.
     #[inline]
     fn load_next_chunk(&mut self) {
-        match self.chunk_receiver.try_recv() {
-            Ok(chunk) => {
+        match self.receive_chunk() {
+            Some(chunk) => {
                 // check for new song
-                if self.current_chunk.as_ref().map(|c| c.song_id != chunk.song_id).unwrap_or(true) {
+                if self.last_song_id != Some(chunk.song_id) {
                     let _ = self.update_sender.send(
                         AudioBackendCommand::Update(AudioUpdate::SongStarts(
                             chunk.song_id
@@ -49,15 +133,85 @@ impl ReceiverSource {
                     );
                 }
 
-                self.current_chunk = Some(chunk);
+                // try_send never blocks the audio thread. If the tap is full, the chunk is not copied.
+                if self.control.sample_tap_enabled.load(Ordering::Relaxed) {
+                    let _ = self.sample_tap.try_send(chunk.clone());
+                }
+
+                self.last_song_id = Some(chunk.song_id);
+                self.expects_chunk = !chunk.last_chunk;
+                self.sample_rate = chunk.sample_rate;
+                self.channels = chunk.channels;
                 self.samples_counter = 0;
+                if chunk.length == 0 {
+                    self.current_chunk = None;
+                    self.silence_counter = self.channels as usize;
+                } else {
+                    self.current_chunk = Some(chunk);
+                }
             }
-            Err(_) => {
+            None => {
+                // one stall is counted once, not for every frame of silence
+                if self.expects_chunk {
+                    self.control.underruns.fetch_add(1, Ordering::Relaxed);
+                    self.expects_chunk = false;
+                }
                 self.current_chunk = None;
                 self.samples_counter = 0;
+                self.silence_counter = self.channels as usize;
             }
         }
     }
+
+    /// Receives the next chunk, that is not outdated.
+    /// A chunk of the newest generation is played, even if the older chunks are not faded out completely.
+    fn receive_chunk(&mut self) -> Option<SamplesChunk> {
+        let newest_generation = self.control.get_generation();
+        while let Ok(chunk) = self.chunk_receiver.try_recv() {
+            if chunk.generation == newest_generation {
+                self.generation = newest_generation;
+                return Some(chunk);
+            }
+            if chunk.generation == self.generation {
+                return Some(chunk);
+            }
+        }
+        None
+    }
+
+    /// Moves the fade gain towards silence, while paused or outdated chunks are played, otherwise towards full volume.
+    /// Returns true, if the source is faded out and paused.
+    #[inline]
+    fn update_fade(&mut self) -> bool {
+        let paused = self.control.paused.load(Ordering::Relaxed);
+        let outdated = self.generation != self.control.get_generation();
+        let fade_samples = self.control.fade_micros.load(Ordering::Relaxed) as f32 * 1e-6 * self.sample_rate as f32 * self.channels as f32;
+        let step = 1.0 / fade_samples.max(1.0);
+
+        if !paused && !outdated {
+            self.fade_gain = (self.fade_gain + step).min(1.0);
+            self.faded_out_reported = false;
+            return false;
+        }
+        self.fade_gain = (self.fade_gain - step).max(0.0);
+        if self.fade_gain > 0.0 {
+            return false;
+        }
+        if outdated {
+            // the outdated chunks are silent now -> drop them and continue with the newest chunks
+            // waiting for the chunks after a seek or skip is no underrun
+            self.generation = self.control.get_generation();
+            self.expects_chunk = false;
+            self.current_chunk = None;
+            self.samples_counter = 0;
+            self.silence_counter = 0;
+        }
+        if paused && !self.faded_out_reported {
+            let _ = self.update_sender.send(AudioBackendCommand::Update(AudioUpdate::FadedOut));
+            self.faded_out_reported = true;
+        }
+        paused
+    }
 }
 
 impl Iterator for ReceiverSource {
@@ -65,25 +219,26 @@ impl Iterator for ReceiverSource {
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        // try to load next chunk, if no current chunk or current chunk completely played
-        match &self.current_chunk {
-            Some(current_chunk) => {
-                // if the current chunk was completely played
-                if self.samples_counter == current_chunk.data.len() {
-                    self.load_next_chunk();
-                }
-            }
-            None => self.load_next_chunk(),
+        if self.update_fade() {
+            // paused sources do not consume chunks
+            self.last_value = 0.0;
+            return Some(0.0);
+        }
+
+        // the first chunk is loaded lazily
+        if self.current_chunk.is_none() && self.silence_counter == 0 {
+            self.load_next_chunk();
         }
 
         // use value from current chunk or do soft fadeout
         let value = match &self.current_chunk {
             Some(chunk) => {
-                let val = chunk.data[self.samples_counter];
+                let val = chunk.data[self.samples_counter] * self.fade_gain;
                 self.samples_counter += 1;
                 val
             }
             None => {
+                self.silence_counter -= 1;
                 // soft fade out
                 if self.last_value.abs() < SOFT_FADEOUT_DECAY {
                     0.0
@@ -92,23 +247,36 @@ impl Iterator for ReceiverSource {
                 }
             }
         };
-
         self.last_value = value;
+
+        // load the next chunk directly after the last sample of a chunk, so the frame length and format are always up to date
+        let finished = match &self.current_chunk {
+            Some(chunk) => self.samples_counter >= chunk.length,
+            None => self.silence_counter == 0,
+        };
+        if finished {
+            self.load_next_chunk();
+        }
+
         Some(value)
     }
 }
 
 impl Source for ReceiverSource {
+    /// The format can only change between chunks, so the rest of the current chunk is one frame.
     fn current_frame_len(&self) -> Option<usize> {
-        self.current_chunk.as_ref().map(|chunk| chunk.data.len())
+        match &self.current_chunk {
+            Some(chunk) => Some(chunk.length - self.samples_counter),
+            None => Some(self.silence_counter.max(1)),
+        }
     }
 
     fn channels(&self) -> u16 {
-        self.current_chunk.as_ref().map(|chunk| chunk.channels).unwrap_or(2)
+        self.channels
     }
 
     fn sample_rate(&self) -> u32 {
-        self.current_chunk.as_ref().map(|chunk| chunk.sample_rate).unwrap_or(44100)
+        self.sample_rate
     }
 
     fn total_duration(&self) -> Option<Duration> {
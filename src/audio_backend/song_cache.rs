@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+
+use crate::audio_backend::chunk::{CHUNK_SIZE, SamplesChunk};
+use crate::song::SongID;
+
+/// The number of samples from the start of a song, that are cached. Enough to start playback, while the loader catches up.
+pub const CACHED_HEAD_SAMPLES: usize = CHUNK_SIZE * 512;
+/// The memory used by cached samples. If it is exceeded, the least recently used songs are removed.
+const CACHE_BUDGET_BYTES: usize = 64 * 1024 * 1024;
+
+/// The decoded start of a song in the output format.
+struct CachedSong {
+	song_id: SongID,
+	format: (u32, u16),
+	/// contiguous chunks starting at position 0
+	chunks: Vec<SamplesChunk>,
+}
+
+impl CachedSong {
+	fn get_end(&self) -> usize {
+		self.chunks.last().map_or(0, |c| c.start_position + c.length)
+	}
+
+	fn is_complete(&self) -> bool {
+		self.chunks.last().is_some_and(|c| c.last_chunk) || self.get_end() >= CACHED_HEAD_SAMPLES
+	}
+
+	fn get_size(&self) -> usize {
+		self.chunks.len() * CHUNK_SIZE * size_of::<f32>()
+	}
+}
+
+/// Keeps the decoded start of recently played and prefetched songs, so they can be played without waiting for the decoder.
+/// The chunks are shared with the played songs, so caching them does not copy samples.
+pub struct SongCache {
+	/// the least recently used song first
+	songs: VecDeque<CachedSong>,
+	budget: usize,
+	size: usize,
+}
+
+impl SongCache {
+	pub fn new() -> SongCache {
+		SongCache::with_budget(CACHE_BUDGET_BYTES)
+	}
+
+	pub fn with_budget(budget: usize) -> SongCache {
+		SongCache {
+			songs: VecDeque::new(),
+			budget,
+			size: 0,
+		}
+	}
+
+	/// Adds a chunk from a loader. Only chunks, that continue the cached start of their song, are kept.
+	pub fn add_chunk(&mut self, chunk: &SamplesChunk) {
+		let format = (chunk.sample_rate, chunk.channels);
+		let index = self.songs.iter().position(|s| s.song_id == chunk.song_id);
+		let cached_song = match index {
+			Some(index) if self.songs[index].format == format => &mut self.songs[index],
+			_ if chunk.start_position == 0 => {
+				// the song is new or was cached in another output format
+				if let Some(index) = index {
+					self.remove(index);
+				}
+				self.songs.push_back(CachedSong { song_id: chunk.song_id, format, chunks: Vec::new() });
+				self.songs.back_mut().unwrap()
+			}
+			_ => return,
+		};
+		let end = cached_song.get_end();
+		let chunk_end = chunk.start_position + chunk.length;
+		if cached_song.is_complete() || chunk.start_position > end || chunk_end < end || (chunk_end == end && !chunk.last_chunk) {
+			return;
+		}
+		// a loader, that continues a cached start, can start a little before its end
+		cached_song.chunks.push(chunk.starting_at(end));
+		self.size += CHUNK_SIZE * size_of::<f32>();
+		self.shrink();
+	}
+
+	/// Returns the cached chunks of the given song in the given format and marks the song as recently used.
+	pub fn get(&mut self, song_id: SongID, format: (u32, u16)) -> Option<Vec<SamplesChunk>> {
+		let index = self.songs.iter().position(|s| s.song_id == song_id && s.format == format)?;
+		let cached_song = self.songs.remove(index)?;
+		let chunks = cached_song.chunks.clone();
+		self.songs.push_back(cached_song);
+		Some(chunks)
+	}
+
+	/// Returns whether the start of the given song is cached completely.
+	pub fn is_complete(&self, song_id: SongID, format: (u32, u16)) -> bool {
+		self.songs.iter().any(|s| s.song_id == song_id && s.format == format && s.is_complete())
+	}
+
+	pub fn clear(&mut self) {
+		self.songs.clear();
+		self.size = 0;
+	}
+
+	fn remove(&mut self, index: usize) {
+		if let Some(cached_song) = self.songs.remove(index) {
+			self.size -= cached_song.get_size();
+		}
+	}
+
+	/// Removes the least recently used songs, until the budget is kept. The most recent song is never removed.
+	fn shrink(&mut self) {
+		while self.size > self.budget && self.songs.len() > 1 {
+			self.remove(0);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use super::*;
+
+	const FORMAT: (u32, u16) = (44100, 2);
+
+	fn create_chunk(song_id: SongID, start_position: usize, length: usize, last_chunk: bool) -> SamplesChunk {
+		SamplesChunk {
+			channels: FORMAT.1,
+			sample_rate: FORMAT.0,
+			start_position,
+			length,
+			data: Arc::new([start_position as f32; CHUNK_SIZE]),
+			song_id,
+			last_chunk,
+			generation: 0,
+		}
+	}
+
+	#[test]
+	fn test_cache_contiguous_start() {
+		let mut cache = SongCache::new();
+		// chunks after a seek are not cached
+		cache.add_chunk(&create_chunk(0, CHUNK_SIZE * 10, CHUNK_SIZE, false));
+		assert!(cache.get(0, FORMAT).is_none());
+
+		cache.add_chunk(&create_chunk(0, 0, CHUNK_SIZE, false));
+		cache.add_chunk(&create_chunk(0, CHUNK_SIZE * 2, CHUNK_SIZE, false));
+		cache.add_chunk(&create_chunk(0, CHUNK_SIZE / 2, CHUNK_SIZE, false));
+		let chunks = cache.get(0, FORMAT).unwrap();
+		assert_eq!(chunks.len(), 2);
+		assert_eq!(chunks[1].start_position, CHUNK_SIZE);
+		assert_eq!(chunks[1].length, CHUNK_SIZE / 2);
+		assert!(!cache.is_complete(0, FORMAT));
+		assert!(cache.get(0, (48000, 2)).is_none());
+
+		cache.add_chunk(&create_chunk(0, CHUNK_SIZE * 3 / 2, 10, true));
+		assert!(cache.is_complete(0, FORMAT));
+	}
+
+	#[test]
+	fn test_least_recently_used_songs_are_removed() {
+		let chunk_bytes = CHUNK_SIZE * size_of::<f32>();
+		let mut cache = SongCache::with_budget(chunk_bytes * 4);
+		for song_id in 0..3 {
+			cache.add_chunk(&create_chunk(song_id, 0, CHUNK_SIZE, false));
+			cache.add_chunk(&create_chunk(song_id, CHUNK_SIZE, CHUNK_SIZE, false));
+			// song 0 is used again before song 2 is cached
+			if song_id == 1 {
+				cache.get(0, FORMAT);
+			}
+		}
+		assert!(cache.get(0, FORMAT).is_some());
+		assert!(cache.get(1, FORMAT).is_none());
+		assert!(cache.get(2, FORMAT).is_some());
+		assert_eq!(cache.size, chunk_bytes * 4);
+	}
+}
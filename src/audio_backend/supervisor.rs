@@ -0,0 +1,192 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::{Receiver, Sender};
+
+use crate::audio_backend::{AudioBackend, AudioBackendCommand, AudioInfo, SamplesChunk};
+use crate::audio_backend::output::OutputConfig;
+
+/// The backend is not restarted anymore, if it failed this often within RESTART_WINDOW.
+const MAX_RESTARTS: usize = 3;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum BackendError {
+	Panicked(String), // the panic message of the backend thread
+	SpawnFailed(String), // the backend thread could not be started
+}
+
+impl Display for BackendError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			BackendError::Panicked(message) => write!(f, "audio backend panicked: {}", message),
+			BackendError::SpawnFailed(message) => write!(f, "failed to start audio backend: {}", message),
+		}
+	}
+}
+
+/// Spawns the audio backend in a supervised thread. If the backend panics, musicus is informed with
+/// AudioInfo::BackendRestarted and a new backend is started, that receives the following commands.
+/// Musicus has to restore the volume and the played song, as the new backend starts with the given volume and output.
+pub fn spawn_backend(
+	info_sender: Sender<AudioInfo>, audio_backend_sender: Sender<AudioBackendCommand>, audio_backend_receiver: Receiver<AudioBackendCommand>,
+	sample_tap: Sender<SamplesChunk>, volume: f32, output_config: OutputConfig
+) {
+	let supervisor_info_sender = info_sender.clone();
+	let remaining_commands = audio_backend_receiver.clone();
+	thread::Builder::new().name("supervisor".to_string()).spawn(move || {
+		supervise(&supervisor_info_sender, move || {
+			let mut audio_backend = AudioBackend::new(
+				info_sender.clone(), audio_backend_sender.clone(), sample_tap.clone(), volume, output_config.clone()
+			);
+			audio_backend.run(audio_backend_receiver.clone());
+		});
+		// without backend the commands are discarded, so sending them does not fail
+		for _command in remaining_commands.iter() {}
+	}).expect("Failed to spawn supervisor thread");
+}
+
+/// Runs the backend in its own thread and restarts it, whenever it fails.
+/// Returns, if the backend finishes normally or failed too often.
+fn supervise<F: Fn() + Send + Clone + 'static>(info_sender: &Sender<AudioInfo>, run_backend: F) {
+	let mut restart_limiter = RestartLimiter::new();
+	loop {
+		let result = thread::Builder::new()
+			.name("backend".to_string())
+			.spawn(run_backend.clone())
+			.map_err(|e| BackendError::SpawnFailed(e.to_string()))
+			.and_then(|handle| handle.join().map_err(|payload| BackendError::Panicked(get_panic_message(payload))));
+		let error = match result {
+			Ok(()) => return,
+			Err(error) => error,
+		};
+		if restart_limiter.try_restart(Instant::now()) {
+			let _ = info_sender.send(AudioInfo::BackendRestarted(error));
+		} else {
+			let _ = info_sender.send(AudioInfo::BackendStopped(error));
+			return;
+		}
+	}
+}
+
+fn get_panic_message(payload: Box<dyn Any + Send>) -> String {
+	if let Some(message) = payload.downcast_ref::<&str>() {
+		message.to_string()
+	} else if let Some(message) = payload.downcast_ref::<String>() {
+		message.clone()
+	} else {
+		"<unknown panic>".to_string()
+	}
+}
+
+/// Prevents restarting a backend, that fails directly after every restart.
+struct RestartLimiter {
+	restarts: VecDeque<Instant>,
+}
+
+impl RestartLimiter {
+	fn new() -> RestartLimiter {
+		RestartLimiter {
+			restarts: VecDeque::new(),
+		}
+	}
+
+	/// Returns whether a restart at the given time is allowed and counts it.
+	fn try_restart(&mut self, now: Instant) -> bool {
+		while self.restarts.front().is_some_and(|restart| now.duration_since(*restart) >= RESTART_WINDOW) {
+			self.restarts.pop_front();
+		}
+		if self.restarts.len() >= MAX_RESTARTS {
+			return false;
+		}
+		self.restarts.push_back(now);
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use crossbeam::unbounded;
+
+	use super::*;
+	use crate::audio_backend::AudioCommand;
+	use crate::audio_backend::output::NullOutput;
+	use crate::song::song_buffer::SongBuffer;
+
+	#[test]
+	fn test_restart_after_panic() {
+		let (info_sender, info_receiver) = unbounded();
+		let runs = Arc::new(AtomicUsize::new(0));
+		let backend_runs = runs.clone();
+		supervise(&info_sender, move || {
+			if backend_runs.fetch_add(1, Ordering::Relaxed) < 2 {
+				panic!("test panic");
+			}
+		});
+
+		assert_eq!(runs.load(Ordering::Relaxed), 3);
+		let infos: Vec<AudioInfo> = info_receiver.try_iter().collect();
+		assert_eq!(infos.len(), 2);
+		assert!(matches!(&infos[0], AudioInfo::BackendRestarted(BackendError::Panicked(message)) if message == "test panic"));
+	}
+
+	#[test]
+	fn test_stop_after_too_many_restarts() {
+		let (info_sender, info_receiver) = unbounded();
+		supervise(&info_sender, || panic!("{}", "always"));
+
+		let infos: Vec<AudioInfo> = info_receiver.try_iter().collect();
+		assert_eq!(infos.len(), MAX_RESTARTS + 1);
+		assert!(infos[..MAX_RESTARTS].iter().all(|info| matches!(info, AudioInfo::BackendRestarted(_))));
+		assert!(matches!(&infos[MAX_RESTARTS], AudioInfo::BackendStopped(BackendError::Panicked(message)) if message == "always"));
+	}
+
+	#[test]
+	fn test_restore_paused_loop_after_restart() {
+		let (info_sender, _info_receiver) = unbounded();
+		let (command_sender, command_receiver) = unbounded();
+		let (state_sender, state_receiver) = unbounded();
+		let mut song_buffer = SongBuffer::new();
+		let song_id = song_buffer.import(Path::new("a.wav"), None);
+		let song = song_buffer.get(song_id).unwrap().clone();
+		let loop_range = Some((Duration::from_secs(1), Duration::from_secs(2)));
+		// musicus sends the restored song at once, so the new backend receives it as one batch
+		for command in AudioCommand::restore_song(song, 1.0, Duration::from_millis(1500), loop_range, true) {
+			command_sender.send(AudioBackendCommand::Command(command)).unwrap();
+		}
+		drop(command_sender);
+
+		let runs = Arc::new(AtomicUsize::new(0));
+		let backend_info_sender = info_sender.clone();
+		supervise(&info_sender, move || {
+			if runs.fetch_add(1, Ordering::Relaxed) == 0 {
+				panic!("test panic");
+			}
+			let output = Box::new(NullOutput::new(0.0));
+			let mut backend = AudioBackend::with_output(backend_info_sender.clone(), unbounded().0, unbounded().0, 1.0, output, OutputConfig::Null(0.0));
+			backend.run(command_receiver.clone());
+			let current_loop = backend.current_song.as_ref().and_then(|current_song| current_song.loop_range);
+			state_sender.send((backend.paused, current_loop)).unwrap();
+		});
+
+		assert_eq!(state_receiver.try_recv(), Ok((true, loop_range)));
+	}
+
+	#[test]
+	fn test_restarts_are_allowed_again_later() {
+		let mut restart_limiter = RestartLimiter::new();
+		let start = Instant::now();
+		for _ in 0..MAX_RESTARTS {
+			assert!(restart_limiter.try_restart(start));
+		}
+		assert!(!restart_limiter.try_restart(start + Duration::from_secs(1)));
+		assert!(restart_limiter.try_restart(start + RESTART_WINDOW));
+	}
+}
@@ -0,0 +1,355 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use crate::audio_backend::chunk::{CHUNK_SIZE, SamplesChunk};
+use crate::song::SongID;
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 3.0;
+/// The length of a segment in seconds. Segments overlap by half of their length.
+const SEGMENT_DURATION: f32 = 0.03;
+/// The maximal distance in seconds between the ideal and the chosen position of a segment.
+const SEARCH_DURATION: f32 = 0.01;
+/// The first search for the best segment only looks at every n-th frame, afterwards the result is refined.
+const COARSE_STRIDE: usize = 4;
+
+/// Changes the speed of a song without changing its pitch (WSOLA).
+/// Chunks of a song are pushed in playing order. The stretched chunks keep the position of the song, so the play time
+/// of the song can be calculated from them as usual.
+pub struct TimeStretcher {
+	speed: f32,
+	song_id: SongID,
+	sample_rate: u32,
+	channels: usize,
+	/// length of a segment in frames, always even
+	segment_frames: usize,
+	search_frames: usize,
+	/// Hann window over a segment
+	window: Vec<f32>,
+	/// pushed samples, that could still be used by a segment
+	input: Vec<f32>,
+	/// the song frame of the first frame in input
+	input_start_frame: usize,
+	/// the ideal song frame of the next segment
+	analysis_frame: f64,
+	/// the song frame of the last chosen segment
+	previous_frame: Option<usize>,
+	/// the windowed second half of the last segment, that is added to the next segment
+	overlap: Vec<f32>,
+	/// stretched samples, that are not returned as chunk yet
+	output: Vec<f32>,
+	/// the song frame, that corresponds to the first frame in output
+	output_song_frame: f64,
+	/// the song frame after the last frame of the song, if the last chunk was pushed
+	end_frame: Option<usize>,
+}
+
+impl TimeStretcher {
+	pub fn new() -> TimeStretcher {
+		TimeStretcher {
+			speed: 1.0,
+			song_id: 0,
+			sample_rate: 0,
+			channels: 0,
+			segment_frames: 0,
+			search_frames: 0,
+			window: Vec::new(),
+			input: Vec::new(),
+			input_start_frame: 0,
+			analysis_frame: 0.0,
+			previous_frame: None,
+			overlap: Vec::new(),
+			output: Vec::new(),
+			output_song_frame: 0.0,
+			end_frame: None,
+		}
+	}
+
+	/// Returns true, if samples are buffered, that are not returned as chunk yet.
+	pub fn is_active(&self) -> bool {
+		self.channels != 0
+	}
+
+	/// Returns the song, whose samples are buffered.
+	pub fn get_song_id(&self) -> Option<SongID> {
+		if self.is_active() {
+			Some(self.song_id)
+		} else {
+			None
+		}
+	}
+
+	/// Drops all buffered samples. Returns the song position of the first sample, that was not returned as chunk.
+	pub fn reset(&mut self) -> Option<usize> {
+		let position = if self.is_active() {
+			Some(self.output_song_frame as usize * self.channels)
+		} else {
+			None
+		};
+		*self = TimeStretcher::new();
+		position
+	}
+
+	/// Adds the next chunk of the song. Chunks of a new song should only be pushed, after the last chunk of the previous
+	/// song was pushed and all chunks were returned. Otherwise, or if the chunk does not continue the last chunk, the
+	/// buffered samples are dropped.
+	pub fn push(&mut self, chunk: &SamplesChunk, speed: f32) {
+		let continues_input = self.is_active()
+			&& chunk.song_id == self.song_id
+			&& chunk.start_position == self.input_start_frame * self.channels + self.input.len();
+		let skip = if continues_input {
+			self.speed = speed;
+			0
+		} else {
+			self.start(chunk, speed)
+		};
+		self.input.extend_from_slice(&chunk.data[skip.min(chunk.length)..chunk.length]);
+		if chunk.last_chunk {
+			self.end_frame = Some((chunk.start_position + chunk.length) / self.channels);
+		}
+		while self.next_segment_available() {
+			self.add_segment();
+		}
+	}
+
+	/// Starts stretching at the given chunk. Returns the number of samples to skip, so the input starts at a frame.
+	fn start(&mut self, chunk: &SamplesChunk, speed: f32) -> usize {
+		let segment_frames = ((chunk.sample_rate as f32 * SEGMENT_DURATION) as usize).max(2) & !1;
+		let channels = chunk.channels as usize;
+		let skip = (channels - chunk.start_position % channels) % channels;
+		let start_frame = (chunk.start_position + skip) / channels;
+		*self = TimeStretcher {
+			speed,
+			song_id: chunk.song_id,
+			sample_rate: chunk.sample_rate,
+			channels: chunk.channels as usize,
+			segment_frames,
+			search_frames: (chunk.sample_rate as f32 * SEARCH_DURATION) as usize,
+			window: (0..segment_frames).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / segment_frames as f32).cos()).collect(),
+			input_start_frame: start_frame,
+			analysis_frame: start_frame as f64,
+			output_song_frame: start_frame as f64,
+			..TimeStretcher::new()
+		};
+		skip
+	}
+
+	fn get_input_end_frame(&self) -> usize {
+		self.input_start_frame + self.input.len() / self.channels
+	}
+
+	/// Returns the sample of the given song frame and channel. Samples after the end of the song are zero.
+	fn get_sample(&self, frame: usize, channel: usize) -> f32 {
+		frame.checked_sub(self.input_start_frame)
+			.and_then(|f| self.input.get(f * self.channels + channel))
+			.copied()
+			.unwrap_or(0.0)
+	}
+
+	fn next_segment_available(&self) -> bool {
+		let half = self.segment_frames / 2;
+		if let Some(end_frame) = self.end_frame {
+			// the song is finished, when the output reaches the end of the song
+			return self.output_song_frame + (self.output.len() / self.channels) as f64 * self.speed as f64 <= end_frame as f64;
+		}
+		let needed_end = (self.analysis_frame as usize + self.search_frames + self.segment_frames)
+			.max(self.previous_frame.map_or(0, |p| p + 2 * half));
+		needed_end <= self.get_input_end_frame()
+	}
+
+	/// Chooses the segment near the analysis frame, that continues the last segment best, and adds it to the output.
+	fn add_segment(&mut self) {
+		let half = self.segment_frames / 2;
+		let ideal_frame = self.analysis_frame as usize;
+		let frame = match self.previous_frame {
+			Some(previous_frame) => self.find_best_segment(ideal_frame, previous_frame + half),
+			None => ideal_frame,
+		};
+
+		for index in 0..half {
+			for channel in 0..self.channels {
+				let sample = self.get_sample(frame + index, channel);
+				let value = match self.previous_frame {
+					Some(_) => self.overlap[index * self.channels + channel] + sample * self.window[index],
+					None => sample, // the first segment is not faded in
+				};
+				self.output.push(value);
+			}
+		}
+		self.overlap.clear();
+		for index in half..self.segment_frames {
+			for channel in 0..self.channels {
+				self.overlap.push(self.get_sample(frame + index, channel) * self.window[index]);
+			}
+		}
+
+		self.previous_frame = Some(frame);
+		self.analysis_frame += half as f64 * self.speed as f64;
+
+		// drop input, that can not be used anymore
+		let keep_from = (self.analysis_frame as usize).saturating_sub(self.search_frames).min(frame + half);
+		if keep_from > self.input_start_frame {
+			let drop_samples = ((keep_from - self.input_start_frame) * self.channels).min(self.input.len() - self.input.len() % self.channels);
+			self.input.drain(..drop_samples);
+			self.input_start_frame += drop_samples / self.channels;
+		}
+	}
+
+	/// Returns the frame near ideal_frame, whose following frames are most similar to the frames after target_frame.
+	fn find_best_segment(&self, ideal_frame: usize, target_frame: usize) -> usize {
+		let half = self.segment_frames / 2;
+		let min_frame = ideal_frame.saturating_sub(self.search_frames).max(self.input_start_frame);
+		let max_frame = ideal_frame + self.search_frames;
+		let similarity = |frame: usize, stride: usize| -> f32 {
+			let mut correlation = 0.0;
+			let mut energy = 1e-9;
+			for index in (0..half).step_by(stride) {
+				for channel in 0..self.channels {
+					let candidate = self.get_sample(frame + index, channel);
+					correlation += candidate * self.get_sample(target_frame + index, channel);
+					energy += candidate * candidate;
+				}
+			}
+			correlation / energy.sqrt()
+		};
+
+		let best_coarse = (min_frame..=max_frame).step_by(COARSE_STRIDE)
+			.max_by(|a, b| similarity(*a, COARSE_STRIDE).total_cmp(&similarity(*b, COARSE_STRIDE)))
+			.unwrap_or(ideal_frame);
+		let refine_start = best_coarse.saturating_sub(COARSE_STRIDE - 1).max(min_frame);
+		let refine_end = (best_coarse + COARSE_STRIDE - 1).min(max_frame);
+		(refine_start..=refine_end)
+			.max_by(|a, b| similarity(*a, 1).total_cmp(&similarity(*b, 1)))
+			.unwrap_or(best_coarse)
+	}
+
+	/// Returns the number of samples of the next chunk and whether it is the last chunk of the song.
+	/// Returns None, if not enough samples are available.
+	fn get_next_chunk_length(&self) -> Option<(usize, bool)> {
+		if !self.is_active() {
+			return None;
+		}
+		let mut available = self.output.len();
+		let mut last_chunk = false;
+		if let Some(end_frame) = self.end_frame {
+			if !self.next_segment_available() {
+				// only the samples before the end of the song are returned
+				let remaining_frames = ((end_frame as f64 - self.output_song_frame) / self.speed as f64).round().max(0.0) as usize;
+				available = available.min(remaining_frames * self.channels);
+				last_chunk = available <= CHUNK_SIZE;
+			}
+		}
+		if available < CHUNK_SIZE && !last_chunk {
+			return None;
+		}
+		Some((available.min(CHUNK_SIZE), last_chunk))
+	}
+
+	pub fn has_chunk(&self) -> bool {
+		self.get_next_chunk_length().is_some()
+	}
+
+	/// Returns the next stretched chunk, if enough samples are available.
+	pub fn next_chunk(&mut self) -> Option<SamplesChunk> {
+		let (length, last_chunk) = self.get_next_chunk_length()?;
+
+		let mut data = [0.0; CHUNK_SIZE];
+		data[..length].copy_from_slice(&self.output[..length]);
+		self.output.drain(..length);
+		let chunk = SamplesChunk {
+			channels: self.channels as u16,
+			sample_rate: self.sample_rate,
+			start_position: self.output_song_frame as usize * self.channels,
+			length,
+			data: Arc::new(data),
+			song_id: self.song_id,
+			last_chunk,
+			generation: 0,
+		};
+		self.output_song_frame += (length / self.channels) as f64 * self.speed as f64;
+		if last_chunk {
+			*self = TimeStretcher::new();
+		}
+		Some(chunk)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Stretches a stereo sine of the given number of chunks and returns the samples and the stretched chunks.
+	fn stretch_sine(frequency: f32, num_chunks: usize, speed: f32) -> (Vec<f32>, Vec<SamplesChunk>) {
+		let sample_rate = 44100;
+		let mut stretcher = TimeStretcher::new();
+		let mut chunks = Vec::new();
+		for chunk_index in 0..num_chunks {
+			let mut data = [0.0; CHUNK_SIZE];
+			for (index, sample) in data.iter_mut().enumerate() {
+				let frame = (chunk_index * CHUNK_SIZE + index) / 2;
+				*sample = (2.0 * PI * frequency * frame as f32 / sample_rate as f32).sin() * 0.5;
+			}
+			stretcher.push(&SamplesChunk {
+				channels: 2,
+				sample_rate,
+				start_position: chunk_index * CHUNK_SIZE,
+				length: CHUNK_SIZE,
+				data: Arc::new(data),
+				song_id: 1,
+				last_chunk: chunk_index == num_chunks - 1,
+				generation: 0,
+			}, speed);
+			while let Some(chunk) = stretcher.next_chunk() {
+				chunks.push(chunk);
+			}
+		}
+		let samples = chunks.iter().flat_map(|c| c.data[..c.length].to_vec()).collect();
+		(samples, chunks)
+	}
+
+	fn count_zero_crossings(samples: &[f32]) -> usize {
+		samples.chunks_exact(2).collect::<Vec<_>>().windows(2).filter(|w| (w[0][0] < 0.0) != (w[1][0] < 0.0)).count()
+	}
+
+	#[test]
+	fn test_speed_changes_duration_but_not_pitch() {
+		let num_chunks = 200;
+		for speed in [0.5, 1.5, 3.0] {
+			let (samples, chunks) = stretch_sine(441.0, num_chunks, speed);
+			let expected_samples = (num_chunks * CHUNK_SIZE) as f32 / speed;
+			assert!((samples.len() as f32 - expected_samples).abs() < 2.0 * CHUNK_SIZE as f32, "speed {}: {} samples", speed, samples.len());
+			// the frequency stays the same: 441 Hz crosses zero 882 times per second
+			let seconds = samples.len() as f32 / 2.0 / 44100.0;
+			let crossings_per_second = count_zero_crossings(&samples) as f32 / seconds;
+			assert!((crossings_per_second - 882.0).abs() < 20.0, "speed {}: {} crossings per second", speed, crossings_per_second);
+			assert!(chunks.last().unwrap().last_chunk);
+		}
+	}
+
+	#[test]
+	fn test_chunks_keep_song_position() {
+		let (_, chunks) = stretch_sine(441.0, 100, 2.0);
+		// every chunk covers twice its length of the song
+		assert_eq!(chunks[0].start_position, 0);
+		assert_eq!(chunks[10].start_position, 10 * 2 * CHUNK_SIZE);
+	}
+
+	#[test]
+	fn test_reset_returns_first_unreturned_position() {
+		let mut stretcher = TimeStretcher::new();
+		assert_eq!(stretcher.reset(), None);
+		let chunk = SamplesChunk {
+			channels: 2,
+			sample_rate: 44100,
+			start_position: 4 * CHUNK_SIZE,
+			length: CHUNK_SIZE,
+			data: Arc::new([0.0; CHUNK_SIZE]),
+			song_id: 1,
+			last_chunk: false,
+			generation: 0,
+		};
+		stretcher.push(&chunk, 2.0);
+		assert_eq!(stretcher.reset(), Some(4 * CHUNK_SIZE));
+		assert!(!stretcher.is_active());
+	}
+}
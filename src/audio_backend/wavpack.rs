@@ -0,0 +1,978 @@
+use std::io::{self, Seek, SeekFrom};
+
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec};
+use symphonia::core::codecs::{CODEC_TYPE_WAVPACK, CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult};
+use symphonia::core::errors::{Error, Result, SeekErrorKind};
+use symphonia::core::formats::{Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream, ReadBytes};
+use symphonia::core::meta::{Metadata, MetadataLog};
+use symphonia::core::probe::{Descriptor, Instantiate, QueryDescriptor};
+use symphonia::core::units::TimeBase;
+use symphonia::core::{support_codec, support_format};
+
+/// Every block of a WavPack file starts with a header of this size.
+const BLOCK_HEADER_SIZE: usize = 32;
+/// The oldest and newest stream versions, that can be decoded.
+const MIN_VERSION: u16 = 0x402;
+const MAX_VERSION: u16 = 0x410;
+const SAMPLE_RATES: [u32; 15] = [6000, 8000, 9600, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000, 64000, 88200, 96000, 192000];
+
+// flags of the block header
+const BYTES_STORED: u32 = 3;
+const MONO_FLAG: u32 = 0x4;
+const HYBRID_FLAG: u32 = 0x8;
+const JOINT_STEREO: u32 = 0x10;
+const FLOAT_DATA: u32 = 0x80;
+const INITIAL_BLOCK: u32 = 0x800;
+const FINAL_BLOCK: u32 = 0x1000;
+const SHIFT_LSB: u32 = 13;
+const SRATE_LSB: u32 = 23;
+const FALSE_STEREO: u32 = 0x4000_0000;
+const DSD_FLAG: u32 = 0x8000_0000;
+
+// ids of the sub blocks of a block
+const ID_UNIQUE: u8 = 0x3f;
+const ID_ODD_SIZE: u8 = 0x40;
+const ID_LARGE: u8 = 0x80;
+const ID_DECORR_TERMS: u8 = 0x2;
+const ID_DECORR_WEIGHTS: u8 = 0x3;
+const ID_DECORR_SAMPLES: u8 = 0x4;
+const ID_ENTROPY_VARS: u8 = 0x5;
+const ID_INT32_INFO: u8 = 0x9;
+const ID_WV_BITSTREAM: u8 = 0xa;
+const ID_WVX_BITSTREAM: u8 = 0xc;
+const ID_CHANNEL_INFO: u8 = 0xd;
+const ID_SAMPLE_RATE: u8 = 0x27;
+
+/// 2^(i/256) as fraction of 256, used to restore values, that are stored as logarithm.
+const EXP2_TABLE: [u8; 256] = [
+	0x00, 0x01, 0x01, 0x02, 0x03, 0x03, 0x04, 0x05, 0x06, 0x06, 0x07, 0x08, 0x08, 0x09, 0x0a, 0x0b,
+	0x0b, 0x0c, 0x0d, 0x0e, 0x0e, 0x0f, 0x10, 0x10, 0x11, 0x12, 0x13, 0x13, 0x14, 0x15, 0x16, 0x16,
+	0x17, 0x18, 0x19, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1d, 0x1e, 0x1f, 0x20, 0x20, 0x21, 0x22, 0x23,
+	0x24, 0x24, 0x25, 0x26, 0x27, 0x28, 0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x2c, 0x2d, 0x2e, 0x2f, 0x30,
+	0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3a, 0x3b, 0x3c, 0x3d,
+	0x3e, 0x3f, 0x40, 0x41, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x48, 0x49, 0x4a, 0x4b,
+	0x4c, 0x4d, 0x4e, 0x4f, 0x50, 0x51, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a,
+	0x5b, 0x5c, 0x5d, 0x5e, 0x5e, 0x5f, 0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+	0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79,
+	0x7a, 0x7b, 0x7c, 0x7d, 0x7e, 0x7f, 0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x87, 0x88, 0x89, 0x8a,
+	0x8b, 0x8c, 0x8d, 0x8e, 0x8f, 0x90, 0x91, 0x92, 0x93, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b,
+	0x9c, 0x9d, 0x9f, 0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa8, 0xa9, 0xaa, 0xab, 0xac, 0xad,
+	0xaf, 0xb0, 0xb1, 0xb2, 0xb3, 0xb4, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xbc, 0xbd, 0xbe, 0xbf, 0xc0,
+	0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc8, 0xc9, 0xca, 0xcb, 0xcd, 0xce, 0xcf, 0xd0, 0xd2, 0xd3, 0xd4,
+	0xd6, 0xd7, 0xd8, 0xd9, 0xdb, 0xdc, 0xdd, 0xde, 0xe0, 0xe1, 0xe2, 0xe4, 0xe5, 0xe6, 0xe8, 0xe9,
+	0xea, 0xec, 0xed, 0xee, 0xf0, 0xf1, 0xf2, 0xf4, 0xf5, 0xf6, 0xf8, 0xf9, 0xfa, 0xfc, 0xfd, 0xff,
+];
+
+/// The header of a block. A block contains the samples of one or two channels.
+#[derive(Copy, Clone, Debug)]
+struct BlockHeader {
+	/// the number of bytes after the header
+	size: usize,
+	block_index: u64,
+	/// the number of samples of the whole file, if it is known
+	total_samples: Option<u64>,
+	block_samples: u32,
+	flags: u32,
+	crc: u32,
+}
+
+impl BlockHeader {
+	fn parse(bytes: &[u8]) -> Result<BlockHeader> {
+		if bytes.len() < BLOCK_HEADER_SIZE || &bytes[0..4] != b"wvpk" {
+			return Err(Error::DecodeError("wavpack: missing block header"));
+		}
+		let read_u32 = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+		let size = (read_u32(4) as usize).checked_sub(BLOCK_HEADER_SIZE - 8).ok_or(Error::DecodeError("wavpack: invalid block size"))?;
+		let version = u16::from_le_bytes([bytes[8], bytes[9]]);
+		if !(MIN_VERSION..=MAX_VERSION).contains(&version) {
+			return Err(Error::Unsupported("wavpack: stream version"));
+		}
+		let total_samples = match read_u32(12) {
+			u32::MAX => None,
+			// the upper byte is subtracted again, as the lower bytes skip u32::MAX
+			total_samples => Some(((bytes[11] as u64) << 32) + total_samples as u64 - bytes[11] as u64),
+		};
+		Ok(BlockHeader {
+			size,
+			block_index: ((bytes[10] as u64) << 32) | read_u32(16) as u64,
+			total_samples,
+			block_samples: read_u32(20),
+			flags: read_u32(24),
+			crc: read_u32(28),
+		})
+	}
+
+	fn is_mono(&self) -> bool {
+		self.flags & MONO_FLAG != 0
+	}
+
+	fn get_sample_rate(&self) -> Option<u32> {
+		SAMPLE_RATES.get(((self.flags >> SRATE_LSB) & 0xf) as usize).copied()
+	}
+}
+
+/// Returns the sub blocks of the body of a block with their id.
+fn sub_blocks(body: &[u8]) -> impl Iterator<Item=Result<(u8, &[u8])>> {
+	let mut position = 0;
+	std::iter::from_fn(move || {
+		if position + 2 > body.len() {
+			return None;
+		}
+		let id = body[position];
+		let mut num_words = body[position + 1] as usize;
+		position += 2;
+		if id & ID_LARGE != 0 {
+			if position + 2 > body.len() {
+				return Some(Err(Error::DecodeError("wavpack: truncated sub block")));
+			}
+			num_words |= (body[position] as usize) << 8 | (body[position + 1] as usize) << 16;
+			position += 2;
+		}
+		let size = num_words * 2;
+		if position + size > body.len() {
+			position = body.len();
+			return Some(Err(Error::DecodeError("wavpack: truncated sub block")));
+		}
+		let data_size = if id & ID_ODD_SIZE != 0 { size.saturating_sub(1) } else { size };
+		let data = &body[position..position + data_size];
+		position += size;
+		Some(Ok((id & ID_UNIQUE, data)))
+	})
+}
+
+/// Reads the blocks of a WavPack file. Every packet contains the blocks of all channels for the same samples.
+pub struct WavPackReader {
+	reader: MediaSourceStream,
+	tracks: Vec<Track>,
+	cues: Vec<Cue>,
+	metadata: MetadataLog,
+	/// the first sample, the end and the byte position of every packet. It is read, when the file is opened, so seeks
+	/// do not read the file.
+	packet_index: Vec<PacketPosition>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct PacketPosition {
+	block_index: u64,
+	block_end: u64,
+	position: u64,
+}
+
+impl WavPackReader {
+	/// Reads the next block header. Returns None at the end of the blocks, e.g. at the APE tag at the end of the file.
+	fn read_header(&mut self) -> Result<Option<(BlockHeader, [u8; BLOCK_HEADER_SIZE])>> {
+		let mut bytes = [0; BLOCK_HEADER_SIZE];
+		match self.reader.read_buf_exact(&mut bytes) {
+			Ok(()) => {}
+			Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+			Err(e) => return Err(e.into()),
+		}
+		if &bytes[0..4] != b"wvpk" {
+			return Ok(None);
+		}
+		Ok(Some((BlockHeader::parse(&bytes)?, bytes)))
+	}
+
+	/// Reads the headers of all blocks from the current position and returns the positions of the packets.
+	/// The bodies of the blocks are skipped.
+	fn read_packet_index(&mut self) -> Result<Vec<PacketPosition>> {
+		let mut packet_index = Vec::new();
+		loop {
+			let position = self.reader.pos();
+			let header = match self.read_header()? {
+				Some((header, _)) => header,
+				None => return Ok(packet_index),
+			};
+			if header.flags & INITIAL_BLOCK != 0 && header.block_samples > 0 {
+				let block_end = header.block_index + header.block_samples as u64;
+				packet_index.push(PacketPosition { block_index: header.block_index, block_end, position });
+			}
+			self.reader.seek(SeekFrom::Current(header.size as i64))?;
+		}
+	}
+
+	fn end_of_stream() -> Error {
+		Error::IoError(io::Error::new(io::ErrorKind::UnexpectedEof, "end of stream"))
+	}
+}
+
+impl QueryDescriptor for WavPackReader {
+	fn query() -> &'static [Descriptor] {
+		&[support_format!("wavpack", "WavPack", &["wv"], &["audio/x-wavpack"], &[b"wvpk"])]
+	}
+
+	fn score(_context: &[u8]) -> u8 {
+		255
+	}
+}
+
+impl FormatReader for WavPackReader {
+	fn try_new(reader: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+		if !reader.is_seekable() {
+			return Err(Error::Unsupported("wavpack: the source is not seekable"));
+		}
+		let first_block_position = reader.pos();
+		let mut wavpack_reader = WavPackReader {
+			reader,
+			tracks: Vec::new(),
+			cues: Vec::new(),
+			metadata: MetadataLog::default(),
+			packet_index: Vec::new(),
+		};
+
+		// the first block with samples describes the stream
+		let (header, body) = loop {
+			let (header, _) = wavpack_reader.read_header()?.ok_or(Error::DecodeError("wavpack: no audio blocks"))?;
+			let body = wavpack_reader.reader.read_boxed_slice_exact(header.size)?;
+			if header.block_samples > 0 {
+				break (header, body);
+			}
+		};
+		if header.flags & DSD_FLAG != 0 {
+			return Err(Error::Unsupported("wavpack: dsd audio"));
+		}
+		let mut num_channels = if header.is_mono() { 1 } else { 2 };
+		let mut channel_mask = 0;
+		let mut sample_rate = header.get_sample_rate();
+		for sub_block in sub_blocks(&body) {
+			match sub_block? {
+				(ID_CHANNEL_INFO, data) if !data.is_empty() => {
+					num_channels = data[0] as u32;
+					channel_mask = data[1..].iter().rev().fold(0, |mask, byte| (mask << 8) | *byte as u32);
+				}
+				(ID_SAMPLE_RATE, data) if data.len() >= 3 => {
+					sample_rate = Some(data.iter().take(4).rev().fold(0, |rate, byte| (rate << 8) | *byte as u32));
+				}
+				_ => {}
+			}
+		}
+		let sample_rate = sample_rate.ok_or(Error::DecodeError("wavpack: unknown sample rate"))?;
+		if num_channels == 0 || num_channels > 32 {
+			return Err(Error::Unsupported("wavpack: number of channels"));
+		}
+		// the first channels take the positions of the mask, the remaining ones the first unused positions
+		let mut channels = Channels::empty();
+		let positions = (0..32).filter(|position| channel_mask & (1 << position) != 0)
+			.chain((0..32).filter(|position| channel_mask & (1 << position) == 0));
+		for position in positions.take(num_channels as usize) {
+			channels |= Channels::from_bits_truncate(1 << position);
+		}
+
+		let mut codec_params = CodecParameters::new();
+		codec_params
+			.for_codec(CODEC_TYPE_WAVPACK)
+			.with_sample_rate(sample_rate)
+			.with_time_base(TimeBase::new(1, sample_rate))
+			.with_bits_per_sample(((header.flags & BYTES_STORED) + 1) * 8)
+			.with_channels(channels);
+
+		wavpack_reader.reader.seek(SeekFrom::Start(first_block_position))?;
+		wavpack_reader.packet_index = wavpack_reader.read_packet_index()?;
+		// the number of samples is not stored, if the encoder did not know it
+		let last_block_end = wavpack_reader.packet_index.last().map(|packet| packet.block_end);
+		if let Some(total_samples) = header.total_samples.or(last_block_end) {
+			codec_params.with_n_frames(total_samples);
+		}
+		wavpack_reader.tracks.push(Track::new(0, codec_params));
+		wavpack_reader.reader.seek(SeekFrom::Start(first_block_position))?;
+		Ok(wavpack_reader)
+	}
+
+	fn cues(&self) -> &[Cue] {
+		&self.cues
+	}
+
+	fn metadata(&mut self) -> Metadata<'_> {
+		self.metadata.metadata()
+	}
+
+	fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+		let timestamp = match to {
+			SeekTo::TimeStamp { ts, .. } => ts,
+			SeekTo::Time { time, .. } => {
+				let sample_rate = self.tracks[0].codec_params.sample_rate.unwrap_or(1);
+				TimeBase::new(1, sample_rate).calc_timestamp(time)
+			}
+		};
+		let index = self.packet_index.partition_point(|packet| packet.block_end <= timestamp);
+		let packet = *self.packet_index.get(index).ok_or(Error::SeekError(SeekErrorKind::OutOfRange))?;
+		self.reader.seek(SeekFrom::Start(packet.position))?;
+		Ok(SeekedTo { track_id: 0, required_ts: timestamp, actual_ts: packet.block_index })
+	}
+
+	fn tracks(&self) -> &[Track] {
+		&self.tracks
+	}
+
+	fn next_packet(&mut self) -> Result<Packet> {
+		let mut data: Vec<u8> = Vec::new();
+		loop {
+			let (header, header_bytes) = self.read_header()?.ok_or_else(WavPackReader::end_of_stream)?;
+			let body = self.reader.read_boxed_slice_exact(header.size)?;
+			// blocks without samples contain e.g. the checksum of the file
+			if header.block_samples == 0 {
+				continue;
+			}
+			if header.flags & INITIAL_BLOCK != 0 {
+				data.clear();
+			}
+			data.extend_from_slice(&header_bytes);
+			data.extend_from_slice(&body);
+			if header.flags & FINAL_BLOCK != 0 {
+				return Ok(Packet::new_from_boxed_slice(0, header.block_index, header.block_samples as u64, data.into_boxed_slice()));
+			}
+		}
+	}
+
+	fn into_inner(self: Box<Self>) -> MediaSourceStream {
+		self.reader
+	}
+}
+
+/// Reads the bits of the bitstream of a block, starting at the least significant bit of every byte.
+struct BitReader<'a> {
+	data: &'a [u8],
+	position: usize,
+}
+
+impl BitReader<'_> {
+	fn new(data: &[u8]) -> BitReader<'_> {
+		BitReader { data, position: 0 }
+	}
+
+	fn read_bit(&mut self) -> Result<u32> {
+		let byte = self.data.get(self.position / 8).ok_or(Error::DecodeError("wavpack: bitstream ended early"))?;
+		let bit = (byte >> (self.position % 8)) & 1;
+		self.position += 1;
+		Ok(bit as u32)
+	}
+
+	fn read_bits(&mut self, num_bits: u32) -> Result<u32> {
+		let mut value = 0;
+		for index in 0..num_bits {
+			value |= self.read_bit()? << index;
+		}
+		Ok(value)
+	}
+
+	/// Counts the set bits before the next unset bit. At most limit set bits are read.
+	fn read_ones(&mut self, limit: u32) -> Result<u32> {
+		let mut count = 0;
+		while count < limit && self.read_bit()? == 1 {
+			count += 1;
+		}
+		Ok(count)
+	}
+
+	/// Reads a number, that is stored as number of bits (counted by ones) followed by the bits without the highest one.
+	fn read_escaped(&mut self) -> Result<u32> {
+		let num_bits = self.read_ones(33)?;
+		match num_bits {
+			0 | 1 => Ok(num_bits),
+			2..=32 => Ok(self.read_bits(num_bits - 1)? | (1 << (num_bits - 1))),
+			_ => Err(Error::DecodeError("wavpack: invalid escape code")),
+		}
+	}
+
+	/// Reads a value between 0 and max_value, that is stored with fewer bits for small values.
+	fn read_code(&mut self, max_value: u32) -> Result<u32> {
+		if max_value == 0 {
+			return Ok(0);
+		}
+		let num_bits = 31 - max_value.leading_zeros();
+		let extras = ((1u64 << (num_bits + 1)) - max_value as u64 - 1) as u32;
+		let code = self.read_bits(num_bits)?;
+		if code >= extras {
+			Ok((code << 1) - extras + self.read_bit()?)
+		} else {
+			Ok(code)
+		}
+	}
+}
+
+/// Restores a value, that is stored as a 16 bit logarithm.
+fn exp2(value: i16) -> i32 {
+	let magnitude = (value as i32).unsigned_abs();
+	let exponent = magnitude >> 8;
+	if exponent > 31 {
+		return i32::MIN;
+	}
+	let mantissa = EXP2_TABLE[(magnitude & 0xff) as usize] as u32 | 0x100;
+	let result = if exponent > 9 { mantissa << (exponent - 9) } else { mantissa >> (9 - exponent) } as i32;
+	if value < 0 { -result } else { result }
+}
+
+/// Restores a weight of a decorrelation pass, that is stored as a signed byte.
+fn restore_weight(weight: i8) -> i32 {
+	let weight = weight as i32 * 8;
+	if weight > 0 {
+		weight + ((weight + 64) >> 7)
+	} else {
+		weight
+	}
+}
+
+/// Decodes the residuals of the samples with adaptive Golomb codes. The medians of the values of every channel determine the codes.
+struct EntropyDecoder<'a> {
+	bits: BitReader<'a>,
+	medians: [[u32; 3]; 2],
+	/// the number of zeros, that follow in the run of zeros
+	zeros: u32,
+	holding_one: bool,
+	holding_zero: bool,
+}
+
+impl EntropyDecoder<'_> {
+	fn get_median(&self, channel: usize, index: usize) -> u32 {
+		(self.medians[channel][index] >> 4) + 1
+	}
+
+	fn increase_median(&mut self, channel: usize, index: usize) {
+		let divisor = 128 >> index;
+		let median = &mut self.medians[channel][index];
+		*median = median.wrapping_add(median.wrapping_add(divisor) / divisor * 5);
+	}
+
+	fn decrease_median(&mut self, channel: usize, index: usize) {
+		let divisor = 128 >> index;
+		let median = &mut self.medians[channel][index];
+		*median = median.wrapping_sub(median.wrapping_add(divisor - 2) / divisor * 2);
+	}
+
+	fn next_value(&mut self, channel: usize) -> Result<i32> {
+		// runs of zeros are stored as their length, while the medians are small
+		if self.medians[0][0] < 2 && self.medians[1][0] < 2 && !self.holding_zero && !self.holding_one {
+			if self.zeros > 0 {
+				self.zeros -= 1;
+				if self.zeros > 0 {
+					return Ok(0);
+				}
+			} else {
+				self.zeros = self.bits.read_escaped()?;
+				if self.zeros > 0 {
+					self.medians = [[0; 3]; 2];
+					return Ok(0);
+				}
+			}
+		}
+
+		let ones = if self.holding_zero {
+			self.holding_zero = false;
+			0
+		} else {
+			let mut ones = self.bits.read_ones(17)?;
+			match ones {
+				17 => return Err(Error::DecodeError("wavpack: too many ones")),
+				16 => ones += self.bits.read_escaped()?,
+				_ => {}
+			}
+			// the lowest bit of the number of ones is added to the next value
+			let ones_count = if self.holding_one { (ones >> 1) + 1 } else { ones >> 1 };
+			self.holding_one = ones & 1 == 1;
+			self.holding_zero = !self.holding_one;
+			ones_count
+		};
+
+		let (low, range) = match ones {
+			0 => {
+				let range = self.get_median(channel, 0) - 1;
+				self.decrease_median(channel, 0);
+				(0, range)
+			}
+			1 => {
+				let low = self.get_median(channel, 0);
+				let range = self.get_median(channel, 1) - 1;
+				self.increase_median(channel, 0);
+				self.decrease_median(channel, 1);
+				(low, range)
+			}
+			2 => {
+				let low = self.get_median(channel, 0).wrapping_add(self.get_median(channel, 1));
+				let range = self.get_median(channel, 2) - 1;
+				self.increase_median(channel, 0);
+				self.increase_median(channel, 1);
+				self.decrease_median(channel, 2);
+				(low, range)
+			}
+			_ => {
+				let low = self.get_median(channel, 0)
+					.wrapping_add(self.get_median(channel, 1))
+					.wrapping_add(self.get_median(channel, 2).wrapping_mul(ones - 2));
+				let range = self.get_median(channel, 2) - 1;
+				self.increase_median(channel, 0);
+				self.increase_median(channel, 1);
+				self.increase_median(channel, 2);
+				(low, range)
+			}
+		};
+		if range >= 0x200_0000 {
+			return Err(Error::DecodeError("wavpack: invalid median"));
+		}
+		let value = low.wrapping_add(self.bits.read_code(range)?) as i32;
+		Ok(if self.bits.read_bit()? == 1 { !value } else { value })
+	}
+}
+
+/// A pass of the decorrelation filter. Positive terms predict a channel from its previous samples,
+/// negative terms predict the channels of stereo blocks from each other.
+#[derive(Copy, Clone, Default)]
+struct DecorrelationPass {
+	term: i32,
+	delta: i32,
+	weight_a: i32,
+	weight_b: i32,
+	samples_a: [i32; 8],
+	samples_b: [i32; 8],
+}
+
+fn apply_weight(weight: i32, sample: i32) -> i32 {
+	((weight as i64 * sample as i64 + 512) >> 10) as i32
+}
+
+fn update_weight(weight: &mut i32, delta: i32, source: i32, result: i32) {
+	if source != 0 && result != 0 {
+		if (source ^ result) < 0 {
+			*weight -= delta;
+		} else {
+			*weight += delta;
+		}
+	}
+}
+
+fn update_weight_clip(weight: &mut i32, delta: i32, source: i32, result: i32) {
+	if source != 0 && result != 0 {
+		if (source ^ result) < 0 {
+			*weight = (*weight - delta).max(-1024);
+		} else {
+			*weight = (*weight + delta).min(1024);
+		}
+	}
+}
+
+impl DecorrelationPass {
+	/// Returns the prediction of the next sample by the previous ones for terms greater than 8.
+	fn predict(samples: &mut [i32; 8], term: i32) -> i32 {
+		let prediction = if term == 17 {
+			samples[0].wrapping_mul(2).wrapping_sub(samples[1])
+		} else {
+			samples[0].wrapping_mul(3).wrapping_sub(samples[1]) >> 1
+		};
+		samples[1] = samples[0];
+		prediction
+	}
+
+	fn decode_mono(&mut self, residual: i32, position: usize) -> i32 {
+		let (prediction, index) = if self.term > 8 {
+			(DecorrelationPass::predict(&mut self.samples_a, self.term), 0)
+		} else {
+			(self.samples_a[position], (position + self.term as usize) & 7)
+		};
+		let sample = residual.wrapping_add(apply_weight(self.weight_a, prediction));
+		update_weight(&mut self.weight_a, self.delta, prediction, residual);
+		self.samples_a[index] = sample;
+		sample
+	}
+
+	fn decode_stereo(&mut self, left: i32, right: i32, position: usize) -> (i32, i32) {
+		match self.term {
+			1..=8 | 17 | 18 => {
+				let (prediction_a, prediction_b, index) = if self.term > 8 {
+					(DecorrelationPass::predict(&mut self.samples_a, self.term), DecorrelationPass::predict(&mut self.samples_b, self.term), 0)
+				} else {
+					(self.samples_a[position], self.samples_b[position], (position + self.term as usize) & 7)
+				};
+				let sample_a = left.wrapping_add(apply_weight(self.weight_a, prediction_a));
+				let sample_b = right.wrapping_add(apply_weight(self.weight_b, prediction_b));
+				update_weight(&mut self.weight_a, self.delta, prediction_a, left);
+				update_weight(&mut self.weight_b, self.delta, prediction_b, right);
+				self.samples_a[index] = sample_a;
+				self.samples_b[index] = sample_b;
+				(sample_a, sample_b)
+			}
+			-1 => {
+				let sample_a = left.wrapping_add(apply_weight(self.weight_a, self.samples_a[0]));
+				update_weight_clip(&mut self.weight_a, self.delta, self.samples_a[0], left);
+				let sample_b = right.wrapping_add(apply_weight(self.weight_b, sample_a));
+				update_weight_clip(&mut self.weight_b, self.delta, sample_a, right);
+				self.samples_a[0] = sample_b;
+				(sample_a, sample_b)
+			}
+			-2 => {
+				let sample_b = right.wrapping_add(apply_weight(self.weight_b, self.samples_b[0]));
+				update_weight_clip(&mut self.weight_b, self.delta, self.samples_b[0], right);
+				let sample_a = left.wrapping_add(apply_weight(self.weight_a, sample_b));
+				update_weight_clip(&mut self.weight_a, self.delta, sample_b, left);
+				self.samples_b[0] = sample_a;
+				(sample_a, sample_b)
+			}
+			_ => {
+				let sample_a = left.wrapping_add(apply_weight(self.weight_a, self.samples_a[0]));
+				update_weight_clip(&mut self.weight_a, self.delta, self.samples_a[0], left);
+				let sample_b = right.wrapping_add(apply_weight(self.weight_b, self.samples_b[0]));
+				update_weight_clip(&mut self.weight_b, self.delta, self.samples_b[0], right);
+				self.samples_b[0] = sample_a;
+				self.samples_a[0] = sample_b;
+				(sample_a, sample_b)
+			}
+		}
+	}
+}
+
+/// Restores the low bits of samples, that were removed because they were the same in all samples.
+#[derive(Copy, Clone, Default)]
+struct Int32Info {
+	/// the number of low bits, that are stored in the wvx bitstream
+	extra_bits: u32,
+	shift: u32,
+	and: i32,
+	or: i32,
+}
+
+impl Int32Info {
+	fn restore(&self, sample: i32, extra_bits: &mut Option<BitReader<'_>>) -> i32 {
+		let mut sample = sample;
+		if self.extra_bits > 0 {
+			sample = sample.wrapping_shl(self.extra_bits);
+			if let Some(bits) = extra_bits.as_mut() {
+				match bits.read_bits(self.extra_bits) {
+					Ok(value) => sample |= value as i32,
+					Err(_) => *extra_bits = None,
+				}
+			}
+		}
+		let bit = (sample & self.and) | self.or;
+		sample.wrapping_add(bit).wrapping_shl(self.shift).wrapping_sub(bit)
+	}
+}
+
+/// Decodes a block into the samples of its one or two channels. The samples keep the bits per sample of the file.
+fn decode_block(header: &BlockHeader, body: &[u8], channels: &mut [Vec<i32>]) -> Result<()> {
+	if header.flags & (HYBRID_FLAG | FLOAT_DATA | DSD_FLAG) != 0 {
+		return Err(Error::Unsupported("wavpack: only lossless integer audio is supported"));
+	}
+	let stereo = !header.is_mono();
+	let stereo_data = stereo && header.flags & FALSE_STEREO == 0;
+	let num_channels = if stereo { 2 } else { 1 };
+	if channels.len() != num_channels {
+		return Err(Error::DecodeError("wavpack: unexpected number of channels"));
+	}
+
+	let mut passes: Vec<DecorrelationPass> = Vec::new();
+	let mut medians = [[0; 3]; 2];
+	let mut int32_info = Int32Info::default();
+	let mut bitstream = None;
+	let mut extra_bitstream = None;
+	for sub_block in sub_blocks(body) {
+		let (id, data) = sub_block?;
+		match id {
+			// the passes are stored in the reverse order of decoding, so are their weights and samples
+			ID_DECORR_TERMS => {
+				passes = data.iter().rev().map(|byte| DecorrelationPass {
+					term: (byte & 0x1f) as i32 - 5,
+					delta: (byte >> 5) as i32,
+					..Default::default()
+				}).collect();
+				if !passes.iter().all(|pass| matches!(pass.term, 1..=8 | 17 | 18) || (stereo_data && matches!(pass.term, -3..=-1))) {
+					return Err(Error::DecodeError("wavpack: invalid decorrelation term"));
+				}
+			}
+			ID_DECORR_WEIGHTS => {
+				let weights_per_pass = if stereo_data { 2 } else { 1 };
+				if data.len() / weights_per_pass > passes.len() {
+					return Err(Error::DecodeError("wavpack: too many decorrelation weights"));
+				}
+				for (pass, weights) in passes.iter_mut().rev().zip(data.chunks_exact(weights_per_pass)) {
+					pass.weight_a = restore_weight(weights[0] as i8);
+					if stereo_data {
+						pass.weight_b = restore_weight(weights[1] as i8);
+					}
+				}
+			}
+			ID_DECORR_SAMPLES => {
+				let mut values = data.chunks_exact(2).map(|bytes| exp2(i16::from_le_bytes([bytes[0], bytes[1]])));
+				for pass in passes.iter_mut().rev() {
+					// the samples of the second channel follow the samples of the first channel for terms greater than 8,
+					// otherwise they are interleaved
+					let positions: Vec<(bool, usize)> = match pass.term {
+						17 | 18 if stereo_data => vec![(false, 0), (false, 1), (true, 0), (true, 1)],
+						17 | 18 => vec![(false, 0), (false, 1)],
+						1..=8 => (0..pass.term as usize)
+							.flat_map(|index| [(false, index), (true, index)])
+							.filter(|(channel_b, _)| stereo_data || !channel_b)
+							.collect(),
+						_ => vec![(false, 0), (true, 0)],
+					};
+					for (channel_b, index) in positions {
+						let value = match values.next() {
+							Some(value) => value,
+							None => break,
+						};
+						if channel_b {
+							pass.samples_b[index] = value;
+						} else {
+							pass.samples_a[index] = value;
+						}
+					}
+				}
+			}
+			ID_ENTROPY_VARS => {
+				let num_medians = if stereo_data { 6 } else { 3 };
+				if data.len() < num_medians * 2 {
+					return Err(Error::DecodeError("wavpack: missing entropy variables"));
+				}
+				for (index, bytes) in data.chunks_exact(2).take(num_medians).enumerate() {
+					medians[index / 3][index % 3] = exp2(i16::from_le_bytes([bytes[0], bytes[1]])) as u32;
+				}
+			}
+			ID_INT32_INFO if data.len() >= 4 => {
+				let (sent_bits, zeros, ones, duplicates) = (data[0] as u32, data[1] as u32, data[2] as u32, data[3] as u32);
+				if sent_bits > 0 {
+					int32_info.extra_bits = sent_bits;
+				} else if zeros > 0 {
+					int32_info.shift = zeros;
+				} else if ones > 0 {
+					int32_info = Int32Info { shift: ones, and: 1, or: 1, ..int32_info };
+				} else if duplicates > 0 {
+					int32_info = Int32Info { shift: duplicates, and: 1, ..int32_info };
+				}
+			}
+			ID_WV_BITSTREAM => bitstream = Some(data),
+			// the checksum of the extra bits comes first
+			ID_WVX_BITSTREAM if data.len() > 4 => extra_bitstream = Some(BitReader::new(&data[4..])),
+			_ => {}
+		}
+	}
+	if int32_info.extra_bits > 32 || int32_info.shift > 32 {
+		return Err(Error::DecodeError("wavpack: invalid int32 info"));
+	}
+
+	let mut entropy = EntropyDecoder {
+		bits: BitReader::new(bitstream.ok_or(Error::DecodeError("wavpack: missing bitstream"))?),
+		medians,
+		zeros: 0,
+		holding_one: false,
+		holding_zero: false,
+	};
+	let shift = (header.flags >> SHIFT_LSB) & 0x1f;
+	let mut crc: u32 = 0xffff_ffff;
+	for sample_index in 0..header.block_samples as usize {
+		let position = sample_index & 7;
+		if stereo_data {
+			let mut left = entropy.next_value(0)?;
+			let mut right = entropy.next_value(1)?;
+			for pass in passes.iter_mut() {
+				(left, right) = pass.decode_stereo(left, right, position);
+			}
+			if header.flags & JOINT_STEREO != 0 {
+				right = right.wrapping_sub(left >> 1);
+				left = left.wrapping_add(right);
+			}
+			crc = crc.wrapping_mul(3).wrapping_add(left as u32).wrapping_mul(3).wrapping_add(right as u32);
+			channels[0].push(int32_info.restore(left, &mut extra_bitstream).wrapping_shl(shift));
+			channels[1].push(int32_info.restore(right, &mut extra_bitstream).wrapping_shl(shift));
+		} else {
+			let mut sample = entropy.next_value(0)?;
+			for pass in passes.iter_mut() {
+				sample = pass.decode_mono(sample, position);
+			}
+			crc = crc.wrapping_mul(3).wrapping_add(sample as u32);
+			let sample = int32_info.restore(sample, &mut extra_bitstream).wrapping_shl(shift);
+			// the channels of false stereo blocks are the same
+			for channel in channels.iter_mut() {
+				channel.push(sample);
+			}
+		}
+	}
+	if crc != header.crc {
+		return Err(Error::DecodeError("wavpack: checksum mismatch"));
+	}
+	Ok(())
+}
+
+/// Decodes the packets of the WavPack reader. Only lossless integer audio is supported, which most WavPack files are.
+pub struct WavPackDecoder {
+	params: CodecParameters,
+	buffer: AudioBuffer<f32>,
+	/// the decoded samples of the channels of the current block
+	block_channels: Vec<Vec<i32>>,
+}
+
+impl Decoder for WavPackDecoder {
+	fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+		let sample_rate = params.sample_rate.ok_or(Error::DecodeError("wavpack: unknown sample rate"))?;
+		let channels = params.channels.ok_or(Error::DecodeError("wavpack: unknown channels"))?;
+		Ok(WavPackDecoder {
+			params: params.clone(),
+			buffer: AudioBuffer::new(0, SignalSpec::new(sample_rate, channels)),
+			block_channels: Vec::new(),
+		})
+	}
+
+	fn supported_codecs() -> &'static [CodecDescriptor] {
+		&[support_codec!(CODEC_TYPE_WAVPACK, "wavpack", "WavPack")]
+	}
+
+	fn reset(&mut self) {}
+
+	fn codec_params(&self) -> &CodecParameters {
+		&self.params
+	}
+
+	fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+		let data = packet.buf();
+		let first_header = BlockHeader::parse(data)?;
+		let num_frames = first_header.block_samples as usize;
+		if self.buffer.capacity() < num_frames {
+			self.buffer = AudioBuffer::new(num_frames as u64, *self.buffer.spec());
+		}
+		self.buffer.clear();
+		self.buffer.render_reserved(Some(num_frames));
+		let num_channels = self.buffer.spec().channels.count();
+
+		let mut position = 0;
+		let mut channel = 0;
+		while position < data.len() {
+			let header = BlockHeader::parse(&data[position..])?;
+			let body_start = position + BLOCK_HEADER_SIZE;
+			let body = data.get(body_start..body_start + header.size).ok_or(Error::DecodeError("wavpack: truncated block"))?;
+			position = body_start + header.size;
+			if header.block_samples as usize != num_frames {
+				return Err(Error::DecodeError("wavpack: blocks of different length"));
+			}
+			let block_num_channels = if header.is_mono() { 1 } else { 2 };
+			if channel + block_num_channels > num_channels {
+				return Err(Error::DecodeError("wavpack: too many channels"));
+			}
+			self.block_channels.resize_with(block_num_channels, Vec::new);
+			self.block_channels.truncate(block_num_channels);
+			self.block_channels.iter_mut().for_each(|samples| samples.clear());
+			decode_block(&header, body, &mut self.block_channels)?;
+
+			let scale = 1.0 / (1u64 << (((header.flags & BYTES_STORED) + 1) * 8 - 1)) as f64;
+			for samples in &self.block_channels {
+				for (output, sample) in self.buffer.chan_mut(channel).iter_mut().zip(samples) {
+					*output = (*sample as f64 * scale) as f32;
+				}
+				channel += 1;
+			}
+		}
+		Ok(self.buffer.as_audio_buffer_ref())
+	}
+
+	fn finalize(&mut self) -> FinalizeResult {
+		FinalizeResult::default()
+	}
+
+	fn last_decoded(&self) -> AudioBufferRef<'_> {
+		self.buffer.as_audio_buffer_ref()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+
+	const MONO_16_BIT: u32 = 1 | MONO_FLAG | INITIAL_BLOCK | FINAL_BLOCK | (9 << SRATE_LSB);
+
+	fn sub_block(id: u8, data: &[u8]) -> Vec<u8> {
+		let odd_size = if data.len() % 2 == 1 { ID_ODD_SIZE } else { 0 };
+		let mut bytes = vec![id | odd_size, data.len().div_ceil(2) as u8];
+		bytes.extend_from_slice(data);
+		bytes.resize(2 + data.len().div_ceil(2) * 2, 0);
+		bytes
+	}
+
+	fn block(block_index: u32, block_samples: u32, flags: u32, crc: u32, sub_blocks: &[Vec<u8>]) -> Vec<u8> {
+		let body = sub_blocks.concat();
+		let mut bytes = b"wvpk".to_vec();
+		bytes.extend_from_slice(&(body.len() as u32 + 24).to_le_bytes());
+		bytes.extend_from_slice(&0x410u16.to_le_bytes());
+		bytes.extend_from_slice(&[0, 0]);
+		bytes.extend_from_slice(&8u32.to_le_bytes());
+		bytes.extend_from_slice(&block_index.to_le_bytes());
+		bytes.extend_from_slice(&block_samples.to_le_bytes());
+		bytes.extend_from_slice(&flags.to_le_bytes());
+		bytes.extend_from_slice(&crc.to_le_bytes());
+		bytes.extend_from_slice(&body);
+		bytes
+	}
+
+	/// A block of four silent samples, that are stored as run of zeros.
+	fn silent_block(block_index: u32) -> Vec<u8> {
+		let crc = 0xffff_ffffu32.wrapping_mul(81);
+		block(block_index, 4, MONO_16_BIT, crc, &[sub_block(ID_WV_BITSTREAM, &[0x07])])
+	}
+
+	fn decode(bytes: &[u8]) -> Result<Vec<i32>> {
+		let header = BlockHeader::parse(bytes)?;
+		let mut channels = vec![Vec::new()];
+		decode_block(&header, &bytes[BLOCK_HEADER_SIZE..], &mut channels)?;
+		Ok(channels.remove(0))
+	}
+
+	#[test]
+	fn test_decode_zero_run() {
+		assert_eq!(decode(&silent_block(0)).unwrap(), vec![0, 0, 0, 0]);
+	}
+
+	#[test]
+	fn test_decode_decorrelation() {
+		let sub_blocks = [
+			// term 1 with delta 2 and weight 516
+			sub_block(ID_DECORR_TERMS, &[0x46]),
+			sub_block(ID_DECORR_WEIGHTS, &[64]),
+			sub_block(ID_DECORR_SAMPLES, &[0, 0]),
+			// all medians are 128
+			sub_block(ID_ENTROPY_VARS, &[0, 8, 0, 8, 0, 8]),
+			// the residuals 5 and -4
+			sub_block(ID_WV_BITSTREAM, &[0xaa, 0x01]),
+		];
+		let crc = 0xffff_ffffu32.wrapping_mul(3).wrapping_add(5).wrapping_mul(3).wrapping_sub(1);
+		assert_eq!(decode(&block(0, 2, MONO_16_BIT, crc, &sub_blocks)).unwrap(), vec![5, -1]);
+		assert!(decode(&block(0, 2, MONO_16_BIT, crc + 1, &sub_blocks)).is_err());
+	}
+
+	#[test]
+	fn test_read_and_seek() {
+		let mut file = [silent_block(0), silent_block(4)].concat();
+		file.extend_from_slice(b"APETAGEX");
+		let source = MediaSourceStream::new(Box::new(Cursor::new(file)), Default::default());
+		let mut reader = WavPackReader::try_new(source, &FormatOptions::default()).unwrap();
+		let codec_params = reader.tracks()[0].codec_params.clone();
+		assert_eq!(codec_params.sample_rate, Some(44100));
+		assert_eq!(codec_params.n_frames, Some(8));
+		assert_eq!(codec_params.channels.map(|channels| channels.count()), Some(1));
+
+		let mut decoder = WavPackDecoder::try_new(&codec_params, &DecoderOptions::default()).unwrap();
+		assert_eq!(reader.next_packet().unwrap().ts(), 0);
+		let packet = reader.next_packet().unwrap();
+		assert_eq!(packet.ts(), 4);
+		assert_eq!(decoder.decode(&packet).unwrap().frames(), 4);
+		assert!(reader.next_packet().is_err());
+
+		let seeked_to = reader.seek(SeekMode::Accurate, SeekTo::TimeStamp { ts: 5, track_id: 0 }).unwrap();
+		assert_eq!((seeked_to.required_ts, seeked_to.actual_ts), (5, 4));
+		assert_eq!(reader.next_packet().unwrap().ts(), 4);
+		let seeked_to = reader.seek(SeekMode::Accurate, SeekTo::TimeStamp { ts: 3, track_id: 0 }).unwrap();
+		assert_eq!(seeked_to.actual_ts, 0);
+		assert_eq!(reader.next_packet().unwrap().ts(), 0);
+		assert!(reader.seek(SeekMode::Accurate, SeekTo::TimeStamp { ts: 8, track_id: 0 }).is_err());
+	}
+
+	#[test]
+	fn test_packet_index() {
+		let mut file = [silent_block(0), silent_block(4)].concat();
+		// the number of samples is unknown, if the encoder read from a pipe
+		file[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+		let source = MediaSourceStream::new(Box::new(Cursor::new(file)), Default::default());
+		let reader = WavPackReader::try_new(source, &FormatOptions::default()).unwrap();
+		assert_eq!(reader.packet_index, vec![
+			PacketPosition { block_index: 0, block_end: 4, position: 0 },
+			PacketPosition { block_index: 4, block_end: 8, position: silent_block(0).len() as u64 },
+		]);
+		assert_eq!(reader.tracks()[0].codec_params.n_frames, Some(8));
+	}
+}
@@ -0,0 +1,67 @@
+use crate::render::{RenderObject, RenderPanel, RenderEntry, RenderColor, Alignment, format_duration};
+use crate::song::{Bookmark, Song};
+
+/// Lists the bookmarks of the current song and lets the user jump to one of them.
+pub struct BookmarkManager {
+	cursor_position: usize,
+	scroll_position: usize,
+}
+
+impl BookmarkManager {
+	pub fn new() -> BookmarkManager {
+		BookmarkManager {
+			cursor_position: 0,
+			scroll_position: 0,
+		}
+	}
+
+	pub fn move_down(&mut self, num_bookmarks: usize, num_rows: usize) {
+		if self.cursor_position + 1 < num_bookmarks {
+			self.cursor_position += 1;
+			self.scroll_position = self.scroll_position.max((self.cursor_position + 1).saturating_sub(num_rows));
+		}
+	}
+
+	pub fn move_up(&mut self) {
+		self.cursor_position = self.cursor_position.saturating_sub(1);
+		self.scroll_position = self.scroll_position.min(self.cursor_position);
+	}
+
+	/// Keeps the cursor on a bookmark, after the song changed or a bookmark was removed.
+	pub fn clamp_cursor(&mut self, num_bookmarks: usize) {
+		self.cursor_position = self.cursor_position.min(num_bookmarks.saturating_sub(1));
+		self.scroll_position = self.scroll_position.min(self.cursor_position);
+	}
+
+	pub fn get_cursor_position(&self) -> usize {
+		self.cursor_position
+	}
+
+	pub fn get_selected_bookmark<'a>(&self, song: Option<&'a Song>) -> Option<&'a Bookmark> {
+		song.and_then(|song| song.get_bookmarks().get(self.cursor_position))
+	}
+
+	pub fn get_render_object(&self, song: Option<&Song>, prefer_tag_titles: bool) -> RenderObject {
+		let mut render_object = RenderObject::new(Alignment::Left);
+		let bookmarks = song.map_or(&[][..], |song| song.get_bookmarks());
+
+		let mut render_panel = RenderPanel::new(self.scroll_position);
+		match song {
+			None => render_panel.entries.push(RenderEntry::new("<no song>".to_string(), RenderColor::White, RenderColor::Black)),
+			Some(song) if bookmarks.is_empty() => {
+				let text = format!("no bookmarks in \"{}\"", song.get_display_title(prefer_tag_titles));
+				render_panel.entries.push(RenderEntry::new(text, RenderColor::White, RenderColor::Black));
+			}
+			Some(_) => {
+				for (index, bookmark) in bookmarks.iter().enumerate() {
+					let background_color = if index == self.cursor_position { RenderColor::Blue } else { RenderColor::Black };
+					let text = format!("{:>8}  {}", format_duration(bookmark.position), bookmark.name);
+					render_panel.entries.push(RenderEntry::new(text, RenderColor::White, background_color));
+				}
+			}
+		}
+		render_object.panels.push(render_panel);
+
+		render_object
+	}
+}
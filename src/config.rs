@@ -1,14 +1,18 @@
 use std::path::PathBuf;
-use crate::file_manager::file_utils::{create_dir, get_dir_entries};
+use crate::file_manager::file_utils::{create_dir, get_dir_entries, write_json_atomically};
 use crate::playlist_manager::PlaylistView;
 use serde::{Serialize, Deserialize};
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter};
+use std::fs::File;
+use std::io::{self, BufReader};
 use std::env::current_dir;
 use crate::musicus::ViewState;
 use crate::song::playlist::{Playlist, PlaylistID};
 use std::collections::HashMap;
 use crate::play_state::PlayMode;
+use crate::audio_backend::output::OutputConfig;
+use crate::audio_backend::loudness::ReplayGainMode;
+use crate::audio_backend::equalizer::EqualizerPreset;
+use crate::audio_backend::DEFAULT_FADE_DURATION;
 
 pub fn get_config_directory() -> PathBuf {
 	dirs::config_dir().unwrap().join("musicus")
@@ -26,18 +30,33 @@ pub fn get_song_buffer_path() -> PathBuf {
 	get_config_directory().join("lib.json")
 }
 
+/// Older versions of lib.json, the cache and the playlists are kept here.
+pub fn get_backup_directory() -> PathBuf {
+	get_config_directory().join("backups")
+}
+
+pub fn get_equalizer_path() -> PathBuf {
+	get_config_directory().join("equalizer.json")
+}
+
 pub fn init_config() {
 	create_dir(&get_config_directory());
 	create_dir(&get_playlist_directory());
 }
 
+#[cfg_attr(not(feature = "sqlite"), allow(unused))]
 pub fn load_playlists() -> Vec<Playlist> {
+	load_playlist_files().into_iter().map(|(_, playlist)| playlist).collect()
+}
+
+/// Loads the playlists together with the files, they were loaded from.
+pub fn load_playlist_files() -> Vec<(PathBuf, Playlist)> {
 	let playlists_directory = get_playlist_directory();
 	let mut playlists = Vec::new();
 	for entry in get_dir_entries(&playlists_directory) {
 		if entry.is_file {
 			if let Ok(playlist) = Playlist::from_file(&entry.path) {
-				playlists.push(playlist);
+				playlists.push((entry.path, playlist));
 			}
 		}
 	}
@@ -52,6 +71,30 @@ pub struct Cache {
 	pub playlist_manager_cache: PlaylistManagerCache,
 	pub volume: i32,
 	pub follow: bool,
+	#[serde(default)]
+	pub output: OutputConfig,
+	#[serde(default)]
+	pub replay_gain_mode: ReplayGainMode,
+	/// the volume is faded out over this number of seconds, before the sleep timer stops playback
+	#[serde(default = "default_sleep_fade")]
+	pub sleep_fade: u64,
+	/// the duration of the volume ramps on pause, unpause, seek and skip in milliseconds
+	#[serde(default = "default_fade_duration")]
+	pub fade_duration: u64,
+	/// show the titles of the song tags instead of the titles derived from the filenames
+	#[serde(default)]
+	pub prefer_tag_titles: bool,
+	/// the directories, that are scanned and watched for songs
+	#[serde(default)]
+	pub music_roots: Vec<PathBuf>,
+}
+
+fn default_sleep_fade() -> u64 {
+	30
+}
+
+fn default_fade_duration() -> u64 {
+	DEFAULT_FADE_DURATION.as_millis() as u64
 }
 
 #[derive(Serialize, Deserialize)]
@@ -83,15 +126,8 @@ impl Cache {
 		}
 	}
 
-	pub fn dump(&self) {
-		let file = OpenOptions::new()
-			.write(true)
-			.truncate(true)
-			.create(true)
-			.open(get_cache_path())
-			.unwrap();
-		let writer = BufWriter::new(file);
-		serde_json::to_writer_pretty(writer, &self).unwrap();
+	pub fn dump(&self) -> io::Result<()> {
+		write_json_atomically(&get_cache_path(), self)
 	}
 
 	pub fn default() -> Cache {
@@ -109,6 +145,47 @@ impl Cache {
 			},
 			volume: 100,
 			follow: true,
+			output: OutputConfig::default(),
+			replay_gain_mode: ReplayGainMode::default(),
+			sleep_fade: default_sleep_fade(),
+			fade_duration: default_fade_duration(),
+			prefer_tag_titles: false,
+			music_roots: Vec::new(),
 		}
 	}
-}
\ No newline at end of file
+}
+
+/// The equalizer presets and which preset is used for which output device.
+#[derive(Serialize, Deserialize)]
+pub struct EqualizerConfig {
+	pub enabled: bool,
+	pub presets: Vec<EqualizerPreset>,
+	/// maps the name of an output device to the name of its preset. Devices without entry use the first preset.
+	#[serde(default)]
+	pub device_presets: HashMap<String, String>,
+}
+
+impl EqualizerConfig {
+	pub fn load() -> Result<EqualizerConfig, ()> {
+		let equalizer_path = get_equalizer_path();
+		if equalizer_path.is_file() {
+			let file = File::open(equalizer_path).map_err(|_| ())?;
+			let reader = BufReader::new(file);
+			serde_json::from_reader(reader).map_err(|_| ())
+		} else {
+			Ok(EqualizerConfig::default())
+		}
+	}
+
+	pub fn dump(&self) -> io::Result<()> {
+		write_json_atomically(&get_equalizer_path(), self)
+	}
+
+	pub fn default() -> EqualizerConfig {
+		EqualizerConfig {
+			enabled: false,
+			presets: EqualizerPreset::defaults(),
+			device_presets: HashMap::new(),
+		}
+	}
+}
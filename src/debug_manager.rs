@@ -1,9 +1,21 @@
+use std::sync::{Mutex, PoisonError};
+
+use crate::audio_backend::health::BackendHealth;
 use crate::render::{RenderObject, RenderPanel, RenderEntry, RenderColor, Alignment};
 
+/// Panic messages of threads other than the ui thread, that are not yet shown in the debug view.
+static PANIC_MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Keeps the panic message of a thread, until the debug manager shows it. Can be called from any thread.
+pub fn log_panic(message: String) {
+    PANIC_MESSAGES.lock().unwrap_or_else(PoisonError::into_inner).push(message);
+}
+
 pub struct DebugManager {
     entries: Vec<Entry>,
     has_update: bool,
     scroll_position: usize,
+    health: Option<BackendHealth>, // the last health report of the audio backend
 }
 
 struct Entry {
@@ -18,6 +30,25 @@ impl DebugManager {
             entries: Vec::new(),
             has_update: false,
             scroll_position: 0,
+            health: None,
+        }
+    }
+
+    /// Shows the given health of the audio backend. New underruns are logged together with the health, they happened with.
+    pub fn set_health(&mut self, health: BackendHealth) {
+        let old_underruns = self.health.as_ref().map_or(0, |h| h.underruns);
+        if health.underruns > old_underruns {
+            self.add_error_entry(format!("audio underrun: {}", health));
+        }
+        self.health = Some(health);
+        self.has_update = true;
+    }
+
+    /// Shows the panic messages of other threads, that were logged since the last call.
+    pub fn show_panics(&mut self) {
+        let messages = std::mem::take(&mut *PANIC_MESSAGES.lock().unwrap_or_else(PoisonError::into_inner));
+        for message in messages {
+            self.add_error_entry(message);
         }
     }
 
@@ -44,6 +75,9 @@ impl DebugManager {
 
     pub fn get_render_object(&self) -> RenderObject {
         let mut render_object = RenderObject::new(Alignment::Left);
+        if let Some(health) = &self.health {
+            render_object.panels.push(Self::get_health_panel(health));
+        }
         let mut render_panel = RenderPanel::new(0);
         for entry in &self.entries {
             let render_entry = RenderEntry::new(entry.text.clone(), entry.foreground_color, entry.background_color);
@@ -58,6 +92,27 @@ impl DebugManager {
         render_object
     }
 
+    fn get_health_panel(health: &BackendHealth) -> RenderPanel {
+        let mut lines = vec![format!("underruns: {}", health.underruns)];
+        if let Some(decode_speed) = health.decode_speed {
+            lines.push(format!("decode:    {:.1}x realtime", decode_speed));
+        }
+        match health.min_queue_depth {
+            Some(min_queue_depth) => lines.push(format!("queue:     {} (min {})", health.queue_depth, min_queue_depth)),
+            None => lines.push(format!("queue:     {}", health.queue_depth)),
+        }
+        if let (Some(latency), Some(max_latency)) = (health.command_latency, health.max_command_latency) {
+            lines.push(format!("latency:   {:.2} ms (max {:.2} ms)", latency.as_secs_f64() * 1000.0, max_latency.as_secs_f64() * 1000.0));
+        }
+
+        let mut panel = RenderPanel::new(0);
+        let color = if health.underruns > 0 { RenderColor::Yellow } else { RenderColor::White };
+        for line in lines {
+            panel.entries.push(RenderEntry::new(line, color, RenderColor::Black));
+        }
+        panel
+    }
+
     pub fn has_update(&mut self) -> bool {
         let hu = self.has_update;
         self.has_update = false;
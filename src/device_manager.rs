@@ -0,0 +1,67 @@
+use crate::audio_backend::output::OutputConfig;
+use crate::render::{RenderObject, RenderPanel, RenderEntry, RenderColor, Alignment};
+
+/// Lists the available outputs and lets the user pick one.
+pub struct DeviceManager {
+	outputs: Vec<OutputConfig>,
+	cursor_position: usize,
+	scroll_position: usize,
+	/// the output that is currently used by the audio backend
+	pub current_output: Option<OutputConfig>,
+	/// the output the user selected. The backend falls back to another output, if it cannot be opened.
+	pub configured_output: OutputConfig,
+}
+
+impl DeviceManager {
+	pub fn new(configured_output: OutputConfig) -> DeviceManager {
+		DeviceManager {
+			outputs: vec![OutputConfig::Null(1.0)],
+			cursor_position: 0,
+			scroll_position: 0,
+			current_output: None,
+			configured_output,
+		}
+	}
+
+	/// Sets the available devices. The null output is always listed after the devices.
+	pub fn set_devices(&mut self, devices: Vec<String>) {
+		self.outputs = devices.into_iter().map(|d| OutputConfig::Device(Some(d))).collect();
+		self.outputs.push(OutputConfig::Null(1.0));
+		self.cursor_position = self.cursor_position.min(self.outputs.len().saturating_sub(1));
+		self.scroll_position = self.scroll_position.min(self.cursor_position);
+	}
+
+	pub fn move_down(&mut self, num_rows: usize) {
+		if self.cursor_position + 1 < self.outputs.len() {
+			self.cursor_position += 1;
+			self.scroll_position = self.scroll_position.max((self.cursor_position + 1).saturating_sub(num_rows));
+		}
+	}
+
+	pub fn move_up(&mut self) {
+		self.cursor_position = self.cursor_position.saturating_sub(1);
+		self.scroll_position = self.scroll_position.min(self.cursor_position);
+	}
+
+	pub fn get_selected_output(&self) -> Option<&OutputConfig> {
+		self.outputs.get(self.cursor_position)
+	}
+
+	pub fn get_render_object(&self) -> RenderObject {
+		let mut render_object = RenderObject::new(Alignment::Left);
+		let mut render_panel = RenderPanel::new(self.scroll_position);
+		for (index, output) in self.outputs.iter().enumerate() {
+			let is_current = self.current_output.as_ref() == Some(output);
+			let (foreground_color, background_color) = match (is_current, index == self.cursor_position) {
+				(true, true) => (RenderColor::Yellow, RenderColor::Blue),
+				(true, false) => (RenderColor::Yellow, RenderColor::Black),
+				(false, true) => (RenderColor::White, RenderColor::Blue),
+				(false, false) => (RenderColor::White, RenderColor::Black),
+			};
+			render_panel.entries.push(RenderEntry::new(output.to_string(), foreground_color, background_color));
+		}
+		render_object.panels.push(render_panel);
+
+		render_object
+	}
+}
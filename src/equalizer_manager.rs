@@ -0,0 +1,132 @@
+use crate::audio_backend::equalizer::{EqualizerPreset, format_band_frequency, MAX_BAND_GAIN, NUM_BANDS};
+use crate::config::EqualizerConfig;
+use crate::render::{RenderObject, RenderPanel, RenderEntry, RenderColor, Alignment};
+
+/// The number of characters of a band bar for MAX_BAND_GAIN.
+const BAR_WIDTH: usize = 12;
+
+/// Lists the equalizer presets, shows the bands of the selected preset and remembers the preset of every device.
+pub struct EqualizerManager {
+	pub config: EqualizerConfig,
+	cursor_position: usize,
+	scroll_position: usize,
+	band_cursor: usize,
+	/// the name of the output device, that is currently used
+	current_device: Option<String>,
+}
+
+impl EqualizerManager {
+	pub fn new(mut config: EqualizerConfig) -> EqualizerManager {
+		if config.presets.is_empty() {
+			config.presets = EqualizerPreset::defaults();
+		}
+		EqualizerManager {
+			config,
+			cursor_position: 0,
+			scroll_position: 0,
+			band_cursor: 0,
+			current_device: None,
+		}
+	}
+
+	pub fn set_current_device(&mut self, device_name: String) {
+		self.current_device = Some(device_name);
+	}
+
+	pub fn toggle_enabled(&mut self) {
+		self.config.enabled = !self.config.enabled;
+	}
+
+	/// Returns the preset of the current device or None, if the equalizer is disabled.
+	pub fn get_active_preset(&self) -> Option<&EqualizerPreset> {
+		if !self.config.enabled {
+			return None;
+		}
+		self.config.presets.get(self.get_active_preset_index())
+	}
+
+	fn get_active_preset_index(&self) -> usize {
+		self.current_device.as_ref()
+			.and_then(|device| self.config.device_presets.get(device))
+			.and_then(|preset_name| self.config.presets.iter().position(|p| &p.name == preset_name))
+			.unwrap_or(0)
+	}
+
+	/// Uses the selected preset for the current device.
+	pub fn select_preset(&mut self) {
+		if let (Some(device), Some(preset)) = (&self.current_device, self.config.presets.get(self.cursor_position)) {
+			self.config.device_presets.insert(device.clone(), preset.name.clone());
+		}
+	}
+
+	/// Changes the gain of the selected band of the selected preset.
+	pub fn change_gain(&mut self, delta: f32) {
+		if let Some(preset) = self.config.presets.get_mut(self.cursor_position) {
+			preset.change_gain(self.band_cursor, delta);
+		}
+	}
+
+	pub fn move_down(&mut self, num_rows: usize) {
+		if self.cursor_position + 1 < self.config.presets.len() {
+			self.cursor_position += 1;
+			self.scroll_position = self.scroll_position.max((self.cursor_position + 1).saturating_sub(num_rows));
+		}
+	}
+
+	pub fn move_up(&mut self) {
+		self.cursor_position = self.cursor_position.saturating_sub(1);
+		self.scroll_position = self.scroll_position.min(self.cursor_position);
+	}
+
+	pub fn move_left(&mut self) {
+		self.band_cursor = self.band_cursor.saturating_sub(1);
+	}
+
+	pub fn move_right(&mut self) {
+		self.band_cursor = (self.band_cursor + 1).min(NUM_BANDS - 1);
+	}
+
+	pub fn get_render_object(&self) -> RenderObject {
+		let mut render_object = RenderObject::new(Alignment::Left);
+
+		let mut presets_panel = RenderPanel::new(self.scroll_position);
+		let active_preset_index = self.get_active_preset_index();
+		for (index, preset) in self.config.presets.iter().enumerate() {
+			let is_active = index == active_preset_index;
+			let (foreground_color, background_color) = match (is_active, index == self.cursor_position) {
+				(true, true) => (RenderColor::Yellow, RenderColor::Blue),
+				(true, false) => (RenderColor::Yellow, RenderColor::Black),
+				(false, true) => (RenderColor::White, RenderColor::Blue),
+				(false, false) => (RenderColor::White, RenderColor::Black),
+			};
+			presets_panel.entries.push(RenderEntry::new(preset.name.clone(), foreground_color, background_color));
+		}
+		render_object.panels.push(presets_panel);
+
+		let mut bands_panel = RenderPanel::new(0);
+		let state = if self.config.enabled { "on" } else { "off" };
+		let device = self.current_device.as_deref().unwrap_or("<no device>");
+		bands_panel.entries.push(RenderEntry::new(format!("equalizer {} for {}", state, device), RenderColor::White, RenderColor::Black));
+		if let Some(preset) = self.config.presets.get(self.cursor_position) {
+			bands_panel.entries.push(RenderEntry::new(format!("{:>5}  {:+5.1} dB", "pre", preset.preamp), RenderColor::White, RenderColor::Black));
+			for (band, gain) in preset.gains.iter().enumerate() {
+				let background_color = if band == self.band_cursor { RenderColor::Blue } else { RenderColor::Black };
+				let text = format!("{:>5}  {:+5.1} dB  {}", format_band_frequency(band), gain, render_bar(*gain));
+				bands_panel.entries.push(RenderEntry::new(text, RenderColor::White, background_color));
+			}
+		}
+		render_object.panels.push(bands_panel);
+
+		render_object
+	}
+}
+
+/// Renders a gain as bar, that grows left or right of the center.
+fn render_bar(gain: f32) -> String {
+	let length = ((gain.abs() / MAX_BAND_GAIN) * BAR_WIDTH as f32).round() as usize;
+	if gain < 0.0 {
+		format!("{:>width$}|{:width$}", "=".repeat(length), "", width = BAR_WIDTH)
+	} else {
+		format!("{:>width$}|{:<width$}", "", "=".repeat(length), width = BAR_WIDTH)
+	}
+}
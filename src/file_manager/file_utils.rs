@@ -1,6 +1,9 @@
 use std::path::{Path, PathBuf};
-use std::fs::DirEntry;
+use std::fs::{DirEntry, File};
 use std::fs;
+use std::io::{self, BufWriter, Write};
+use serde::Serialize;
+use crate::audio_backend::format::AudioFormat;
 
 pub fn get_dir_entries(path: &Path) -> Vec<DirectoryEntry> {
 	let mut entries = Vec::new();
@@ -32,8 +35,9 @@ pub struct DirectoryEntry {
 }
 
 impl DirectoryEntry {
+	/// Returns whether this file is in a known audio format. Songs in unsupported formats are included and fail to open.
 	pub fn is_song_file(&self) -> bool {
-		self.is_file && (self.filename.ends_with(".wav") || self.filename.ends_with(".mp3") || self.filename.ends_with(".ogg"))
+		self.is_file && AudioFormat::guess(&self.path).is_some()
 	}
 }
 
@@ -95,6 +99,18 @@ pub fn create_dir(path: &Path) {
 	}
 }
 
+/// Writes the value as JSON to a hidden temporary file next to path and renames it to path afterwards.
+/// So path always contains a complete file, even if musicus crashes while writing.
+pub fn write_json_atomically<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+	let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+	let temp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+	let mut writer = BufWriter::new(File::create(&temp_path)?);
+	serde_json::to_writer_pretty(&mut writer, value)?;
+	writer.flush()?;
+	writer.get_ref().sync_all()?;
+	fs::rename(&temp_path, path)
+}
+
 pub fn normalize_dir(path: &mut PathBuf) {
 	while !path.is_dir() {
 		path.pop();
@@ -105,6 +121,19 @@ pub fn normalize_dir(path: &mut PathBuf) {
 mod tests {
 	use super::*;
 
+	#[test]
+	fn test_write_json_atomically() {
+		let path = std::env::temp_dir().join(format!("musicus_test_atomic_{}.json", std::process::id()));
+		fs::write(&path, "old content").unwrap();
+		write_json_atomically(&path, &vec![1, 2]).unwrap();
+		let content = fs::read_to_string(&path).unwrap();
+		let temp_exists = path.with_file_name(format!(".{}.tmp", path.file_name().unwrap().to_string_lossy())).exists();
+		let _ = fs::remove_file(&path);
+
+		assert_eq!(serde_json::from_str::<Vec<i32>>(&content).unwrap(), vec![1, 2]);
+		assert!(!temp_exists);
+	}
+
 	#[test]
 	fn test_common_ends() {
 		let a = "startINBETWEENend";
@@ -0,0 +1,63 @@
+use crate::render::{RenderObject, RenderPanel, RenderEntry, RenderColor, Alignment};
+use crate::song::SongID;
+use crate::song::song_buffer::SongBuffer;
+
+/// Lists the songs, whose file is missing, e.g. after a music drive was mounted somewhere else.
+pub struct LibraryManager {
+	missing_songs: Vec<SongID>,
+	cursor_position: usize,
+	scroll_position: usize,
+}
+
+impl LibraryManager {
+	pub fn new() -> LibraryManager {
+		LibraryManager {
+			missing_songs: Vec::new(),
+			cursor_position: 0,
+			scroll_position: 0,
+		}
+	}
+
+	/// Searches the songs, whose file does not exist anymore.
+	pub fn refresh(&mut self, song_buffer: &SongBuffer) {
+		self.missing_songs = song_buffer.get_missing_songs();
+		self.cursor_position = self.cursor_position.min(self.missing_songs.len().saturating_sub(1));
+		self.scroll_position = self.scroll_position.min(self.cursor_position);
+	}
+
+	pub fn move_down(&mut self, num_rows: usize) {
+		if self.cursor_position + 1 < self.missing_songs.len() {
+			self.cursor_position += 1;
+			self.scroll_position = self.scroll_position.max((self.cursor_position + 1).saturating_sub(num_rows));
+		}
+	}
+
+	pub fn move_up(&mut self) {
+		self.cursor_position = self.cursor_position.saturating_sub(1);
+		self.scroll_position = self.scroll_position.min(self.cursor_position);
+	}
+
+	pub fn get_missing_songs(&self) -> &[SongID] {
+		&self.missing_songs
+	}
+
+	pub fn get_selected_song(&self) -> Option<SongID> {
+		self.missing_songs.get(self.cursor_position).copied()
+	}
+
+	pub fn get_render_object(&self, song_buffer: &SongBuffer, prefer_tag_titles: bool) -> RenderObject {
+		let mut render_object = RenderObject::new(Alignment::Left);
+		let mut render_panel = RenderPanel::new(self.scroll_position);
+		if self.missing_songs.is_empty() {
+			render_panel.entries.push(RenderEntry::new("<no missing songs>".to_string(), RenderColor::White, RenderColor::Black));
+		}
+		for (index, song) in self.missing_songs.iter().filter_map(|song_id| song_buffer.get(*song_id)).enumerate() {
+			let background_color = if index == self.cursor_position { RenderColor::Blue } else { RenderColor::Black };
+			let text = format!("{}  ({})", song.get_display_title(prefer_tag_titles), song.get_path().display());
+			render_panel.entries.push(RenderEntry::new(text, RenderColor::White, background_color));
+		}
+		render_object.panels.push(render_panel);
+
+		render_object
+	}
+}
@@ -1,3 +1,8 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::thread;
+
 use crate::musicus::Musicus;
 
 mod musicus;
@@ -8,11 +13,54 @@ mod playlist_manager;
 mod config;
 mod play_state;
 mod debug_manager;
+mod device_manager;
+mod equalizer_manager;
+mod bookmark_manager;
+mod library_manager;
+mod visualizer_manager;
 mod song;
+mod storage;
 mod string_helpers;
 
 fn main() {
+	install_panic_hook();
 	let mut musicus = Musicus::new();
-	musicus.run();
+	if panic::catch_unwind(AssertUnwindSafe(|| musicus.run())).is_err() {
+		// save the songs and playlists, that changed since the last save
+		musicus.shutdown();
+		process::exit(101);
+	}
+}
+
+/// Restores the terminal, before the panic message of the ui thread is printed. Otherwise curses would hide the message.
+/// Panics of other threads, like the audio backend, do not end musicus. Their messages are shown in the debug view,
+/// as printing them would break the curses screen.
+fn install_panic_hook() {
+	let default_hook = panic::take_hook();
+	panic::set_hook(Box::new(move |info| {
+		let current_thread = thread::current();
+		match current_thread.name() {
+			Some("main") => {
+				pancurses::endwin();
+				default_hook(info);
+			}
+			thread_name => {
+				let location = info.location().map_or(String::new(), |location| format!(" at {}", location));
+				debug_manager::log_panic(format!(
+					"thread '{}' panicked{}: {}", thread_name.unwrap_or("<unnamed>"), location, get_panic_message(info.payload())
+				));
+			}
+		}
+	}));
+}
+
+fn get_panic_message(payload: &dyn Any) -> &str {
+	if let Some(message) = payload.downcast_ref::<&str>() {
+		message
+	} else if let Some(message) = payload.downcast_ref::<String>() {
+		message
+	} else {
+		"<unknown panic>"
+	}
 }
 
@@ -1,25 +1,53 @@
-use crate::audio_backend::{AudioBackend, AudioCommand, AudioInfo, SeekCommand, SeekDirection, AudioBackendCommand};
+use crate::audio_backend::{AudioCommand, AudioInfo, SeekCommand, SeekDirection, SeekPosition, AudioBackendCommand};
+use crate::audio_backend::loudness::ReplayGainMode;
+use crate::audio_backend::supervisor::spawn_backend;
 use crate::file_manager::FileManager;
 use crate::render::{RenderObject, Renderable, RenderColor, RenderPanel, format_duration, Alignment};
 use pancurses::{Window, Input};
 use std::fs::OpenOptions;
 use std::io::Write;
-use std::collections::HashMap;
-use crossbeam::{unbounded, Sender, Receiver};
-use std::thread;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use crossbeam::{bounded, unbounded, Sender, Receiver};
 use crate::playlist_manager::PlaylistManager;
-use crate::config::{load_playlists, init_config, get_playlist_directory, Cache, FileManagerCache};
+use crate::config::{init_config, Cache, EqualizerConfig, FileManagerCache};
 use serde::{Serialize, Deserialize};
-use std::time::Duration;
-use crate::play_state::{PlayPosition, PlayState, PlayMode};
+use std::time::{Duration, Instant};
+use crate::play_state::{PlayPosition, PlayState, PlayMode, PlayStatus};
 use crate::debug_manager::DebugManager;
-use crate::song::{Song, SongID};
+use crate::device_manager::DeviceManager;
+use crate::equalizer_manager::EqualizerManager;
+use crate::bookmark_manager::BookmarkManager;
+use crate::library_manager::LibraryManager;
+use crate::visualizer_manager::{SAMPLE_TAP_SIZE, VisualizerManager};
+use crate::song::{Bookmark, Song, SongID};
 use crate::song::song_buffer::SongBuffer;
+use crate::song::tags::TagScanner;
+use crate::song::library_scanner::{LibraryEvent, LibraryScanner};
+use crate::song::fingerprint::Fingerprint;
+use crate::storage::Storage;
+use crate::song::cue::is_cue_file;
 use crate::string_helpers::{cut_str_left, limit_str_right};
 
 const FILE_BROWSER_OFFSET: i32 = 5;
 const ENTER_CHAR: char = 10 as char;
+const ESCAPE_CHAR: char = 27 as char;
+const BACKSPACE_CHAR: char = 127 as char;
 const CURSES_TIMEOUT: i32 = 200;
+/// the visualizer is updated more often
+const VISUALIZER_TIMEOUT: i32 = 40;
+/// the crossfade durations in seconds, that can be selected by pressing 'x'
+const CROSSFADE_STEPS: [u64; 4] = [0, 2, 5, 10];
+/// the playback speeds, that can be selected by pressing '<' and '>'
+const SPEED_STEPS: [f32; 9] = [0.5, 0.75, 1.0, 1.25, 1.5, 1.75, 2.0, 2.5, 3.0];
+/// the durations of the sleep timer in minutes, that can be selected by pressing 'z'
+const SLEEP_TIMER_STEPS: [u64; 4] = [15, 30, 60, 90];
+/// the number of upcoming songs, whose start is decoded in advance
+const PREFETCH_SONGS: usize = 3;
+/// changes of songs, playlists and the cache are written this often
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+/// the maximum number of broken songs, that are skipped to find the next song. Shuffling can choose broken songs again.
+const MAX_SKIPPED_SONGS: usize = 100;
 
 pub struct Musicus {
     command_sender: Sender<AudioBackendCommand>,
@@ -27,7 +55,17 @@ pub struct Musicus {
 	file_manager: FileManager,
 	playlist_manager: PlaylistManager,
 	debug_manager: DebugManager,
+	device_manager: DeviceManager,
+	equalizer_manager: EqualizerManager,
+	bookmark_manager: BookmarkManager,
+	library_manager: LibraryManager,
+	visualizer_manager: VisualizerManager,
 	pub song_buffer: SongBuffer,
+	storage: Storage,
+	last_save: Instant,
+	tag_scanner: TagScanner,
+	library_scanner: LibraryScanner,
+	music_roots: Vec<PathBuf>,
 	window: Window,
 	color_pairs: HashMap<(RenderColor, RenderColor), i16>,
 	color_pair_counter: i16,
@@ -35,16 +73,67 @@ pub struct Musicus {
 	view_state: ViewState,
 	playing_song_info: Option<SongInfo>,
 	volume: i32,
+	crossfade: Duration,
+	replay_gain_mode: ReplayGainMode,
+	sleep_timer: Option<SleepTimer>,
+	sleep_fade: Duration,
+	fade_duration: Duration,
+	/// the volume is multiplied with this factor, while the sleep timer fades out
+	sleep_volume_factor: f32,
 	follow: bool,
+	prefer_tag_titles: bool,
 	screen_dimensions: (i32, i32), // height, width
 	clipboard: Option<SongID>,
+	prompt: Option<Prompt>,
 }
 
 struct SongInfo {
+	song_id: SongID,
 	title: String,
 	play_position: Duration,
 	total_duration: Duration,
 	queued_next: bool,
+	/// the start (A) and end (B) of the loop in this song
+	loop_start: Option<Duration>,
+	loop_end: Option<Duration>,
+}
+
+/// Stops playback after some time. The volume is faded out before.
+#[derive(Copy, Clone, Debug)]
+enum SleepTimer {
+	Time(usize, Instant), // index in SLEEP_TIMER_STEPS, the time playback is paused
+	EndOfSong,
+	EndOfPlaylist,
+}
+
+/// A line of text, that is entered in the status bar.
+enum Prompt {
+	Seek(String), // the text entered after pressing 'g'
+	Bookmark(Duration, String), // position of the new bookmark, the name entered after pressing 'm'
+	RewriteFrom(String), // the path prefix, that is replaced after pressing 'w' in the library view
+	RewriteTo(PathBuf, String), // the replaced path prefix, the new prefix
+}
+
+impl Prompt {
+	fn get_text_mut(&mut self) -> &mut String {
+		match self {
+			Prompt::Seek(text) => text,
+			Prompt::Bookmark(_, text) => text,
+			Prompt::RewriteFrom(text) => text,
+			Prompt::RewriteTo(_, text) => text,
+		}
+	}
+}
+
+impl SongInfo {
+	/// Limits the given play position to the duration of the song, if the duration is known.
+	fn clamp_play_position(&self, play_position: Duration) -> Duration {
+		if self.total_duration.is_zero() {
+			play_position
+		} else {
+			play_position.min(self.total_duration)
+		}
+	}
 }
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
@@ -52,6 +141,11 @@ pub enum ViewState {
 	FileManager,
 	Playlists,
 	Debug,
+	Devices,
+	Equalizer,
+	Bookmarks,
+	Visualizer,
+	Library,
 }
 
 impl Musicus {
@@ -61,20 +155,32 @@ impl Musicus {
 
 		let mut debug_manager = DebugManager::new();
 
-		let cache = if let Ok(cache) = Cache::load() {
+		let (mut storage, storage_error) = Storage::open();
+		if let Some(storage_error) = storage_error {
+			debug_manager.add_error_entry(storage_error);
+		}
+
+		let cache = if let Ok(cache) = storage.load_cache() {
 			cache
 		} else {
 			debug_manager.add_entry_color("Failed to load cache. Using default.".to_string(), RenderColor::Red, RenderColor::Black);
 			Cache::default()
 		};
 
-		let song_buffer = if let Ok(song_buffer) = SongBuffer::load() {
+		let song_buffer = if let Ok(song_buffer) = storage.load_song_buffer() {
 			song_buffer
 		} else {
 			debug_manager.add_entry_color("Failed to load song buffer. Using empty.".to_string(), RenderColor::Red, RenderColor::Black);
 			SongBuffer::new()
 		};
 
+		let equalizer_config = if let Ok(equalizer_config) = EqualizerConfig::load() {
+			equalizer_config
+		} else {
+			debug_manager.add_entry_color("Failed to load equalizer presets. Using default.".to_string(), RenderColor::Red, RenderColor::Black);
+			EqualizerConfig::default()
+		};
+
 		// setup curses
 		let window = pancurses::initscr();
 		Musicus::init_curses(&window);
@@ -82,27 +188,50 @@ impl Musicus {
 		// setup audio backend
 		let (audio_backend_sender, audio_backend_receiver) = unbounded();
         let (info_sender, info_receiver) = unbounded();
-
-		let audio_backend_sender_clone = audio_backend_sender.clone();
+		let (sample_tap_sender, sample_tap_receiver) = bounded(SAMPLE_TAP_SIZE);
 
 		let backend_volume = cache.volume as f32 * 0.01;
+		spawn_backend(info_sender, audio_backend_sender.clone(), audio_backend_receiver, sample_tap_sender, backend_volume, cache.output.clone());
 
-		thread::Builder::new().name("backend".to_string()).spawn(move || {
-			let mut audio_backend = AudioBackend::new(info_sender, audio_backend_sender_clone, backend_volume);
-			audio_backend.run(audio_backend_receiver);
-		}).expect("Failed to spawn backend thread");
+		audio_backend_sender.send(AudioBackendCommand::Command(AudioCommand::SetReplayGainMode(cache.replay_gain_mode))).unwrap();
+		audio_backend_sender.send(AudioBackendCommand::Command(AudioCommand::SetFadeDuration(Duration::from_millis(cache.fade_duration)))).unwrap();
 
 		// load playlists
-		let playlists = load_playlists();
+		let playlists = storage.load_playlists().unwrap_or_else(|_| {
+			debug_manager.add_error_entry("Failed to load playlists. Using none.".to_string());
+			Vec::new()
+		});
 		let screen_dimensions = window.get_max_yx();
 
+		// songs, that were imported before tags were read, are scanned in the background
+		let mut tag_scanner = TagScanner::new();
+		tag_scanner.scan_new_songs(&song_buffer);
+		let mut library_manager = LibraryManager::new();
+		if matches!(cache.view, ViewState::Library) {
+			library_manager.refresh(&song_buffer);
+		}
+		let library_scanner = LibraryScanner::new();
+		if !cache.music_roots.is_empty() {
+			library_scanner.set_roots(cache.music_roots.clone(), song_buffer.known_paths());
+		}
+
 		Musicus {
 			command_sender: audio_backend_sender,
             info_receiver,
 			file_manager: FileManager::new(&cache.filemanager_cache),
 			playlist_manager: PlaylistManager::new(playlists, &cache.playlist_manager_cache),
 			debug_manager,
+			device_manager: DeviceManager::new(cache.output.clone()),
+			equalizer_manager: EqualizerManager::new(equalizer_config),
+			bookmark_manager: BookmarkManager::new(),
+			library_manager,
+			visualizer_manager: VisualizerManager::new(sample_tap_receiver),
 			song_buffer,
+			storage,
+			last_save: Instant::now(),
+			tag_scanner,
+			library_scanner,
+			music_roots: cache.music_roots,
 			window,
 			color_pairs: HashMap::new(),
 			color_pair_counter: 1,
@@ -110,9 +239,17 @@ impl Musicus {
 			view_state: cache.view,
 			playing_song_info: None,
 			volume: cache.volume,
+			crossfade: Duration::ZERO,
+			replay_gain_mode: cache.replay_gain_mode,
+			sleep_timer: None,
+			sleep_fade: Duration::from_secs(cache.sleep_fade),
+			fade_duration: Duration::from_millis(cache.fade_duration),
+			sleep_volume_factor: 1.0,
 			follow: cache.follow,
+			prefer_tag_titles: cache.prefer_tag_titles,
 			screen_dimensions,
 			clipboard: None,
+			prompt: None,
 		}
 	}
 
@@ -126,14 +263,17 @@ impl Musicus {
 
 	pub fn shutdown(&mut self) {
 		pancurses::endwin();
-		let playlists_path = get_playlist_directory();
-		for playlist in &self.playlist_manager.playlists {
-			let playlist_path = playlists_path.join(playlist.name.to_lowercase().replace(" ", "_")).with_extension("json");
-			playlist.dump_to_file(&playlist_path);
+		let cache = self.create_cache();
+		if let Err(e) = self.storage.shutdown(&mut self.song_buffer, &self.playlist_manager.playlists, &cache) {
+			eprintln!("Failed to save library: {}", e);
+		}
+		if let Err(e) = self.equalizer_manager.config.dump() {
+			eprintln!("Failed to save equalizer presets: {}", e);
 		}
+	}
 
-		// dump cache
-		let cache = Cache {
+	fn create_cache(&self) -> Cache {
+		Cache {
 			view: self.view_state,
 			play_mode: self.play_state.mode,
 			filemanager_cache: FileManagerCache {
@@ -142,11 +282,25 @@ impl Musicus {
 			playlist_manager_cache: self.playlist_manager.create_cache(),
 			volume: self.volume,
 			follow: self.follow,
-		};
-		cache.dump();
+			output: self.device_manager.configured_output.clone(),
+			replay_gain_mode: self.replay_gain_mode,
+			sleep_fade: self.sleep_fade.as_secs(),
+			fade_duration: self.fade_duration.as_millis() as u64,
+			prefer_tag_titles: self.prefer_tag_titles,
+			music_roots: self.music_roots.clone(),
+		}
+	}
 
-		// dump song buffer
-		self.song_buffer.dump();
+	/// Writes the changes of songs, playlists and the cache, so they are not lost, if musicus crashes.
+	fn save_changes(&mut self) {
+		if self.last_save.elapsed() < SAVE_INTERVAL {
+			return;
+		}
+		self.last_save = Instant::now();
+		let cache = self.create_cache();
+		if let Err(e) = self.storage.save_changes(&mut self.song_buffer, &self.playlist_manager.playlists, &cache) {
+			self.debug_manager.add_error_entry(format!("Failed to save library: {}", e));
+		}
 	}
 
 	pub fn run(&mut self) {
@@ -155,16 +309,24 @@ impl Musicus {
 		while running {
 			let got_input = self.handle_input(&mut running);
 			let got_update = self.handle_audio_backend();
+			let got_library_update = self.update_library();
+			let got_tags = self.update_tags(got_input || got_library_update);
+			self.save_changes();
+			self.update_sleep_timer();
+			let got_samples = self.update_visualizer();
+			self.debug_manager.show_panics();
 			let got_log = self.debug_manager.has_update();
-			self.render(got_input || got_update || (matches!(self.view_state, ViewState::Debug) && got_log));
+			self.render(got_input || got_update || got_tags || got_samples || (matches!(self.view_state, ViewState::Debug) && got_log));
 		}
 		self.shutdown();
 	}
 
 	fn start_next_song(&mut self) {
-		if let Some(PlayPosition::Playlist(song_id, ..)) = self.play_state.peek_next_song() {
+		self.skip_broken_next_songs();
+		if let Some(play_position @ PlayPosition::Playlist(song_id, ..)) = self.play_state.peek_next_song() {
 			let song = self.song_buffer.get(song_id).unwrap();
 			self.command_sender.send(AudioBackendCommand::Command(AudioCommand::Play(song.clone()))).unwrap();
+			self.send_speed(play_position);
 			if let Err(msg) = self.play_state.play_next_song(&self.playlist_manager) {
 				self.debug_manager.add_error_entry(format!("failed to start next song: {}", msg));
 			}
@@ -176,12 +338,227 @@ impl Musicus {
 		}
 	}
 
+	/// Replaces the next song, while it is broken. Returns false, if there is no playable next song.
+	fn skip_broken_next_songs(&mut self) -> bool {
+		for _ in 0..MAX_SKIPPED_SONGS {
+			match self.play_state.peek_next_song() {
+				Some(play_position) if self.song_buffer.check_playable(play_position.get_id()) => return true,
+				Some(play_position) => {
+					let title = self.song_buffer.get(play_position.get_id()).map_or("<unknown song>", |song| song.get_title());
+					self.debug_manager.add_entry(format!("skipping broken song \"{}\"", title));
+					let _ = self.play_state.skip_next_song(&self.playlist_manager);
+				}
+				None => return false,
+			}
+		}
+		false
+	}
+
+	/// Lets the backend decode the start of the upcoming songs, so they start without delay.
+	fn send_prefetch(&self) {
+		let songs = self.play_state.get_upcoming_songs(&self.playlist_manager, PREFETCH_SONGS).into_iter()
+			.filter_map(|song_id| self.song_buffer.get(song_id).filter(|song| !song.is_broken()).cloned())
+			.collect();
+		self.command_sender.send(AudioBackendCommand::Command(AudioCommand::Prefetch(songs))).unwrap();
+	}
+
 	fn follow_playlist(&mut self) {
 		if let Some(PlayPosition::Playlist(_, playlist_index, song_index, false)) = &mut self.play_state.get_current_play_position() { // only match songs, that are not deleted
 			self.playlist_manager.set_cursor_position(*playlist_index, *song_index, self.get_num_rows());
 		}
 	}
 
+	/// The audio backend only sends samples, while the visualizer is shown. Returns true, if new samples were received.
+	fn update_visualizer(&mut self) -> bool {
+		let shown = matches!(self.view_state, ViewState::Visualizer);
+		if shown != self.visualizer_manager.is_enabled() {
+			self.visualizer_manager.set_enabled(shown);
+			self.command_sender.send(AudioBackendCommand::Command(AudioCommand::SetSampleTap(shown))).unwrap();
+			self.window.timeout(if shown { VISUALIZER_TIMEOUT } else { CURSES_TIMEOUT });
+		}
+		shown && self.visualizer_manager.receive_samples()
+	}
+
+	/// Requests the tags of new songs, after input or the library scanner could have imported songs, and stores the read tags.
+	/// Returns true, if shown titles could have changed.
+	fn update_tags(&mut self, songs_imported: bool) -> bool {
+		if songs_imported {
+			self.tag_scanner.scan_new_songs(&self.song_buffer);
+		}
+		let song_ids = self.tag_scanner.receive_tags(&mut self.song_buffer);
+		if song_ids.is_empty() {
+			return false;
+		}
+		let current_song_id = self.play_state.get_current_play_position().map(|play_position| play_position.get_id());
+		if current_song_id.is_some_and(|song_id| song_ids.contains(&song_id)) {
+			self.update_playing_title();
+		}
+		self.prefer_tag_titles && matches!(self.view_state, ViewState::Playlists | ViewState::Bookmarks)
+	}
+
+	/// Imports the songs found by the library scanner and applies the moved and removed files to the known songs.
+	/// Returns true, if songs could have been imported.
+	fn update_library(&mut self) -> bool {
+		let events = self.library_scanner.receive_events();
+		let got_events = !events.is_empty();
+		for event in events {
+			match event {
+				LibraryEvent::Found(scanned_file) => {
+					if let Some(song) = self.song_buffer.get_by_path(&scanned_file.path) {
+						let song_id = song.get_id();
+						self.song_buffer.check_playable(song_id);
+						if let Some(fingerprint) = scanned_file.fingerprint {
+							self.song_buffer.set_fingerprint(song_id, fingerprint);
+						}
+					} else if let Some(old_path) = scanned_file.fingerprint.and_then(|fingerprint| self.song_buffer.find_moved_file(fingerprint)) {
+						// the file was moved, while musicus was not running or from a directory, that is not watched
+						self.debug_manager.add_entry(format!("found \"{}\" at \"{}\"", old_path.display(), scanned_file.path.display()));
+						self.move_files(&old_path, &scanned_file.path);
+					} else {
+						let song_id = self.song_buffer.import(&scanned_file.path, scanned_file.title.as_deref());
+						if let Some(fingerprint) = scanned_file.fingerprint {
+							self.song_buffer.set_fingerprint(song_id, fingerprint);
+						}
+					}
+				}
+				LibraryEvent::FoundTracks(tracks) => {
+					for track in tracks {
+						let song_id = self.song_buffer.import_track(&track);
+						self.song_buffer.check_playable(song_id);
+					}
+				}
+				LibraryEvent::Removed(path) => {
+					for song_id in self.song_buffer.get_songs_in(&path) {
+						self.song_buffer.set_broken(song_id, true);
+					}
+				}
+				LibraryEvent::Moved(from, to) => self.move_files(&from, &to),
+				LibraryEvent::Located(located) => {
+					self.debug_manager.add_entry(format!("found {} missing files in the music roots", located.len()));
+					for (from, to) in located {
+						self.move_files(&from, &to);
+					}
+					self.library_manager.refresh(&self.song_buffer);
+				}
+				LibraryEvent::ScanFinished(num_songs) => self.debug_manager.add_entry(format!("found {} songs in the music roots", num_songs)),
+				LibraryEvent::Error(error) => self.debug_manager.add_error_entry(error),
+			}
+		}
+		got_events
+	}
+
+	/// Lets the songs at from refer to the files at to. The songs keep their ids, so playlists keep them.
+	fn move_files(&mut self, from: &Path, to: &Path) {
+		for song_id in self.song_buffer.move_files(from, to) {
+			self.song_buffer.check_playable(song_id);
+		}
+	}
+
+	/// Lets the songs, whose path starts with from, start with to instead, e.g. after a music drive was mounted somewhere else.
+	fn rewrite_path_prefix(&mut self, from: &Path, to: &Path) {
+		let song_ids = self.song_buffer.move_files(from, to);
+		self.debug_manager.add_entry(format!("changed the paths of {} songs from \"{}\" to \"{}\"", song_ids.len(), from.display(), to.display()));
+		for song_id in song_ids {
+			self.song_buffer.check_playable(song_id);
+		}
+		self.library_manager.refresh(&self.song_buffer);
+	}
+
+	/// Lets the library scanner search the music roots for the files of the missing songs.
+	fn locate_missing_files(&mut self) {
+		if self.music_roots.is_empty() {
+			self.debug_manager.add_error_entry("No music roots to search. Add them by pressing 'M' in the file browser.".to_string());
+			return;
+		}
+		let mut files: Vec<(PathBuf, Option<Fingerprint>)> = Vec::new();
+		for song in self.library_manager.get_missing_songs().iter().filter_map(|song_id| self.song_buffer.get(*song_id)) {
+			if !files.iter().any(|(path, _)| path == song.get_path()) {
+				files.push((song.get_path().to_path_buf(), song.get_fingerprint()));
+			}
+		}
+		self.debug_manager.add_entry(format!("searching {} missing files in the music roots", files.len()));
+		self.library_scanner.locate_files(files);
+	}
+
+	/// Removes the missing songs, that are not in a playlist and are not played.
+	fn prune_orphaned_songs(&mut self) {
+		let mut referenced_songs: HashSet<SongID> = self.playlist_manager.playlists.iter()
+			.flat_map(|playlist| playlist.songs.iter().copied())
+			.collect();
+		referenced_songs.extend(self.play_state.history.iter().chain(&self.play_state.current_song).chain(&self.play_state.next_song).map(|play_position| play_position.get_id()));
+		referenced_songs.extend(self.clipboard);
+		let orphaned_songs: Vec<SongID> = self.library_manager.get_missing_songs().iter()
+			.filter(|song_id| !referenced_songs.contains(song_id))
+			.copied()
+			.collect();
+		for song_id in &orphaned_songs {
+			self.song_buffer.remove(*song_id);
+		}
+		self.debug_manager.add_entry(format!("removed {} missing songs, that are not in a playlist", orphaned_songs.len()));
+		self.library_manager.refresh(&self.song_buffer);
+	}
+
+	/// Adds the selected directory of the file browser to the music roots or removes it.
+	fn toggle_music_root(&mut self) {
+		let path = self.file_manager.current_path.clone();
+		if let Some(index) = self.music_roots.iter().position(|root| *root == path) {
+			self.music_roots.remove(index);
+			self.debug_manager.add_entry(format!("removed music root \"{}\"", path.display()));
+		} else if path.is_dir() {
+			self.debug_manager.add_entry(format!("added music root \"{}\"", path.display()));
+			self.music_roots.push(path);
+		} else {
+			self.debug_manager.add_error_entry(format!("\"{}\" is not a directory", path.display()));
+			return;
+		}
+		self.library_scanner.set_roots(self.music_roots.clone(), self.song_buffer.known_paths());
+	}
+
+	fn toggle_prefer_tag_titles(&mut self) {
+		self.prefer_tag_titles = !self.prefer_tag_titles;
+		self.update_playing_title();
+	}
+
+	fn update_playing_title(&mut self) {
+		if let (Some(playing_song), Some(play_position)) = (&mut self.playing_song_info, self.play_state.get_current_play_position()) {
+			if let Some(song) = self.song_buffer.get(play_position.get_id()) {
+				playing_song.title = song.get_display_title(self.prefer_tag_titles).to_string();
+			}
+		}
+	}
+
+	/// Sends the settings and the played song to a restarted backend, that starts without them.
+	fn restore_backend(&self) {
+		self.send_volume();
+		let commands = [
+			AudioCommand::SetReplayGainMode(self.replay_gain_mode),
+			AudioCommand::SetFadeDuration(self.fade_duration),
+			AudioCommand::SetCrossfade(self.crossfade),
+			AudioCommand::SetStopAfterCurrent(matches!(self.sleep_timer, Some(SleepTimer::EndOfSong))),
+			AudioCommand::SetSampleTap(self.visualizer_manager.is_enabled()),
+		];
+		for command in commands {
+			self.command_sender.send(AudioBackendCommand::Command(command)).unwrap();
+		}
+		// the equalizer is sent, when the backend reports its output
+		let output_config = self.device_manager.configured_output.clone();
+		self.command_sender.send(AudioBackendCommand::Command(AudioCommand::SetOutput(output_config))).unwrap();
+
+		if matches!(self.play_state.status, PlayStatus::Stopped) {
+			return;
+		}
+		if let Some(play_position) = self.play_state.get_current_play_position() {
+			if let Some(song) = self.song_buffer.get(play_position.get_id()) {
+				let position = self.playing_song_info.as_ref().map_or(Duration::ZERO, |playing_song| playing_song.play_position);
+				let speed = Self::get_speed(play_position, &self.song_buffer, &self.playlist_manager);
+				let paused = matches!(self.play_state.status, PlayStatus::Paused);
+				for command in AudioCommand::restore_song(song.clone(), speed, position, self.get_loop_range(), paused) {
+					self.command_sender.send(AudioBackendCommand::Command(command)).unwrap();
+				}
+			}
+		}
+	}
+
 	fn get_num_rows(&self) -> usize {
 		(self.window.get_max_y()-1) as usize
 	}
@@ -189,18 +566,24 @@ impl Musicus {
 	fn handle_audio_backend(&mut self) -> bool {
 		let mut has_to_render = false;
 		let mut should_follow = false;
-		for info in self.info_receiver.try_iter() {
+		let mut playback_finished = false;
+		let infos: Vec<AudioInfo> = self.info_receiver.try_iter().collect();
+		for info in infos {
 			match info {
 				AudioInfo::Playing(_song_id, play_position) => {
+					if self.playing_song_info.as_ref().is_some_and(|playing_song| !playing_song.queued_next) {
+						self.skip_broken_next_songs();
+					}
 					if let Some(playing_song) = &mut self.playing_song_info {
 						playing_song.play_position = play_position;
 
 						// check for queue command
 						if !playing_song.queued_next {
 							match self.play_state.peek_next_song() {
-								Some(song) => {
-									let song = self.song_buffer.get(song.get_id()).unwrap();
+								Some(play_position) => {
+									let song = self.song_buffer.get(play_position.get_id()).unwrap();
 									self.command_sender.send(AudioBackendCommand::Command(AudioCommand::Queue(song.clone()))).unwrap();
+									Self::send_speed_command(&self.command_sender, play_position, &self.song_buffer, &self.playlist_manager);
 									self.debug_manager.add_entry(format!("queuing \"{}\"", song.get_title()));
 									playing_song.queued_next = true;
 								},
@@ -211,12 +594,41 @@ impl Musicus {
 						self.debug_manager.add_entry_color("Got playing update, but playing song info is not set.".to_string(), RenderColor::Red, RenderColor::Black);
 					}
 				}
+				AudioInfo::PlaybackFinished => {
+					self.play_state.status = PlayStatus::Stopped;
+					Self::reset_playing_song_info(&mut self.playing_song_info);
+					// resuming continues with the next song
+					if self.play_state.peek_next_song().is_some() {
+						let _ = self.play_state.play_next_song(&self.playlist_manager);
+					}
+					playback_finished = true;
+					has_to_render = true;
+				}
 				AudioInfo::FailedOpen(song_id, e) => {
 					self.debug_manager.add_entry_color(
-						format!("Failed to open \"{}\": {:?}\n", self.song_buffer.get(song_id).map(|s| s.get_title()).unwrap_or("<unknown song>"), e),
+						format!("Failed to open \"{}\": {}\n", self.song_buffer.get(song_id).map(|s| s.get_title()).unwrap_or("<unknown song>"), e),
 					    RenderColor::Red,
 						RenderColor::Black
 					);
+					self.song_buffer.set_broken(song_id, true);
+					has_to_render = true;
+					let is_current = self.play_state.get_current_play_position().is_some_and(|play_position| play_position.get_id() == song_id);
+					if is_current && !matches!(self.play_state.status, PlayStatus::Stopped) {
+						// continue with the next song, that can be played
+						if self.skip_broken_next_songs() {
+							self.start_next_song();
+						} else {
+							self.stop();
+						}
+					} else if self.play_state.peek_next_song().is_some_and(|play_position| play_position.get_id() == song_id) {
+						// the queued song is replaced, when the next playing update arrives. Without replacement the backend
+						// finishes playback after the current song.
+						if self.skip_broken_next_songs() {
+							if let Some(playing_song) = &mut self.playing_song_info {
+								playing_song.queued_next = false;
+							}
+						}
+					}
 				}
 				AudioInfo::SongStarts(song_id) => {
 					if let Some(current_song) = self.play_state.current_song {
@@ -227,16 +639,30 @@ impl Musicus {
 							}
 						}
 					}
+					self.song_buffer.set_broken(song_id, false);
+					if let Err(e) = self.storage.record_play(song_id) {
+						self.debug_manager.add_error_entry(format!("Failed to record play: {}", e));
+					}
 					let song = self.song_buffer.get(song_id).unwrap();
+					self.bookmark_manager.clamp_cursor(song.get_bookmarks().len());
+					// the backend keeps the loop, if the same song is started again (e.g. after a restart of the backend)
+					let (loop_start, loop_end) = match &self.playing_song_info {
+						Some(playing_song) if playing_song.song_id == song_id => (playing_song.loop_start, playing_song.loop_end),
+						_ => (None, None),
+					};
 					self.playing_song_info = Some(SongInfo {
-						title: song.get_title().to_string(),
+						song_id,
+						title: song.get_display_title(self.prefer_tag_titles).to_string(),
 						play_position: Duration::new(0, 0),
 						total_duration: song.get_total_duration().unwrap_or(Duration::new(0, 0)), // TODO: fix; SongInfo.total_duration should be Option
 						queued_next: false,
+						loop_start,
+						loop_end,
 					});
 					has_to_render = true;
 					self.debug_manager.add_entry(format!("start song \"{}\"", song.get_title()));
 					should_follow = true;
+					self.send_prefetch();
 				}
 				AudioInfo::SongDuration(song_id, duration) => {
 					self.song_buffer.update_total_duration(song_id, duration);
@@ -244,11 +670,50 @@ impl Musicus {
 						has_to_render = true;
 					}
 				}
+				AudioInfo::SongLoudness(song_id, loudness) => {
+					self.song_buffer.update_loudness(song_id, loudness);
+				}
+				AudioInfo::OutputDevices(devices) => {
+					self.device_manager.set_devices(devices);
+					has_to_render = true;
+				}
+				AudioInfo::OutputChanged(output_config, name) => {
+					self.debug_manager.add_entry(format!("using output \"{}\"", name));
+					self.device_manager.current_output = Some(output_config);
+					// every device has its own equalizer preset
+					self.equalizer_manager.set_current_device(name);
+					self.send_equalizer();
+					has_to_render = true;
+				}
+				AudioInfo::OutputDeviceFailed(e) => {
+					self.debug_manager.add_error_entry(e.to_string());
+				}
+				AudioInfo::Health(health) => {
+					self.debug_manager.set_health(health);
+				}
+				AudioInfo::BackendRestarted(e) => {
+					self.debug_manager.add_error_entry(format!("{}. Restarted audio backend.", e));
+					self.restore_backend();
+					has_to_render = true;
+				}
+				AudioInfo::SpawnFailed(thread_name, e) => {
+					self.debug_manager.add_error_entry(format!("Failed to start {} thread: {}", thread_name, e));
+				}
+				AudioInfo::BackendStopped(e) => {
+					self.debug_manager.add_error_entry(format!("{}. Audio backend failed too often and is not restarted.", e));
+					self.play_state.status = PlayStatus::Stopped;
+					Self::reset_playing_song_info(&mut self.playing_song_info);
+					has_to_render = true;
+				}
 			}
 		}
 		if should_follow && self.follow {
 			self.follow_playlist();
 		}
+		if playback_finished && matches!(self.sleep_timer, Some(SleepTimer::EndOfSong | SleepTimer::EndOfPlaylist)) {
+			self.debug_manager.add_entry("sleep timer stopped playback".to_string());
+			self.set_sleep_timer(None);
+		}
 		has_to_render
 	}
 
@@ -260,6 +725,10 @@ impl Musicus {
 			got_valid_input = true;
 		}
 		if let Some(input) = self.window.getch() {
+			if self.prompt.is_some() {
+				self.handle_prompt_input(input);
+				return true;
+			}
 			match input {
 				Input::Character(c) => {
 					got_valid_input = true;
@@ -268,9 +737,19 @@ impl Musicus {
 						('L', _) => self.seek(SeekDirection::Forward),
 						('H', _) => self.seek(SeekDirection::Backward),
 						('J', _) => self.start_next_song(),
+						('g', _) => self.prompt = Some(Prompt::Seek(String::new())),
+						('a', _) => self.set_loop_start(),
+						('b', _) => self.set_loop_end(),
+						('A', _) => self.clear_loop(),
+						('m', _) => {
+							if let Some(playing_song) = &self.playing_song_info {
+								self.prompt = Some(Prompt::Bookmark(playing_song.play_position, String::new()));
+							}
+						}
 						(ENTER_CHAR, ViewState::FileManager) => self.filemanager_context_action(),
 						('y', ViewState::FileManager) => self.file_manager_add_to_playlist(),
 						('n', ViewState::FileManager) => self.file_manager_new_playlist(),
+						('M', ViewState::FileManager) => self.toggle_music_root(),
 						('h', ViewState::FileManager) => self.file_manager.move_left(),
 						('j', ViewState::FileManager) => self.file_manager.move_down(self.get_num_rows()),
 						('k', ViewState::FileManager) => self.file_manager.move_up(),
@@ -285,10 +764,63 @@ impl Musicus {
 						('p', ViewState::Playlists) => self.paste_clipboard_song_to_playlist(),
 						('j', ViewState::Debug) => self.debug_manager.scroll(1),
 						('k', ViewState::Debug) => self.debug_manager.scroll(-1),
+						(ENTER_CHAR, ViewState::Devices) => self.select_output_device(),
+						('j', ViewState::Devices) => self.device_manager.move_down(self.get_num_rows()),
+						('k', ViewState::Devices) => self.device_manager.move_up(),
+						('r', ViewState::Devices) => {
+							self.command_sender.send(AudioBackendCommand::Command(AudioCommand::ListOutputDevices)).unwrap();
+						}
+						(ENTER_CHAR, ViewState::Equalizer) => {
+							self.equalizer_manager.select_preset();
+							self.send_equalizer();
+						}
+						('j', ViewState::Equalizer) => self.equalizer_manager.move_down(self.get_num_rows()),
+						('k', ViewState::Equalizer) => self.equalizer_manager.move_up(),
+						('h', ViewState::Equalizer) => self.equalizer_manager.move_left(),
+						('l', ViewState::Equalizer) => self.equalizer_manager.move_right(),
+						('u', ViewState::Equalizer) => {
+							self.equalizer_manager.change_gain(1.0);
+							self.send_equalizer();
+						}
+						('d', ViewState::Equalizer) => {
+							self.equalizer_manager.change_gain(-1.0);
+							self.send_equalizer();
+						}
+						(ENTER_CHAR, ViewState::Bookmarks) => self.jump_to_bookmark(),
+						('j', ViewState::Bookmarks) => {
+							let num_bookmarks = self.get_current_song().map_or(0, |song| song.get_bookmarks().len());
+							self.bookmark_manager.move_down(num_bookmarks, self.get_num_rows());
+						}
+						('k', ViewState::Bookmarks) => self.bookmark_manager.move_up(),
+						('D', ViewState::Bookmarks) => self.remove_bookmark(),
+						('j', ViewState::Library) => self.library_manager.move_down(self.get_num_rows()),
+						('k', ViewState::Library) => self.library_manager.move_up(),
+						('r', ViewState::Library) => self.library_manager.refresh(&self.song_buffer),
+						('w', ViewState::Library) => {
+							// the directory of the selected song is a good start for the replaced prefix
+							let directory = self.library_manager.get_selected_song()
+								.and_then(|song_id| self.song_buffer.get(song_id))
+								.and_then(|song| song.get_path().parent())
+								.map(|directory| directory.to_string_lossy().into_owned());
+							self.prompt = Some(Prompt::RewriteFrom(directory.unwrap_or_default()));
+						}
+						('F', ViewState::Library) => self.locate_missing_files(),
+						('P', ViewState::Library) => self.prune_orphaned_songs(),
 						('c', _) => self.toggle_pause(),
+						('v', _) => self.stop(),
+						('z', _) => self.cycle_sleep_timer(),
+						('Z', _) => self.toggle_stop_after_current(),
 						('1', _) => self.view_state = ViewState::FileManager,
 						('2', _) => self.view_state = ViewState::Playlists,
 						('3', _) => self.view_state = ViewState::Debug,
+						('4', _) => self.view_state = ViewState::Devices,
+						('5', _) => self.view_state = ViewState::Equalizer,
+						('6', _) => self.view_state = ViewState::Bookmarks,
+						('7', _) => self.view_state = ViewState::Visualizer,
+						('8', _) => {
+							self.library_manager.refresh(&self.song_buffer);
+							self.view_state = ViewState::Library;
+						}
 						('s', _) => {
 							match self.play_state.toggle_mode(&self.playlist_manager) {
 								Err(msg) => self.debug_manager.add_error_entry(format!("Failed to define next song, when toggling mode: {}", msg)),
@@ -299,6 +831,18 @@ impl Musicus {
 							};
 						},
 						('f', _) => self.follow = !self.follow,
+						('T', _) => self.toggle_prefer_tag_titles(),
+						('x', _) => self.toggle_crossfade(),
+						('R', _) => self.toggle_replay_gain_mode(),
+						('<', _) => self.change_song_speed(-1),
+						('>', _) => self.change_song_speed(1),
+						('=', _) => self.reset_song_speed(),
+						('{', _) => self.change_playlist_speed(-1),
+						('}', _) => self.change_playlist_speed(1),
+						('e', _) => {
+							self.equalizer_manager.toggle_enabled();
+							self.send_equalizer();
+						}
 						('F', ViewState::Playlists) => self.follow_playlist(),
 						('+', _) => self.change_volume(5),
 						('-', _) => self.change_volume(-5),
@@ -332,11 +876,276 @@ impl Musicus {
 
 	fn change_volume(&mut self, volume_change: i32) {
 		self.volume = (self.volume + volume_change).clamp(0, 100);
+		self.send_volume();
+	}
+
+	/// Sends the volume to the backend. The volume is reduced, while the sleep timer fades out.
+	fn send_volume(&self) {
         self.command_sender.send(
-			AudioBackendCommand::Command(AudioCommand::SetVolume(self.volume as f32 * 0.01))
+			AudioBackendCommand::Command(AudioCommand::SetVolume(self.volume as f32 * 0.01 * self.sleep_volume_factor))
 		).unwrap();
 	}
 
+	/// Sets the sleep timer or removes it. The volume is reset.
+	fn set_sleep_timer(&mut self, sleep_timer: Option<SleepTimer>) {
+		let stop_after_current = matches!(sleep_timer, Some(SleepTimer::EndOfSong));
+		self.command_sender.send(AudioBackendCommand::Command(AudioCommand::SetStopAfterCurrent(stop_after_current))).unwrap();
+		self.sleep_timer = sleep_timer;
+		self.set_sleep_volume_factor(1.0);
+	}
+
+	/// Switches to the next sleep timer duration. After the longest duration, playback stops at the end of the playlist.
+	fn cycle_sleep_timer(&mut self) {
+		let next_step = match self.sleep_timer {
+			None | Some(SleepTimer::EndOfSong) => Some(0),
+			Some(SleepTimer::Time(step, _)) => Some(step + 1),
+			Some(SleepTimer::EndOfPlaylist) => None,
+		};
+		let sleep_timer = next_step.map(|step| match SLEEP_TIMER_STEPS.get(step) {
+			Some(minutes) => SleepTimer::Time(step, Instant::now() + Duration::from_secs(minutes * 60)),
+			None => SleepTimer::EndOfPlaylist,
+		});
+		self.set_sleep_timer(sleep_timer);
+	}
+
+	fn toggle_stop_after_current(&mut self) {
+		if matches!(self.sleep_timer, Some(SleepTimer::EndOfSong)) {
+			self.set_sleep_timer(None);
+		} else {
+			self.set_sleep_timer(Some(SleepTimer::EndOfSong));
+		}
+	}
+
+	/// Returns the time until the sleep timer stops playback or None, if this is not known yet.
+	fn get_sleep_time_left(&mut self) -> Option<Duration> {
+		match self.sleep_timer? {
+			SleepTimer::Time(_, end) => Some(end.saturating_duration_since(Instant::now())),
+			SleepTimer::EndOfSong => self.get_song_time_left(),
+			SleepTimer::EndOfPlaylist if self.play_state.peek_next_song().is_none() => self.get_song_time_left(),
+			SleepTimer::EndOfPlaylist => None,
+		}
+	}
+
+	/// Returns the time until the current song is finished, considering its speed.
+	fn get_song_time_left(&self) -> Option<Duration> {
+		let playing_song = self.playing_song_info.as_ref().filter(|s| !s.total_duration.is_zero())?;
+		let speed = self.play_state.get_current_play_position()
+			.map_or(1.0, |play_position| Self::get_speed(play_position, &self.song_buffer, &self.playlist_manager));
+		Some(playing_song.total_duration.saturating_sub(playing_song.play_position).div_f32(speed))
+	}
+
+	/// Fades out the volume before the sleep timer stops playback and pauses, when the time of the sleep timer is over.
+	fn update_sleep_timer(&mut self) {
+		let time_left = match self.get_sleep_time_left() {
+			Some(time_left) => time_left,
+			None => return,
+		};
+		if time_left.is_zero() && matches!(self.sleep_timer, Some(SleepTimer::Time(..))) {
+			self.command_sender.send(AudioBackendCommand::Command(AudioCommand::Pause)).unwrap();
+			self.play_state.status = PlayStatus::Paused;
+			self.debug_manager.add_entry("sleep timer paused playback".to_string());
+			self.set_sleep_timer(None);
+			return;
+		}
+		if self.play_state.status == PlayStatus::Playing && time_left < self.sleep_fade {
+			self.set_sleep_volume_factor(time_left.as_secs_f32() / self.sleep_fade.as_secs_f32());
+		}
+	}
+
+	fn set_sleep_volume_factor(&mut self, factor: f32) {
+		// the volume is only sent, if the change is audible
+		if (factor - self.sleep_volume_factor).abs() >= 0.01 || (factor == 1.0 && self.sleep_volume_factor != 1.0) {
+			self.sleep_volume_factor = factor;
+			self.send_volume();
+		}
+	}
+
+	fn handle_prompt_input(&mut self, input: Input) {
+		match input {
+			Input::Character(ENTER_CHAR) => {
+				match self.prompt.take() {
+					Some(Prompt::Seek(text)) => {
+						match text.parse::<SeekPosition>() {
+							Ok(seek_position) => self.seek_to(seek_position),
+							Err(msg) => self.debug_manager.add_error_entry(format!("failed to seek: {}", msg)),
+						}
+					}
+					Some(Prompt::Bookmark(position, name)) => self.add_bookmark(position, name),
+					Some(Prompt::RewriteFrom(from)) if !from.is_empty() => self.prompt = Some(Prompt::RewriteTo(PathBuf::from(from), String::new())),
+					Some(Prompt::RewriteTo(from, to)) if !to.is_empty() => self.rewrite_path_prefix(&from, Path::new(&to)),
+					Some(Prompt::RewriteFrom(_) | Prompt::RewriteTo(..)) => {}
+					None => {}
+				}
+			}
+			Input::Character(ESCAPE_CHAR) => self.prompt = None,
+			Input::KeyBackspace | Input::Character(BACKSPACE_CHAR) => {
+				if let Some(prompt) = &mut self.prompt {
+					prompt.get_text_mut().pop();
+				}
+			}
+			Input::Character(c) => {
+				if let Some(prompt) = &mut self.prompt {
+					prompt.get_text_mut().push(c);
+				}
+			}
+			_ => {}
+		}
+	}
+
+	fn get_current_song(&self) -> Option<&Song> {
+		self.play_state.get_current_play_position().and_then(|play_position| self.song_buffer.get(play_position.get_id()))
+	}
+
+	/// Sets the start of the loop (A) to the current position. The end is removed, if it is before the start.
+	fn set_loop_start(&mut self) {
+		if let Some(playing_song) = &mut self.playing_song_info {
+			let loop_start = playing_song.play_position;
+			playing_song.loop_start = Some(loop_start);
+			if playing_song.loop_end.is_some_and(|loop_end| loop_end <= loop_start) {
+				playing_song.loop_end = None;
+			}
+			self.send_loop();
+		}
+	}
+
+	/// Sets the end of the loop (B) to the current position and starts the loop. Without a start, the loop starts at
+	/// the beginning of the song.
+	fn set_loop_end(&mut self) {
+		if let Some(playing_song) = &mut self.playing_song_info {
+			let loop_end = playing_song.play_position;
+			let loop_start = *playing_song.loop_start.get_or_insert(Duration::ZERO);
+			if loop_end > loop_start {
+				playing_song.loop_end = Some(loop_end);
+				self.send_loop();
+			}
+		}
+	}
+
+	fn clear_loop(&mut self) {
+		if let Some(playing_song) = &mut self.playing_song_info {
+			playing_song.loop_start = None;
+			playing_song.loop_end = None;
+			self.send_loop();
+		}
+	}
+
+	/// Sends the loop of the current song to the backend. Only loops with start and end are sent.
+	fn send_loop(&self) {
+		self.command_sender.send(AudioBackendCommand::Command(AudioCommand::SetLoop(self.get_loop_range()))).unwrap();
+	}
+
+	fn get_loop_range(&self) -> Option<(Duration, Duration)> {
+		self.playing_song_info.as_ref().and_then(|playing_song| playing_song.loop_start.zip(playing_song.loop_end))
+	}
+
+	/// Adds a bookmark to the current song. Bookmarks without name are named by their position.
+	fn add_bookmark(&mut self, position: Duration, name: String) {
+		if let Some(play_position) = self.play_state.get_current_play_position() {
+			let name = if name.trim().is_empty() { format_duration(position) } else { name.trim().to_string() };
+			self.debug_manager.add_entry(format!("add bookmark \"{}\" at {}", name, format_duration(position)));
+			self.song_buffer.add_bookmark(play_position.get_id(), Bookmark { name, position });
+		}
+	}
+
+	fn remove_bookmark(&mut self) {
+		if let Some(play_position) = self.play_state.get_current_play_position() {
+			self.song_buffer.remove_bookmark(play_position.get_id(), self.bookmark_manager.get_cursor_position());
+			let num_bookmarks = self.get_current_song().map_or(0, |song| song.get_bookmarks().len());
+			self.bookmark_manager.clamp_cursor(num_bookmarks);
+		}
+	}
+
+	fn jump_to_bookmark(&mut self) {
+		if let Some(bookmark) = self.bookmark_manager.get_selected_bookmark(self.get_current_song()) {
+			self.seek_to(SeekPosition::Time(bookmark.position));
+		}
+	}
+
+	/// Switches to the next crossfade duration.
+	fn toggle_crossfade(&mut self) {
+		let current_step = CROSSFADE_STEPS.iter().position(|s| *s == self.crossfade.as_secs()).unwrap_or(0);
+		self.crossfade = Duration::from_secs(CROSSFADE_STEPS[(current_step + 1) % CROSSFADE_STEPS.len()]);
+		self.command_sender.send(AudioBackendCommand::Command(AudioCommand::SetCrossfade(self.crossfade))).unwrap();
+	}
+
+	fn toggle_replay_gain_mode(&mut self) {
+		self.replay_gain_mode = self.replay_gain_mode.next();
+		self.command_sender.send(AudioBackendCommand::Command(AudioCommand::SetReplayGainMode(self.replay_gain_mode))).unwrap();
+	}
+
+	/// Returns the speed of the song at the given play position. The speed of the song is used, if it was set, otherwise
+	/// the speed of the playlist the song is played from.
+	fn get_speed(play_position: PlayPosition, song_buffer: &SongBuffer, playlist_manager: &PlaylistManager) -> f32 {
+		let song_speed = song_buffer.get(play_position.get_id()).and_then(|song| song.get_speed());
+		let playlist_speed = match play_position {
+			PlayPosition::Playlist(_, playlist_index, ..) => playlist_manager.playlists.get(playlist_index).and_then(|p| p.speed),
+			PlayPosition::File(_) => None,
+		};
+		song_speed.or(playlist_speed).unwrap_or(1.0)
+	}
+
+	fn send_speed_command(command_sender: &Sender<AudioBackendCommand>, play_position: PlayPosition, song_buffer: &SongBuffer, playlist_manager: &PlaylistManager) {
+		let speed = Self::get_speed(play_position, song_buffer, playlist_manager);
+		command_sender.send(AudioBackendCommand::Command(AudioCommand::SetSpeed(play_position.get_id(), speed))).unwrap();
+	}
+
+	fn send_speed(&self, play_position: PlayPosition) {
+		Self::send_speed_command(&self.command_sender, play_position, &self.song_buffer, &self.playlist_manager);
+	}
+
+	/// Sends the speed of the current and the queued song, after a speed was changed.
+	fn update_speeds(&mut self) {
+		if let Some(play_position) = self.play_state.get_current_play_position() {
+			self.send_speed(play_position);
+		}
+		if self.playing_song_info.as_ref().is_some_and(|s| s.queued_next) {
+			if let Some(play_position) = self.play_state.peek_next_song() {
+				self.send_speed(play_position);
+			}
+		}
+	}
+
+	/// Returns the speed, that is steps steps away from the given speed.
+	fn step_speed(speed: f32, steps: i32) -> f32 {
+		let current_step = SPEED_STEPS.iter().position(|s| *s >= speed).unwrap_or(SPEED_STEPS.len() - 1) as i32;
+		SPEED_STEPS[(current_step + steps).clamp(0, SPEED_STEPS.len() as i32 - 1) as usize]
+	}
+
+	/// Changes the speed of the current song. The speed is remembered for this song.
+	fn change_song_speed(&mut self, steps: i32) {
+		if let Some(play_position) = self.play_state.get_current_play_position() {
+			let speed = Self::step_speed(Self::get_speed(play_position, &self.song_buffer, &self.playlist_manager), steps);
+			self.song_buffer.set_speed(play_position.get_id(), Some(speed));
+			self.update_speeds();
+		}
+	}
+
+	/// The current song uses the speed of its playlist again.
+	fn reset_song_speed(&mut self) {
+		if let Some(play_position) = self.play_state.get_current_play_position() {
+			self.song_buffer.set_speed(play_position.get_id(), None);
+			self.update_speeds();
+		}
+	}
+
+	/// Changes the speed of the playlist, the current song is played from. The speed is used for all songs of the
+	/// playlist, that have no own speed.
+	fn change_playlist_speed(&mut self, steps: i32) {
+		if let Some(PlayPosition::Playlist(_, playlist_index, ..)) = self.play_state.get_current_play_position() {
+			if let Some(playlist) = self.playlist_manager.playlists.get_mut(playlist_index) {
+				let speed = Self::step_speed(playlist.speed.unwrap_or(1.0), steps);
+				playlist.speed = if speed == 1.0 { None } else { Some(speed) };
+				self.update_speeds();
+			}
+		}
+	}
+
+	/// Sends the preset of the current device to the backend or disables the equalizer.
+	fn send_equalizer(&self) {
+		let preset = self.equalizer_manager.get_active_preset().cloned();
+		self.command_sender.send(AudioBackendCommand::Command(AudioCommand::SetEqualizer(preset))).unwrap();
+	}
+
 	fn seek(&mut self, direction: SeekDirection) {
 		let duration = Duration::from_secs(5);
 		self.command_sender.send(
@@ -345,32 +1154,96 @@ impl Musicus {
 				direction,
 			}))
 		).unwrap();
-		/*
 		if let Some(playing_song) = &mut self.playing_song_info {
-			playing_song.play_position = (playing_song.play_position + duration).min(playing_song.total_duration) // TODO: use direction here
+			playing_song.play_position = match direction {
+				SeekDirection::Forward => playing_song.clamp_play_position(playing_song.play_position + duration),
+				SeekDirection::Backward => playing_song.play_position.saturating_sub(duration),
+			};
+		}
+	}
+
+	fn seek_to(&mut self, seek_position: SeekPosition) {
+		self.command_sender.send(AudioBackendCommand::Command(AudioCommand::SeekTo(seek_position))).unwrap();
+		if let Some(playing_song) = &mut self.playing_song_info {
+			playing_song.play_position = match seek_position {
+				SeekPosition::Time(duration) => playing_song.clamp_play_position(duration),
+				SeekPosition::Fraction(fraction) => playing_song.total_duration.mul_f64(fraction),
+			};
+		}
+	}
+
+	/// Switches to the selected output. Only outputs selected by the user are saved, not the fallbacks of the backend.
+	fn select_output_device(&mut self) {
+		if let Some(output_config) = self.device_manager.get_selected_output().cloned() {
+			self.device_manager.configured_output = output_config.clone();
+			self.command_sender.send(
+				AudioBackendCommand::Command(AudioCommand::SetOutput(output_config))
+			).unwrap();
 		}
-		 */
 	}
 
+	/// Pauses or unpauses. If playback is stopped, the current song is played from the beginning.
 	fn toggle_pause(&mut self) {
-		if self.play_state.playing {
-			self.command_sender.send(AudioBackendCommand::Command(AudioCommand::Pause)).unwrap();
-		} else {
-			self.command_sender.send(AudioBackendCommand::Command(AudioCommand::Unpause)).unwrap();
+		match self.play_state.status {
+			PlayStatus::Playing => {
+				self.command_sender.send(AudioBackendCommand::Command(AudioCommand::Pause)).unwrap();
+				self.play_state.status = PlayStatus::Paused;
+			}
+			PlayStatus::Paused => {
+				self.command_sender.send(AudioBackendCommand::Command(AudioCommand::Unpause)).unwrap();
+				self.play_state.status = PlayStatus::Playing;
+			}
+			PlayStatus::Stopped => {
+				if let Some(play_position) = self.play_state.get_current_play_position() {
+					if let Some(song) = self.song_buffer.get(play_position.get_id()) {
+						Self::play(&self.command_sender, &mut self.play_state, song.clone());
+						self.send_speed(play_position);
+					}
+				}
+			}
+		}
+	}
+
+	/// Stops playback. The current song is played from the beginning, when playback is continued.
+	fn stop(&mut self) {
+		self.command_sender.send(AudioBackendCommand::Command(AudioCommand::Stop)).unwrap();
+		self.play_state.status = PlayStatus::Stopped;
+		Self::reset_playing_song_info(&mut self.playing_song_info);
+		// the backend does not stop after the current song anymore
+		if matches!(self.sleep_timer, Some(SleepTimer::EndOfSong)) {
+			self.set_sleep_timer(None);
+		}
+	}
+
+	/// The song info is kept after stopping, because playing the same song again does not start it again in the source.
+	fn reset_playing_song_info(playing_song_info: &mut Option<SongInfo>) {
+		if let Some(playing_song) = playing_song_info {
+			playing_song.play_position = Duration::ZERO;
+			playing_song.queued_next = false;
+			playing_song.loop_start = None;
+			playing_song.loop_end = None;
 		}
-		self.play_state.playing = !self.play_state.playing;
 	}
 
 	fn filemanager_context_action(&mut self) {
-		let song_id = self.song_buffer.import(&self.file_manager.current_path, None);
+		// a CUE sheet is played from its first track
+		let song_id = if is_cue_file(&self.file_manager.current_path) {
+			match Song::songs_from_path(&self.file_manager.current_path, &mut self.song_buffer).first() {
+				Some(song_id) => *song_id,
+				None => return,
+			}
+		} else {
+			self.song_buffer.import(&self.file_manager.current_path, None)
+		};
 		let song = self.song_buffer.get(song_id).unwrap();
 		Self::play(&self.command_sender, &mut self.play_state, song.clone());
+		self.send_speed(PlayPosition::File(song_id));
 		let _ = self.play_state.play_song(PlayPosition::File(song_id), &self.playlist_manager);
 	}
 
 	fn play(command_sender: &Sender<AudioBackendCommand>, play_state: &mut PlayState, song: Song) {
 		command_sender.send(AudioBackendCommand::Command(AudioCommand::Play(song))).unwrap();
-		play_state.playing = true;
+		play_state.status = PlayStatus::Playing;
 	}
 
 	fn playlist_manager_context_action(&mut self) {
@@ -383,6 +1256,7 @@ impl Musicus {
 					self.playlist_manager.get_shown_song_index().unwrap(),
 					false,
 				);
+				self.send_speed(new_play_position);
 				let _ = self.play_state.play_song(new_play_position, &self.playlist_manager);
 			} else {
 				self.debug_manager.add_entry_color(format!("Failed to start song id {}", song_id), RenderColor::Red, RenderColor::Black);
@@ -410,8 +1284,13 @@ impl Musicus {
 		if everything {
 			let render_object = match self.view_state {
 				ViewState::FileManager => self.file_manager.get_render_object(),
-				ViewState::Playlists => self.playlist_manager.get_render_object(&self.play_state, &self.song_buffer),
+				ViewState::Playlists => self.playlist_manager.get_render_object(&self.play_state, &self.song_buffer, self.prefer_tag_titles),
 				ViewState::Debug => self.debug_manager.get_render_object(),
+				ViewState::Devices => self.device_manager.get_render_object(),
+				ViewState::Equalizer => self.equalizer_manager.get_render_object(),
+				ViewState::Bookmarks => self.bookmark_manager.get_render_object(self.get_current_song(), self.prefer_tag_titles),
+				ViewState::Visualizer => self.visualizer_manager.get_render_object(self.get_num_rows(), self.window.get_max_x().max(0) as usize),
+				ViewState::Library => self.library_manager.get_render_object(&self.song_buffer, self.prefer_tag_titles),
 			};
 			self.window.erase();
 			self.render_panels(&render_object);
@@ -435,26 +1314,70 @@ impl Musicus {
 		self.set_color(RenderColor::Black, RenderColor::Cyan);
 		self.window.mv(self.window.get_max_y() - 1, 0);
 		self.window.hline(' ', self.window.get_max_x());
-		let playing_str = if self.play_state.playing { ">" } else { "|" };
+		let playing_str = match self.play_state.status {
+			PlayStatus::Playing => ">",
+			PlayStatus::Paused => "|",
+			PlayStatus::Stopped => ".",
+		};
 		let play_mode_str = match self.play_state.mode {
 			PlayMode::Normal => " ",
 			PlayMode::Shuffle => "S",
 		};
 		let follow_str = if self.follow { "F" } else { " " };
+		let mut extra_str = String::new();
+		if !self.crossfade.is_zero() {
+			extra_str.push_str(&format!("  crossfade: {}s", self.crossfade.as_secs()));
+		}
+		if self.replay_gain_mode != ReplayGainMode::Off {
+			extra_str.push_str(&format!("  replaygain: {}", self.replay_gain_mode));
+		}
+		if let Some(play_position) = self.play_state.get_current_play_position() {
+			let speed = Self::get_speed(play_position, &self.song_buffer, &self.playlist_manager);
+			if speed != 1.0 {
+				extra_str.push_str(&format!("  speed: {}x", speed));
+			}
+		}
+		if let Some(preset) = self.equalizer_manager.get_active_preset() {
+			extra_str.push_str(&format!("  eq: {}", preset.name));
+		}
+		match self.sleep_timer {
+			Some(SleepTimer::Time(_, end)) => {
+				extra_str.push_str(&format!("  sleep: {}", format_duration(end.saturating_duration_since(Instant::now()))));
+			}
+			Some(SleepTimer::EndOfSong) => extra_str.push_str("  sleep: end of song"),
+			Some(SleepTimer::EndOfPlaylist) => extra_str.push_str("  sleep: end of playlist"),
+			None => {}
+		}
+		if let Some(loop_start) = self.playing_song_info.as_ref().and_then(|s| s.loop_start) {
+			let loop_end = self.playing_song_info.as_ref().and_then(|s| s.loop_end).map_or(String::new(), format_duration);
+			extra_str.push_str(&format!("  loop: {}-{}", format_duration(loop_start), loop_end));
+		}
+
+		if let Some(prompt) = &self.prompt {
+			let prompt = match prompt {
+				Prompt::Seek(text) => format!("seek to (e.g. 2:31 or 40%): {}", text),
+				Prompt::Bookmark(position, text) => format!("bookmark name at {}: {}", format_duration(*position), text),
+				Prompt::RewriteFrom(text) => format!("change paths starting with: {}", text),
+				Prompt::RewriteTo(from, text) => format!("replace \"{}\" with: {}", from.display(), text),
+			};
+			self.window.mvaddstr(self.window.get_max_y()-1, 1, prompt);
+			return;
+		}
 
 		let play_state_str = match &self.playing_song_info {
 			None => {
 				format!(
-					"{} {}{}          0:00 / 0:00 vol: {}%",
+					"{} {}{}          0:00 / 0:00 vol: {}%{}",
 					playing_str,
 					play_mode_str,
 					follow_str,
 					self.volume,
+					extra_str,
 				)
 			}
 			Some(current_song) => {
 				format!(
-					"{} {}{} {}  {} / {}  vol: {}%",
+					"{} {}{} {}  {} / {}  vol: {}%{}",
 					playing_str,
 					play_mode_str,
 					follow_str,
@@ -462,6 +1385,7 @@ impl Musicus {
 					format_duration(current_song.play_position),
 					format_duration(current_song.total_duration),
 					self.volume,
+					extra_str,
 				)
 			}
 		};
@@ -4,7 +4,7 @@ use crate::playlist_manager::PlaylistManager;
 use crate::song::SongID;
 
 pub struct PlayState {
-	pub playing: bool,
+	pub status: PlayStatus,
 	pub mode: PlayMode,
 	pub history: Vec<PlayPosition>,
 	pub current_song: Option<PlayPosition>,
@@ -15,7 +15,7 @@ pub struct PlayState {
 impl PlayState {
 	pub fn new(mode: PlayMode) -> PlayState {
 		PlayState {
-			playing: false,
+			status: PlayStatus::Stopped,
 			mode,
 			history: Vec::new(),
 			current_song: None,
@@ -68,6 +68,30 @@ impl PlayState {
 		}
 	}
 
+	/// Returns the next song and the songs following it in the playlist, as far as they are known.
+	/// In shuffle mode only the next song is known.
+	pub fn get_upcoming_songs(&self, playlist_manager: &PlaylistManager, count: usize) -> Vec<SongID> {
+		let mut songs = Vec::new();
+		if let Some(next_song) = self.next_song {
+			songs.push(next_song.get_id());
+			if let (PlayMode::Normal, PlayPosition::Playlist(_, playlist_index, song_index, _)) = (self.mode, next_song) {
+				let following = (song_index + 1..).map_while(|index| playlist_manager.get_song(playlist_index, index));
+				songs.extend(following.take(count.saturating_sub(1)));
+			}
+		}
+		songs.truncate(count);
+		songs
+	}
+
+	/// Replaces the next song by the song following it, e.g. if the next song cannot be played.
+	/// If there is no song following it, no next song is set.
+	pub fn skip_next_song(&mut self, playlist_manager: &PlaylistManager) -> Result<(), String> {
+		let next_song = self.next_song.take().ok_or("no next song".to_string())?;
+		let (song_id, song_index, playlist_index) = PlayState::generate_next_song(&self.mode, &next_song, playlist_manager, &mut self.random_generator)?;
+		self.set_next_song(PlayPosition::Playlist(song_id, playlist_index, song_index, false));
+		Ok(())
+	}
+
 	/**
 	 * If necessary generates the next play position and writes it into the history.
 	 * It is impossible to generate next songs for PlayState::Empty, PlayState::File or end of playlist.
@@ -161,6 +185,14 @@ impl PlayPosition {
 	}
 }
 
+/// Whether a song is played. A paused song continues at the same position, a stopped song starts from the beginning.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PlayStatus {
+	Playing,
+	Paused,
+	Stopped,
+}
+
 #[derive(Copy, Clone, Serialize, Deserialize)]
 pub enum PlayMode {
 	Normal,
@@ -147,7 +147,7 @@ impl PlaylistManager {
 		}
 	}
 
-	pub fn get_render_object(&self, play_state: &PlayState, song_buffer: &SongBuffer) -> RenderObject {
+	pub fn get_render_object(&self, play_state: &PlayState, song_buffer: &SongBuffer, prefer_tag_titles: bool) -> RenderObject {
 		let mut render_object = RenderObject::new(Alignment::Left);
 
 		// add overview panel
@@ -207,8 +207,10 @@ impl PlaylistManager {
 					}
 				};
 				let song = song_buffer.get(*song_id).unwrap();
+				// songs, that could not be opened, are marked red
+				let foreground_color = if song.is_broken() && foreground_color != RenderColor::Yellow { RenderColor::Red } else { foreground_color };
 				songs_panel.entries.push(RenderEntry::new(
-					song.get_title().to_string(),
+					song.get_display_title(prefer_tag_titles).to_string(),
 					foreground_color,
 					background_color
 				));
@@ -306,6 +308,7 @@ impl PlaylistManager {
 			id,
 			name,
 			songs,
+			speed: None,
 		});
 		id
 	}
@@ -0,0 +1,236 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::song::{Loudness, TrackRange};
+
+/// Positions in CUE sheets are given in frames of a CD, with 75 frames per second.
+const CUE_FRAMES_PER_SECOND: u64 = 75;
+
+/// A track of a CUE sheet, that is a part of an audio file.
+#[derive(Debug, PartialEq)]
+pub struct CueTrack {
+	pub number: u32,
+	pub title: Option<String>,
+	/// the performer of this track, if it differs from the performer of the album
+	pub performer: Option<String>,
+	/// the audio file, that contains this track
+	pub path: PathBuf,
+	pub range: TrackRange,
+	/// from the REM REPLAYGAIN lines of the CUE sheet
+	pub loudness: Option<Loudness>,
+}
+
+impl CueTrack {
+	/// Returns the title of the song of this track, like "02 Performer - Title".
+	pub fn get_song_title(&self) -> String {
+		let title = self.title.clone().unwrap_or_else(|| "<no title>".to_string());
+		match &self.performer {
+			Some(performer) => format!("{:02} {} - {}", self.number, performer, title),
+			None => format!("{:02} {}", self.number, title),
+		}
+	}
+}
+
+/// Returns whether the given file is a CUE sheet, judged by its extension.
+pub fn is_cue_file(path: &Path) -> bool {
+	path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("cue"))
+}
+
+/// Reads the CUE sheet at path and returns its tracks in order.
+/// Audio files are searched relative to the directory of the CUE sheet.
+pub fn read_cue_sheet(path: &Path) -> Option<Vec<CueTrack>> {
+	let content = fs::read(path).ok()?;
+	// CUE sheets are often not UTF-8 encoded
+	let content = String::from_utf8_lossy(&content);
+	let directory = path.parent().unwrap_or_else(|| Path::new(""));
+	Some(parse_cue_sheet(&content, directory))
+}
+
+/// Parses the content of a CUE sheet. Tracks end, where the next track in the same file starts.
+pub fn parse_cue_sheet(content: &str, directory: &Path) -> Vec<CueTrack> {
+	let mut tracks: Vec<CueTrack> = Vec::new();
+	let mut current_file: Option<PathBuf> = None;
+	let mut album_gain = None;
+	let mut album_peak = None;
+	// the track, whose lines are parsed at the moment. None before the first TRACK line.
+	let mut current_track: Option<CueTrack> = None;
+
+	for line in content.trim_start_matches('\u{feff}').lines() {
+		let line = line.trim();
+		let (command, arguments) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+		let arguments = arguments.trim();
+		match command.to_ascii_uppercase().as_str() {
+			"FILE" => {
+				finish_track(&mut tracks, current_track.take());
+				// the last word is the type of the file (e.g. WAVE)
+				let name = arguments.rsplit_once(char::is_whitespace).map_or(arguments, |(name, _)| name);
+				current_file = Some(find_audio_file(directory, unquote(name)));
+			}
+			"TRACK" => {
+				finish_track(&mut tracks, current_track.take());
+				if let Some(path) = &current_file {
+					let number = arguments.split_whitespace().next().and_then(|n| n.parse().ok()).unwrap_or(tracks.len() as u32 + 1);
+					current_track = Some(CueTrack {
+						number,
+						title: None,
+						performer: None,
+						path: path.clone(),
+						range: TrackRange { start: Duration::ZERO, end: None },
+						loudness: None,
+					});
+				}
+			}
+			"TITLE" => {
+				if let Some(track) = &mut current_track {
+					track.title = Some(unquote(arguments).to_string());
+				}
+			}
+			"PERFORMER" => {
+				if let Some(track) = &mut current_track {
+					track.performer = Some(unquote(arguments).to_string());
+				}
+			}
+			"INDEX" => {
+				let mut parts = arguments.split_whitespace();
+				if let (Some(track), Some("01"), Some(time)) = (&mut current_track, parts.next(), parts.next()) {
+					if let Some(start) = parse_cue_time(time) {
+						track.range.start = start;
+					}
+				}
+			}
+			"REM" => {
+				let mut parts = arguments.split_whitespace();
+				let key = parts.next().unwrap_or("").to_ascii_uppercase();
+				let value = parts.next().and_then(|value| value.parse::<f32>().ok());
+				match (key.as_str(), &mut current_track) {
+					("REPLAYGAIN_ALBUM_GAIN", _) => album_gain = value,
+					("REPLAYGAIN_ALBUM_PEAK", _) => album_peak = value,
+					("REPLAYGAIN_TRACK_GAIN", Some(track)) => if let Some(track_gain) = value {
+						track.loudness = Some(Loudness { track_gain, track_peak: None, album_gain: None, album_peak: None });
+					}
+					("REPLAYGAIN_TRACK_PEAK", Some(track)) => if let Some(loudness) = &mut track.loudness {
+						loudness.track_peak = value;
+					}
+					_ => {}
+				}
+			}
+			_ => {}
+		}
+	}
+	finish_track(&mut tracks, current_track);
+
+	for track in tracks.iter_mut() {
+		if let Some(loudness) = &mut track.loudness {
+			loudness.album_gain = album_gain;
+			loudness.album_peak = album_peak;
+		}
+	}
+	tracks
+}
+
+/// Adds the track to tracks and lets the previous track in the same file end at its start.
+fn finish_track(tracks: &mut Vec<CueTrack>, track: Option<CueTrack>) {
+	if let Some(track) = track {
+		if let Some(previous) = tracks.last_mut() {
+			if previous.path == track.path {
+				previous.range.end = Some(track.range.start);
+			}
+		}
+		tracks.push(track);
+	}
+}
+
+/// Parses a time in the format mm:ss:ff, where ff are frames of 1/75 seconds.
+fn parse_cue_time(time: &str) -> Option<Duration> {
+	let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+	let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+	let frames = (minutes * 60 + seconds) * CUE_FRAMES_PER_SECOND + frames;
+	Some(Duration::from_micros(frames * 1_000_000 / CUE_FRAMES_PER_SECOND))
+}
+
+fn unquote(text: &str) -> &str {
+	text.trim().trim_matches('"')
+}
+
+/// Returns the path of the referenced audio file. CUE sheets often reference the file, the album was ripped to
+/// (e.g. a .wav file), while the album was converted afterwards. In this case a file with the same name but another extension is used.
+fn find_audio_file(directory: &Path, name: &str) -> PathBuf {
+	let path = directory.join(name);
+	if path.is_file() {
+		return path;
+	}
+	let stem = path.file_stem().map(|stem| stem.to_os_string());
+	let replacement = fs::read_dir(directory).ok().and_then(|read_dir| {
+		read_dir.flatten()
+			.map(|entry| entry.path())
+			.find(|candidate| candidate.is_file() && !is_cue_file(candidate) && candidate.file_stem().map(|s| s.to_os_string()) == stem)
+	});
+	replacement.unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CUE_SHEET: &str = "\u{feff}REM GENRE Rock
+REM REPLAYGAIN_ALBUM_GAIN -7.50 dB
+PERFORMER \"The Band\"
+TITLE \"The Album\"
+FILE \"album.flac\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"First\"
+    REM REPLAYGAIN_TRACK_GAIN -6.25 dB
+    REM REPLAYGAIN_TRACK_PEAK 0.950000
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Second\"
+    PERFORMER \"Guest\"
+    INDEX 00 03:58:50
+    INDEX 01 04:00:00
+  TRACK 03 AUDIO
+    TITLE \"Third\"
+    INDEX 01 07:30:37
+";
+
+	#[test]
+	fn test_parse_cue_sheet() {
+		let tracks = parse_cue_sheet(CUE_SHEET, Path::new("/music"));
+		assert_eq!(tracks.len(), 3);
+
+		assert_eq!(tracks[0].title.as_deref(), Some("First"));
+		assert_eq!(tracks[0].get_song_title(), "01 First");
+		assert_eq!(tracks[0].path, Path::new("/music/album.flac"));
+		assert_eq!(tracks[0].range, TrackRange { start: Duration::ZERO, end: Some(Duration::from_secs(240)) });
+		let loudness = tracks[0].loudness.unwrap();
+		assert_eq!(loudness.track_gain, -6.25);
+		assert_eq!(loudness.track_peak, Some(0.95));
+		assert_eq!(loudness.album_gain, Some(-7.5));
+
+		assert_eq!(tracks[1].number, 2);
+		assert_eq!(tracks[1].get_song_title(), "02 Guest - Second");
+		assert_eq!(tracks[1].range.start, Duration::from_secs(240));
+		assert_eq!(tracks[1].range.end, Some(Duration::from_micros(450_493_333)));
+		assert!(tracks[1].loudness.is_none());
+
+		// the last track ends with the file
+		assert_eq!(tracks[2].range.end, None);
+	}
+
+	#[test]
+	fn test_parse_multiple_files() {
+		let content = "FILE \"a.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\nFILE \"b.wav\" WAVE\nTRACK 02 AUDIO\nINDEX 01 00:00:00\n";
+		let tracks = parse_cue_sheet(content, Path::new(""));
+		assert_eq!(tracks.len(), 2);
+		// tracks in different files do not end each other
+		assert_eq!(tracks[0].range.end, None);
+		assert_eq!(tracks[1].path, Path::new("b.wav"));
+	}
+
+	#[test]
+	fn test_parse_cue_time() {
+		assert_eq!(parse_cue_time("01:02:15"), Some(Duration::from_millis(62_200)));
+		assert_eq!(parse_cue_time("01:02"), None);
+		assert_eq!(parse_cue_time("xx:00:00"), None);
+	}
+}
@@ -0,0 +1,61 @@
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+use twox_hash::XxHash64;
+
+/// The number of bytes, that are hashed at the start and at the end of a file.
+const HASHED_BYTES: u64 = 64 * 1024;
+
+/// Identifies the content of a file without reading all of it. Used to find songs, whose file was moved.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+	pub size: u64,
+	pub hash: u64, // of the first and last HASHED_BYTES of the file
+}
+
+impl Fingerprint {
+	pub fn of_file(path: &Path) -> io::Result<Fingerprint> {
+		let mut file = File::open(path)?;
+		let size = file.metadata()?.len();
+		let mut hasher = XxHash64::with_seed(0);
+		let mut bytes = Vec::new();
+		(&mut file).take(HASHED_BYTES).read_to_end(&mut bytes)?;
+		// the end of the file is read, too. Tags at the start of files are changed more often than the audio.
+		if size > HASHED_BYTES {
+			file.seek(SeekFrom::Start((size - HASHED_BYTES).max(HASHED_BYTES)))?;
+			file.take(HASHED_BYTES).read_to_end(&mut bytes)?;
+		}
+		hasher.write(&bytes);
+		Ok(Fingerprint { size, hash: hasher.finish() })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::*;
+
+	#[test]
+	fn test_fingerprint_of_moved_file() {
+		let directory = std::env::temp_dir().join(format!("musicus_test_fingerprint_{}", std::process::id()));
+		fs::create_dir_all(&directory).unwrap();
+		let content: Vec<u8> = (0..HASHED_BYTES * 3).map(|index| (index % 251) as u8).collect();
+		let mut changed_end = content.clone();
+		*changed_end.last_mut().unwrap() += 1;
+		fs::write(directory.join("a.mp3"), &content).unwrap();
+		fs::write(directory.join("b.mp3"), &content).unwrap();
+		fs::write(directory.join("c.mp3"), &changed_end).unwrap();
+		let fingerprints: Vec<Fingerprint> = ["a.mp3", "b.mp3", "c.mp3"].iter()
+			.map(|name| Fingerprint::of_file(&directory.join(name)).unwrap())
+			.collect();
+		let _ = fs::remove_dir_all(&directory);
+
+		assert_eq!(fingerprints[0], fingerprints[1]);
+		assert_eq!(fingerprints[0].size, HASHED_BYTES * 3);
+		assert_ne!(fingerprints[0], fingerprints[2]);
+	}
+}
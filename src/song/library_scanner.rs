@@ -0,0 +1,368 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::{select, unbounded, Receiver, Sender};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::event::{AccessKind, AccessMode, CreateKind, ModifyKind, RenameMode};
+
+use crate::audio_backend::format::AudioFormat;
+use crate::file_manager::file_utils::get_dir_entries;
+use crate::song::cue::{is_cue_file, read_cue_sheet, CueTrack};
+use crate::song::fingerprint::Fingerprint;
+use crate::song::DirectorySongs;
+
+/// A file, that was moved away, is counted as removed, if it does not appear in a watched directory within this time.
+const RENAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What the library scanner found in the music roots.
+pub enum LibraryEvent {
+	Found(ScannedFile), // an audio file, that is not part of a CUE sheet
+	FoundTracks(Vec<CueTrack>), // the tracks of a CUE sheet
+	Removed(PathBuf), // a file or a directory with all its files
+	Moved(PathBuf, PathBuf), // from, to. A file or a directory with all its files
+	ScanFinished(usize), // the number of found songs
+	Located(Vec<(PathBuf, PathBuf)>), // the missing files, that were found in the music roots, with their new path
+	Error(String),
+}
+
+pub struct ScannedFile {
+	pub path: PathBuf,
+	/// the title derived from the names of all files in the directory
+	pub title: Option<String>,
+	/// only computed for files, that are not known yet. Used to find songs, whose file was moved.
+	pub fingerprint: Option<Fingerprint>,
+}
+
+enum ScannerCommand {
+	SetRoots(Vec<PathBuf>, HashSet<PathBuf>), // the music roots, the paths of the known songs
+	Locate(Vec<(PathBuf, Option<Fingerprint>)>), // the missing files with their fingerprint, if it is known
+}
+
+/// Walks the music roots in a background thread and watches them afterwards, so added, moved and removed files are found.
+pub struct LibraryScanner {
+	command_sender: Sender<ScannerCommand>,
+	event_receiver: Receiver<LibraryEvent>,
+}
+
+impl LibraryScanner {
+	pub fn new() -> LibraryScanner {
+		let (command_sender, command_receiver) = unbounded();
+		let (event_sender, event_receiver) = unbounded();
+		thread::Builder::new().name("library scanner".to_string()).spawn(move || {
+			ScannerThread::new(event_sender).run(command_receiver);
+		}).expect("Failed to spawn library scanner thread");
+		LibraryScanner {
+			command_sender,
+			event_receiver,
+		}
+	}
+
+	/// Scans the given roots and watches them instead of the previous ones. Fingerprints are only computed for files, that are not in known_paths.
+	pub fn set_roots(&self, roots: Vec<PathBuf>, known_paths: HashSet<PathBuf>) {
+		let _ = self.command_sender.send(ScannerCommand::SetRoots(roots, known_paths));
+	}
+
+	/// Searches the music roots for the given missing files. The result is sent as LibraryEvent::Located.
+	pub fn locate_files(&self, files: Vec<(PathBuf, Option<Fingerprint>)>) {
+		let _ = self.command_sender.send(ScannerCommand::Locate(files));
+	}
+
+	/// Returns the events, that happened since the last call.
+	pub fn receive_events(&self) -> Vec<LibraryEvent> {
+		self.event_receiver.try_iter().collect()
+	}
+}
+
+struct ScannerThread {
+	event_sender: Sender<LibraryEvent>,
+	roots: Vec<PathBuf>,
+	known_paths: HashSet<PathBuf>,
+	/// files and directories, that were moved away, with the tracker of the rename and the time of the move
+	pending_renames: Vec<(PathBuf, usize, Instant)>,
+}
+
+impl ScannerThread {
+	fn new(event_sender: Sender<LibraryEvent>) -> ScannerThread {
+		ScannerThread {
+			event_sender,
+			roots: Vec::new(),
+			known_paths: HashSet::new(),
+			pending_renames: Vec::new(),
+		}
+	}
+
+	fn run(mut self, command_receiver: Receiver<ScannerCommand>) {
+		let (watch_sender, watch_receiver) = unbounded();
+		let mut watcher = match notify::recommended_watcher(move |event| { let _ = watch_sender.send(event); }) {
+			Ok(watcher) => Some(watcher),
+			Err(e) => {
+				self.send(LibraryEvent::Error(format!("Failed to watch music roots: {}", e)));
+				None
+			}
+		};
+
+		loop {
+			select! {
+				recv(command_receiver) -> command => match command {
+					Ok(ScannerCommand::SetRoots(roots, known_paths)) => {
+						self.known_paths = known_paths;
+						self.set_roots(roots, watcher.as_mut());
+					}
+					Ok(ScannerCommand::Locate(files)) => self.send(LibraryEvent::Located(locate_files(&files, &self.roots))),
+					Err(_) => break,
+				},
+				recv(watch_receiver) -> event => match event {
+					Ok(Ok(event)) => self.handle_event(event),
+					Ok(Err(e)) => self.send(LibraryEvent::Error(format!("Failed to watch music roots: {}", e))),
+					Err(_) => {}
+				},
+				default(RENAME_TIMEOUT) => {}
+			}
+			self.remove_expired_renames();
+		}
+	}
+
+	fn send(&self, event: LibraryEvent) {
+		let _ = self.event_sender.send(event);
+	}
+
+	fn set_roots(&mut self, roots: Vec<PathBuf>, mut watcher: Option<&mut RecommendedWatcher>) {
+		// events are reported with the paths of the watched directories, so roots are watched with their canonical path
+		let roots: Vec<PathBuf> = roots.iter().filter_map(|root| root.canonicalize().ok()).collect();
+		if let Some(watcher) = watcher.as_mut() {
+			for root in self.roots.iter().filter(|root| !roots.contains(root)) {
+				let _ = watcher.unwatch(root);
+			}
+		}
+		let mut num_songs = 0;
+		let mut visited = HashSet::new();
+		for root in &roots {
+			if let Some(watcher) = watcher.as_mut() {
+				if !self.roots.contains(root) {
+					if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+						self.send(LibraryEvent::Error(format!("Failed to watch \"{}\": {}", root.display(), e)));
+					}
+				}
+			}
+			num_songs += self.scan_directory(root, &mut visited);
+		}
+		self.roots = roots;
+		self.send(LibraryEvent::ScanFinished(num_songs));
+	}
+
+	/// Finds the songs in the given directory and its sub directories like Song::songs_from_path. Returns the number of found songs.
+	fn scan_directory(&mut self, path: &Path, visited: &mut HashSet<PathBuf>) -> usize {
+		// symlinks could lead to a directory, that is scanned already
+		if !visited.insert(path.canonicalize().unwrap_or_else(|_| path.to_path_buf())) {
+			return 0;
+		}
+		let directory_songs = DirectorySongs::read(path);
+
+		let mut num_songs = 0;
+		for tracks in directory_songs.cue_tracks {
+			num_songs += tracks.len();
+			self.send(LibraryEvent::FoundTracks(tracks));
+		}
+		for (sound_file, title) in directory_songs.sound_files {
+			self.found_file(&sound_file, Some(title));
+			num_songs += 1;
+		}
+
+		for sub_directory in &directory_songs.sub_directories {
+			num_songs += self.scan_directory(sub_directory, visited);
+		}
+		num_songs
+	}
+
+	/// Scans a file or directory, that was added to a music root.
+	fn scan_path(&mut self, path: &Path) {
+		if path.is_dir() {
+			self.scan_directory(path, &mut HashSet::new());
+		} else if is_cue_file(path) {
+			self.send(LibraryEvent::FoundTracks(read_cue_sheet(path).unwrap_or_default()));
+		} else if path.is_file() && AudioFormat::guess(path).is_some() {
+			self.found_file(path, None);
+		}
+	}
+
+	fn found_file(&mut self, path: &Path, title: Option<String>) {
+		let fingerprint = if self.known_paths.insert(path.to_path_buf()) {
+			Fingerprint::of_file(path).ok()
+		} else {
+			None
+		};
+		self.send(LibraryEvent::Found(ScannedFile { path: path.to_path_buf(), title, fingerprint }));
+	}
+
+	fn handle_event(&mut self, event: Event) {
+		let path = match event.paths.first() {
+			Some(path) => path.clone(),
+			None => return,
+		};
+		match event.kind {
+			EventKind::Create(CreateKind::Folder) => self.scan_path(&path),
+			// files are scanned, when they are written completely. Their fingerprint changed, if they were known before.
+			EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+				self.known_paths.remove(&path);
+				self.scan_path(&path);
+			}
+			EventKind::Remove(_) => {
+				self.known_paths.retain(|known_path| !known_path.starts_with(&path));
+				self.send(LibraryEvent::Removed(path));
+			}
+			EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+				// moves of watched directories themselves are reported without tracker. Their parent reports them, too.
+				if let Some(tracker) = event.tracker() {
+					self.pending_renames.push((path, tracker, Instant::now()));
+				}
+			}
+			EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+				let rename_index = self.pending_renames.iter().position(|(_, tracker, _)| Some(*tracker) == event.tracker());
+				match rename_index {
+					Some(rename_index) => {
+						let (from, _, _) = self.pending_renames.remove(rename_index);
+						self.known_paths = self.known_paths.drain()
+							.map(|known_path| match known_path.strip_prefix(&from) {
+								Ok(rest) if rest.as_os_str().is_empty() => path.clone(),
+								Ok(rest) => path.join(rest),
+								Err(_) => known_path,
+							})
+							.collect();
+						self.send(LibraryEvent::Moved(from, path));
+					}
+					// moved here from a directory, that is not watched
+					None => self.scan_path(&path),
+				}
+			}
+			_ => {}
+		}
+	}
+
+	/// Files, that were moved out of the music roots, are removed.
+	fn remove_expired_renames(&mut self) {
+		let (expired, pending): (Vec<_>, Vec<_>) = self.pending_renames.drain(..).partition(|(_, _, time)| time.elapsed() >= RENAME_TIMEOUT);
+		self.pending_renames = pending;
+		for (path, _, _) in expired {
+			self.known_paths.retain(|known_path| !known_path.starts_with(&path));
+			self.send(LibraryEvent::Removed(path));
+		}
+	}
+}
+
+/// Searches the roots for the given missing files. Files with a known fingerprint are found by their size and content, even if
+/// they were renamed. Other files are found by their filename, if only one file in the roots has this name.
+/// Returns the found files with their new path.
+fn locate_files(files: &[(PathBuf, Option<Fingerprint>)], roots: &[PathBuf]) -> Vec<(PathBuf, PathBuf)> {
+	let mut candidates = Vec::new();
+	let mut visited = HashSet::new();
+	for root in roots {
+		collect_files(root, &mut visited, &mut candidates);
+	}
+	let mut by_name: HashMap<OsString, Vec<&PathBuf>> = HashMap::new();
+	let mut by_size: HashMap<u64, Vec<&PathBuf>> = HashMap::new();
+	for (path, size) in &candidates {
+		if let Some(file_name) = path.file_name() {
+			by_name.entry(file_name.to_os_string()).or_default().push(path);
+		}
+		by_size.entry(*size).or_default().push(path);
+	}
+
+	let mut fingerprints: HashMap<&PathBuf, Option<Fingerprint>> = HashMap::new();
+	let mut located = Vec::new();
+	for (path, fingerprint) in files {
+		let new_path = match fingerprint {
+			Some(fingerprint) => {
+				let mut same_size = by_size.get(&fingerprint.size).cloned().unwrap_or_default();
+				// files with the same name are checked first
+				same_size.sort_by_key(|candidate| candidate.file_name() != path.file_name());
+				same_size.into_iter().find(|candidate| {
+					*fingerprints.entry(candidate).or_insert_with(|| Fingerprint::of_file(candidate).ok()) == Some(*fingerprint)
+				})
+			}
+			None => match path.file_name().and_then(|file_name| by_name.get(file_name)) {
+				Some(same_name) if same_name.len() == 1 => Some(same_name[0]),
+				_ => None,
+			},
+		};
+		if let Some(new_path) = new_path {
+			located.push((path.clone(), new_path.clone()));
+		}
+	}
+	located
+}
+
+/// Adds the files in the given directory and its sub directories with their size to files.
+fn collect_files(path: &Path, visited: &mut HashSet<PathBuf>, files: &mut Vec<(PathBuf, u64)>) {
+	if !visited.insert(path.canonicalize().unwrap_or_else(|_| path.to_path_buf())) {
+		return;
+	}
+	for dir_entry in get_dir_entries(path) {
+		if dir_entry.is_file {
+			if let Ok(metadata) = dir_entry.path.metadata() {
+				files.push((dir_entry.path, metadata.len()));
+			}
+		} else {
+			collect_files(&dir_entry.path, visited, files);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::*;
+
+	fn rename_event(mode: RenameMode, path: &str, tracker: usize) -> Event {
+		Event::new(EventKind::Modify(ModifyKind::Name(mode))).add_path(PathBuf::from(path)).set_tracker(tracker)
+	}
+
+	#[test]
+	fn test_renames_are_paired() {
+		let (event_sender, event_receiver) = unbounded();
+		let mut scanner = ScannerThread::new(event_sender);
+		scanner.known_paths.insert(PathBuf::from("/music/old/a.mp3"));
+		scanner.handle_event(rename_event(RenameMode::From, "/music/old", 1));
+		scanner.handle_event(rename_event(RenameMode::From, "/music/b.mp3", 2));
+		scanner.handle_event(rename_event(RenameMode::To, "/music/new", 1));
+		scanner.remove_expired_renames();
+
+		let events: Vec<LibraryEvent> = event_receiver.try_iter().collect();
+		assert!(matches!(&events[..], [LibraryEvent::Moved(from, to)] if from == Path::new("/music/old") && to == Path::new("/music/new")));
+		assert!(scanner.known_paths.contains(Path::new("/music/new/a.mp3")));
+
+		// files, that are not moved to a watched directory, are removed
+		scanner.pending_renames[0].2 -= RENAME_TIMEOUT;
+		scanner.remove_expired_renames();
+		assert!(matches!(event_receiver.try_recv(), Ok(LibraryEvent::Removed(path)) if path == Path::new("/music/b.mp3")));
+	}
+
+	#[test]
+	fn test_locate_files() {
+		let root = std::env::temp_dir().join(format!("musicus_test_locate_{}", std::process::id()));
+		fs::create_dir_all(root.join("album")).unwrap();
+		fs::write(root.join("album").join("renamed.mp3"), b"first song").unwrap();
+		fs::write(root.join("album").join("second.mp3"), b"second song").unwrap();
+		fs::write(root.join("other.mp3"), b"other song").unwrap();
+		fs::write(root.join("album").join("other.mp3"), b"another song").unwrap();
+		let fingerprint = Fingerprint::of_file(&root.join("album").join("renamed.mp3")).unwrap();
+
+		let files = vec![
+			(PathBuf::from("/mnt/old/first.mp3"), Some(fingerprint)),
+			(PathBuf::from("/mnt/old/second.mp3"), None),
+			// ambiguous filename
+			(PathBuf::from("/mnt/old/other.mp3"), None),
+			(PathBuf::from("/mnt/old/unknown.mp3"), Some(Fingerprint { size: 10, hash: 1 })),
+		];
+		let located = locate_files(&files, std::slice::from_ref(&root));
+		let _ = fs::remove_dir_all(&root);
+
+		assert_eq!(located, vec![
+			(PathBuf::from("/mnt/old/first.mp3"), root.join("album").join("renamed.mp3")),
+			(PathBuf::from("/mnt/old/second.mp3"), root.join("album").join("second.mp3")),
+		]);
+	}
+}
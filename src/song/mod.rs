@@ -1,5 +1,9 @@
 pub mod song_buffer;
 pub mod playlist;
+pub mod cue;
+pub mod tags;
+pub mod fingerprint;
+pub mod library_scanner;
 
 use std::ffi::OsString;
 use std::path::{PathBuf, Path};
@@ -8,7 +12,11 @@ use std::fmt::{Debug, Formatter};
 use serde::{Serialize, Deserialize};
 use crate::playlist_manager::normalize_title;
 use crate::song::song_buffer::SongBuffer;
+use crate::song::cue::{is_cue_file, read_cue_sheet, CueTrack};
 use std::time::Duration;
+use crate::audio_backend::format::AudioFormat;
+use crate::song::tags::SongTags;
+use crate::song::fingerprint::Fingerprint;
 
 pub type SongID = u32;
 
@@ -18,6 +26,49 @@ pub struct Song {
 	title: String,
 	path: PathBuf,
 	total_duration: Option<Duration>,
+	#[serde(default)]
+	loudness: Option<Loudness>,
+	/// the playback speed of this song, if it was changed for this song
+	#[serde(default)]
+	speed: Option<f32>,
+	/// named positions in the song, sorted by position
+	#[serde(default)]
+	bookmarks: Vec<Bookmark>,
+	/// the part of the file, that is this song. Set for tracks of a CUE sheet, which share one file.
+	#[serde(default)]
+	track_range: Option<TrackRange>,
+	/// the song could not be opened, the last time it was played
+	#[serde(default)]
+	broken: bool,
+	/// the metadata read from the file. None, if the file was not scanned yet.
+	#[serde(default)]
+	tags: Option<Box<SongTags>>,
+	/// identifies the content of the file, so the song can be found, if its file is moved
+	#[serde(default)]
+	fingerprint: Option<Fingerprint>,
+}
+
+/// The part of an audio file, that belongs to one song.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TrackRange {
+	pub start: Duration,
+	pub end: Option<Duration>, // None, if the song ends with the file
+}
+
+/// The gains of a song, that normalize its loudness. Read from ReplayGain tags or measured by the audio backend.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Loudness {
+	pub track_gain: f32, // in dB
+	pub track_peak: Option<f32>, // highest absolute sample value
+	pub album_gain: Option<f32>,
+	pub album_peak: Option<f32>,
+}
+
+/// A named position in a song, that can be jumped to.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Bookmark {
+	pub name: String,
+	pub position: Duration, // time since the start of the song
 }
 
 impl Song {
@@ -33,10 +84,22 @@ impl Song {
 		self.title = String::from(title);
 	}
 
+	/// Returns the title of the tags, if it should be preferred and is known. Otherwise the title derived from the filename.
+	pub fn get_display_title(&self, prefer_tag_titles: bool) -> &str {
+		match self.tags.as_ref().and_then(|tags| tags.title.as_deref()) {
+			Some(tag_title) if prefer_tag_titles => tag_title,
+			_ => &self.title,
+		}
+	}
+
 	pub fn get_path(&self) -> &Path {
 		&self.path
 	}
 
+	pub fn get_track_range(&self) -> Option<TrackRange> {
+		self.track_range
+	}
+
 	pub fn get_total_duration(&self) -> Option<Duration> {
 		self.total_duration
 	}
@@ -45,41 +108,126 @@ impl Song {
 		self.total_duration = Some(duration);
 	}
 
-	pub fn songs_from_path(path: &Path, song_buffer: &mut SongBuffer) -> Vec<SongID> {
-		let dir_entries = get_dir_entries(path);
-		let sound_files: Vec<&DirectoryEntry> = dir_entries.iter().filter(|de| de.is_song_file()).collect();
-		let sub_directories: Vec<&DirectoryEntry> = dir_entries.iter().filter(|de| !de.is_file).collect();
+	pub fn get_loudness(&self) -> Option<&Loudness> {
+		self.loudness.as_ref()
+	}
 
-		let mut songs = Song::songs_from_sound_files(sound_files, song_buffer);
+	pub fn update_loudness(&mut self, loudness: Loudness) {
+		self.loudness = Some(loudness);
+	}
 
-		for sub_directory in sub_directories {
-			songs.extend(Song::songs_from_path(&sub_directory.path, song_buffer));
-		}
+	pub fn get_speed(&self) -> Option<f32> {
+		self.speed
+	}
 
-		songs
+	pub fn set_speed(&mut self, speed: Option<f32>) {
+		self.speed = speed;
+	}
+
+	pub fn is_broken(&self) -> bool {
+		self.broken
+	}
+
+	pub fn set_broken(&mut self, broken: bool) {
+		self.broken = broken;
 	}
 
-	pub fn songs_from_sound_files(sound_files: Vec<&DirectoryEntry>, song_buffer: &mut SongBuffer) -> Vec<SongID> {
-		let (mut start, mut end) = ("", "");
-		// matching same name parts only makes sense for more than one song
-		if sound_files.len() > 1 {
-			(start, end) = get_common_ends(sound_files.iter().map(|de| &*de.filename)).unwrap();
+	/// Returns whether the file of this song exists and has a format, that can be decoded.
+	pub fn is_readable(&self) -> bool {
+		AudioFormat::sniff(&self.path).is_some_and(|format| format.is_supported())
+	}
+
+	pub fn get_tags(&self) -> Option<&SongTags> {
+		self.tags.as_deref()
+	}
+
+	/// Sets the tags read from the file of this song. Tracks of CUE sheets only keep the tags, that describe the whole album.
+	pub fn set_tags(&mut self, tags: SongTags) {
+		self.tags = Some(Box::new(if self.track_range.is_some() { tags.for_track() } else { tags }));
+	}
+
+	pub fn get_fingerprint(&self) -> Option<Fingerprint> {
+		self.fingerprint
+	}
+
+	pub fn get_bookmarks(&self) -> &[Bookmark] {
+		&self.bookmarks
+	}
+
+	/// Adds the given bookmark. The bookmarks stay sorted by position.
+	pub fn add_bookmark(&mut self, bookmark: Bookmark) {
+		let index = self.bookmarks.partition_point(|b| b.position <= bookmark.position);
+		self.bookmarks.insert(index, bookmark);
+	}
+
+	pub fn remove_bookmark(&mut self, index: usize) {
+		if index < self.bookmarks.len() {
+			self.bookmarks.remove(index);
 		}
+	}
 
-		let mut songs = Vec::new();
+	pub fn songs_from_path(path: &Path, song_buffer: &mut SongBuffer) -> Vec<SongID> {
+		let directory_songs = DirectorySongs::read(path);
 
-		for (index, sound_file) in sound_files.iter().enumerate() {
-			let title = &sound_file.filename[start.len()..sound_file.filename.len()-end.len()];
-			let title = normalize_title(title, index+1);
+		let mut songs: Vec<SongID> = directory_songs.cue_tracks.iter().flatten()
+			.map(|track| song_buffer.import_track(track))
+			.collect();
+		for (sound_file, title) in &directory_songs.sound_files {
+			songs.push(song_buffer.import(sound_file, Some(title)));
+		}
 
-			let id = song_buffer.import(&sound_file.path, Some(&title));
+		for sub_directory in &directory_songs.sub_directories {
+			songs.extend(Song::songs_from_path(sub_directory, song_buffer));
+		}
 
-			songs.push(id);
-		};
 		songs
 	}
 }
 
+/// The songs of one directory without the songs of its sub directories.
+pub struct DirectorySongs {
+	/// the tracks of the CUE sheets in the directory, one list per CUE sheet
+	pub cue_tracks: Vec<Vec<CueTrack>>,
+	/// the sound files, that are not part of a CUE sheet, with their titles
+	pub sound_files: Vec<(PathBuf, String)>,
+	pub sub_directories: Vec<PathBuf>,
+}
+
+impl DirectorySongs {
+	pub fn read(path: &Path) -> DirectorySongs {
+		let dir_entries = get_dir_entries(path);
+
+		// the tracks of CUE sheets replace the audio files, they refer to
+		let cue_tracks: Vec<Vec<CueTrack>> = dir_entries.iter()
+			.filter(|de| de.is_file && is_cue_file(&de.path))
+			.map(|cue_file| read_cue_sheet(&cue_file.path).unwrap_or_default())
+			.collect();
+		let sound_files: Vec<&DirectoryEntry> = dir_entries.iter()
+			.filter(|de| de.is_song_file() && !cue_tracks.iter().flatten().any(|track| track.path == de.path))
+			.collect();
+		let filenames: Vec<&str> = sound_files.iter().map(|de| &*de.filename).collect();
+		let sound_files = sound_files.iter().map(|de| de.path.clone()).zip(titles_from_filenames(&filenames)).collect();
+
+		DirectorySongs {
+			cue_tracks,
+			sound_files,
+			sub_directories: dir_entries.iter().filter(|de| !de.is_file).map(|de| de.path.clone()).collect(),
+		}
+	}
+}
+
+/// Creates the titles of the songs of one directory. Parts, that all filenames share, are removed and track numbers are added.
+pub fn titles_from_filenames(filenames: &[&str]) -> Vec<String> {
+	let (mut start, mut end) = ("", "");
+	// matching same name parts only makes sense for more than one song
+	if filenames.len() > 1 {
+		(start, end) = get_common_ends(filenames.iter().copied()).unwrap();
+	}
+	filenames.iter().enumerate()
+		.map(|(index, filename)| normalize_title(&filename[start.len()..filename.len()-end.len()], index+1))
+		.collect()
+}
+
 pub fn title_from_path(path: &Path) -> String {
 	path.file_name().unwrap_or(&OsString::from("<no filename>")).to_string_lossy().into_owned()
 }
@@ -1,16 +1,20 @@
 use crate::song::SongID;
 use std::path::Path;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter};
+use std::fs::File;
+use std::io::{self, BufReader};
+use crate::file_manager::file_utils::write_json_atomically;
 use serde::{Serialize, Deserialize};
 
 pub type PlaylistID = u32;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Playlist {
 	pub id: PlaylistID,
 	pub name: String,
 	pub songs: Vec<SongID>,
+	/// the playback speed of songs in this playlist, that have no own speed
+	#[serde(default)]
+	pub speed: Option<f32>,
 }
 
 impl Playlist {
@@ -20,15 +24,8 @@ impl Playlist {
 		serde_json::from_reader(reader)
 	}
 
-	pub fn dump_to_file(&self, path: &Path) {
-		let file = OpenOptions::new()
-			.write(true)
-			.truncate(true)
-			.create(true)
-			.open(path)
-			.unwrap();
-		let writer = BufWriter::new(file);
-		serde_json::to_writer_pretty(writer, &self).unwrap();
+	pub fn dump_to_file(&self, path: &Path) -> io::Result<()> {
+		write_json_atomically(path, self)
 	}
 
 	/*
@@ -1,62 +1,217 @@
-use crate::song::{Song, SongID, title_from_path};
-use serde::{Serialize, Deserialize};
-use std::path::Path;
-use std::fs::{OpenOptions, File};
+use crate::song::{Bookmark, Loudness, Song, SongID, TrackRange, title_from_path};
+use crate::song::cue::CueTrack;
+use crate::song::tags::{FileInfo, SongTags};
+use crate::song::fingerprint::Fingerprint;
+use serde::{Serialize, Serializer, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::fs::File;
 use crate::config::get_song_buffer_path;
-use std::io::{BufWriter, BufReader};
+use std::io::{self, BufReader};
+use crate::file_manager::file_utils::write_json_atomically;
 use std::time::Duration;
 
-#[derive(Clone, Serialize, Deserialize)]
+/// All known songs. Songs can be found by id and by path without searching.
+#[derive(Clone, Deserialize)]
+#[serde(from = "StoredSongBuffer")]
 pub struct SongBuffer {
+	songs: HashMap<SongID, Song>,
+	/// the ids of the songs by their canonical path and the part of the file they play
+	path_index: HashMap<(PathBuf, Option<TrackRange>), SongID>,
+	/// the ids of the songs by the content of their file
+	fingerprint_index: HashMap<Fingerprint, Vec<SongID>>,
+	next_id: SongID,
+	/// the songs, that were imported or could have been changed since the last call of take_changed_songs
+	changed_songs: HashSet<SongID>,
+}
+
+/// The format of lib.json. The songs are stored as list sorted by id.
+#[derive(Deserialize)]
+struct StoredSongBuffer {
 	songs: Vec<Song>,
 	next_id: SongID,
+	/// lib.json of older versions stores the paths as they were given by the user
+	#[serde(default)]
+	canonical_paths: bool,
+}
+
+#[derive(Serialize)]
+struct StoredSongBufferRef<'a> {
+	songs: Vec<&'a Song>,
+	next_id: SongID,
+	canonical_paths: bool,
+}
+
+impl From<StoredSongBuffer> for SongBuffer {
+	fn from(stored: StoredSongBuffer) -> SongBuffer {
+		if stored.canonical_paths {
+			return SongBuffer::from_songs(stored.songs, stored.next_id);
+		}
+		// the paths are canonicalized once. Songs on drives, that are not mounted, keep their path and are found by it.
+		let mut songs = stored.songs;
+		for song in &mut songs {
+			song.path = canonicalize(&song.path);
+		}
+		let mut song_buffer = SongBuffer::from_songs(songs, stored.next_id);
+		// all songs are written again, so the canonical paths are stored
+		let song_ids: Vec<SongID> = song_buffer.songs.keys().copied().collect();
+		song_buffer.changed_songs.extend(song_ids);
+		song_buffer
+	}
+}
+
+impl Serialize for SongBuffer {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		let mut songs: Vec<&Song> = self.songs.values().collect();
+		songs.sort_by_key(|song| song.get_id());
+		StoredSongBufferRef { songs, next_id: self.next_id, canonical_paths: true }.serialize(serializer)
+	}
+}
+
+/// Resolves symlinks and relative parts of the given path, so the same file is always imported with the same path.
+/// Paths, that do not exist, are returned unchanged.
+fn canonicalize(path: &Path) -> PathBuf {
+	path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
 }
 
 impl SongBuffer {
 	pub fn new() -> SongBuffer {
 		SongBuffer {
-			songs: Vec::new(),
+			songs: HashMap::new(),
+			path_index: HashMap::new(),
+			fingerprint_index: HashMap::new(),
 			next_id: 0,
+			changed_songs: HashSet::new(),
+		}
+	}
+
+	/// Creates a song buffer of stored songs. The songs are not marked as changed.
+	/// The paths are used as stored, so the files are not accessed and music drives do not have to be mounted.
+	pub fn from_songs(songs: Vec<Song>, next_id: SongID) -> SongBuffer {
+		let mut song_buffer = SongBuffer::new();
+		// songs with the same path as another song are kept, as playlists can refer to them. Imports find the first one.
+		for song in songs {
+			song_buffer.insert(song);
+		}
+		song_buffer.next_id = song_buffer.next_id.max(next_id);
+		song_buffer.changed_songs.clear();
+		song_buffer
+	}
+
+	/// Adds the given song and indexes it. Returns its id. The path of the song should be canonical.
+	fn insert(&mut self, song: Song) -> SongID {
+		let id = song.get_id();
+		self.path_index.entry((song.path.clone(), song.track_range)).or_insert(id);
+		if let Some(fingerprint) = song.fingerprint {
+			self.fingerprint_index.entry(fingerprint).or_default().push(id);
 		}
+		self.next_id = self.next_id.max(id + 1);
+		self.songs.insert(id, song);
+		self.changed_songs.insert(id);
+		id
+	}
+
+	/// Returns the id of the song, that plays the given part of the file at path.
+	/// Stored paths, that could not be canonicalized, are found by the path as given.
+	fn find(&self, path: &Path, track_range: Option<TrackRange>) -> Option<SongID> {
+		self.path_index.get(&(canonicalize(path), track_range))
+			.or_else(|| self.path_index.get(&(path.to_path_buf(), track_range)))
+			.copied()
 	}
 
 	pub fn import(&mut self, path: &Path, title: Option<&str>) -> SongID {
-		if let Some(song) = self.get_mut_by_path(path) {
-			return song.get_id();
+		if let Some(id) = self.find(path, None) {
+			return id;
 		}
-		self.import_new(path, title)
+		self.import_new(&canonicalize(path), title, None)
 	}
 
-	fn import_new(&mut self, path: &Path, title: Option<&str>) -> SongID {
+	fn import_new(&mut self, path: &Path, title: Option<&str>, track_range: Option<TrackRange>) -> SongID {
 		let title = title.map(|t| t.to_string()).unwrap_or_else(|| title_from_path(path));
-		let id = self.next_id;
 		let song = Song {
-			id,
+			id: self.next_id,
 			title,
 			path: path.to_path_buf(),
 			total_duration: None,
+			loudness: None,
+			speed: None,
+			bookmarks: Vec::new(),
+			track_range,
+			broken: false,
+			tags: None,
+			fingerprint: None,
 		};
-		self.next_id += 1;
-		self.songs.push(song);
+		self.insert(song)
+	}
+
+	/// Imports a track of a CUE sheet as song, that plays a part of the audio file of the track.
+	pub fn import_track(&mut self, track: &CueTrack) -> SongID {
+		if let Some(id) = self.find(&track.path, Some(track.range)) {
+			return id;
+		}
+		let id = self.import_new(&canonicalize(&track.path), Some(&track.get_song_title()), Some(track.range));
+		let song = self.get_mut(id).unwrap();
+		song.total_duration = track.range.end.map(|end| end.saturating_sub(track.range.start));
+		song.loudness = track.loudness;
 		id
 	}
 
+	/// Returns the id, that the next imported song gets. All songs have smaller ids.
+	pub fn get_next_id(&self) -> SongID {
+		self.next_id
+	}
+
 	pub fn get(&self, id: SongID) -> Option<&Song> {
-		self.songs.iter().find(|s| s.get_id() == id)
+		self.songs.get(&id)
 	}
 
-	#[allow(unused)]
+	/// Returns the song to change it. The song is marked as changed.
 	pub fn get_mut(&mut self, id: SongID) -> Option<&mut Song> {
-		self.songs.iter_mut().find(|s| s.get_id() == id)
+		let song = self.songs.get_mut(&id)?;
+		self.changed_songs.insert(id);
+		Some(song)
+	}
+
+	/// Removes the song. Playlists must not refer to it anymore.
+	pub fn remove(&mut self, id: SongID) -> Option<Song> {
+		let song = self.songs.remove(&id)?;
+		let key = (song.path.clone(), song.track_range);
+		if self.path_index.get(&key) == Some(&id) {
+			self.path_index.remove(&key);
+			// another song with the same path is found instead
+			if let Some(other_song) = self.songs.values().filter(|other_song| other_song.path == key.0 && other_song.track_range == key.1).min_by_key(|other_song| other_song.id) {
+				self.path_index.insert(key, other_song.id);
+			}
+		}
+		if let Some(song_ids) = song.fingerprint.and_then(|fingerprint| self.fingerprint_index.get_mut(&fingerprint)) {
+			song_ids.retain(|song_id| *song_id != id);
+		}
+		// storages write, that the song is gone
+		self.changed_songs.insert(id);
+		Some(song)
 	}
 
-	#[allow(unused)]
-	pub fn get_by_path(&self,path: &Path) -> Option<&Song> {
-		self.songs.iter().find(|s| s.get_path() == path)
+	#[cfg_attr(not(feature = "sqlite"), allow(unused))]
+	pub fn iter(&self) -> impl Iterator<Item=&Song> {
+		self.songs.values()
 	}
 
-	pub fn get_mut_by_path(&mut self, path: &Path) -> Option<&mut Song> {
-		self.songs.iter_mut().find(|s| s.get_path() == path)
+	/// Marks the given songs as changed again, e.g. if writing them failed.
+	#[cfg_attr(not(feature = "sqlite"), allow(unused))]
+	pub fn mark_changed(&mut self, song_ids: &[SongID]) {
+		self.changed_songs.extend(song_ids);
+	}
+
+	/// Returns the ids of the songs, that were imported or changed since the last call.
+	pub fn take_changed_songs(&mut self) -> Vec<SongID> {
+		let mut song_ids: Vec<SongID> = self.changed_songs.drain().collect();
+		song_ids.sort_unstable();
+		song_ids
+	}
+
+	/// Returns the song, that plays the whole file at path. Tracks of CUE sheets are ignored.
+	pub fn get_by_path(&self, path: &Path) -> Option<&Song> {
+		self.find(path, None).and_then(|id| self.songs.get(&id))
 	}
 
 	pub fn update_total_duration(&mut self, song_id: SongID, duration: Duration) {
@@ -65,15 +220,142 @@ impl SongBuffer {
 		}
 	}
 
-	pub fn dump(&self) {
-		let file = OpenOptions::new()
-			.write(true)
-			.truncate(true)
-			.create(true)
-			.open(get_song_buffer_path())
-			.unwrap();
-		let writer = BufWriter::new(file);
-		serde_json::to_writer_pretty(writer, &self).unwrap();
+	pub fn update_loudness(&mut self, song_id: SongID, loudness: Loudness) {
+		if let Some(song) = self.get_mut(song_id) {
+			song.update_loudness(loudness);
+		}
+	}
+
+	pub fn set_speed(&mut self, song_id: SongID, speed: Option<f32>) {
+		if let Some(song) = self.get_mut(song_id) {
+			song.set_speed(speed);
+		}
+	}
+
+	pub fn set_broken(&mut self, song_id: SongID, broken: bool) {
+		if let Some(song) = self.get_mut(song_id) {
+			song.set_broken(broken);
+		}
+	}
+
+	/// Returns whether the given song should be played. A broken song is played again, when its file can be read again.
+	/// Only songs, whose broken flag changes, are marked as changed.
+	pub fn check_playable(&mut self, song_id: SongID) -> bool {
+		match self.songs.get(&song_id) {
+			Some(song) if song.is_broken() && song.is_readable() => {
+				self.set_broken(song_id, false);
+				true
+			}
+			Some(song) => !song.is_broken(),
+			None => false,
+		}
+	}
+
+	pub fn set_tags(&mut self, song_id: SongID, tags: SongTags) {
+		if let Some(song) = self.get_mut(song_id) {
+			song.set_tags(tags);
+		}
+	}
+
+	/// Stores the information read from the file of the song by the tag scanner.
+	pub fn set_file_info(&mut self, song_id: SongID, file_info: FileInfo) {
+		if let Some(tags) = file_info.tags {
+			self.set_tags(song_id, tags);
+		}
+		// the duration of tracks of CUE sheets is known from the sheet. Durations measured while playing are more exact.
+		if let Some(duration) = file_info.duration {
+			if self.songs.get(&song_id).is_some_and(|song| song.track_range.is_none() && song.total_duration.is_none()) {
+				self.update_total_duration(song_id, duration);
+			}
+		}
+		if let Some(fingerprint) = file_info.fingerprint {
+			self.set_fingerprint(song_id, fingerprint);
+		}
+	}
+
+	pub fn set_fingerprint(&mut self, song_id: SongID, fingerprint: Fingerprint) {
+		let old_fingerprint = match self.songs.get(&song_id) {
+			Some(song) if song.fingerprint != Some(fingerprint) => song.fingerprint,
+			_ => return,
+		};
+		if let Some(song_ids) = old_fingerprint.and_then(|old_fingerprint| self.fingerprint_index.get_mut(&old_fingerprint)) {
+			song_ids.retain(|id| *id != song_id);
+		}
+		self.fingerprint_index.entry(fingerprint).or_default().push(song_id);
+		self.get_mut(song_id).unwrap().fingerprint = Some(fingerprint);
+	}
+
+	/// Returns the former path of a file with the given fingerprint, that does not exist anymore.
+	pub fn find_moved_file(&self, fingerprint: Fingerprint) -> Option<PathBuf> {
+		self.fingerprint_index.get(&fingerprint)?.iter()
+			.filter_map(|id| self.songs.get(id))
+			.map(|song| song.get_path())
+			.find(|path| !path.exists())
+			.map(|path| path.to_path_buf())
+	}
+
+	/// Returns the songs, whose file does not exist.
+	pub fn get_missing_songs(&self) -> Vec<SongID> {
+		let mut song_ids: Vec<SongID> = self.songs.values()
+			.filter(|song| !song.get_path().exists())
+			.map(|song| song.get_id())
+			.collect();
+		song_ids.sort_unstable();
+		song_ids
+	}
+
+	/// Returns the songs, whose file is at path or inside of the directory at path.
+	pub fn get_songs_in(&self, path: &Path) -> Vec<SongID> {
+		let mut song_ids: Vec<SongID> = self.songs.values()
+			.filter(|song| song.get_path().starts_with(path))
+			.map(|song| song.get_id())
+			.collect();
+		song_ids.sort_unstable();
+		song_ids
+	}
+
+	/// Changes the paths of the songs, whose file was at from or inside of the directory at from, to the new location.
+	/// Returns the ids of the moved songs.
+	pub fn move_files(&mut self, from: &Path, to: &Path) -> Vec<SongID> {
+		let (from, to) = (canonicalize(from), canonicalize(to));
+		let song_ids = self.get_songs_in(&from);
+		for song_id in &song_ids {
+			let song = self.songs.get_mut(song_id).unwrap();
+			let key = (song.path.clone(), song.track_range);
+			let new_path = match song.path.strip_prefix(&from) {
+				Ok(rest) if rest.as_os_str().is_empty() => to.to_path_buf(),
+				Ok(rest) => to.join(rest),
+				Err(_) => continue,
+			};
+			song.path = new_path.clone();
+			if self.path_index.get(&key) == Some(song_id) {
+				self.path_index.remove(&key);
+			}
+			self.path_index.entry((new_path, key.1)).or_insert(*song_id);
+			self.changed_songs.insert(*song_id);
+		}
+		song_ids
+	}
+
+	/// Returns the paths of the files of all songs.
+	pub fn known_paths(&self) -> HashSet<PathBuf> {
+		self.songs.values().map(|song| song.get_path().to_path_buf()).collect()
+	}
+
+	pub fn add_bookmark(&mut self, song_id: SongID, bookmark: Bookmark) {
+		if let Some(song) = self.get_mut(song_id) {
+			song.add_bookmark(bookmark);
+		}
+	}
+
+	pub fn remove_bookmark(&mut self, song_id: SongID, index: usize) {
+		if let Some(song) = self.get_mut(song_id) {
+			song.remove_bookmark(index);
+		}
+	}
+
+	pub fn dump(&self) -> io::Result<()> {
+		write_json_atomically(&get_song_buffer_path(), self)
 	}
 
 	pub fn load() -> Result<SongBuffer, ()> {
@@ -93,4 +375,117 @@ impl SongBuffer {
 			Ok(SongBuffer::new())
 		}
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_symlinked_files_are_imported_once() {
+		let directory = std::env::temp_dir().join(format!("musicus_test_song_buffer_{}", std::process::id()));
+		std::fs::create_dir_all(&directory).unwrap();
+		let path = directory.join("song.wav");
+		let link = directory.join("link.wav");
+		File::create(&path).unwrap();
+		let _ = std::fs::remove_file(&link);
+		std::os::unix::fs::symlink(&path, &link).unwrap();
+
+		let mut song_buffer = SongBuffer::new();
+		let song_id = song_buffer.import(&path, None);
+		let link_id = song_buffer.import(&link, None);
+		let relative_id = song_buffer.import(&directory.join("..").join(directory.file_name().unwrap()).join("song.wav"), None);
+		let _ = std::fs::remove_dir_all(&directory);
+
+		assert_eq!(song_id, link_id);
+		assert_eq!(song_id, relative_id);
+		assert_eq!(song_buffer.get_next_id(), 1);
+	}
+
+	#[test]
+	fn test_load_stored_song_buffer() {
+		let json = r#"{"songs": [
+			{"id": 4, "title": "b", "path": "/music/b.mp3", "total_duration": null},
+			{"id": 2, "title": "a", "path": "/music/a.mp3", "total_duration": {"secs": 3, "nanos": 0}}
+		], "next_id": 7}"#;
+		let mut song_buffer: SongBuffer = serde_json::from_str(json).unwrap();
+		assert_eq!(song_buffer.get(2).unwrap().get_title(), "a");
+		assert_eq!(song_buffer.import(Path::new("/music/b.mp3"), None), 4);
+		assert_eq!(song_buffer.import(Path::new("/music/c.mp3"), None), 7);
+
+		// songs are stored sorted by id
+		let stored: serde_json::Value = serde_json::to_value(&song_buffer).unwrap();
+		let ids: Vec<u64> = stored["songs"].as_array().unwrap().iter().map(|song| song["id"].as_u64().unwrap()).collect();
+		assert_eq!(ids, vec![2, 4, 7]);
+		assert_eq!(stored["next_id"], 8);
+	}
+
+	#[test]
+	fn test_load_legacy_paths() {
+		let directory = std::env::temp_dir().join(format!("musicus_test_legacy_paths_{}", std::process::id()));
+		std::fs::create_dir_all(&directory).unwrap();
+		let path = directory.join("song.wav");
+		let link = directory.join("link.wav");
+		File::create(&path).unwrap();
+		let _ = std::fs::remove_file(&link);
+		std::os::unix::fs::symlink(&path, &link).unwrap();
+
+		// lib.json of older versions stores the path, that the song was imported with
+		let json = serde_json::json!({"songs": [
+			{"id": 0, "title": "song", "path": link, "total_duration": null},
+			{"id": 1, "title": "missing", "path": "/unmounted/./a.mp3", "total_duration": null},
+		], "next_id": 2});
+		let mut song_buffer: SongBuffer = serde_json::from_value(json).unwrap();
+		let song_path = song_buffer.get(0).unwrap().get_path().to_path_buf();
+		let song_id = song_buffer.import(&path, None);
+		let link_id = song_buffer.import(&link, None);
+		let canonical_path = path.canonicalize().unwrap();
+		let _ = std::fs::remove_dir_all(&directory);
+
+		assert_eq!(song_path, canonical_path);
+		assert_eq!(song_id, 0);
+		assert_eq!(link_id, 0);
+		// paths, that cannot be canonicalized, are kept and found as given
+		assert_eq!(song_buffer.import(Path::new("/unmounted/./a.mp3"), None), 1);
+		assert_eq!(song_buffer.get_next_id(), 2);
+		// the canonical paths are written again
+		assert_eq!(song_buffer.take_changed_songs(), vec![0, 1]);
+		assert_eq!(serde_json::to_value(&song_buffer).unwrap()["canonical_paths"], true);
+	}
+
+	#[test]
+	fn test_check_playable_keeps_unchanged_songs() {
+		let mut song_buffer = SongBuffer::new();
+		let song_id = song_buffer.import(Path::new("/music/a.mp3"), None);
+		song_buffer.take_changed_songs();
+
+		assert!(song_buffer.check_playable(song_id));
+		assert!(song_buffer.take_changed_songs().is_empty());
+		// a broken song, whose file still cannot be read, stays broken and unchanged
+		song_buffer.set_broken(song_id, true);
+		song_buffer.take_changed_songs();
+		assert!(!song_buffer.check_playable(song_id));
+		assert!(song_buffer.take_changed_songs().is_empty());
+	}
+
+	#[test]
+	fn test_move_files() {
+		let mut song_buffer = SongBuffer::new();
+		let moved_id = song_buffer.import(Path::new("/music/old/a.mp3"), None);
+		let other_id = song_buffer.import(Path::new("/music/older/b.mp3"), None);
+		let fingerprint = Fingerprint { size: 3, hash: 7 };
+		song_buffer.set_fingerprint(moved_id, fingerprint);
+		assert_eq!(song_buffer.find_moved_file(fingerprint), Some(PathBuf::from("/music/old/a.mp3")));
+
+		assert_eq!(song_buffer.move_files(Path::new("/music/old"), Path::new("/music/new")), vec![moved_id]);
+		assert_eq!(song_buffer.get(moved_id).unwrap().get_path(), Path::new("/music/new/a.mp3"));
+		assert_eq!(song_buffer.get(other_id).unwrap().get_path(), Path::new("/music/older/b.mp3"));
+		// the moved song is found at its new path and the old path can be imported again
+		assert_eq!(song_buffer.import(Path::new("/music/new/a.mp3"), None), moved_id);
+		assert_ne!(song_buffer.import(Path::new("/music/old/a.mp3"), None), moved_id);
+
+		song_buffer.remove(moved_id);
+		assert_eq!(song_buffer.find_moved_file(fingerprint), None);
+		assert_ne!(song_buffer.import(Path::new("/music/new/a.mp3"), None), moved_id);
+	}
+}
@@ -0,0 +1,236 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::{unbounded, Receiver, Sender};
+use serde::{Serialize, Deserialize};
+use symphonia::core::codecs::CODEC_TYPE_NULL;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+
+use crate::audio_backend::format::{AudioFormat, get_probe};
+use crate::song::SongID;
+use crate::song::fingerprint::Fingerprint;
+use crate::song::song_buffer::SongBuffer;
+
+/// The metadata of a song, read from the ID3v2, Vorbis comment, FLAC or MP4 tags of its file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SongTags {
+	pub title: Option<String>,
+	pub artist: Option<String>,
+	pub album: Option<String>,
+	pub album_artist: Option<String>,
+	pub track_number: Option<u32>,
+	pub disc_number: Option<u32>,
+	pub year: Option<u32>,
+	pub genre: Option<String>,
+	pub composer: Option<String>,
+}
+
+impl SongTags {
+	/// Adds the given tags. Fields, that are already set, are kept.
+	pub fn add_tags(&mut self, tags: &[Tag]) {
+		for tag in tags {
+			let value = tag.value.to_string();
+			let value = value.trim();
+			if value.is_empty() {
+				continue;
+			}
+			match tag.std_key {
+				Some(StandardTagKey::TrackTitle) => set_text(&mut self.title, value),
+				Some(StandardTagKey::Artist) => set_text(&mut self.artist, value),
+				Some(StandardTagKey::Album) => set_text(&mut self.album, value),
+				Some(StandardTagKey::AlbumArtist) => set_text(&mut self.album_artist, value),
+				Some(StandardTagKey::TrackNumber) => self.track_number = self.track_number.or(parse_number(value)),
+				Some(StandardTagKey::DiscNumber) => self.disc_number = self.disc_number.or(parse_number(value)),
+				Some(StandardTagKey::Date | StandardTagKey::ReleaseDate | StandardTagKey::OriginalDate) => {
+					self.year = self.year.or(parse_year(value));
+				}
+				Some(StandardTagKey::Genre) => set_text(&mut self.genre, value),
+				Some(StandardTagKey::Composer) => set_text(&mut self.composer, value),
+				_ => {}
+			}
+		}
+	}
+
+	/// Removes the fields, that describe the whole file instead of a track of a CUE sheet.
+	pub fn for_track(self) -> SongTags {
+		SongTags {
+			title: None,
+			artist: None,
+			track_number: None,
+			composer: None,
+			..self
+		}
+	}
+}
+
+fn set_text(field: &mut Option<String>, value: &str) {
+	if field.is_none() {
+		*field = Some(value.to_string());
+	}
+}
+
+/// Parses track and disc numbers like "3" or "3/12".
+fn parse_number(value: &str) -> Option<u32> {
+	value.split('/').next()?.trim().parse().ok()
+}
+
+/// Parses the year of dates like "2003", "2003-05-01" or "2003-05-01T12:00:00".
+fn parse_year(value: &str) -> Option<u32> {
+	let year = value.get(..4)?;
+	if year.chars().all(|c| c.is_ascii_digit()) {
+		year.parse().ok()
+	} else {
+		None
+	}
+}
+
+/// Reads the tags and the duration of the given file. Returns None, if the file cannot be read. Files without known tags return empty tags.
+pub fn read_tags(path: &Path) -> Option<(SongTags, Option<Duration>)> {
+	let audio_format = AudioFormat::sniff(path)?;
+	let file = File::open(path).ok()?;
+	let media_source_stream = MediaSourceStream::new(Box::new(file), Default::default());
+	let mut hint = Hint::new();
+	hint.with_extension(audio_format.get_extension());
+
+	let mut tags = SongTags::default();
+	let mut probed = match get_probe().format(&hint, media_source_stream, &FormatOptions::default(), &MetadataOptions::default()) {
+		Ok(probed) => probed,
+		Err(_) => return Some((tags, None)),
+	};
+	// tags can be found before the container (e.g. ID3v2) or inside of it
+	if let Some(revision) = probed.metadata.get().as_ref().and_then(|metadata| metadata.current()) {
+		tags.add_tags(revision.tags());
+	}
+	if let Some(revision) = probed.format.metadata().current() {
+		tags.add_tags(revision.tags());
+	}
+	let duration = probed.format.tracks().iter()
+		.find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+		.and_then(|track| {
+			let n_frames = track.codec_params.n_frames?;
+			match (track.codec_params.time_base, track.codec_params.sample_rate) {
+				(Some(time_base), _) => {
+					let time = time_base.calc_time(n_frames);
+					Some(Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac))
+				}
+				(None, Some(sample_rate)) => Some(Duration::from_secs_f64(n_frames as f64 / sample_rate as f64)),
+				(None, None) => None,
+			}
+		});
+	Some((tags, duration))
+}
+
+/// What the tag scanner read from the file of a song.
+pub struct FileInfo {
+	pub tags: Option<SongTags>,
+	pub duration: Option<Duration>,
+	pub fingerprint: Option<Fingerprint>,
+}
+
+/// Reads the tags, durations and fingerprints of songs in a background thread, so reading many files does not block the ui.
+pub struct TagScanner {
+	request_sender: Sender<(SongID, PathBuf)>,
+	result_receiver: Receiver<(SongID, FileInfo)>,
+	/// songs with smaller ids were already checked for missing tags
+	checked_ids: SongID,
+}
+
+impl TagScanner {
+	pub fn new() -> TagScanner {
+		let (request_sender, request_receiver) = unbounded::<(SongID, PathBuf)>();
+		let (result_sender, result_receiver) = unbounded();
+		thread::Builder::new().name("tag scanner".to_string()).spawn(move || {
+			for (song_id, path) in request_receiver.iter() {
+				let (tags, duration) = read_tags(&path).unzip();
+				let file_info = FileInfo {
+					tags,
+					duration: duration.flatten(),
+					fingerprint: Fingerprint::of_file(&path).ok(),
+				};
+				if (file_info.tags.is_some() || file_info.fingerprint.is_some()) && result_sender.send((song_id, file_info)).is_err() {
+					break;
+				}
+			}
+		}).expect("Failed to spawn tag scanner thread");
+		TagScanner {
+			request_sender,
+			result_receiver,
+			checked_ids: 0,
+		}
+	}
+
+	/// Requests the tags and fingerprints of all songs, that were imported since the last call and were not scanned yet.
+	pub fn scan_new_songs(&mut self, song_buffer: &SongBuffer) {
+		let next_id = song_buffer.get_next_id();
+		for song_id in self.checked_ids..next_id {
+			if let Some(song) = song_buffer.get(song_id).filter(|song| song.get_tags().is_none() || song.get_fingerprint().is_none()) {
+				let _ = self.request_sender.send((song_id, song.get_path().to_path_buf()));
+			}
+		}
+		self.checked_ids = next_id;
+	}
+
+	/// Stores the tags and fingerprints, that were read since the last call, in the song buffer. Returns the ids of the updated songs.
+	pub fn receive_tags(&self, song_buffer: &mut SongBuffer) -> Vec<SongID> {
+		let mut song_ids = Vec::new();
+		while let Ok((song_id, file_info)) = self.result_receiver.try_recv() {
+			song_buffer.set_file_info(song_id, file_info);
+			song_ids.push(song_id);
+		}
+		song_ids
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use symphonia::core::meta::Value;
+
+	use super::*;
+
+	fn create_tag(std_key: StandardTagKey, value: &str) -> Tag {
+		Tag::new(Some(std_key), "", Value::from(value))
+	}
+
+	#[test]
+	fn test_add_tags() {
+		let mut tags = SongTags::default();
+		tags.add_tags(&[
+			create_tag(StandardTagKey::TrackTitle, " Intro "),
+			create_tag(StandardTagKey::Artist, ""),
+			create_tag(StandardTagKey::TrackNumber, "3/12"),
+			create_tag(StandardTagKey::DiscNumber, "x"),
+			create_tag(StandardTagKey::Date, "2003-05-01"),
+			create_tag(StandardTagKey::Genre, "Jazz"),
+		]);
+		// tags, that were read before, are kept
+		tags.add_tags(&[
+			create_tag(StandardTagKey::TrackTitle, "Other"),
+			create_tag(StandardTagKey::Artist, "Someone"),
+			create_tag(StandardTagKey::OriginalDate, "1999"),
+		]);
+		assert_eq!(tags, SongTags {
+			title: Some("Intro".to_string()),
+			artist: Some("Someone".to_string()),
+			track_number: Some(3),
+			year: Some(2003),
+			genre: Some("Jazz".to_string()),
+			..Default::default()
+		});
+	}
+
+	#[test]
+	fn test_parse_numbers() {
+		assert_eq!(parse_number("7"), Some(7));
+		assert_eq!(parse_number(" 2 / 2"), Some(2));
+		assert_eq!(parse_number("/3"), None);
+		assert_eq!(parse_year("2003"), Some(2003));
+		assert_eq!(parse_year("1987-11-02T00:00:00"), Some(1987));
+		assert_eq!(parse_year("May 2003"), None);
+		assert_eq!(parse_year("03"), None);
+	}
+}
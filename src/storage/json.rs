@@ -0,0 +1,192 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::{get_backup_directory, get_cache_path, get_config_directory, get_playlist_directory, get_song_buffer_path, load_playlist_files, Cache};
+use crate::song::playlist::{Playlist, PlaylistID};
+use crate::song::song_buffer::SongBuffer;
+
+/// lib.json is written, when its oldest unsaved change is this old. Writing a large library takes a while.
+const LIBRARY_SAVE_DELAY: Duration = Duration::from_secs(5);
+/// The number of older versions, that are kept of every file.
+const MAX_BACKUPS: usize = 3;
+
+/// Stores the songs in lib.json, the cache in cache.json and every playlist in its own file.
+/// Only changed files are written. Before a file is written the first time in a run of musicus, a backup of it is made.
+pub struct JsonStore {
+	/// the time of the oldest change of the songs, that is not written yet
+	library_changed: Option<Instant>,
+	/// the playlists as they were written last and their files
+	written_playlists: HashMap<PlaylistID, (PathBuf, Playlist)>,
+	written_cache: Option<String>,
+	/// the files, that were backed up in this run
+	backed_up: HashSet<PathBuf>,
+}
+
+impl JsonStore {
+	pub fn new() -> JsonStore {
+		JsonStore {
+			library_changed: None,
+			written_playlists: HashMap::new(),
+			written_cache: None,
+			backed_up: HashSet::new(),
+		}
+	}
+
+	pub fn load_cache(&mut self) -> Result<Cache, ()> {
+		let cache = Cache::load()?;
+		self.written_cache = serde_json::to_string(&cache).ok();
+		Ok(cache)
+	}
+
+	pub fn load_playlists(&mut self) -> Vec<Playlist> {
+		let playlist_files = load_playlist_files();
+		self.written_playlists = playlist_files.iter().map(|(path, playlist)| (playlist.id, (path.clone(), playlist.clone()))).collect();
+		playlist_files.into_iter().map(|(_, playlist)| playlist).collect()
+	}
+
+	/// Writes the changed playlists and the cache. The songs are written, when they changed LIBRARY_SAVE_DELAY ago or force is set.
+	/// The files of deleted and renamed playlists are removed.
+	pub fn save_changes(&mut self, song_buffer: &mut SongBuffer, playlists: &[Playlist], cache: &Cache, force: bool) -> io::Result<()> {
+		if !song_buffer.take_changed_songs().is_empty() && self.library_changed.is_none() {
+			self.library_changed = Some(Instant::now());
+		}
+		if self.library_changed.is_some_and(|changed| force || changed.elapsed() >= LIBRARY_SAVE_DELAY) {
+			self.back_up(&get_song_buffer_path())?;
+			song_buffer.dump()?;
+			self.library_changed = None;
+		}
+
+		let playlist_paths: HashMap<PlaylistID, PathBuf> = playlists.iter().map(|playlist| (playlist.id, get_playlist_path(playlist))).collect();
+		let stale_files = get_stale_playlist_files(&self.written_playlists, &playlist_paths);
+		for playlist in playlists {
+			let playlist_path = &playlist_paths[&playlist.id];
+			let unchanged = self.written_playlists.get(&playlist.id).is_some_and(|(path, written)| path == playlist_path && written == playlist);
+			if !unchanged {
+				self.back_up(playlist_path)?;
+				playlist.dump_to_file(playlist_path)?;
+				self.written_playlists.insert(playlist.id, (playlist_path.clone(), playlist.clone()));
+			}
+		}
+		// the new files are written, before the old ones are removed
+		for stale_file in stale_files {
+			self.remove(&stale_file)?;
+		}
+		self.written_playlists.retain(|playlist_id, _| playlist_paths.contains_key(playlist_id));
+
+		let cache_json = serde_json::to_string(cache)?;
+		if self.written_cache.as_ref() != Some(&cache_json) {
+			self.back_up(&get_cache_path())?;
+			cache.dump()?;
+			self.written_cache = Some(cache_json);
+		}
+		Ok(())
+	}
+
+	/// Copies the file to the backup directory, the first time it is written in this run.
+	fn back_up(&mut self, path: &Path) -> io::Result<()> {
+		if self.backed_up.insert(path.to_path_buf()) {
+			rotate_backups(path, &get_backup_directory_of(path))?;
+		}
+		Ok(())
+	}
+
+	/// Removes the file. It is always backed up before, as its last version would be lost otherwise.
+	fn remove(&mut self, path: &Path) -> io::Result<()> {
+		rotate_backups(path, &get_backup_directory_of(path))?;
+		match fs::remove_file(path) {
+			Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// Returns the directory, the backups of the given file are stored in. The backup directory mirrors the config directory.
+fn get_backup_directory_of(path: &Path) -> PathBuf {
+	let relative_directory = path.parent()
+		.and_then(|parent| parent.strip_prefix(get_config_directory()).ok())
+		.unwrap_or(Path::new(""));
+	get_backup_directory().join(relative_directory)
+}
+
+/// Returns the files of written playlists, that were deleted or renamed. Files, that another playlist is written to, are kept.
+fn get_stale_playlist_files(written_playlists: &HashMap<PlaylistID, (PathBuf, Playlist)>, playlist_paths: &HashMap<PlaylistID, PathBuf>) -> Vec<PathBuf> {
+	let mut stale_files: Vec<PathBuf> = written_playlists.iter()
+		.filter(|(playlist_id, (path, _))| playlist_paths.get(playlist_id) != Some(path))
+		.map(|(_, (path, _))| path.clone())
+		.filter(|path| !playlist_paths.values().any(|playlist_path| playlist_path == path))
+		.collect();
+	stale_files.sort();
+	stale_files.dedup();
+	stale_files
+}
+
+fn get_playlist_path(playlist: &Playlist) -> PathBuf {
+	get_playlist_directory().join(playlist.name.to_lowercase().replace(" ", "_")).with_extension("json")
+}
+
+/// Copies the file at path to "<backup_directory>/<filename>.1". Older backups are renamed to ".2", ".3" and so on.
+/// The oldest backup is replaced, if there are MAX_BACKUPS backups.
+fn rotate_backups(path: &Path, backup_directory: &Path) -> io::Result<()> {
+	let file_name = match path.file_name() {
+		Some(file_name) if path.is_file() => file_name.to_string_lossy(),
+		_ => return Ok(()),
+	};
+	fs::create_dir_all(backup_directory)?;
+	let get_backup_path = |index: usize| backup_directory.join(format!("{}.{}", file_name, index));
+	for index in (1..MAX_BACKUPS).rev() {
+		let backup_path = get_backup_path(index);
+		if backup_path.is_file() {
+			fs::rename(backup_path, get_backup_path(index + 1))?;
+		}
+	}
+	fs::copy(path, get_backup_path(1))?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_rotate_backups() {
+		let directory = std::env::temp_dir().join(format!("musicus_test_backups_{}", std::process::id()));
+		let backup_directory = directory.join("backups");
+		fs::create_dir_all(&directory).unwrap();
+		let path = directory.join("lib.json");
+		for version in 0..=MAX_BACKUPS {
+			fs::write(&path, version.to_string()).unwrap();
+			rotate_backups(&path, &backup_directory).unwrap();
+		}
+		let backups: Vec<String> = (1..=MAX_BACKUPS + 1)
+			.map(|index| fs::read_to_string(backup_directory.join(format!("lib.json.{}", index))).unwrap_or_default())
+			.collect();
+		let _ = fs::remove_dir_all(&directory);
+
+		// the newest backup comes first, the oldest one was dropped
+		assert_eq!(backups, vec!["3", "2", "1", ""]);
+	}
+
+	#[test]
+	fn test_stale_playlist_files() {
+		let playlist = |id: PlaylistID, name: &str| Playlist { id, name: name.to_string(), songs: Vec::new(), speed: None };
+		let written_playlists: HashMap<PlaylistID, (PathBuf, Playlist)> = [
+			(0, (PathBuf::from("kept.json"), playlist(0, "kept"))),
+			(1, (PathBuf::from("deleted.json"), playlist(1, "deleted"))),
+			(2, (PathBuf::from("old_name.json"), playlist(2, "old name"))),
+			(3, (PathBuf::from("taken.json"), playlist(3, "taken"))),
+		].into_iter().collect();
+		// playlist 3 was renamed and playlist 4 was created with its old name
+		let playlist_paths: HashMap<PlaylistID, PathBuf> = [
+			(0, PathBuf::from("kept.json")),
+			(2, PathBuf::from("new_name.json")),
+			(3, PathBuf::from("other.json")),
+			(4, PathBuf::from("taken.json")),
+		].into_iter().collect();
+
+		let stale_files = get_stale_playlist_files(&written_playlists, &playlist_paths);
+		assert_eq!(stale_files, vec![PathBuf::from("deleted.json"), PathBuf::from("old_name.json")]);
+	}
+}
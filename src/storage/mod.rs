@@ -0,0 +1,84 @@
+pub mod json;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use crate::config::Cache;
+use crate::song::SongID;
+use crate::song::playlist::Playlist;
+use crate::song::song_buffer::SongBuffer;
+use crate::storage::json::JsonStore;
+#[cfg(feature = "sqlite")]
+use crate::storage::sqlite::{get_library_path, SqliteStore};
+
+/// Where the songs, playlists and the cache are stored between runs of musicus.
+pub enum Storage {
+	Json(JsonStore), // lib.json, cache.json and one file per playlist
+	#[cfg(feature = "sqlite")]
+	Sqlite(SqliteStore), // lib.sqlite, that is written as changes happen
+}
+
+impl Storage {
+	/// Opens the SQLite library, if musicus is built with it. The JSON files are imported into a new library once.
+	/// Returns the error, if the JSON files have to be used instead.
+	pub fn open() -> (Storage, Option<String>) {
+		#[cfg(feature = "sqlite")]
+		return match SqliteStore::open(&get_library_path()).and_then(|mut store| store.import_json().map(|_| store)) {
+			Ok(store) => (Storage::Sqlite(store), None),
+			Err(e) => (Storage::Json(JsonStore::new()), Some(format!("Failed to open library: {}. Using JSON files.", e))),
+		};
+		#[cfg(not(feature = "sqlite"))]
+		(Storage::Json(JsonStore::new()), None)
+	}
+
+	pub fn load_cache(&mut self) -> Result<Cache, ()> {
+		match self {
+			Storage::Json(store) => store.load_cache(),
+			#[cfg(feature = "sqlite")]
+			Storage::Sqlite(store) => store.load_cache().map_err(|_| ()),
+		}
+	}
+
+	pub fn load_song_buffer(&mut self) -> Result<SongBuffer, ()> {
+		match self {
+			Storage::Json(_) => SongBuffer::load(),
+			#[cfg(feature = "sqlite")]
+			Storage::Sqlite(store) => store.load_song_buffer().map_err(|_| ()),
+		}
+	}
+
+	pub fn load_playlists(&mut self) -> Result<Vec<Playlist>, ()> {
+		match self {
+			Storage::Json(store) => Ok(store.load_playlists()),
+			#[cfg(feature = "sqlite")]
+			Storage::Sqlite(store) => store.load_playlists().map_err(|_| ()),
+		}
+	}
+
+	/// Writes the songs, playlists and cache, that changed since the last call. lib.json is written with a delay.
+	pub fn save_changes(&mut self, song_buffer: &mut SongBuffer, playlists: &[Playlist], cache: &Cache) -> Result<(), String> {
+		match self {
+			Storage::Json(store) => store.save_changes(song_buffer, playlists, cache, false).map_err(|e| e.to_string()),
+			#[cfg(feature = "sqlite")]
+			Storage::Sqlite(store) => store.save_changes(song_buffer, playlists, cache).map_err(|e| e.to_string()),
+		}
+	}
+
+	/// Writes everything, that was not written yet.
+	pub fn shutdown(&mut self, song_buffer: &mut SongBuffer, playlists: &[Playlist], cache: &Cache) -> Result<(), String> {
+		match self {
+			Storage::Json(store) => store.save_changes(song_buffer, playlists, cache, true).map_err(|e| e.to_string()),
+			#[cfg(feature = "sqlite")]
+			Storage::Sqlite(_) => self.save_changes(song_buffer, playlists, cache),
+		}
+	}
+
+	/// Counts that the given song started playing. Play statistics are only kept in the SQLite library.
+	#[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+	pub fn record_play(&mut self, song_id: SongID) -> Result<(), String> {
+		match self {
+			Storage::Json(_) => Ok(()),
+			#[cfg(feature = "sqlite")]
+			Storage::Sqlite(store) => store.record_play(song_id).map_err(|e| e.to_string()),
+		}
+	}
+}
@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::config::{get_config_directory, load_playlists, Cache};
+use crate::song::{Song, SongID};
+use crate::song::playlist::{Playlist, PlaylistID};
+use crate::song::song_buffer::SongBuffer;
+
+/// The SQL, that updates the schema from one version to the next. The version of a schema is the number of applied migrations.
+const MIGRATIONS: &[&str] = &[
+	// 1: songs are stored as JSON, so new fields of songs need no migration
+	"CREATE TABLE songs (
+		id INTEGER PRIMARY KEY,
+		path TEXT NOT NULL,
+		data TEXT NOT NULL
+	);
+	CREATE INDEX songs_path ON songs (path);
+	CREATE TABLE playlists (
+		id INTEGER PRIMARY KEY,
+		name TEXT NOT NULL,
+		speed REAL
+	);
+	CREATE TABLE playlist_songs (
+		playlist_id INTEGER NOT NULL,
+		position INTEGER NOT NULL,
+		song_id INTEGER NOT NULL,
+		PRIMARY KEY (playlist_id, position)
+	);
+	CREATE TABLE play_statistics (
+		song_id INTEGER PRIMARY KEY,
+		play_count INTEGER NOT NULL,
+		last_played INTEGER NOT NULL
+	);
+	CREATE TABLE state (
+		key TEXT PRIMARY KEY,
+		value TEXT NOT NULL
+	);",
+];
+
+pub fn get_library_path() -> PathBuf {
+	get_config_directory().join("lib.sqlite")
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+	Database(rusqlite::Error),
+	Format(serde_json::Error), // a stored value could not be converted
+	Import(String), // the JSON file, that could not be imported
+	NewerSchema(usize), // the schema version of a library, that was written by a newer version of musicus
+}
+
+impl Display for StorageError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			StorageError::Database(e) => write!(f, "database error: {}", e),
+			StorageError::Format(e) => write!(f, "invalid stored data: {}", e),
+			StorageError::Import(file) => write!(f, "failed to import {}", file),
+			StorageError::NewerSchema(version) => write!(f, "unknown schema version {}", version),
+		}
+	}
+}
+
+impl From<rusqlite::Error> for StorageError {
+	fn from(e: rusqlite::Error) -> StorageError {
+		StorageError::Database(e)
+	}
+}
+
+impl From<serde_json::Error> for StorageError {
+	fn from(e: serde_json::Error) -> StorageError {
+		StorageError::Format(e)
+	}
+}
+
+/// Stores songs, playlists, play statistics and the cache in a SQLite database.
+/// Only the songs and playlists, that changed, are written.
+pub struct SqliteStore {
+	connection: Connection,
+	/// the playlists as they were written last
+	written_playlists: HashMap<PlaylistID, Playlist>,
+	written_cache: Option<String>,
+	written_next_id: Option<SongID>,
+}
+
+impl SqliteStore {
+	/// Opens or creates the library at the given path and updates its schema.
+	pub fn open(path: &Path) -> Result<SqliteStore, StorageError> {
+		let mut connection = Connection::open(path)?;
+		// changes are written often in small transactions, which is much faster with a write-ahead log
+		connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+		connection.pragma_update(None, "synchronous", "NORMAL")?;
+		migrate(&mut connection)?;
+		Ok(SqliteStore {
+			connection,
+			written_playlists: HashMap::new(),
+			written_cache: None,
+			written_next_id: None,
+		})
+	}
+
+	/// Imports lib.json, cache.json and the playlist files, if they were not imported before. The JSON files are kept.
+	pub fn import_json(&mut self) -> Result<(), StorageError> {
+		if self.get_state("json_imported")?.is_some() {
+			return Ok(());
+		}
+		let song_buffer = SongBuffer::load().map_err(|_| StorageError::Import("lib.json".to_string()))?;
+		let cache = Cache::load().map_err(|_| StorageError::Import("cache.json".to_string()))?;
+		self.import(&song_buffer, &load_playlists(), &cache)
+	}
+
+	fn import(&mut self, song_buffer: &SongBuffer, playlists: &[Playlist], cache: &Cache) -> Result<(), StorageError> {
+		let transaction = self.connection.transaction()?;
+		for song in song_buffer.iter() {
+			write_song(&transaction, song)?;
+		}
+		for playlist in playlists {
+			write_playlist(&transaction, playlist)?;
+		}
+		set_state(&transaction, "cache", &serde_json::to_string(cache)?)?;
+		set_state(&transaction, "next_id", &song_buffer.get_next_id().to_string())?;
+		set_state(&transaction, "json_imported", "1")?;
+		transaction.commit()?;
+		Ok(())
+	}
+
+	pub fn load_cache(&mut self) -> Result<Cache, StorageError> {
+		let cache = match self.get_state("cache")? {
+			Some(cache_json) => serde_json::from_str(&cache_json)?,
+			None => Cache::default(),
+		};
+		self.written_cache = Some(serde_json::to_string(&cache)?);
+		Ok(cache)
+	}
+
+	pub fn load_song_buffer(&mut self) -> Result<SongBuffer, StorageError> {
+		let mut statement = self.connection.prepare("SELECT data FROM songs ORDER BY id")?;
+		let songs = statement.query_map([], |row| row.get::<_, String>(0))?
+			.map(|data| Ok(serde_json::from_str(&data?)?))
+			.collect::<Result<Vec<Song>, StorageError>>()?;
+		drop(statement);
+		let next_id = self.get_state("next_id")?.and_then(|next_id| next_id.parse().ok()).unwrap_or(0);
+		let song_buffer = SongBuffer::from_songs(songs, next_id);
+		self.written_next_id = Some(song_buffer.get_next_id());
+		Ok(song_buffer)
+	}
+
+	pub fn load_playlists(&mut self) -> Result<Vec<Playlist>, StorageError> {
+		let mut playlists = Vec::new();
+		let mut playlist_statement = self.connection.prepare("SELECT id, name, speed FROM playlists ORDER BY id")?;
+		let mut songs_statement = self.connection.prepare("SELECT song_id FROM playlist_songs WHERE playlist_id = ?1 ORDER BY position")?;
+		let rows = playlist_statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+		for row in rows {
+			let (id, name, speed): (PlaylistID, String, Option<f32>) = row?;
+			let songs = songs_statement.query_map([id], |row| row.get(0))?.collect::<Result<Vec<SongID>, _>>()?;
+			playlists.push(Playlist { id, name, songs, speed });
+		}
+		self.written_playlists = playlists.iter().map(|playlist| (playlist.id, playlist.clone())).collect();
+		Ok(playlists)
+	}
+
+	/// Writes the songs, playlists and cache, that changed since they were written or loaded, in one transaction.
+	pub fn save_changes(&mut self, song_buffer: &mut SongBuffer, playlists: &[Playlist], cache: &Cache) -> Result<(), StorageError> {
+		let cache_json = serde_json::to_string(cache)?;
+		let song_ids = song_buffer.take_changed_songs();
+		let changed_playlists: Vec<&Playlist> = playlists.iter()
+			.filter(|playlist| self.written_playlists.get(&playlist.id) != Some(playlist))
+			.collect();
+		let removed_playlists: Vec<PlaylistID> = self.written_playlists.keys()
+			.filter(|id| !playlists.iter().any(|playlist| playlist.id == **id))
+			.copied()
+			.collect();
+		let cache_changed = self.written_cache.as_ref() != Some(&cache_json);
+		let next_id = song_buffer.get_next_id();
+		if song_ids.is_empty() && changed_playlists.is_empty() && removed_playlists.is_empty() && !cache_changed && self.written_next_id == Some(next_id) {
+			return Ok(());
+		}
+
+		let result = (|| -> Result<(), StorageError> {
+			let transaction = self.connection.transaction()?;
+			for song_id in &song_ids {
+				match song_buffer.get(*song_id) {
+					Some(song) => write_song(&transaction, song)?,
+					None => {
+						transaction.execute("DELETE FROM songs WHERE id = ?1", [song_id])?;
+					}
+				}
+			}
+			for playlist in &changed_playlists {
+				write_playlist(&transaction, playlist)?;
+			}
+			for playlist_id in &removed_playlists {
+				transaction.execute("DELETE FROM playlists WHERE id = ?1", [playlist_id])?;
+				transaction.execute("DELETE FROM playlist_songs WHERE playlist_id = ?1", [playlist_id])?;
+			}
+			if cache_changed {
+				set_state(&transaction, "cache", &cache_json)?;
+			}
+			set_state(&transaction, "next_id", &next_id.to_string())?;
+			transaction.commit()?;
+			Ok(())
+		})();
+		if let Err(e) = result {
+			// the songs are written with the next changes
+			song_buffer.mark_changed(&song_ids);
+			return Err(e);
+		}
+
+		for playlist in changed_playlists {
+			self.written_playlists.insert(playlist.id, playlist.clone());
+		}
+		for playlist_id in removed_playlists {
+			self.written_playlists.remove(&playlist_id);
+		}
+		self.written_cache = Some(cache_json);
+		self.written_next_id = Some(next_id);
+		Ok(())
+	}
+
+	/// Counts that the given song started playing and remembers the time.
+	pub fn record_play(&mut self, song_id: SongID) -> Result<(), StorageError> {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+		self.connection.execute(
+			"INSERT INTO play_statistics (song_id, play_count, last_played) VALUES (?1, 1, ?2)
+			ON CONFLICT (song_id) DO UPDATE SET play_count = play_count + 1, last_played = ?2",
+			params![song_id, now],
+		)?;
+		Ok(())
+	}
+
+	fn get_state(&self, key: &str) -> Result<Option<String>, StorageError> {
+		Ok(self.connection.query_row("SELECT value FROM state WHERE key = ?1", [key], |row| row.get(0)).optional()?)
+	}
+}
+
+/// Creates the schema or updates it to the newest version.
+fn migrate(connection: &mut Connection) -> Result<(), StorageError> {
+	let transaction = connection.transaction()?;
+	transaction.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)", [])?;
+	let version: usize = transaction.query_row("SELECT version FROM schema_version", [], |row| row.get(0)).optional()?.unwrap_or(0);
+	if version > MIGRATIONS.len() {
+		return Err(StorageError::NewerSchema(version));
+	}
+	for migration in &MIGRATIONS[version..] {
+		transaction.execute_batch(migration)?;
+	}
+	transaction.execute("DELETE FROM schema_version", [])?;
+	transaction.execute("INSERT INTO schema_version (version) VALUES (?1)", [MIGRATIONS.len()])?;
+	transaction.commit()?;
+	Ok(())
+}
+
+fn write_song(connection: &Connection, song: &Song) -> Result<(), StorageError> {
+	connection.execute(
+		"INSERT OR REPLACE INTO songs (id, path, data) VALUES (?1, ?2, ?3)",
+		params![song.get_id(), song.get_path().to_string_lossy(), serde_json::to_string(song)?],
+	)?;
+	Ok(())
+}
+
+fn write_playlist(connection: &Connection, playlist: &Playlist) -> rusqlite::Result<()> {
+	connection.execute(
+		"INSERT OR REPLACE INTO playlists (id, name, speed) VALUES (?1, ?2, ?3)",
+		params![playlist.id, playlist.name, playlist.speed],
+	)?;
+	connection.execute("DELETE FROM playlist_songs WHERE playlist_id = ?1", [playlist.id])?;
+	let mut statement = connection.prepare_cached("INSERT INTO playlist_songs (playlist_id, position, song_id) VALUES (?1, ?2, ?3)")?;
+	for (position, song_id) in playlist.songs.iter().enumerate() {
+		statement.execute(params![playlist.id, position, song_id])?;
+	}
+	Ok(())
+}
+
+fn set_state(connection: &Connection, key: &str, value: &str) -> rusqlite::Result<()> {
+	connection.execute("INSERT OR REPLACE INTO state (key, value) VALUES (?1, ?2)", [key, value])?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn open_store() -> SqliteStore {
+		SqliteStore::open(Path::new(":memory:")).unwrap()
+	}
+
+	#[test]
+	fn test_migrate_once() {
+		let mut store = open_store();
+		migrate(&mut store.connection).unwrap();
+		let version: usize = store.connection.query_row("SELECT version FROM schema_version", [], |row| row.get(0)).unwrap();
+		assert_eq!(version, MIGRATIONS.len());
+
+		store.connection.execute("UPDATE schema_version SET version = ?1", [MIGRATIONS.len() + 1]).unwrap();
+		assert!(matches!(migrate(&mut store.connection), Err(StorageError::NewerSchema(_))));
+	}
+
+	#[test]
+	fn test_save_changes() {
+		let mut store = open_store();
+		let mut song_buffer = SongBuffer::new();
+		let first = song_buffer.import(Path::new("/music/a.mp3"), None);
+		let second = song_buffer.import(Path::new("/music/b.mp3"), None);
+		let mut playlists = vec![
+			Playlist { id: 0, name: "first".to_string(), songs: vec![second, first], speed: None },
+			Playlist { id: 1, name: "second".to_string(), songs: vec![first], speed: Some(1.5) },
+		];
+		store.import(&song_buffer, &playlists, &Cache::default()).unwrap();
+		song_buffer.take_changed_songs();
+		assert_eq!(store.load_playlists().unwrap(), playlists);
+
+		song_buffer.set_speed(first, Some(2.0));
+		song_buffer.import(Path::new("/music/c.mp3"), None);
+		playlists.remove(1);
+		playlists[0].songs.push(first);
+		store.save_changes(&mut song_buffer, &playlists, &Cache::default()).unwrap();
+
+		let mut loaded_song_buffer = store.load_song_buffer().unwrap();
+		assert_eq!(loaded_song_buffer.get(first).unwrap().get_speed(), Some(2.0));
+		assert_eq!(loaded_song_buffer.get_next_id(), 3);
+		assert!(loaded_song_buffer.take_changed_songs().is_empty());
+		assert_eq!(store.load_playlists().unwrap(), playlists);
+	}
+
+	#[test]
+	fn test_record_play() {
+		let mut store = open_store();
+		store.record_play(4).unwrap();
+		store.record_play(4).unwrap();
+		let play_count: u32 = store.connection.query_row("SELECT play_count FROM play_statistics WHERE song_id = 4", [], |row| row.get(0)).unwrap();
+		assert_eq!(play_count, 2);
+	}
+}
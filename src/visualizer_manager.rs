@@ -0,0 +1,215 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crossbeam::Receiver;
+use realfft::{RealFftPlanner, RealToComplex};
+
+use crate::audio_backend::SamplesChunk;
+use crate::render::{RenderObject, RenderPanel, RenderEntry, RenderColor, Alignment};
+
+/// The number of chunks, that can wait in the sample tap, before the audio thread drops them.
+pub const SAMPLE_TAP_SIZE: usize = 64;
+/// The number of mono samples the spectrum is computed of.
+const FFT_SIZE: usize = 2048;
+/// The frequency range shown by the spectrum. Bars are spaced logarithmically.
+const MIN_FREQUENCY: f32 = 30.0;
+const MAX_FREQUENCY: f32 = 16000.0;
+/// Bars are empty at MIN_DB and full at 0 dB.
+const MIN_DB: f32 = -72.0;
+/// Falling bars lose this many dB per update, so the spectrum does not flicker.
+const SPECTRUM_DECAY_DB: f32 = 3.0;
+/// The number of peaks, that are kept for the scrolling waveform.
+const MAX_PEAKS: usize = 1024;
+/// Eighths of a block, the first one is empty.
+const BLOCKS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Shows a spectrum analyzer and a scrolling waveform of the samples, that are played at the moment.
+/// The samples are copied out of the audio thread by the sample tap of the audio backend.
+pub struct VisualizerManager {
+	sample_receiver: Receiver<SamplesChunk>,
+	enabled: bool,
+	/// the newest played samples, mixed to mono
+	samples: VecDeque<f32>,
+	sample_rate: u32,
+	/// the highest absolute sample value of every received chunk, the newest at the back
+	peaks: VecDeque<f32>,
+	fft: Arc<dyn RealToComplex<f32>>,
+	window: Vec<f32>,
+	/// the level in dB of every frequency bin of the fft
+	spectrum: Vec<f32>,
+}
+
+impl VisualizerManager {
+	pub fn new(sample_receiver: Receiver<SamplesChunk>) -> VisualizerManager {
+		let fft = RealFftPlanner::<f32>::new().plan_fft_forward(FFT_SIZE);
+		// hann window
+		let window = (0..FFT_SIZE)
+			.map(|index| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * index as f32 / FFT_SIZE as f32).cos())
+			.collect();
+		VisualizerManager {
+			sample_receiver,
+			enabled: false,
+			samples: VecDeque::from(vec![0.0; FFT_SIZE]),
+			sample_rate: 44100,
+			peaks: VecDeque::new(),
+			fft,
+			window,
+			spectrum: vec![MIN_DB; FFT_SIZE / 2 + 1],
+		}
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	/// The sample tap should only be enabled, while the visualizer is shown. Disabling clears the shown samples.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+		// chunks, that were sent before, are outdated
+		while self.sample_receiver.try_recv().is_ok() {}
+		if !enabled {
+			self.samples.iter_mut().for_each(|sample| *sample = 0.0);
+			self.peaks.clear();
+			self.spectrum.fill(MIN_DB);
+		}
+	}
+
+	/// Receives the chunks of the sample tap and updates the spectrum. Returns true, if new samples were received.
+	pub fn receive_samples(&mut self) -> bool {
+		let mut received = false;
+		while let Ok(chunk) = self.sample_receiver.try_recv() {
+			let channels = chunk.channels.max(1) as usize;
+			let samples = &chunk.data[..chunk.length];
+			for frame in samples.chunks_exact(channels) {
+				self.samples.pop_front();
+				self.samples.push_back(frame.iter().sum::<f32>() / channels as f32);
+			}
+			self.peaks.push_back(samples.iter().fold(0.0, |peak: f32, sample| peak.max(sample.abs())));
+			if self.peaks.len() > MAX_PEAKS {
+				self.peaks.pop_front();
+			}
+			self.sample_rate = chunk.sample_rate;
+			received = true;
+		}
+		if received {
+			self.update_spectrum();
+		}
+		received
+	}
+
+	fn update_spectrum(&mut self) {
+		let mut input: Vec<f32> = self.samples.iter().zip(&self.window).map(|(sample, factor)| sample * factor).collect();
+		let mut output = self.fft.make_output_vec();
+		if self.fft.process(&mut input, &mut output).is_err() {
+			return;
+		}
+		// a full scale sine has an amplitude of 1.0 (0 dB)
+		let normalization = 2.0 / self.window.iter().sum::<f32>();
+		for (level, bin) in self.spectrum.iter_mut().zip(output) {
+			let db = 20.0 * (bin.norm() * normalization).max(1e-6).log10();
+			*level = db.max(*level - SPECTRUM_DECAY_DB);
+		}
+	}
+
+	/// Returns the level of the given frequency band between 0.0 and 1.0.
+	fn get_band_level(&self, low_frequency: f32, high_frequency: f32) -> f32 {
+		let bin_width = self.sample_rate as f32 / FFT_SIZE as f32;
+		let low_bin = ((low_frequency / bin_width).round() as usize).min(self.spectrum.len() - 1);
+		let high_bin = ((high_frequency / bin_width).round() as usize).clamp(low_bin, self.spectrum.len() - 1);
+		let db = self.spectrum[low_bin..=high_bin].iter().fold(MIN_DB, |max, level| max.max(*level));
+		(1.0 - db / MIN_DB).clamp(0.0, 1.0)
+	}
+
+	pub fn get_render_object(&self, num_rows: usize, num_columns: usize) -> RenderObject {
+		let mut render_object = RenderObject::new(Alignment::Left);
+		let mut panel = RenderPanel::new(0);
+		let num_columns = num_columns.max(1);
+
+		// the spectrum uses two thirds of the rows, the waveform the rest. Two rows are used for labels and the peak meter.
+		let num_rows = num_rows.saturating_sub(2).max(2);
+		let spectrum_rows = (num_rows * 2 / 3).max(1);
+		let waveform_rows = (num_rows - spectrum_rows).max(1);
+
+		let band_factor = MAX_FREQUENCY / MIN_FREQUENCY;
+		let bands: Vec<f32> = (0..num_columns).map(|column| {
+			let low_frequency = MIN_FREQUENCY * band_factor.powf(column as f32 / num_columns as f32);
+			let high_frequency = MIN_FREQUENCY * band_factor.powf((column + 1) as f32 / num_columns as f32);
+			self.get_band_level(low_frequency, high_frequency)
+		}).collect();
+		for line in render_bars(&bands, spectrum_rows) {
+			panel.entries.push(RenderEntry::new(line, RenderColor::Cyan, RenderColor::Black));
+		}
+		let high_label = format!("{} kHz", MAX_FREQUENCY / 1000.0);
+		let low_label = format!("{} Hz", MIN_FREQUENCY);
+		let label = format!("{}{:>width$}", low_label, high_label, width = num_columns.saturating_sub(low_label.len()));
+		panel.entries.push(RenderEntry::new(label, RenderColor::White, RenderColor::Black));
+
+		// the newest peak is shown on the right
+		let mut peaks: Vec<f32> = self.peaks.iter().rev().take(num_columns).rev().copied().collect();
+		peaks.splice(0..0, std::iter::repeat_n(0.0, num_columns - peaks.len()));
+		for line in render_bars(&peaks, waveform_rows) {
+			panel.entries.push(RenderEntry::new(line, RenderColor::Yellow, RenderColor::Black));
+		}
+
+		let peak = self.peaks.back().copied().unwrap_or(0.0);
+		let peak_db = 20.0 * peak.max(1e-6).log10();
+		let meter_width = num_columns.saturating_sub(20);
+		let meter_length = ((1.0 - peak_db / MIN_DB).clamp(0.0, 1.0) * meter_width as f32).round() as usize;
+		let color = if peak >= 1.0 { RenderColor::Red } else { RenderColor::White };
+		let meter = format!("peak {:>7.1} dB  {}{}", peak_db.max(MIN_DB), "█".repeat(meter_length), "─".repeat(meter_width - meter_length));
+		panel.entries.push(RenderEntry::new(meter, color, RenderColor::Black));
+
+		render_object.panels.push(panel);
+		render_object
+	}
+}
+
+/// Renders the given values between 0.0 and 1.0 as vertical bars with the given height. Returns one line per row, the top row first.
+fn render_bars(values: &[f32], num_rows: usize) -> Vec<String> {
+	(0..num_rows).map(|row| {
+		let row_bottom = (num_rows - 1 - row) as f32;
+		values.iter().map(|value| {
+			let fill = (value.clamp(0.0, 1.0) * num_rows as f32 - row_bottom).clamp(0.0, 1.0);
+			BLOCKS[(fill * 8.0).round() as usize]
+		}).collect()
+	}).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use crossbeam::bounded;
+
+	use super::*;
+
+	#[test]
+	fn test_render_bars() {
+		let lines = render_bars(&[0.0, 0.25, 0.5, 1.0], 2);
+		assert_eq!(lines, vec!["   █".to_string(), " ▄██".to_string()]);
+	}
+
+	#[test]
+	fn test_spectrum_peak_at_sine_frequency() {
+		let (sender, receiver) = bounded(SAMPLE_TAP_SIZE);
+		let mut visualizer = VisualizerManager::new(receiver);
+		visualizer.set_enabled(true);
+		let samples: Vec<f32> = (0..4096).map(|index| (2.0 * std::f32::consts::PI * 1000.0 * index as f32 / 48000.0).sin()).collect();
+		for (index, data) in samples.chunks_exact(1024).enumerate() {
+			sender.send(SamplesChunk {
+				channels: 1,
+				sample_rate: 48000,
+				start_position: index * 1024,
+				length: 1024,
+				data: Arc::new(data.try_into().unwrap()),
+				song_id: 0,
+				last_chunk: false,
+				generation: 0,
+			}).unwrap();
+		}
+		assert!(visualizer.receive_samples());
+
+		// a full scale sine fills the bar of its frequency, other bands stay low
+		assert!(visualizer.get_band_level(950.0, 1050.0) > 0.95);
+		assert!(visualizer.get_band_level(4000.0, 8000.0) < 0.3);
+		assert!((visualizer.peaks[0] - 1.0).abs() < 1e-3);
+	}
+}